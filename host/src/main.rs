@@ -0,0 +1,353 @@
+//! Loads the CLAP build of the plugin and checks that it processes audio and restores its state.
+//!
+//! Usage: `sarus-test-host <path to libsarus_plugin.so>`. Exits with an error if any check fails.
+
+use std::ffi::{c_void, CString};
+use std::io::Read;
+use std::os::raw::c_char;
+use std::ptr;
+
+use anyhow::{anyhow, bail};
+
+use clap_sys::audio_buffer::clap_audio_buffer;
+use clap_sys::entry::clap_plugin_entry;
+use clap_sys::events::{clap_event_header, clap_input_events, clap_output_events};
+use clap_sys::ext::state::{clap_plugin_state, CLAP_EXT_STATE};
+use clap_sys::factory::plugin_factory::{clap_plugin_factory, CLAP_PLUGIN_FACTORY_ID};
+use clap_sys::host::clap_host;
+use clap_sys::plugin::clap_plugin;
+use clap_sys::process::CLAP_PROCESS_ERROR;
+use clap_sys::process::clap_process;
+use clap_sys::stream::{clap_istream, clap_ostream};
+use clap_sys::version::CLAP_VERSION;
+
+const SAMPLE_RATE: f64 = 48000.0;
+const BLOCK_SIZE: usize = 256;
+
+fn main() -> anyhow::Result<()> {
+    let path = std::env::args().nth(1).ok_or_else(|| anyhow!("Usage: sarus-test-host <plugin>"))?;
+
+    let mut plugin = Plugin::load(&path)?;
+    println!("Loaded {}", plugin.name());
+
+    plugin.activate()?;
+
+    // The default patch wires INPUT straight to OUTPUT
+    let input = (0..SAMPLE_RATE as usize).map(|n| (2.0 * std::f32::consts::PI * 440.0 * n as f32 / SAMPLE_RATE as f32).sin() * 0.5).collect::<Vec<_>>();
+    let output = plugin.process(&input)?;
+    check_gain(&input, &output, 1.0)?;
+    println!("Passthrough: ok");
+
+    // Restoring a saved state and saving again gives the same state
+    let state = plugin.save_state()?;
+    plugin.load_state(&state)?;
+    let resaved = plugin.save_state()?;
+    if serde_json::from_slice::<serde_json::Value>(&state)? != serde_json::from_slice::<serde_json::Value>(&resaved)? {
+        bail!("State changed after being restored:\n{}\n{}", String::from_utf8_lossy(&state), String::from_utf8_lossy(&resaved));
+    }
+    println!("State round trip: ok");
+
+    // Replacing the patch in the state changes what's played
+    plugin.load_state(&with_gain_patch(&state)?)?;
+    let output = plugin.process(&input)?;
+    check_gain(&input, &output, 2.0)?;
+    println!("Restored patch: ok");
+
+    Ok(())
+}
+
+fn check_gain(input: &[f32], output: &[f32], gain: f32) -> anyhow::Result<()> {
+    if input.len() != output.len() {
+        bail!("Expected {} samples but got {}", input.len(), output.len());
+    }
+
+    for (n, (a, b)) in input.iter().zip(output.iter()).enumerate() {
+        if (a * gain - b).abs() > 1e-6 {
+            bail!("Sample {} should be {} but is {}", n, a * gain, b);
+        }
+    }
+
+    Ok(())
+}
+
+// Edits a saved state so its patch doubles the input, using the `double` function of the built-in code
+fn with_gain_patch(state: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut state: serde_json::Value = serde_json::from_slice(state)?;
+
+    // Persisted fields are stored as serialized strings
+    let field = state.pointer_mut("/fields/patch").ok_or_else(|| anyhow!("State has no patch"))?;
+    let mut patch: serde_json::Value = serde_json::from_str(field.as_str().ok_or_else(|| anyhow!("Patch isn't a string"))?)?;
+
+    let nodes = patch["nodes"].as_array_mut().ok_or_else(|| anyhow!("Patch has no nodes"))?;
+    let index_of = |nodes: &Vec<serde_json::Value>, id: &str| nodes.iter().position(|node| node["id"] == id).ok_or_else(|| anyhow!("Patch has no {} node", id));
+    let input = index_of(nodes, "INPUT")?;
+    let output = index_of(nodes, "OUTPUT")?;
+
+    nodes.push(serde_json::json!({
+        "func_name": "double",
+        "id": "gain",
+        "port_defaults": [0.0, 0.0],
+        "position": [0.0, 0.0],
+    }));
+    let gain = nodes.len() - 1;
+
+    patch["connections"] = serde_json::json!([
+        { "src_node": input, "dst_node": gain, "src_port": 0, "dst_port": 0 },
+        { "src_node": gain, "dst_node": output, "src_port": 0, "dst_port": 0 },
+    ]);
+
+    *field = serde_json::Value::String(serde_json::to_string(&patch)?);
+
+    Ok(serde_json::to_vec(&state)?)
+}
+
+struct Plugin {
+    entry: *const clap_plugin_entry,
+    plugin: *const clap_plugin,
+    // Pointed to by the plugin so it has to outlive it
+    _host: Box<clap_host>,
+    active: bool,
+    steady_time: i64,
+    // Unloaded last
+    _library: libloading::Library,
+}
+
+impl Plugin {
+    fn load(path: &str) -> anyhow::Result<Self> {
+        unsafe {
+            let library = libloading::Library::new(path)?;
+            let entry = *library.get::<*const clap_plugin_entry>(b"clap_entry\0")?;
+
+            let path = CString::new(path)?;
+            if !((*entry).init.ok_or_else(|| anyhow!("Missing init"))?)(path.as_ptr()) {
+                bail!("Failed to initialize {}", path.to_string_lossy());
+            }
+
+            let get_factory = (*entry).get_factory.ok_or_else(|| anyhow!("Missing get_factory"))?;
+            let factory = get_factory(CLAP_PLUGIN_FACTORY_ID.as_ptr()) as *const clap_plugin_factory;
+            if factory.is_null() {
+                bail!("No plugin factory");
+            }
+
+            let get_count = (*factory).get_plugin_count.ok_or_else(|| anyhow!("Missing get_plugin_count"))?;
+            if get_count(factory) == 0 {
+                bail!("The factory has no plugins");
+            }
+
+            let get_descriptor = (*factory).get_plugin_descriptor.ok_or_else(|| anyhow!("Missing get_plugin_descriptor"))?;
+            let descriptor = get_descriptor(factory, 0);
+
+            let host = Box::new(clap_host {
+                clap_version: CLAP_VERSION,
+                host_data: ptr::null_mut(),
+                name: b"sarus-test-host\0".as_ptr() as *const c_char,
+                vendor: b"sarus\0".as_ptr() as *const c_char,
+                url: b"\0".as_ptr() as *const c_char,
+                version: b"0.1.0\0".as_ptr() as *const c_char,
+                get_extension: Some(host_get_extension),
+                request_restart: Some(host_request),
+                request_process: Some(host_request),
+                request_callback: Some(host_request),
+            });
+
+            let create_plugin = (*factory).create_plugin.ok_or_else(|| anyhow!("Missing create_plugin"))?;
+            let plugin = create_plugin(factory, &*host, (*descriptor).id);
+            if plugin.is_null() {
+                bail!("Failed to create the plugin");
+            }
+
+            let plugin = Self {
+                entry,
+                plugin,
+                _host: host,
+                active: false,
+                steady_time: 0,
+                _library: library,
+            };
+
+            if !((*plugin.plugin).init.ok_or_else(|| anyhow!("Missing init"))?)(plugin.plugin) {
+                bail!("Failed to initialize the plugin");
+            }
+
+            Ok(plugin)
+        }
+    }
+
+    fn name(&self) -> String {
+        unsafe { std::ffi::CStr::from_ptr((*(*self.plugin).desc).name).to_string_lossy().into_owned() }
+    }
+
+    fn activate(&mut self) -> anyhow::Result<()> {
+        unsafe {
+            let plugin = &*self.plugin;
+            if !(plugin.activate.ok_or_else(|| anyhow!("Missing activate"))?)(self.plugin, SAMPLE_RATE, 1, BLOCK_SIZE as u32) {
+                bail!("Failed to activate the plugin");
+            }
+            self.active = true;
+
+            if !(plugin.start_processing.ok_or_else(|| anyhow!("Missing start_processing"))?)(self.plugin) {
+                bail!("Failed to start processing");
+            }
+        }
+
+        Ok(())
+    }
+
+    // Plays the same signal into both input channels and returns the left output, checking the right matches it
+    fn process(&mut self, input: &[f32]) -> anyhow::Result<Vec<f32>> {
+        let mut output = Vec::with_capacity(input.len());
+
+        let in_events = clap_input_events {
+            ctx: ptr::null_mut(),
+            size: Some(input_events_size),
+            get: Some(input_events_get),
+        };
+        let out_events = clap_output_events {
+            ctx: ptr::null_mut(),
+            try_push: Some(output_events_try_push),
+        };
+
+        for chunk in input.chunks(BLOCK_SIZE) {
+            let mut left = chunk.to_vec();
+            let mut right = chunk.to_vec();
+            let mut out_left = vec![0.0f32; chunk.len()];
+            let mut out_right = vec![0.0f32; chunk.len()];
+
+            let mut in_channels = [left.as_mut_ptr(), right.as_mut_ptr()];
+            let mut out_channels = [out_left.as_mut_ptr(), out_right.as_mut_ptr()];
+
+            let audio_inputs = clap_audio_buffer {
+                data32: in_channels.as_mut_ptr(),
+                data64: ptr::null_mut(),
+                channel_count: 2,
+                latency: 0,
+                constant_mask: 0,
+            };
+            let mut audio_outputs = clap_audio_buffer {
+                data32: out_channels.as_mut_ptr(),
+                data64: ptr::null_mut(),
+                channel_count: 2,
+                latency: 0,
+                constant_mask: 0,
+            };
+
+            let process = clap_process {
+                steady_time: self.steady_time,
+                frames_count: chunk.len() as u32,
+                transport: ptr::null(),
+                audio_inputs: &audio_inputs,
+                audio_outputs: &mut audio_outputs,
+                audio_inputs_count: 1,
+                audio_outputs_count: 1,
+                in_events: &in_events,
+                out_events: &out_events,
+            };
+
+            let status = unsafe { ((*self.plugin).process.ok_or_else(|| anyhow!("Missing process"))?)(self.plugin, &process) };
+            if status == CLAP_PROCESS_ERROR {
+                bail!("The plugin failed to process a block");
+            }
+
+            if out_left != out_right {
+                bail!("The left and right outputs differ");
+            }
+
+            output.extend_from_slice(&out_left);
+            self.steady_time += chunk.len() as i64;
+        }
+
+        Ok(output)
+    }
+
+    fn state_extension(&self) -> anyhow::Result<&clap_plugin_state> {
+        unsafe {
+            let get_extension = (*self.plugin).get_extension.ok_or_else(|| anyhow!("Missing get_extension"))?;
+            let state = get_extension(self.plugin, CLAP_EXT_STATE.as_ptr()) as *const clap_plugin_state;
+            state.as_ref().ok_or_else(|| anyhow!("The plugin has no state extension"))
+        }
+    }
+
+    fn save_state(&self) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let stream = clap_ostream {
+            ctx: &mut data as *mut Vec<u8> as *mut c_void,
+            write: Some(write_stream),
+        };
+
+        let save = self.state_extension()?.save.ok_or_else(|| anyhow!("Missing save"))?;
+        if !unsafe { save(self.plugin, &stream) } {
+            bail!("Failed to save the state");
+        }
+
+        Ok(data)
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let mut reader = data;
+        let stream = clap_istream {
+            ctx: &mut reader as *mut &[u8] as *mut c_void,
+            read: Some(read_stream),
+        };
+
+        let load = self.state_extension()?.load.ok_or_else(|| anyhow!("Missing load"))?;
+        if !unsafe { load(self.plugin, &stream) } {
+            bail!("Failed to load the state");
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        unsafe {
+            let plugin = &*self.plugin;
+            if self.active {
+                if let Some(stop_processing) = plugin.stop_processing {
+                    stop_processing(self.plugin);
+                }
+                if let Some(deactivate) = plugin.deactivate {
+                    deactivate(self.plugin);
+                }
+            }
+            if let Some(destroy) = plugin.destroy {
+                destroy(self.plugin);
+            }
+            if let Some(deinit) = (*self.entry).deinit {
+                deinit();
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn host_get_extension(_host: *const clap_host, _extension_id: *const c_char) -> *const c_void {
+    ptr::null()
+}
+
+unsafe extern "C" fn host_request(_host: *const clap_host) {}
+
+unsafe extern "C" fn input_events_size(_list: *const clap_input_events) -> u32 {
+    0
+}
+
+unsafe extern "C" fn input_events_get(_list: *const clap_input_events, _index: u32) -> *const clap_event_header {
+    ptr::null()
+}
+
+unsafe extern "C" fn output_events_try_push(_list: *const clap_output_events, _event: *const clap_event_header) -> bool {
+    true
+}
+
+unsafe extern "C" fn write_stream(stream: *const clap_ostream, buffer: *const c_void, size: u64) -> i64 {
+    let data = &mut *((*stream).ctx as *mut Vec<u8>);
+    data.extend_from_slice(std::slice::from_raw_parts(buffer as *const u8, size as usize));
+    size as i64
+}
+
+unsafe extern "C" fn read_stream(stream: *const clap_istream, buffer: *mut c_void, size: u64) -> i64 {
+    let reader = &mut *((*stream).ctx as *mut &[u8]);
+    match reader.read(std::slice::from_raw_parts_mut(buffer as *mut u8, size as usize)) {
+        Ok(read) => read as i64,
+        Err(_) => -1,
+    }
+}
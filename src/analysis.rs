@@ -0,0 +1,136 @@
+use crate::fft::{fft, hann};
+
+/// Size of the FFT frames used for the spectrum
+pub const FFT_SIZE: usize = 2048;
+/// Number of logarithmically spaced frequency bands the spectrum is reduced to for display
+pub const SPECTRUM_BANDS: usize = 128;
+/// Maximum number of frames kept for the spectrogram
+pub const SPECTROGRAM_FRAMES: usize = 200;
+
+// Lowest frequency shown in the spectrum
+const MIN_FREQUENCY: f64 = 20.0;
+// Level used in place of silence, in dB
+const FLOOR_DB: f32 = -120.0;
+
+/// Measurements of a rendered signal
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub sample_rate: u32,
+    pub num_samples: usize,
+
+    /// Peak level in dBFS
+    pub peak_db: f32,
+    /// RMS level in dBFS
+    pub rms_db: f32,
+    /// Number of samples with a magnitude of 1.0 or more
+    pub clip_count: usize,
+    /// Mean value of the signal
+    pub dc_offset: f64,
+
+    /// Magnitude in dB of each band, averaged over the whole signal
+    pub spectrum: Vec<f32>,
+    /// Magnitude in dB of each band, per frame
+    pub spectrogram: Vec<Vec<f32>>,
+}
+
+impl Analysis {
+    pub fn new(samples: &[f64], sample_rate: u32) -> Self {
+        let num_samples = samples.len();
+
+        let peak = samples.iter().fold(0.0f64, |peak, sample| peak.max(sample.abs()));
+        let sum_squares = samples.iter().map(|sample| sample * sample).sum::<f64>();
+        let rms = if num_samples > 0 { (sum_squares / num_samples as f64).sqrt() } else { 0.0 };
+        let clip_count = samples.iter().filter(|sample| sample.abs() >= 1.0).count();
+        let dc_offset = if num_samples > 0 { samples.iter().sum::<f64>() / num_samples as f64 } else { 0.0 };
+
+        let bands = band_edges(sample_rate);
+        let window = hann(FFT_SIZE);
+        // Scale so that a full scale sine reads as 0 dB
+        let window_gain = 2.0 / window.iter().sum::<f64>();
+
+        // Frames overlap by half
+        let hop = FFT_SIZE / 2;
+        let num_frames = if num_samples >= FFT_SIZE { (num_samples - FFT_SIZE) / hop + 1 } else { 1 };
+        // Only every nth frame is kept for the spectrogram
+        let spectrogram_stride = (num_frames + SPECTROGRAM_FRAMES - 1) / SPECTROGRAM_FRAMES;
+
+        let mut power_sum = vec![0.0f64; FFT_SIZE / 2];
+        let mut spectrogram = Vec::new();
+
+        let mut re = vec![0.0; FFT_SIZE];
+        let mut im = vec![0.0; FFT_SIZE];
+
+        for frame in 0..num_frames {
+            let start = frame * hop;
+            for i in 0..FFT_SIZE {
+                re[i] = samples.get(start + i).cloned().unwrap_or(0.0) * window[i];
+                im[i] = 0.0;
+            }
+
+            fft(&mut re, &mut im);
+
+            let magnitudes = (0..FFT_SIZE / 2)
+                .map(|bin| (re[bin] * re[bin] + im[bin] * im[bin]).sqrt() * window_gain)
+                .collect::<Vec<_>>();
+
+            for (sum, magnitude) in power_sum.iter_mut().zip(magnitudes.iter()) {
+                *sum += magnitude * magnitude;
+            }
+
+            if frame % spectrogram_stride == 0 {
+                spectrogram.push(reduce_to_bands(&magnitudes, &bands));
+            }
+        }
+
+        let average = power_sum.iter().map(|power| (power / num_frames as f64).sqrt()).collect::<Vec<_>>();
+
+        Self {
+            sample_rate,
+            num_samples,
+
+            peak_db: to_db(peak),
+            rms_db: to_db(rms),
+            clip_count,
+            dc_offset,
+
+            spectrum: reduce_to_bands(&average, &bands),
+            spectrogram,
+        }
+    }
+
+    /// Returns the centre frequency of a band in Hz
+    pub fn band_frequency(&self, band: usize) -> f64 {
+        let max_frequency = self.sample_rate as f64 / 2.0;
+        MIN_FREQUENCY * (max_frequency / MIN_FREQUENCY).powf((band as f64 + 0.5) / SPECTRUM_BANDS as f64)
+    }
+}
+
+pub fn to_db(magnitude: f64) -> f32 {
+    if magnitude > 0.0 {
+        (20.0 * magnitude.log10() as f32).max(FLOOR_DB)
+    } else {
+        FLOOR_DB
+    }
+}
+
+// Returns the first FFT bin of each band plus the end of the last band
+fn band_edges(sample_rate: u32) -> Vec<usize> {
+    let max_frequency = sample_rate as f64 / 2.0;
+    let bin_width = sample_rate as f64 / FFT_SIZE as f64;
+
+    (0..=SPECTRUM_BANDS).map(|band| {
+        let frequency = MIN_FREQUENCY * (max_frequency / MIN_FREQUENCY).powf(band as f64 / SPECTRUM_BANDS as f64);
+        ((frequency / bin_width) as usize).min(FFT_SIZE / 2)
+    }).collect()
+}
+
+// Takes the peak magnitude within each band, in dB.
+// Low bands narrower than one bin use the bin they fall in.
+fn reduce_to_bands(magnitudes: &[f64], edges: &[usize]) -> Vec<f32> {
+    edges.windows(2).map(|edge| {
+        let start = edge[0].min(magnitudes.len() - 1);
+        let end = edge[1].max(start + 1).min(magnitudes.len());
+        let peak = magnitudes[start..end].iter().cloned().fold(0.0, f64::max);
+        to_db(peak)
+    }).collect()
+}
@@ -0,0 +1,130 @@
+use crate::library::Library;
+
+/// Directory searched for additional libraries of sarus functions
+pub const LIBRARY_DIR: &str = "library";
+
+pub const STYLE: &str = r#"
+    .node {
+        background-color: #303030;
+    }
+    .socket {
+        background-color: green;
+    }
+    
+    .node_label {
+        background-color: #303099;
+    }
+    .palette {
+        background-color: #d2d2d2;
+        color: black;
+    }
+    .palette_query {
+        background-color: #f2f2f2;
+    }
+    .palette_category {
+        color: #505050;
+    }
+    .palette_entry {
+        color: black;
+        background-color: #d2d2d2;
+    }
+    .palette_entry:hover {
+        background-color: #e2e2e2;
+    }
+    .palette_entry:checked {
+        background-color: #b2b2e2;
+    }
+    .palette_signature {
+        color: #707070;
+    }
+    .analysis_mode {
+        background-color: #505050;
+    }
+    .analysis_mode:hover {
+        background-color: #606060;
+    }
+    .probe_info {
+        background-color: #202020a0;
+        color: #d2d2d2;
+    }
+"#;
+
+/// Sarus functions available to every graph
+pub const BUILTIN_CODE: &str = r#"
+fn main(a) -> (b) {
+    b = 0.05 * a
+}
+
+// category: Notes
+fn note_A() -> (b) {
+    b = 440.0
+} 
+
+// category: Notes
+fn note_Cs() -> (b) {
+    b = 554.37
+} 
+
+// category: Notes
+fn note_E() -> (b) {
+    b = 659.25
+} 
+
+// category: Math
+fn double(a) -> (b) {
+    b = 2.0 * a
+}
+
+// category: Math
+fn divide_three(a) -> (b) {
+    b = a / 3.0
+}
+
+// category: Shaping
+fn tanh_node(a) -> (b) {
+    b = tanh(a)
+}
+
+// category: Shaping
+fn sin_node(a) -> (b) {
+    b = sin(a)
+}
+
+// category: Constants
+fn const_01() -> (b) {
+    b = 0.0001
+}
+
+// category: Math
+fn mul_node(a, b) -> (c) {
+    c = a * b
+}
+
+// category: Math
+fn add_node(a, b) -> (c) {
+    c = a + b
+}
+
+// category: Oscillators
+fn sine_wave(n, f) -> (a) {
+    a = sin(2.0 * 3.1415926 * f * (n / 48000.0))
+}
+
+"#;
+
+/// Loads the built-in functions and native nodes, followed by the libraries in `LIBRARY_DIR`
+pub fn load_library() -> anyhow::Result<Library> {
+    let mut library = Library::parse("Built-in", BUILTIN_CODE)?;
+    library.extend(Library::native());
+
+    // Any libraries in the library directory are added with their file name as the default category
+    if let Ok(dir) = std::fs::read_dir(LIBRARY_DIR) {
+        let mut paths = dir.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect::<Vec<_>>();
+        paths.sort();
+        for path in paths.iter().filter(|path| path.extension().map_or(false, |ext| ext == "sarus")) {
+            library.extend(Library::load(path)?);
+        }
+    }
+
+    Ok(library)
+}
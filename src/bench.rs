@@ -0,0 +1,168 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::patch::Patch;
+use crate::render::{process_block, SAMPLE_RATE, STEP_SIZE};
+
+/// Seconds of audio rendered for each block size
+pub const BENCH_SECONDS: usize = 10;
+
+/// Block sizes the graph is timed at
+pub const BLOCK_SIZES: [usize; 6] = [16, 32, 64, 128, 256, 512];
+
+/// Timings of rendering a graph with one block size
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockBench {
+    pub block_size: usize,
+    pub compile_time: Duration,
+    pub blocks: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// Seconds of audio rendered per second of processing. Below 1.0 the graph can't run in real time.
+    pub real_time_factor: f64,
+}
+
+impl BlockBench {
+    /// Time available to render one block in real time
+    pub fn budget(&self) -> Duration {
+        Duration::from_secs_f64(self.block_size as f64 / SAMPLE_RATE as f64)
+    }
+
+    /// Percentage of the real time budget used on average
+    pub fn cpu_load(&self) -> f64 {
+        100.0 / self.real_time_factor
+    }
+
+    /// True if even the slowest block was rendered within its real time budget
+    pub fn is_real_time_safe(&self) -> bool {
+        self.max < self.budget()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub rows: Vec<BlockBench>,
+}
+
+impl BenchReport {
+    /// Returns the timings for the block size the engine runs at
+    pub fn configured(&self) -> Option<&BlockBench> {
+        self.rows.iter().find(|row| row.block_size == STEP_SIZE)
+    }
+
+    /// Formats each row of the table as a line of text
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{:>6} {:>10} {:>9} {:>9} {:>9} {:>9} {:>8} {:>7}",
+            "block", "compile", "p50", "p90", "p99", "max", "rt x", "load"
+        )];
+
+        for row in self.rows.iter() {
+            lines.push(format!(
+                "{:>6} {:>10} {:>9} {:>9} {:>9} {:>9} {:>8.1} {:>6.1}%{}",
+                row.block_size,
+                format_duration(row.compile_time),
+                format_duration(row.p50),
+                format_duration(row.p90),
+                format_duration(row.p99),
+                format_duration(row.max),
+                row.real_time_factor,
+                row.cpu_load(),
+                if row.is_real_time_safe() { "" } else { " !" },
+            ));
+        }
+
+        lines
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in self.lines() {
+            writeln!(f, "{}", line)?;
+        }
+        writeln!(f, "Block sizes marked with ! had blocks over their real time budget at {} Hz", SAMPLE_RATE)
+    }
+}
+
+/// Measures compile time and render time of a patch across a range of block sizes
+pub fn bench(patch: &Patch) -> anyhow::Result<BenchReport> {
+    bench_while(patch, || true)?.ok_or_else(|| anyhow::anyhow!("Benchmark was stopped"))
+}
+
+/// Number of blocks rendered by a benchmark across all the block sizes
+pub fn total_blocks() -> usize {
+    BLOCK_SIZES.iter().map(|&block_size| blocks(block_size)).sum()
+}
+
+/// Runs the benchmark, calling `keep_going` before each block. Returns `None` if it was stopped early.
+pub fn bench_while(patch: &Patch, mut keep_going: impl FnMut() -> bool) -> anyhow::Result<Option<BenchReport>> {
+    let mut rows = Vec::with_capacity(BLOCK_SIZES.len());
+    for &block_size in BLOCK_SIZES.iter() {
+        match bench_block_size(patch, block_size, &mut keep_going)? {
+            Some(row) => rows.push(row),
+            None => return Ok(None),
+        }
+    }
+
+    Ok(Some(BenchReport { rows }))
+}
+
+fn blocks(block_size: usize) -> usize {
+    BENCH_SECONDS * SAMPLE_RATE as usize / block_size
+}
+
+fn bench_block_size(patch: &Patch, block_size: usize, keep_going: &mut impl FnMut() -> bool) -> anyhow::Result<Option<BlockBench>> {
+    let start = Instant::now();
+    let mut program = patch.compile()?;
+    let compile_time = start.elapsed();
+
+    let blocks = blocks(block_size);
+    let mut timings = Vec::with_capacity(blocks);
+
+    let mut n = 0;
+    let mut audio_buffer = vec![0.0f64; block_size];
+    for _ in 0..blocks {
+        if !keep_going() {
+            return Ok(None);
+        }
+
+        let start = Instant::now();
+        process_block(&mut program, &mut n, &mut audio_buffer, &[]);
+        timings.push(start.elapsed());
+    }
+
+    let total = timings.iter().sum::<Duration>();
+    timings.sort();
+
+    Ok(Some(BlockBench {
+        block_size,
+        compile_time,
+        blocks,
+        p50: percentile(&timings, 0.5),
+        p90: percentile(&timings, 0.9),
+        p99: percentile(&timings, 0.99),
+        max: timings.last().cloned().unwrap_or_default(),
+        real_time_factor: BENCH_SECONDS as f64 / total.as_secs_f64().max(f64::EPSILON),
+    }))
+}
+
+// Expects the timings to be sorted
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
+
+fn format_duration(duration: Duration) -> String {
+    let micros = duration.as_secs_f64() * 1e6;
+    if micros >= 1000.0 {
+        format!("{:.2}ms", micros / 1000.0)
+    } else {
+        format!("{:.1}us", micros)
+    }
+}
@@ -0,0 +1,79 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+
+use super::{Backend, Processor};
+use crate::render::SAMPLE_RATE;
+
+/// Plays the output on the default audio device of the default host
+pub struct DeviceBackend {
+    stream: Option<cpal::Stream>,
+}
+
+impl DeviceBackend {
+    pub fn new() -> Self {
+        Self { stream: None }
+    }
+}
+
+impl Backend for DeviceBackend {
+    fn name(&self) -> String {
+        "device".to_string()
+    }
+
+    fn start(&mut self, processor: Processor) -> anyhow::Result<()> {
+        self.stop()?;
+
+        let host = cpal::default_host();
+        let device = host.default_output_device().ok_or_else(|| anyhow::anyhow!("No audio output device available"))?;
+        let default_config = device.default_output_config()?;
+
+        // The graph is written for a fixed sample rate so ask the device for it rather than using its default
+        let config = cpal::StreamConfig {
+            channels: default_config.channels(),
+            sample_rate: cpal::SampleRate(SAMPLE_RATE),
+            buffer_size: cpal::BufferSize::Default,
+        };
+
+        let stream = match default_config.sample_format() {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, processor)?,
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, processor)?,
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, processor)?,
+            format => anyhow::bail!("Unsupported sample format {:?}", format),
+        };
+
+        stream.play()?;
+        self.stream = Some(stream);
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        // Dropping the stream stops the callback
+        self.stream = None;
+        Ok(())
+    }
+}
+
+fn build_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, mut processor: Processor) -> anyhow::Result<cpal::Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            // The graph is mono so the same sample goes to every channel
+            for frame in data.chunks_mut(channels) {
+                let sample = T::from_sample(processor.next_sample() as f32);
+                for out in frame.iter_mut() {
+                    *out = sample;
+                }
+            }
+        },
+        |err| println!("Audio stream error: {}", err),
+        None,
+    )?;
+
+    Ok(stream)
+}
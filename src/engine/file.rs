@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+
+use super::{Backend, BlockThread, Processor};
+use crate::render::SAMPLE_RATE;
+
+/// Streams the output to a 32 bit float WAV file.
+///
+/// Runs in real time by default so it behaves like a device, which can be turned off to render as fast as possible.
+pub struct FileBackend {
+    path: PathBuf,
+    paced: bool,
+    length: Option<usize>,
+    thread: Option<BlockThread>,
+}
+
+impl FileBackend {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            paced: true,
+            length: None,
+            thread: None,
+        }
+    }
+
+    /// Writes blocks as fast as they can be rendered
+    pub fn unpaced(mut self) -> Self {
+        self.paced = false;
+        self
+    }
+
+    /// Stops writing after a number of blocks
+    pub fn with_length(mut self, blocks: usize) -> Self {
+        self.length = Some(blocks);
+        self
+    }
+}
+
+impl Backend for FileBackend {
+    fn name(&self) -> String {
+        format!("file:{}", self.path.display())
+    }
+
+    fn start(&mut self, processor: Processor) -> anyhow::Result<()> {
+        self.stop()?;
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&self.path, spec)?;
+
+        self.thread = Some(BlockThread::spawn(processor, self.paced, self.length, move |block| {
+            for sample in block.iter() {
+                writer.write_sample(*sample as f32)?;
+            }
+            Ok(())
+        }));
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        // The writer is finalized when the thread's sink is dropped
+        match self.thread.take() {
+            Some(thread) => thread.stop(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use midir::{MidiInput as MidirInput, MidiInputConnection};
+
+use super::MidiSender;
+use crate::midi::MidiMessage;
+
+/// Plays the messages from a MIDI input port into the engine
+pub struct MidiInput {
+    name: String,
+    // Dropping the connection closes the port
+    _connection: MidiInputConnection<()>,
+}
+
+impl MidiInput {
+    /// Connects to the first port whose name contains `port`, so an empty string picks the first port
+    pub fn connect(port: &str, sender: MidiSender) -> anyhow::Result<Self> {
+        let input = MidirInput::new("sarus-plugin")?;
+
+        let ports = input.ports();
+        let found = ports.iter()
+            .find(|found| input.port_name(found).map_or(false, |name| name.contains(port)))
+            .ok_or_else(|| anyhow::anyhow!("No MIDI input matching '{}'", port))?;
+        let name = input.port_name(found)?;
+
+        let connection = input.connect(found, "sarus-plugin-input", move |_, bytes, _| {
+            if let Some(message) = MidiMessage::from_bytes(bytes) {
+                sender.send(message);
+            }
+        }, ())
+        .map_err(|err| anyhow::anyhow!("Failed to connect to MIDI input {}: {}", name, err))?;
+
+        Ok(Self {
+            name,
+            _connection: connection,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
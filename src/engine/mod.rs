@@ -0,0 +1,972 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::midi::{MidiEvent, MidiMessage};
+use crate::render::{sine_sweep, SAMPLE_RATE, STEP_SIZE};
+use crate::program::{ParameterSpec, Program, ProgramLayout, Transport};
+
+pub mod null;
+pub use null::NullBackend;
+
+pub mod file;
+pub use file::FileBackend;
+
+#[cfg(feature = "device")]
+pub mod device;
+#[cfg(feature = "device")]
+pub use device::DeviceBackend;
+
+#[cfg(feature = "device")]
+pub mod midi_input;
+#[cfg(feature = "device")]
+pub use midi_input::MidiInput;
+
+/// Somewhere for the engine's audio to go.
+///
+/// A backend owns the thread or callback that pulls blocks from the `Processor` for as long as it's running.
+pub trait Backend {
+    fn name(&self) -> String;
+
+    /// Starts pulling audio from the processor
+    fn start(&mut self, processor: Processor) -> anyhow::Result<()>;
+
+    /// Stops pulling audio and releases the processor. Reports any error the backend hit while running.
+    fn stop(&mut self) -> anyhow::Result<()>;
+}
+
+/// The backends which can be chosen by name, e.g. from the command line
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendKind {
+    /// The default audio output device
+    Device,
+    /// Runs in real time and discards the output
+    Null,
+    /// Streams the output to a WAV file in real time
+    File(PathBuf),
+}
+
+impl BackendKind {
+    pub fn create(&self) -> anyhow::Result<Box<dyn Backend>> {
+        match self {
+            #[cfg(feature = "device")]
+            BackendKind::Device => Ok(Box::new(DeviceBackend::new())),
+            #[cfg(not(feature = "device"))]
+            BackendKind::Device => anyhow::bail!("Built without the device feature, use the null or file backend"),
+            BackendKind::Null => Ok(Box::new(NullBackend::new())),
+            BackendKind::File(path) => Ok(Box::new(FileBackend::new(path.clone()))),
+        }
+    }
+}
+
+impl Default for BackendKind {
+    fn default() -> Self {
+        if cfg!(feature = "device") {
+            BackendKind::Device
+        } else {
+            BackendKind::Null
+        }
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    /// Parses `device`, `null` or `file:<path>`
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "device" => Ok(BackendKind::Device),
+            "null" => Ok(BackendKind::Null),
+            _ => match name.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(BackendKind::File(PathBuf::from(path))),
+                _ => anyhow::bail!("Unknown backend '{}', expected device, null or file:<path>", name),
+            },
+        }
+    }
+}
+
+/// Counters updated by the audio thread
+#[derive(Debug, Default)]
+pub struct EngineStats {
+    pub blocks: AtomicUsize,
+    /// Levels of a graph in which a node panicked on one of the graph's worker threads
+    pub worker_failures: AtomicUsize,
+    /// MIDI events left out of blocks which already had MAX_BLOCK_EVENTS
+    pub dropped_events: AtomicUsize,
+}
+
+// Graphs played live are processed on as many threads as their patch asks for. Without them the graph
+// still plays, on the audio thread alone.
+fn start_workers(graph: &mut Program) {
+    if let Err(err) = graph.start_workers() {
+        println!("Processing the graph on one thread: {}", err);
+    }
+}
+
+/// Default length of the crossfade when the running graph is replaced
+pub const DEFAULT_CROSSFADE: Duration = Duration::from_millis(30);
+
+/// Gains of the outgoing and incoming signals `t` of the way through an equal-power crossfade.
+/// The summed power stays constant, so uncorrelated signals don't dip in loudness half way.
+pub fn equal_power_gains(t: f64) -> (f64, f64) {
+    let angle = t.max(0.0).min(1.0) * std::f64::consts::FRAC_PI_2;
+    (angle.cos(), angle.sin())
+}
+
+// A graph sent to replace the running one, crossfading over `fade_samples`
+struct GraphSwap {
+    graph: Program,
+    fade_samples: usize,
+}
+
+// Changes sent to the audio thread, applied in order at the start of a block
+enum Message {
+    Swap(GraphSwap),
+    // Index into the parameters of the graph most recently sent
+    SetParameter(usize, f64),
+    // Played at the start of the next block
+    Midi(MidiMessage),
+    Transport(Transport),
+    // Position in beats and seconds to move the transport to
+    Locate(f64, f64),
+}
+
+/// Most MIDI events handled in one block without allocating
+const MAX_BLOCK_EVENTS: usize = 256;
+
+/// Most graphs fading out at once. A swap arriving when this many are still fading waits for the oldest.
+const MAX_FADES: usize = 8;
+
+/// Retired graphs waiting to be freed, enough for the running, fading and waiting graphs to be cut off at once
+const RETIRED_GRAPHS: usize = MAX_FADES + 2;
+
+// A graph being faded out after a swap
+struct Fade {
+    graph: Program,
+    position: usize,
+    length: usize,
+    block: [f64; STEP_SIZE],
+}
+
+/// Runs the compiled graph on the audio thread.
+///
+/// New graphs and parameter values are received from a `GraphSender` and applied at the start of a block.
+/// A new graph runs alongside the previous one for the length of the crossfade. A graph replaced while
+/// its own fade is running keeps fading, so the new graph fades in from what was being heard. Replaced
+/// graphs are sent back so they're freed off the audio thread, and are kept until there's room to send them.
+pub struct Processor {
+    graph: Program,
+    // Graphs fading out, oldest first. Each one's fade goes from the mix of those before it to the next.
+    fades: Vec<Fade>,
+    // A swap waiting for room in `fades`
+    pending: Option<GraphSwap>,
+    n: usize,
+    // Events for the block being processed: messages received live followed by those passed in
+    midi: Vec<MidiEvent>,
+    block: [f64; STEP_SIZE],
+    // Index of the next sample of `block` handed out by `next_sample`
+    position: usize,
+    messages: Receiver<Message>,
+    retired: SyncSender<Program>,
+    // Retired graphs which didn't fit in `retired`, sent at the start of a later block
+    parked: VecDeque<Program>,
+    stats: Arc<EngineStats>,
+}
+
+impl Processor {
+    /// Creates a processor running `graph` along with the sender used to replace it
+    pub fn new(mut graph: Program, stats: Arc<EngineStats>) -> (Self, GraphSender) {
+        start_workers(&mut graph);
+        let (message_sender, message_receiver) = mpsc::channel();
+        let (retired_sender, retired_receiver) = mpsc::sync_channel(RETIRED_GRAPHS);
+        let parameters = graph.parameters().to_vec();
+        let layout = graph.layout();
+
+        let processor = Self {
+            graph,
+            fades: Vec::with_capacity(MAX_FADES),
+            pending: None,
+            n: 0,
+            midi: Vec::with_capacity(MAX_BLOCK_EVENTS),
+            block: [0.0; STEP_SIZE],
+            position: STEP_SIZE,
+            messages: message_receiver,
+            retired: retired_sender,
+            parked: VecDeque::with_capacity(RETIRED_GRAPHS),
+            stats,
+        };
+
+        let sender = GraphSender {
+            messages: message_sender,
+            retired: retired_receiver,
+            parameters,
+            layout,
+            crossfade: DEFAULT_CROSSFADE,
+        };
+
+        (processor, sender)
+    }
+
+    /// Processes a buffer of any length in place, the buffer holding the input on entry
+    pub fn process(&mut self, buffer: &mut [f64]) {
+        self.process_midi(buffer, &[]);
+    }
+
+    /// Processes a buffer along with MIDI events sorted by time, where time 0 is the first sample of the buffer
+    pub fn process_midi(&mut self, buffer: &mut [f64], events: &[MidiEvent]) {
+        for (i, chunk) in buffer.chunks_mut(STEP_SIZE).enumerate() {
+            let start = i * STEP_SIZE;
+            let first = events.partition_point(|event| event.time < start);
+            let last = events.partition_point(|event| event.time < start + chunk.len());
+            self.process_chunk(chunk, &events[first..last], start);
+        }
+    }
+
+    // `offset` is the time of the first sample of the chunk
+    fn process_chunk(&mut self, chunk: &mut [f64], events: &[MidiEvent], offset: usize) {
+        self.midi.clear();
+        self.send_parked();
+
+        // A waiting swap goes ahead of any received since, once a fade has finished
+        if self.fades.len() < MAX_FADES {
+            if let Some(swap) = self.pending.take() {
+                self.swap(swap);
+            }
+        }
+
+        // Events which don't fit are dropped rather than growing the list on the audio thread
+        let mut dropped = 0;
+        while let Ok(message) = self.messages.try_recv() {
+            match message {
+                Message::Swap(swap) => self.swap(swap),
+                Message::SetParameter(index, value) => self.set_parameter(index, value),
+                Message::Midi(message) if self.midi.len() < self.midi.capacity() => {
+                    self.midi.push(MidiEvent { time: offset, message })
+                }
+                Message::Midi(_) => dropped += 1,
+                Message::Transport(settings) => self.set_transport(settings),
+                Message::Locate(beats, seconds) => self.locate(beats, seconds),
+            }
+        }
+
+        let room = (self.midi.capacity() - self.midi.len()).min(events.len());
+        self.midi.extend_from_slice(&events[..room]);
+        dropped += events.len() - room;
+        if dropped > 0 {
+            self.stats.dropped_events.fetch_add(dropped, Ordering::Relaxed);
+        }
+
+        let len = chunk.len();
+
+        // Every graph sees the same input, MIDI and sample count while fading
+        for fade in self.fades.iter_mut() {
+            fade.block[..len].copy_from_slice(chunk);
+            fade.graph.process_midi(self.n, &mut fade.block[..len], &self.midi, offset);
+        }
+
+        self.graph.process_midi(self.n, chunk, &self.midi, offset);
+
+        let failures = self.graph.take_worker_failures()
+            + self.fades.iter_mut().map(|fade| fade.graph.take_worker_failures()).sum::<usize>();
+        if failures > 0 {
+            self.stats.worker_failures.fetch_add(failures, Ordering::Relaxed);
+        }
+
+        // Each fade mixes the output of those before it into the next graph, the last one into the running graph
+        for index in 0..self.fades.len() {
+            let (before, after) = self.fades.split_at_mut(index + 1);
+            let fade = &mut before[index];
+            let next = match after.first_mut() {
+                Some(next) => &mut next.block[..len],
+                None => &mut *chunk,
+            };
+
+            for (i, (sample, old)) in next.iter_mut().zip(fade.block.iter()).enumerate() {
+                let (fade_out, fade_in) = equal_power_gains((fade.position + i) as f64 / fade.length as f64);
+                *sample = old * fade_out + *sample * fade_in;
+            }
+            fade.position += len;
+        }
+
+        // Once a fade has finished, it and every graph mixed into it are silent
+        if let Some(finished) = self.fades.iter().rposition(|fade| fade.position >= fade.length) {
+            self.retire_fades(finished + 1);
+        }
+
+        self.n += len;
+        self.stats.blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Runs the next block through the graph, with a sine sweep as the input
+    pub fn next_block(&mut self) -> &[f64; STEP_SIZE] {
+        let mut block = [0.0; STEP_SIZE];
+        sine_sweep(self.n, &mut block);
+        self.process(&mut block);
+
+        self.block = block;
+        self.position = 0;
+
+        &self.block
+    }
+
+    /// Returns the next sample, running a new block when the current one is used up.
+    /// Lets backends with any buffer size pull from the fixed size blocks of the graph.
+    pub fn next_sample(&mut self) -> f64 {
+        if self.position >= STEP_SIZE {
+            self.next_block();
+        }
+
+        let sample = self.block[self.position];
+        self.position += 1;
+        sample
+    }
+
+    /// Parameters of the running graph
+    pub fn parameters(&self) -> &[ParameterSpec] {
+        self.graph.parameters()
+    }
+
+    /// Sets a parameter of the running graph by its index in `Program::parameters`.
+    /// A graph which is fading out keeps the value it had.
+    pub fn set_parameter(&mut self, index: usize, value: f64) {
+        self.graph.set_parameter(index, value);
+    }
+
+    /// Latency in samples of the running graph
+    pub fn latency(&self) -> usize {
+        self.graph.latency()
+    }
+
+    /// Transport settings of the running graph
+    pub fn transport(&self) -> &Transport {
+        self.graph.transport()
+    }
+
+    /// Changes the transport of the running graph, and of those fading out so they stay in time
+    pub fn set_transport(&mut self, settings: Transport) {
+        for fade in self.fades.iter_mut() {
+            fade.graph.set_transport(settings.clone());
+        }
+        self.graph.set_transport(settings);
+    }
+
+    /// Moves the transport to a position in beats and seconds, e.g. the one reported by a plugin host
+    pub fn locate(&mut self, beats: f64, seconds: f64) {
+        for fade in self.fades.iter_mut() {
+            fade.graph.locate(beats, seconds);
+        }
+        self.graph.locate(beats, seconds);
+    }
+
+    fn swap(&mut self, mut swap: GraphSwap) {
+        // Only the latest of several swaps arriving while the fades are full is heard
+        if swap.fade_samples > 0 && self.fades.len() >= MAX_FADES {
+            if let Some(superseded) = self.pending.replace(swap) {
+                self.retire(superseded.graph);
+            }
+            return;
+        }
+
+        // An older swap still waiting would otherwise be applied after this one
+        if let Some(superseded) = self.pending.take() {
+            self.retire(superseded.graph);
+        }
+
+        // Nodes which haven't changed continue where they were. The sender matched them up, so this only
+        // copies their state, which leaves the old graph able to keep running while it fades out.
+        swap.graph.apply_carry(&self.graph);
+
+        let old = std::mem::replace(&mut self.graph, swap.graph);
+
+        if swap.fade_samples > 0 {
+            // A graph fading in when it's replaced carries on fading from the graphs before it
+            self.fades.push(Fade {
+                graph: old,
+                position: 0,
+                length: swap.fade_samples,
+                block: [0.0; STEP_SIZE],
+            });
+        } else {
+            // Swapping without a fade cuts everything off straight away
+            self.retire(old);
+            self.retire_fades(self.fades.len());
+        }
+    }
+
+    // Retires the oldest `count` fading graphs
+    fn retire_fades(&mut self, count: usize) {
+        for _ in 0..count {
+            let fade = self.fades.remove(0);
+            self.retire(fade.graph);
+        }
+    }
+
+    fn retire(&mut self, graph: Program) {
+        // Graphs are sent back in the order they were retired
+        if !self.parked.is_empty() {
+            self.parked.push_back(graph);
+            return;
+        }
+
+        // A graph which can't be sent, because the queue is full or the sender has gone away, is kept until
+        // it can be rather than being freed here. The parking space only grows if more graphs than
+        // RETIRED_GRAPHS are retired before the sender collects them, when many are sent within one block.
+        match self.retired.try_send(graph) {
+            Ok(()) => {}
+            Err(TrySendError::Full(graph)) | Err(TrySendError::Disconnected(graph)) => self.parked.push_back(graph),
+        }
+    }
+
+    // Sends back the parked graphs the queue has room for
+    fn send_parked(&mut self) {
+        while let Some(graph) = self.parked.pop_front() {
+            if let Err(TrySendError::Full(graph)) | Err(TrySendError::Disconnected(graph)) = self.retired.try_send(graph) {
+                self.parked.push_front(graph);
+                break;
+            }
+        }
+    }
+}
+
+/// Replaces the graph of a `Processor` and sets its parameters from another thread
+pub struct GraphSender {
+    messages: Sender<Message>,
+    retired: Receiver<Program>,
+    crossfade: Duration,
+    // Parameters of the graph most recently sent
+    parameters: Vec<ParameterSpec>,
+    // Nodes of the graph most recently sent, which the next one is matched against before it's sent.
+    // If the processor skips a graph, because several arrive while its fades are full, the state of the
+    // next one isn't carried over.
+    layout: ProgramLayout,
+}
+
+impl GraphSender {
+    /// Sets how long the old and new graphs are crossfaded for when the graph is replaced.
+    /// A zero duration swaps immediately.
+    pub fn set_crossfade(&mut self, crossfade: Duration) {
+        self.crossfade = crossfade;
+    }
+
+    /// Replaces the running graph at the start of the next block, crossfading from the old one
+    pub fn send(&mut self, graph: Program) {
+        let fade_samples = (self.crossfade.as_secs_f64() * SAMPLE_RATE as f64).round() as usize;
+        self.send_with_fade(graph, fade_samples);
+    }
+
+    pub fn send_with_fade(&mut self, mut graph: Program, fade_samples: usize) {
+        self.collect_garbage();
+        self.parameters = graph.parameters().to_vec();
+
+        // The matching allocates, so it's done here rather than on the audio thread, as is starting the workers
+        graph.plan_carry(&self.layout);
+        self.layout = graph.layout();
+        start_workers(&mut graph);
+
+        // The processor may have gone away, in which case there's nothing to replace
+        let _ = self.messages.send(Message::Swap(GraphSwap { graph, fade_samples }));
+    }
+
+    /// Plays a MIDI message at the start of the next block
+    pub fn send_midi(&self, message: MidiMessage) {
+        let _ = self.messages.send(Message::Midi(message));
+    }
+
+    /// A handle for sending MIDI to the processor from another thread, such as a MIDI input callback
+    pub fn midi_sender(&self) -> MidiSender {
+        MidiSender {
+            messages: self.messages.clone(),
+        }
+    }
+
+    /// Parameters of the graph most recently sent, in the order of their indices
+    pub fn parameters(&self) -> &[ParameterSpec] {
+        &self.parameters
+    }
+
+    /// Sets a parameter by name, taking effect at the start of the next block
+    pub fn set_parameter(&mut self, name: &str, value: f64) -> anyhow::Result<()> {
+        let index = self.parameters.iter()
+            .position(|parameter| parameter.name == name)
+            .ok_or_else(|| anyhow::anyhow!("The graph has no parameter {}", name))?;
+
+        let _ = self.messages.send(Message::SetParameter(index, value));
+        Ok(())
+    }
+
+    /// Changes the transport at the start of the next block
+    pub fn set_transport(&self, settings: Transport) {
+        let _ = self.messages.send(Message::Transport(settings));
+    }
+
+    /// Moves the transport to a position at the start of the next block
+    pub fn locate(&self, beats: f64, seconds: f64) {
+        let _ = self.messages.send(Message::Locate(beats, seconds));
+    }
+
+    /// Frees graphs the audio thread has finished with
+    pub fn collect_garbage(&mut self) {
+        for graph in self.retired.try_iter() {
+            drop(graph);
+        }
+    }
+}
+
+/// Sends MIDI messages to a `Processor`, played at the start of its next block
+#[derive(Clone)]
+pub struct MidiSender {
+    messages: Sender<Message>,
+}
+
+impl MidiSender {
+    pub fn send(&self, message: MidiMessage) {
+        // The processor may have gone away, in which case there's nothing to play
+        let _ = self.messages.send(Message::Midi(message));
+    }
+}
+
+/// Streams a compiled graph to a backend in real time
+pub struct Engine {
+    backend: Box<dyn Backend>,
+    sender: Option<GraphSender>,
+    stats: Arc<EngineStats>,
+    crossfade: Duration,
+    // Port to listen to while running, see `set_midi_input`
+    midi_port: Option<String>,
+    #[cfg(feature = "device")]
+    midi_input: Option<MidiInput>,
+}
+
+impl Engine {
+    pub fn new(backend: Box<dyn Backend>) -> Self {
+        Self {
+            backend,
+            sender: None,
+            stats: Arc::new(EngineStats::default()),
+            crossfade: DEFAULT_CROSSFADE,
+            midi_port: None,
+            #[cfg(feature = "device")]
+            midi_input: None,
+        }
+    }
+
+    /// Plays a MIDI input into the graph while the engine runs: the first port whose name contains `port`,
+    /// so an empty string picks the first port. Takes effect the next time the engine starts.
+    pub fn set_midi_input(&mut self, port: Option<String>) {
+        self.midi_port = port;
+    }
+
+    /// Sets how long the old and new graphs are crossfaded for when the graph is replaced.
+    /// A zero duration swaps immediately.
+    pub fn set_crossfade(&mut self, crossfade: Duration) {
+        self.crossfade = crossfade;
+        if let Some(sender) = &mut self.sender {
+            sender.set_crossfade(crossfade);
+        }
+    }
+
+    pub fn backend_name(&self) -> String {
+        self.backend.name()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Starts streaming the graph, restarting the backend if it's already running
+    pub fn start(&mut self, graph: Program) -> anyhow::Result<()> {
+        self.stop()?;
+
+        self.stats = Arc::new(EngineStats::default());
+
+        let (processor, mut sender) = Processor::new(graph, self.stats.clone());
+        sender.set_crossfade(self.crossfade);
+
+        self.backend.start(processor)?;
+
+        // Playing without MIDI is still useful, so failing to open the input doesn't stop the engine
+        if let Some(port) = &self.midi_port {
+            if let Err(err) = self.connect_midi(port.clone(), sender.midi_sender()) {
+                println!("No MIDI input: {}", err);
+            }
+        }
+
+        self.sender = Some(sender);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "device")]
+    fn connect_midi(&mut self, port: String, midi_sender: MidiSender) -> anyhow::Result<()> {
+        let input = MidiInput::connect(&port, midi_sender)?;
+        println!("Listening to MIDI input {}", input.name());
+        self.midi_input = Some(input);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "device"))]
+    fn connect_midi(&mut self, _port: String, _midi_sender: MidiSender) -> anyhow::Result<()> {
+        anyhow::bail!("Built without the device feature")
+    }
+
+    /// Sends MIDI to the graph being played, e.g. from an on-screen keyboard
+    pub fn send_midi(&self, message: MidiMessage) {
+        if let Some(sender) = &self.sender {
+            sender.send_midi(message);
+        }
+    }
+
+    /// Replaces the running graph at the start of the next block, crossfading from the old one.
+    /// Does nothing if the engine isn't running.
+    pub fn set_graph(&mut self, graph: Program) {
+        if let Some(sender) = &mut self.sender {
+            sender.send(graph);
+        }
+    }
+
+    /// Parameters of the graph being played, empty if the engine isn't running
+    pub fn parameters(&self) -> Vec<ParameterSpec> {
+        self.sender.as_ref().map_or_else(Vec::new, |sender| sender.parameters().to_vec())
+    }
+
+    /// Sets a parameter of the graph being played without recompiling it
+    pub fn set_parameter(&mut self, name: &str, value: f64) -> anyhow::Result<()> {
+        match &mut self.sender {
+            Some(sender) => sender.set_parameter(name, value),
+            None => anyhow::bail!("The engine isn't running"),
+        }
+    }
+
+    /// Changes the transport of the graph being played. Does nothing if the engine isn't running.
+    pub fn set_transport(&self, settings: Transport) {
+        if let Some(sender) = &self.sender {
+            sender.set_transport(settings);
+        }
+    }
+
+    /// Moves the transport of the graph being played. Does nothing if the engine isn't running.
+    pub fn locate(&self, beats: f64, seconds: f64) {
+        if let Some(sender) = &self.sender {
+            sender.locate(beats, seconds);
+        }
+    }
+
+    pub fn stop(&mut self) -> anyhow::Result<()> {
+        #[cfg(feature = "device")]
+        {
+            self.midi_input = None;
+        }
+
+        match self.sender.take() {
+            // Graphs retired by the processor are freed along with the sender
+            Some(_) => self.backend.stop(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn blocks_processed(&self) -> usize {
+        self.stats.blocks.load(Ordering::Relaxed)
+    }
+
+    /// Levels in which a node panicked on a worker thread since the engine started. The audio carries on
+    /// with the outputs of those nodes left as they were.
+    pub fn worker_failures(&self) -> usize {
+        self.stats.worker_failures.load(Ordering::Relaxed)
+    }
+
+    /// MIDI events dropped since the engine started because too many arrived for one block
+    pub fn dropped_events(&self) -> usize {
+        self.stats.dropped_events.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            println!("Audio backend failed: {}", err);
+        }
+    }
+}
+
+/// A thread pulling blocks from a processor into a sink, shared by the backends which don't have
+/// an audio callback of their own.
+pub(crate) struct BlockThread {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl BlockThread {
+    /// When `paced` the blocks are produced no faster than real time, otherwise as fast as possible.
+    /// The thread stops by itself after `length` blocks if given.
+    pub fn spawn<S>(mut processor: Processor, paced: bool, length: Option<usize>, mut sink: S) -> Self
+    where
+        S: FnMut(&[f64; STEP_SIZE]) -> anyhow::Result<()> + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+
+        let handle = thread::spawn(move || {
+            let block_duration = Duration::from_secs_f64(STEP_SIZE as f64 / SAMPLE_RATE as f64);
+            let start = Instant::now();
+            let mut blocks = 0u32;
+
+            while !stop_flag.load(Ordering::Relaxed) && length.map_or(true, |length| (blocks as usize) < length) {
+                sink(processor.next_block())?;
+                blocks += 1;
+
+                if paced {
+                    // Sleep until the block would have finished playing, measured from the start
+                    // so the timing doesn't drift
+                    let deadline = start + block_duration * blocks;
+                    let now = Instant::now();
+                    if deadline > now {
+                        thread::sleep(deadline - now);
+                    }
+                }
+            }
+
+            Ok(())
+        });
+
+        Self { stop, handle }
+    }
+
+    pub fn stop(self) -> anyhow::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.join().map_err(|_| anyhow::anyhow!("Audio thread panicked"))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{read_wav, Fixture, FIXTURE_DIR};
+    use crate::render::render_while;
+    use std::path::Path;
+
+    fn compile_fixture(name: &str) -> (Fixture, Program) {
+        let fixture = Fixture::load(&Path::new(FIXTURE_DIR).join(name)).expect("Failed to load fixture");
+        let program = fixture.patch.compile().expect("Failed to compile fixture");
+        (fixture, program)
+    }
+
+    fn wait_for_blocks(engine: &Engine, blocks: usize) {
+        let start = Instant::now();
+        while engine.blocks_processed() < blocks {
+            assert!(start.elapsed() < Duration::from_secs(10), "Engine stalled at {} blocks", engine.blocks_processed());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn backend_names() {
+        assert_eq!("null".parse::<BackendKind>().unwrap(), BackendKind::Null);
+        assert_eq!("device".parse::<BackendKind>().unwrap(), BackendKind::Device);
+        assert_eq!("file:out.wav".parse::<BackendKind>().unwrap(), BackendKind::File(PathBuf::from("out.wav")));
+        assert!("file:".parse::<BackendKind>().is_err());
+        assert!("speakers".parse::<BackendKind>().is_err());
+    }
+
+    #[test]
+    fn file_backend_matches_offline_render() {
+        let (fixture, graph) = compile_fixture("sine_440.json");
+        let blocks = 200;
+
+        let path = std::env::temp_dir().join(format!("sarus_engine_{}.wav", std::process::id()));
+        let mut engine = Engine::new(Box::new(FileBackend::new(path.clone()).unpaced().with_length(blocks)));
+
+        engine.start(graph).unwrap();
+        wait_for_blocks(&engine, blocks);
+        engine.stop().unwrap();
+
+        let streamed = read_wav(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let mut offline_graph = fixture.patch.compile().unwrap();
+        let offline = render_while(&mut offline_graph, blocks, &[], || true).unwrap();
+
+        assert_eq!(streamed.len(), offline.len());
+        for (streamed, offline) in streamed.iter().zip(offline.iter()) {
+            assert_eq!(*streamed, *offline as f32 as f64);
+        }
+    }
+
+    #[test]
+    fn null_backend_runs_in_real_time() {
+        let (_, graph) = compile_fixture("passthrough.json");
+        let mut engine = Engine::new(Box::new(NullBackend::new()));
+
+        let start = Instant::now();
+        engine.start(graph).unwrap();
+        // 100ms of audio
+        let blocks = SAMPLE_RATE as usize / 10 / STEP_SIZE;
+        wait_for_blocks(&engine, blocks);
+        let elapsed = start.elapsed();
+        engine.stop().unwrap();
+
+        assert!(elapsed >= Duration::from_millis(90), "Null backend ran faster than real time: {:?}", elapsed);
+    }
+
+    fn sweep(n: usize) -> f64 {
+        ((n as f64).powi(2) * 0.000001).sin()
+    }
+
+    #[test]
+    fn set_graph_swaps_between_blocks() {
+        let (_, passthrough) = compile_fixture("passthrough.json");
+        let (_, gain) = compile_fixture("gain.json");
+        let (mut processor, mut sender) = Processor::new(passthrough, Arc::new(EngineStats::default()));
+
+        // Pulling single samples runs a block every STEP_SIZE samples
+        for _ in 0..STEP_SIZE * 3 {
+            processor.next_sample();
+        }
+        assert_eq!(processor.stats.blocks.load(Ordering::Relaxed), 3);
+
+        sender.send_with_fade(gain, 0);
+        let block = *processor.next_block();
+        assert_eq!(sender.retired.try_iter().count(), 1);
+
+        // The gain graph doubles the sine sweep fed to INPUT, and the sample counter carries on
+        for (i, sample) in block.iter().enumerate() {
+            assert!((sample - 2.0 * sweep(STEP_SIZE * 3 + i)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn midi_plays_at_its_sample() {
+        let (_, graph) = compile_fixture("midi_gate.json");
+        let (mut processor, sender) = Processor::new(graph, Arc::new(EngineStats::default()));
+
+        // Events passed with a buffer land on their sample, even across blocks
+        let mut buffer = [0.0; STEP_SIZE * 2];
+        let note_on = MidiEvent { time: STEP_SIZE + 3, message: MidiMessage::NoteOn { note: 60, velocity: 127 } };
+        processor.process_midi(&mut buffer, &[note_on]);
+        assert!(buffer[..STEP_SIZE + 3].iter().all(|&sample| sample == 0.0));
+        assert!(buffer[STEP_SIZE + 3..].iter().all(|&sample| sample == 1.0));
+
+        // Messages sent live play from the start of the next block
+        sender.send_midi(MidiMessage::NoteOff { note: 60 });
+        assert_eq!(*processor.next_block(), [0.0; STEP_SIZE]);
+    }
+
+    #[test]
+    fn events_beyond_a_blocks_capacity_are_counted_and_dropped() {
+        let (_, graph) = compile_fixture("midi_gate.json");
+        let (mut processor, _sender) = Processor::new(graph, Arc::new(EngineStats::default()));
+
+        let note_on = MidiEvent { time: 0, message: MidiMessage::NoteOn { note: 60, velocity: 127 } };
+        let mut buffer = [0.0; STEP_SIZE];
+        processor.process_midi(&mut buffer, &[note_on; MAX_BLOCK_EVENTS + 10]);
+        assert_eq!(processor.midi.capacity(), MAX_BLOCK_EVENTS);
+        assert_eq!(processor.stats.dropped_events.load(Ordering::Relaxed), 10);
+        assert!(buffer.iter().all(|&sample| sample == 1.0));
+    }
+
+    #[test]
+    fn parameters_change_without_recompiling() {
+        // Automation only applies offline, so live the parameter starts at its default
+        let (_, graph) = compile_fixture("parameter_automation.json");
+        let (mut processor, mut sender) = Processor::new(graph, Arc::new(EngineStats::default()));
+        assert_eq!(sender.parameters()[0].name, "level");
+        assert_eq!(*processor.next_block(), [0.25; STEP_SIZE]);
+
+        sender.set_parameter("level", 0.75).unwrap();
+        assert_eq!(*processor.next_block(), [0.75; STEP_SIZE]);
+
+        assert!(sender.set_parameter("missing", 1.0).is_err());
+    }
+
+    #[test]
+    fn swap_crossfades_with_equal_power() {
+        let (_, passthrough) = compile_fixture("passthrough.json");
+        let (_, gain) = compile_fixture("gain.json");
+        let (mut processor, mut sender) = Processor::new(passthrough, Arc::new(EngineStats::default()));
+
+        let fade_samples = STEP_SIZE * 4;
+        sender.send_with_fade(gain, fade_samples);
+
+        for block_index in 0..6 {
+            let block = *processor.next_block();
+            for (i, sample) in block.iter().enumerate() {
+                let n = block_index * STEP_SIZE + i;
+                let (fade_out, fade_in) = equal_power_gains(n as f64 / fade_samples as f64);
+                let expected = sweep(n) * fade_out + 2.0 * sweep(n) * fade_in;
+                assert!((sample - expected).abs() < 1e-9, "sample {}: {} != {}", n, sample, expected);
+            }
+
+            // The old graph is released once the fade has finished
+            let finished = (block_index + 1) * STEP_SIZE >= fade_samples;
+            assert_eq!(processor.fades.is_empty(), finished);
+        }
+
+        assert_eq!(sender.retired.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn swap_during_a_fade_fades_from_the_mix() {
+        let (_, passthrough) = compile_fixture("passthrough.json");
+        let (_, gain) = compile_fixture("gain.json");
+        let (_, second_passthrough) = compile_fixture("passthrough.json");
+        let (mut processor, mut sender) = Processor::new(passthrough, Arc::new(EngineStats::default()));
+
+        // The gain graph is replaced half way through fading in, so nothing is cut off
+        let fade_samples = STEP_SIZE * 4;
+        let second_swap = STEP_SIZE * 2;
+        sender.send_with_fade(gain, fade_samples);
+        processor.next_block();
+        processor.next_block();
+        sender.send_with_fade(second_passthrough, fade_samples);
+
+        for block_index in 2..8 {
+            let block = *processor.next_block();
+            for (i, sample) in block.iter().enumerate() {
+                let n = block_index * STEP_SIZE + i;
+                let (first_out, first_in) = equal_power_gains(n as f64 / fade_samples as f64);
+                let (second_out, second_in) = equal_power_gains((n - second_swap) as f64 / fade_samples as f64);
+                let mix = sweep(n) * first_out + 2.0 * sweep(n) * first_in;
+                let expected = mix * second_out + sweep(n) * second_in;
+                assert!((sample - expected).abs() < 1e-9, "sample {}: {} != {}", n, sample, expected);
+            }
+        }
+
+        assert!(processor.fades.is_empty());
+        assert_eq!(sender.retired.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn retired_graphs_wait_for_room_to_be_sent_back() {
+        let (_, passthrough) = compile_fixture("passthrough.json");
+        let (mut processor, mut sender) = Processor::new(passthrough, Arc::new(EngineStats::default()));
+
+        // Every swap arrives in the same block, so more graphs are retired than the queue holds
+        for _ in 0..RETIRED_GRAPHS + 2 {
+            let (_, graph) = compile_fixture("passthrough.json");
+            sender.send_with_fade(graph, 0);
+        }
+        processor.next_block();
+        assert_eq!(processor.parked.len(), 2);
+        assert_eq!(sender.retired.try_iter().count(), RETIRED_GRAPHS);
+
+        processor.next_block();
+        assert!(processor.parked.is_empty());
+        assert_eq!(sender.retired.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn equal_power_gains_keep_power_constant() {
+        assert_eq!(equal_power_gains(0.0), (1.0, 0.0));
+        let (fade_out, fade_in) = equal_power_gains(1.0);
+        assert!(fade_out.abs() < 1e-12 && (fade_in - 1.0).abs() < 1e-12);
+
+        for i in 0..=100 {
+            let (fade_out, fade_in) = equal_power_gains(i as f64 / 100.0);
+            assert!((fade_out * fade_out + fade_in * fade_in - 1.0).abs() < 1e-12);
+        }
+    }
+}
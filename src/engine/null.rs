@@ -0,0 +1,32 @@
+use super::{Backend, BlockThread, Processor};
+
+/// Runs the graph in real time and throws the output away.
+/// Useful for exercising the engine on machines without an audio device.
+pub struct NullBackend {
+    thread: Option<BlockThread>,
+}
+
+impl NullBackend {
+    pub fn new() -> Self {
+        Self { thread: None }
+    }
+}
+
+impl Backend for NullBackend {
+    fn name(&self) -> String {
+        "null".to_string()
+    }
+
+    fn start(&mut self, processor: Processor) -> anyhow::Result<()> {
+        self.stop()?;
+        self.thread = Some(BlockThread::spawn(processor, true, None, |_| Ok(())));
+        Ok(())
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread.stop(),
+            None => Ok(()),
+        }
+    }
+}
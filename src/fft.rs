@@ -0,0 +1,95 @@
+use std::f64::consts::PI;
+
+/// In-place radix-2 FFT of a complex signal split into real and imaginary parts.
+///
+/// Both slices must have the same length, which must be a power of two.
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    for stage in 0..fft_stages(re.len()) {
+        fft_stage(re, im, stage, false);
+    }
+}
+
+/// In-place inverse FFT, including the 1/N scaling
+pub fn ifft(re: &mut [f64], im: &mut [f64]) {
+    for stage in 0..fft_stages(re.len()) {
+        fft_stage(re, im, stage, true);
+    }
+}
+
+/// Number of stages `fft_stage` splits a transform of `len` samples into
+pub fn fft_stages(len: usize) -> usize {
+    len.trailing_zeros() as usize + 1
+}
+
+/// Runs one stage of an in-place FFT, so the work of a transform can be spread out. Running stages 0 to
+/// `fft_stages(len) - 1` in order is the same as `fft`, or `ifft` when `inverse` is set.
+pub fn fft_stage(re: &mut [f64], im: &mut [f64], stage: usize, inverse: bool) {
+    let n = re.len();
+    assert_eq!(n, im.len(), "real and imaginary parts must have the same length");
+    assert!(n.is_power_of_two(), "FFT length must be a power of two");
+
+    if stage == 0 {
+        bit_reverse(re, im);
+    } else {
+        butterflies(re, im, 1 << stage, inverse);
+    }
+
+    if inverse && stage + 1 == fft_stages(n) {
+        let scale = 1.0 / n as f64;
+        for (r, i) in re.iter_mut().zip(im.iter_mut()) {
+            *r *= scale;
+            *i *= scale;
+        }
+    }
+}
+
+/// Returns a Hann window of the given length
+pub fn hann(len: usize) -> Vec<f64> {
+    (0..len).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / len as f64).cos()).collect()
+}
+
+fn bit_reverse(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    if n < 2 {
+        return;
+    }
+
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if j > i {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+}
+
+// Combines pairs of transforms of half of `len` into transforms of `len`
+fn butterflies(re: &mut [f64], im: &mut [f64], len: usize, inverse: bool) {
+    let n = re.len();
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let angle = sign * 2.0 * PI / len as f64;
+    let (w_im, w_re) = angle.sin_cos();
+
+    for start in (0..n).step_by(len) {
+        let mut cur_re = 1.0;
+        let mut cur_im = 0.0;
+
+        for k in 0..len / 2 {
+            let a = start + k;
+            let b = a + len / 2;
+
+            let t_re = re[b] * cur_re - im[b] * cur_im;
+            let t_im = re[b] * cur_im + im[b] * cur_re;
+
+            re[b] = re[a] - t_re;
+            im[b] = im[a] - t_im;
+            re[a] += t_re;
+            im[a] += t_im;
+
+            let next_re = cur_re * w_re - cur_im * w_im;
+            cur_im = cur_re * w_im + cur_im * w_re;
+            cur_re = next_re;
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::midi::read_smf;
+use crate::patch::Patch;
+use crate::render::{render_while, write_wav, SAMPLE_RATE, STEP_SIZE};
+
+/// Directory containing the graph fixtures and their golden renders
+pub const FIXTURE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+
+/// When set, the golden files are rewritten from the current renders instead of being compared against
+pub const BLESS_VAR: &str = "SARUS_BLESS";
+/// Overrides the tolerance of every fixture
+pub const TOLERANCE_VAR: &str = "SARUS_GOLDEN_TOLERANCE";
+
+/// Maximum absolute difference allowed between a render and its golden file, unless the fixture sets one
+pub const DEFAULT_TOLERANCE: f64 = 1e-6;
+/// Number of blocks rendered for each fixture, unless the fixture sets one
+pub const DEFAULT_STEPS: usize = SAMPLE_RATE as usize / 2 / STEP_SIZE;
+
+/// A patch to render along with how closely it must match its golden file.
+/// Stored as JSON next to a WAV file with the same name.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Fixture {
+    #[serde(flatten)]
+    pub patch: Patch,
+    #[serde(default)]
+    pub tolerance: Option<f64>,
+    #[serde(default)]
+    pub steps: Option<usize>,
+    /// Standard MIDI File in the fixture directory played into the patch
+    #[serde(default)]
+    pub midi: Option<String>,
+}
+
+impl Fixture {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GoldenOptions {
+    pub bless: bool,
+    pub tolerance: Option<f64>,
+}
+
+impl GoldenOptions {
+    /// Reads the options from the SARUS_BLESS and SARUS_GOLDEN_TOLERANCE environment variables
+    pub fn from_env() -> Self {
+        Self {
+            bless: std::env::var_os(BLESS_VAR).map_or(false, |value| value != "0"),
+            tolerance: std::env::var(TOLERANCE_VAR).ok().and_then(|value| value.parse().ok()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Difference {
+    pub index: usize,
+    pub actual: f64,
+    pub expected: f64,
+}
+
+/// Result of comparing a render against its golden file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub tolerance: f64,
+    pub actual_len: usize,
+    pub expected_len: usize,
+    /// Largest absolute difference and the sample it occurred at
+    pub max_error: f64,
+    pub max_error_index: usize,
+    /// First sample differing by more than the tolerance
+    pub first_difference: Option<Difference>,
+}
+
+impl Comparison {
+    pub fn passed(&self) -> bool {
+        self.actual_len == self.expected_len && self.first_difference.is_none()
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.actual_len != self.expected_len {
+            write!(f, "rendered {} samples but the golden file has {}, ", self.actual_len, self.expected_len)?;
+        }
+
+        write!(f, "max error {:e} at sample {} (tolerance {:e})", self.max_error, self.max_error_index, self.tolerance)?;
+
+        if let Some(difference) = self.first_difference {
+            write!(f, ", first difference at sample {}: rendered {} expected {}", difference.index, difference.actual, difference.expected)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub enum Outcome {
+    Compared(Comparison),
+    Blessed,
+}
+
+/// Compares two signals sample by sample
+pub fn compare(actual: &[f64], expected: &[f64], tolerance: f64) -> Comparison {
+    let mut comparison = Comparison {
+        tolerance,
+        actual_len: actual.len(),
+        expected_len: expected.len(),
+        max_error: 0.0,
+        max_error_index: 0,
+        first_difference: None,
+    };
+
+    for (index, (&actual, &expected)) in actual.iter().zip(expected.iter()).enumerate() {
+        // NaN never compares within tolerance
+        let error = if actual.is_nan() || expected.is_nan() {
+            if actual.is_nan() && expected.is_nan() { 0.0 } else { f64::INFINITY }
+        } else {
+            (actual - expected).abs()
+        };
+
+        if error > comparison.max_error {
+            comparison.max_error = error;
+            comparison.max_error_index = index;
+        }
+
+        if error > tolerance && comparison.first_difference.is_none() {
+            comparison.first_difference = Some(Difference { index, actual, expected });
+        }
+    }
+
+    comparison
+}
+
+/// Renders a fixture and compares it with its golden file, or rewrites the golden file when blessing
+pub fn check(fixture_path: &Path, options: &GoldenOptions) -> anyhow::Result<Outcome> {
+    let fixture = Fixture::load(fixture_path)?;
+    let golden_path = fixture_path.with_extension("wav");
+
+    let midi = match &fixture.midi {
+        Some(name) => read_smf(&fixture_path.with_file_name(name))?,
+        None => Vec::new(),
+    };
+
+    let mut program = fixture.patch.compile()?;
+    let rendered = render_while(&mut program, fixture.steps.unwrap_or(DEFAULT_STEPS), &midi, || true).unwrap_or_default();
+
+    if options.bless {
+        write_wav(&rendered, &golden_path)?;
+        return Ok(Outcome::Blessed);
+    }
+
+    if !golden_path.exists() {
+        anyhow::bail!("missing golden file {}, run with {}=1 to create it", golden_path.display(), BLESS_VAR);
+    }
+
+    let expected = read_wav(&golden_path)?;
+
+    // Goldens are stored as 32 bit floats, so compare at the same precision
+    let rendered = rendered.iter().map(|sample| *sample as f32 as f64).collect::<Vec<_>>();
+
+    let tolerance = options.tolerance.or(fixture.tolerance).unwrap_or(DEFAULT_TOLERANCE);
+
+    Ok(Outcome::Compared(compare(&rendered, &expected, tolerance)))
+}
+
+/// Returns the paths of all fixtures in a directory, sorted by name
+pub fn fixtures(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+pub fn read_wav(path: &Path) -> anyhow::Result<Vec<f64>> {
+    let mut reader = hound::WavReader::open(path)?;
+    let samples = reader.samples::<f32>()
+        .map(|sample| sample.map(|sample| sample as f64))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_fixtures() {
+        let options = GoldenOptions::from_env();
+        let mut failures = Vec::new();
+
+        for path in fixtures(Path::new(FIXTURE_DIR)).expect("Failed to read fixture directory") {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            match check(&path, &options) {
+                Ok(Outcome::Compared(comparison)) if comparison.passed() => {}
+                Ok(Outcome::Compared(comparison)) => failures.push(format!("{}: {}", name, comparison)),
+                Ok(Outcome::Blessed) => println!("Blessed {}", name),
+                Err(err) => failures.push(format!("{}: {}", name, err)),
+            }
+        }
+
+        assert!(failures.is_empty(), "Golden renders differ:\n{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn compare_reports_first_difference() {
+        let comparison = compare(&[0.0, 0.5, 1.0, 0.0], &[0.0, 0.5, 0.9, 0.3], 0.05);
+        assert!(!comparison.passed());
+        assert_eq!(comparison.first_difference, Some(Difference { index: 2, actual: 1.0, expected: 0.9 }));
+        assert_eq!(comparison.max_error_index, 3);
+        assert!((comparison.max_error - 0.3).abs() < 1e-12);
+    }
+
+    #[test]
+    fn compare_fails_on_length_mismatch() {
+        let comparison = compare(&[0.0, 0.5], &[0.0, 0.5, 1.0], 0.0);
+        assert!(!comparison.passed());
+        assert!(comparison.first_difference.is_none());
+    }
+}
@@ -0,0 +1,29 @@
+pub mod ui;
+
+pub mod app;
+
+pub mod library;
+
+pub mod render;
+
+pub mod fft;
+
+pub mod analysis;
+
+pub mod patch;
+
+pub mod bench;
+
+pub mod worker;
+
+pub mod program;
+
+pub mod engine;
+
+pub mod midi;
+
+#[cfg(feature = "plugin")]
+pub mod plugin;
+
+#[cfg(test)]
+mod golden;
@@ -0,0 +1,104 @@
+use std::path::Path;
+
+use sarus::parser;
+
+use crate::program::{blocks, convolution_desc, midi_sources, natives, sample_player_desc, transport_sources, voice_mix_desc, wavetable_desc, ParameterSpec, Pattern};
+use crate::ui::NodeDesc;
+
+/// A collection of sarus functions which can be inserted as nodes.
+///
+/// Functions can be put into a category with a comment on the line above the declaration:
+///
+/// ```text
+/// // category: Oscillators
+/// fn sine_wave(n, f) -> (a) { ... }
+/// ```
+///
+/// Functions without a category comment are put into a category named after the library.
+pub struct Library {
+    pub code: String,
+    pub nodes: Vec<NodeDesc>,
+}
+
+impl Library {
+    pub fn parse(name: &str, source: &str) -> anyhow::Result<Self> {
+        // Comments are stripped before handing the code to the parser
+        let mut code = String::new();
+        let mut categories = Vec::new();
+        let mut category = None;
+
+        for line in source.lines() {
+            let trimmed = line.trim_start();
+            if let Some(comment) = trimmed.strip_prefix("//") {
+                let comment = comment.trim_start_matches('/').trim();
+                if let Some(name) = comment.strip_prefix("category:") {
+                    category = Some(name.trim().to_string());
+                }
+                code.push('\n');
+                continue;
+            }
+
+            if let Some(decl) = trimmed.strip_prefix("fn ") {
+                let fn_name = decl.split(|c: char| c == '(' || c.is_whitespace()).next().unwrap_or_default();
+                categories.push((fn_name.to_string(), category.take()));
+            } else if !trimmed.is_empty() {
+                category = None;
+            }
+
+            code.push_str(line);
+            code.push('\n');
+        }
+
+        let ast = parser::program(&code)?;
+
+        let nodes = ast.into_iter().map(|decl| {
+            let category = categories.iter()
+                .find(|(fn_name, _)| *fn_name == decl.name)
+                .and_then(|(_, category)| category.clone())
+                .unwrap_or_else(|| name.to_string());
+
+            NodeDesc {
+                name: decl.name.to_string(),
+                inputs: decl.params.clone(),
+                outputs: decl.returns.clone(),
+                category,
+            }
+        }).collect();
+
+        Ok(Self {
+            code,
+            nodes,
+        })
+    }
+
+    /// Loads a library from a file, using the file name as the default category
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let source = std::fs::read_to_string(path)?;
+        let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("Library");
+        Self::parse(name, &source)
+    }
+
+    /// The nodes implemented in Rust, which have no sarus code
+    pub fn native() -> Self {
+        Self {
+            code: String::new(),
+            nodes: natives().iter().map(|native| native.node_desc())
+                .chain(blocks().iter().map(|block| block.node_desc()))
+                .chain(midi_sources().iter().map(|source| source.node_desc()))
+                .chain(transport_sources().iter().map(|source| source.node_desc()))
+                .chain(std::iter::once(ParameterSpec::node_desc()))
+                .chain(std::iter::once(voice_mix_desc()))
+                .chain(std::iter::once(Pattern::node_desc()))
+                .chain(std::iter::once(sample_player_desc()))
+                .chain(std::iter::once(wavetable_desc()))
+                .chain(std::iter::once(convolution_desc()))
+                .collect(),
+        }
+    }
+
+    /// Appends the functions of another library to this one
+    pub fn extend(&mut self, other: Library) {
+        self.code.push_str(&other.code);
+        self.nodes.extend(other.nodes);
+    }
+}
@@ -1,99 +1,135 @@
-use sarus::{frontend::Declaration, jit, parser, run_string};
+use sarus::{jit, run_string};
 
 use tuix::*;
 
-mod ui;
-use ui::*;
+use sarus_plugin::app::{load_library, STYLE};
+use sarus_plugin::{bench, midi, render};
+use sarus_plugin::engine::{self, BackendKind, Engine};
+use sarus_plugin::patch::Patch;
+use sarus_plugin::ui::*;
 
-const STYLE: &str = r#"
-    .node {
-        background-color: #303030;
-    }
-    .socket {
-        background-color: green;
-    }
-    
-    .node_label {
-        background-color: #303099;
-    }
-    popup {
-        background-color: #d2d2d2;
-    }
-    list>button {
-        height: 30px;
-        child-space: 1s;
-        color: black;
-        background-color: #d2d2d2;
-    }
-    list>button:hover {
-        background-color: #e2e2e2;
+fn main() -> anyhow::Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+
+    // Number of threads a patch played with `--play` is processed on, with `--threads <n>`
+    let threads: Option<usize> = match args.iter().position(|arg| arg == "--threads") {
+        Some(index) => Some(args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Usage: sarus-plugin --threads <n>"))?.parse()?),
+        None => None,
+    };
+
+    // Benchmark a patch from the command line with `--bench <patch.json>`
+    if let Some(index) = args.iter().position(|arg| arg == "--bench") {
+        let path = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Usage: sarus-plugin --bench <patch.json>"))?;
+        let patch = Patch::load(std::path::Path::new(path))?;
+        print!("{}", bench::bench(&patch)?);
+        return Ok(());
     }
-    list>button:active {
-        background-color: #c2c2c2;
+
+    // Audio backend for live playback with `--backend device|null|file:<path>`
+    let backend = match args.iter().position(|arg| arg == "--backend") {
+        Some(index) => args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Usage: sarus-plugin --backend device|null|file:<path>"))?.parse()?,
+        None => BackendKind::default(),
+    };
+
+    // Length of the crossfade when the playing graph is replaced, with `--crossfade <ms>`
+    let crossfade = match args.iter().position(|arg| arg == "--crossfade") {
+        Some(index) => {
+            let ms: f64 = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Usage: sarus-plugin --crossfade <ms>"))?.parse()?;
+            std::time::Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+        }
+        None => engine::DEFAULT_CROSSFADE,
+    };
+
+    // MIDI input port to play from while streaming, with `--midi <port>`. Any port whose name contains it is used,
+    // so `--midi ""` picks the first one.
+    let midi_port = match args.iter().position(|arg| arg == "--midi") {
+        Some(index) => Some(args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Usage: sarus-plugin --midi <port>"))?.clone()),
+        None => None,
+    };
+
+    // Standard MIDI File played into offline renders, with `--midi-file <file.mid>`
+    let midi = match args.iter().position(|arg| arg == "--midi-file") {
+        Some(index) => {
+            let path = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Usage: sarus-plugin --midi-file <file.mid>"))?;
+            midi::read_smf(std::path::Path::new(path))?
+        }
+        None => Vec::new(),
+    };
+
+    // Render a patch to a WAV file without the editor with `--render <patch.json> <out.wav> [--seconds <n>]`
+    if let Some(index) = args.iter().position(|arg| arg == "--render") {
+        let usage = || anyhow::anyhow!("Usage: sarus-plugin --render <patch.json> <out.wav>");
+        let patch_path = args.get(index + 1).ok_or_else(usage)?;
+        let wav_path = args.get(index + 2).ok_or_else(usage)?;
+        let seconds: f64 = match args.iter().position(|arg| arg == "--seconds") {
+            Some(index) => args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Missing number of seconds"))?.parse()?,
+            None => 5.0,
+        };
+
+        let mut patch = Patch::load(std::path::Path::new(patch_path))?;
+        apply_transport_args(&args, &mut patch)?;
+        let mut program = patch.compile()?;
+        if !program.optimization().is_empty() {
+            println!("{}", program.optimization());
+        }
+        let steps = (seconds * render::SAMPLE_RATE as f64) as usize / render::STEP_SIZE;
+        let output = render::render_while(&mut program, steps, &midi, || true).unwrap_or_default();
+        render::write_wav(&output, std::path::Path::new(wav_path))?;
+        println!("Rendered {}s of {} to {}", seconds, patch_path, wav_path);
+        return Ok(());
     }
-"#;
 
+    // Stream a patch without the editor with `--play <patch.json> [--seconds <n>]`
+    if let Some(index) = args.iter().position(|arg| arg == "--play") {
+        let path = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Usage: sarus-plugin --play <patch.json>"))?;
+        let seconds = match args.iter().position(|arg| arg == "--seconds") {
+            Some(index) => args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Missing number of seconds"))?.parse()?,
+            None => 10.0,
+        };
+
+        let mut patch = Patch::load(std::path::Path::new(path))?;
+        patch.threads = threads.or(patch.threads);
+        apply_transport_args(&args, &mut patch)?;
+        let mut engine = Engine::new(backend.create()?);
+        engine.set_crossfade(crossfade);
+        engine.set_midi_input(midi_port.clone());
+        engine.start(patch.compile()?)?;
+
+        // Parameters can be set with `--param <name>=<value>`, as many times as needed
+        for (index, _) in args.iter().enumerate().filter(|(_, arg)| *arg == "--param") {
+            let setting = args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Usage: --param <name>=<value>"))?;
+            let (name, value) = setting.split_once('=').ok_or_else(|| anyhow::anyhow!("Expected <name>=<value>, got {}", setting))?;
+            engine.set_parameter(name, value.parse()?)?;
+        }
+        for parameter in engine.parameters() {
+            println!("Parameter {}: {} to {} {}", parameter.name, parameter.min, parameter.max, parameter.unit);
+        }
+
+        println!("Playing {} on {} for {}s", path, engine.backend_name(), seconds);
+        std::thread::sleep(std::time::Duration::from_secs_f64(seconds));
+        engine.stop()?;
+        println!("Processed {} blocks", engine.blocks_processed());
+        if engine.worker_failures() > 0 {
+            println!("A node panicked on a worker thread in {} levels", engine.worker_failures());
+        }
+        if engine.dropped_events() > 0 {
+            println!("Dropped {} MIDI events which arrived too close together", engine.dropped_events());
+        }
+        return Ok(());
+    }
 
-fn main() -> anyhow::Result<()> {
     // Create the JIT instance, which manages all generated functions and data.
     let mut jit = jit::JIT::default();
 
-    let code = r#"
-fn main(a) -> (b) {
-    b = 0.05 * a
-}
-
-fn note_A() -> (b) {
-    b = 440.0
-} 
-
-fn note_Cs() -> (b) {
-    b = 554.37
-} 
+    let library = load_library()?;
 
-fn note_E() -> (b) {
-    b = 659.25
-} 
-
-fn double(a) -> (b) {
-    b = 2.0 * a
-}
-
-fn divide_three(a) -> (b) {
-    b = a / 3.0
-}
-
-fn tanh_node(a) -> (b) {
-    b = tanh(a)
-}
-
-fn sin_node(a) -> (b) {
-    b = sin(a)
-}
-
-fn const_01() -> (b) {
-    b = 0.0001
-}
-
-fn mul_node(a, b) -> (c) {
-    c = a * b
-}
-
-fn add_node(a, b) -> (c) {
-    c = a + b
-}
-
-fn sine_wave(n, f) -> (a) {
-    a = sin(2.0 * 3.1415926 * f * (n / 48000.0))
-}
-
-"#;
+    let code = library.code.clone();
 
     // Run string with jit instance.
     // This function is unsafe since it relies on the caller to provide it with the correct
     // input and output types. Using incorrect types at this point may corrupt the program's state.
     // Check out run_string() source if you need to separate out execution and parsing steps
-    let result: f64 = unsafe { run_string(&mut jit, code, "main", (100.0f64, 200.0f64))? };
+    let result: f64 = unsafe { run_string(&mut jit, &code, "main", (100.0f64, 200.0f64))? };
 
     println!("the answer is: {}", result);
 
@@ -102,16 +138,14 @@ fn sine_wave(n, f) -> (a) {
         
         state.add_theme(STYLE);
         window.set_background_color(state, Color::rgb(30,30,30));
-        let node_app = NodeApp::new(code).build(state, window, |builder| builder);
-
-        let ast: Vec<Declaration> = parser::program(code).expect("Failed to parse code");
-
-        for decl in ast.into_iter() {
-            node_app.emit(state, AppEvent::AddNode(NodeDesc {
-                name: decl.name.to_string(),
-                inputs: decl.params.clone(),
-                outputs: decl.returns.clone(),
-            }));
+        let node_app = NodeApp::new(&code, backend.clone())
+            .with_crossfade(crossfade)
+            .with_midi(midi.clone())
+            .with_midi_input(midi_port.clone())
+            .build(state, window, |builder| builder);
+
+        for node_desc in library.nodes.iter() {
+            node_app.emit(state, AppEvent::AddNode(node_desc.clone()));
         }
         
 
@@ -120,5 +154,36 @@ fn sine_wave(n, f) -> (a) {
     app.run();
 
 
+    Ok(())
+}
+
+// Overrides the transport saved with a patch with `--bpm <bpm>`, `--time-signature <beats>/<note value>`
+// and `--loop <start>:<end>` in beats
+fn apply_transport_args(args: &[String], patch: &mut Patch) -> anyhow::Result<()> {
+    let value = |flag: &str| -> anyhow::Result<Option<&String>> {
+        match args.iter().position(|arg| arg == flag) {
+            Some(index) => Ok(Some(args.get(index + 1).ok_or_else(|| anyhow::anyhow!("Missing value for {}", flag))?)),
+            None => Ok(None),
+        }
+    };
+
+    let mut transport = patch.transport.clone().unwrap_or_default();
+
+    if let Some(bpm) = value("--bpm")? {
+        transport.bpm = bpm.parse()?;
+    }
+    if let Some(signature) = value("--time-signature")? {
+        let (beats, note) = signature.split_once('/').ok_or_else(|| anyhow::anyhow!("Expected <beats>/<note value>, got {}", signature))?;
+        transport.time_signature = (beats.parse()?, note.parse()?);
+    }
+    if let Some(range) = value("--loop")? {
+        let (start, end) = range.split_once(':').ok_or_else(|| anyhow::anyhow!("Expected <start>:<end>, got {}", range))?;
+        transport.loop_range = Some((start.parse()?, end.parse()?));
+    }
+
+    if transport != patch.transport.clone().unwrap_or_default() {
+        patch.transport = Some(transport);
+    }
+
     Ok(())
 }
@@ -0,0 +1,158 @@
+use std::path::Path;
+
+use midly::{MetaMessage, Smf, Timing, TrackEventKind};
+
+use crate::render::SAMPLE_RATE;
+
+/// The MIDI messages graphs respond to. Channels are ignored, every graph listens to all of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiMessage {
+    NoteOn { note: u8, velocity: u8 },
+    NoteOff { note: u8 },
+    /// Aftertouch of a single note
+    PolyPressure { note: u8, pressure: u8 },
+    /// Aftertouch of the whole channel
+    ChannelPressure(u8),
+    ControlChange { controller: u8, value: u8 },
+}
+
+impl MidiMessage {
+    /// Parses a raw message, returning `None` for messages graphs don't use
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let status = *bytes.first()?;
+        let data = |index: usize| bytes.get(index).map(|byte| byte & 0x7f);
+
+        match status & 0xf0 {
+            0x80 => Some(MidiMessage::NoteOff { note: data(1)? }),
+            // A note on with no velocity is a note off
+            0x90 => match data(2)? {
+                0 => Some(MidiMessage::NoteOff { note: data(1)? }),
+                velocity => Some(MidiMessage::NoteOn { note: data(1)?, velocity }),
+            },
+            0xa0 => Some(MidiMessage::PolyPressure { note: data(1)?, pressure: data(2)? }),
+            0xb0 => Some(MidiMessage::ControlChange { controller: data(1)?, value: data(2)? }),
+            0xd0 => Some(MidiMessage::ChannelPressure(data(1)?)),
+            _ => None,
+        }
+    }
+}
+
+/// A message at a point in time, measured in samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MidiEvent {
+    pub time: usize,
+    pub message: MidiMessage,
+}
+
+/// Frequency in Hz of a MIDI note number, with A4 (note 69) at 440Hz
+pub fn note_to_hz(note: f64) -> f64 {
+    440.0 * 2f64.powf((note - 69.0) / 12.0)
+}
+
+/// Reads the notes, aftertouch and controllers of a Standard MIDI File, with their times converted to samples
+pub fn read_smf(path: &Path) -> anyhow::Result<Vec<MidiEvent>> {
+    let bytes = std::fs::read(path)?;
+    parse_smf(&bytes)
+}
+
+/// Parses a Standard MIDI File, merging all its tracks into one list of events sorted by time
+pub fn parse_smf(bytes: &[u8]) -> anyhow::Result<Vec<MidiEvent>> {
+    let smf = Smf::parse(bytes)?;
+
+    // Absolute tick of every event, with the track index so simultaneous events keep their file order
+    let mut events = Vec::new();
+    for (track_index, track) in smf.tracks.iter().enumerate() {
+        let mut tick = 0u64;
+        for event in track.iter() {
+            tick += event.delta.as_int() as u64;
+            events.push((tick, track_index, event.kind));
+        }
+    }
+    events.sort_by_key(|(tick, track_index, _)| (*tick, *track_index));
+
+    let seconds_per_tick = |tempo: u32| match smf.header.timing {
+        Timing::Metrical(ticks_per_beat) => tempo as f64 / 1_000_000.0 / ticks_per_beat.as_int() as f64,
+        Timing::Timecode(fps, subframe) => 1.0 / fps.as_f32() as f64 / subframe as f64,
+    };
+
+    // Tempo changes apply from their tick onwards, 120 BPM until the first one
+    let mut seconds_per_tick_now = seconds_per_tick(500_000);
+    let mut last_tick = 0;
+    let mut seconds = 0.0;
+
+    let mut midi_events = Vec::new();
+    for (tick, _, kind) in events {
+        seconds += (tick - last_tick) as f64 * seconds_per_tick_now;
+        last_tick = tick;
+
+        let message = match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                seconds_per_tick_now = seconds_per_tick(tempo.as_int());
+                continue;
+            }
+
+            TrackEventKind::Midi { message, .. } => match message {
+                midly::MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => MidiMessage::NoteOn { note: key.as_int(), velocity: vel.as_int() },
+                midly::MidiMessage::NoteOn { key, .. } | midly::MidiMessage::NoteOff { key, .. } => MidiMessage::NoteOff { note: key.as_int() },
+                midly::MidiMessage::Aftertouch { key, vel } => MidiMessage::PolyPressure { note: key.as_int(), pressure: vel.as_int() },
+                midly::MidiMessage::ChannelAftertouch { vel } => MidiMessage::ChannelPressure(vel.as_int()),
+                midly::MidiMessage::Controller { controller, value } => MidiMessage::ControlChange { controller: controller.as_int(), value: value.as_int() },
+                _ => continue,
+            },
+
+            _ => continue,
+        };
+
+        midi_events.push(MidiEvent {
+            time: (seconds * SAMPLE_RATE as f64).round() as usize,
+            message,
+        });
+    }
+
+    Ok(midi_events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_messages() {
+        assert_eq!(MidiMessage::from_bytes(&[0x91, 60, 100]), Some(MidiMessage::NoteOn { note: 60, velocity: 100 }));
+        assert_eq!(MidiMessage::from_bytes(&[0x90, 60, 0]), Some(MidiMessage::NoteOff { note: 60 }));
+        assert_eq!(MidiMessage::from_bytes(&[0xb0, 1, 64]), Some(MidiMessage::ControlChange { controller: 1, value: 64 }));
+        assert_eq!(MidiMessage::from_bytes(&[0xd0, 10]), Some(MidiMessage::ChannelPressure(10)));
+        assert_eq!(MidiMessage::from_bytes(&[0xc0, 5]), None);
+        assert_eq!(MidiMessage::from_bytes(&[0x90, 60]), None);
+    }
+
+    #[test]
+    fn note_frequencies() {
+        assert_eq!(note_to_hz(69.0), 440.0);
+        assert!((note_to_hz(60.0) - 261.6256).abs() < 1e-4);
+    }
+
+    #[test]
+    fn smf_times_follow_tempo_changes() {
+        #[rustfmt::skip]
+        let bytes = [
+            // Header: format 0, one track, 480 ticks per beat
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0x01, 0xe0,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 21,
+            // Note on at tick 0
+            0x00, 0x90, 60, 100,
+            // Tempo of 60 BPM after one beat at the default 120 BPM (0.5s)
+            0x83, 0x60, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40,
+            // Note off one beat later (1s)
+            0x83, 0x60, 0x80, 60, 0,
+            // End of track
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+
+        let events = parse_smf(&bytes).unwrap();
+        assert_eq!(events, vec![
+            MidiEvent { time: 0, message: MidiMessage::NoteOn { note: 60, velocity: 100 } },
+            MidiEvent { time: 3 * SAMPLE_RATE as usize / 2, message: MidiMessage::NoteOff { note: 60 } },
+        ]);
+    }
+}
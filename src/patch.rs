@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::program::{Automation, ParameterSpec, Pattern, Polyphony, Program, Rate, Transport};
+
+/// Serializable description of a graph: the sarus code plus the nodes and connections using it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Patch {
+    pub code: String,
+    pub nodes: Vec<PatchNode>,
+    pub connections: Vec<PatchConnection>,
+    /// Parameter changes over time, applied when the patch is rendered offline
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub automation: Vec<Automation>,
+    /// Number of voices and how they're stolen, for patches with a Voice Mix node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub polyphony: Option<Polyphony>,
+    /// Tempo, time signature and loop of the transport, which starts from the beginning when rendering
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport: Option<Transport>,
+    /// Threads the graph is processed on, one when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threads: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PatchNode {
+    pub func_name: String,
+    pub id: String,
+    /// Default value of each input followed by each output
+    pub port_defaults: Vec<f64>,
+    /// Position of the node in the editor
+    #[serde(default)]
+    pub position: (f32, f32),
+    /// Name, range and default of a parameter node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameter: Option<ParameterSpec>,
+    /// Steps played by a sequencer node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<Pattern>,
+    /// WAV file read by a sample player, wavetable oscillator or convolution node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample: Option<String>,
+    /// How often a sarus node is evaluated, inferred from its inputs when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<Rate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PatchConnection {
+    pub src_node: usize,
+    pub dst_node: usize,
+    pub src_port: usize,
+    pub dst_port: usize,
+}
+
+impl Patch {
+    /// The graph a new editor starts with: INPUT connected straight to OUTPUT, plus the COUNTER
+    pub fn passthrough(code: &str) -> Self {
+        let io_node = |name: &str| PatchNode {
+            func_name: name.to_string(),
+            id: name.to_string(),
+            port_defaults: vec![0.0],
+            position: (0.0, 0.0),
+            parameter: None,
+            pattern: None,
+            sample: None,
+            rate: None,
+        };
+
+        Self {
+            code: code.to_string(),
+            nodes: vec![io_node("INPUT"), io_node("OUTPUT"), io_node("COUNTER")],
+            connections: vec![
+                PatchConnection {
+                    src_node: 0,
+                    dst_node: 1,
+                    src_port: 0,
+                    dst_port: 0,
+                },
+            ],
+            automation: Vec::new(),
+            polyphony: None,
+            transport: None,
+            threads: None,
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// Returns the index of the node with the given id
+    pub fn node_index(&self, id: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.id == id)
+    }
+
+    /// JIT compiles the patch, leaving out the nodes which can't affect its output and folding
+    /// constant nodes into the defaults they feed
+    pub fn compile(&self) -> anyhow::Result<Program> {
+        Program::optimized(self)
+    }
+}
@@ -0,0 +1,169 @@
+use std::any::Any;
+use std::sync::{Arc, Mutex};
+
+use nih_plug::prelude::*;
+use tuix::*;
+
+use super::{SarusParams, PARAMETER_SLOTS};
+use crate::app::STYLE;
+use crate::engine::{BackendKind, GraphSender};
+use crate::library::Library;
+use crate::midi::MidiMessage;
+use crate::patch::Patch;
+use crate::program::Program;
+use crate::ui::{AppEvent, GraphHost, NodeApp};
+
+const WIDTH: u32 = 1200;
+const HEIGHT: u32 = 800;
+
+/// Opens the node editor inside the host's plugin window
+pub struct NodeEditor {
+    params: Arc<SarusParams>,
+    sender: Arc<Mutex<Option<GraphSender>>>,
+}
+
+impl NodeEditor {
+    pub fn new(params: Arc<SarusParams>, sender: Arc<Mutex<Option<GraphSender>>>) -> Self {
+        Self { params, sender }
+    }
+}
+
+impl Editor for NodeEditor {
+    fn spawn(&self, parent: ParentWindowHandle, context: Arc<dyn GuiContext>) -> Box<dyn Any + Send> {
+        let mut patch = self.params.patch.read().unwrap().clone();
+
+        // The sliders start from the slots, which the host may have moved since the patch was saved
+        let specs = patch.nodes.iter_mut().filter_map(|node| node.parameter.as_mut());
+        for (spec, slot) in specs.zip(self.params.slots.iter()) {
+            spec.default = spec.denormalize(slot.value.value() as f64);
+        }
+
+        let host = PluginHost {
+            params: self.params.clone(),
+            sender: self.sender.clone(),
+            context,
+        };
+
+        let window_description = WindowDescription::new().with_title("Sarus").with_inner_size(WIDTH, HEIGHT);
+        let app = Application::new(window_description, move |state, window| {
+            state.add_theme(STYLE);
+            window.set_background_color(state, Color::rgb(30, 30, 30));
+
+            // The palette offers the nodes of the code saved with the patch, so it always matches the graph
+            let library = Library::parse("Patch", &patch.code)
+                .map(|mut library| {
+                    library.extend(Library::native());
+                    library
+                })
+                .unwrap_or_else(|_| Library::native());
+
+            // The host plays the graph, so the editor's own engine is never started
+            let node_app = NodeApp::new(&patch.code, BackendKind::Null)
+                .with_patch(patch.clone())
+                .with_host(Box::new(host.clone()))
+                .build(state, window, |builder| builder);
+
+            for node_desc in library.nodes.iter() {
+                node_app.emit(state, AppEvent::AddNode(node_desc.clone()));
+            }
+        });
+
+        Box::new(EditorHandle(app.open_parented(&parent)))
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (WIDTH, HEIGHT)
+    }
+
+    fn set_scale_factor(&self, _factor: f32) -> bool {
+        false
+    }
+
+    fn param_value_changed(&self, _id: &str, _normalized_value: f32) {}
+
+    fn param_modulation_changed(&self, _id: &str, _modulation_offset: f32) {}
+
+    fn param_values_changed(&self) {}
+}
+
+/// Stores each compiled patch in the plugin state and sends its graph to the audio thread
+#[derive(Clone)]
+struct PluginHost {
+    params: Arc<SarusParams>,
+    sender: Arc<Mutex<Option<GraphSender>>>,
+    // Moves the host's slots so the host sees, and can record, the changes made in the editor
+    context: Arc<dyn GuiContext>,
+}
+
+impl PluginHost {
+    fn set_slot(&self, index: usize, normalized: f64) {
+        let setter = ParamSetter::new(self.context.as_ref());
+        let slot = &self.params.slots[index].value;
+        setter.begin_set_parameter(slot);
+        setter.set_parameter(slot, normalized as f32);
+        setter.end_set_parameter(slot);
+    }
+}
+
+impl GraphHost for PluginHost {
+    fn set_graph(&mut self, patch: Patch, mut program: Program) {
+        *self.params.patch.write().unwrap() = patch;
+
+        // A slot keeps its value, which the host may be automating, while it holds the same parameter.
+        // Only slots given a new parameter are moved, to its default.
+        let previous = std::mem::replace(&mut *self.params.specs.write().unwrap(), program.parameters().to_vec());
+        let specs = program.parameters().iter().take(PARAMETER_SLOTS).cloned().collect::<Vec<_>>();
+        for (index, spec) in specs.iter().enumerate() {
+            if previous.get(index).map_or(false, |old| old.name == spec.name) {
+                let normalized = self.params.slots[index].value.value() as f64;
+                program.set_parameter(index, spec.denormalize(normalized));
+            } else {
+                self.set_slot(index, spec.normalize(spec.default));
+            }
+        }
+
+        // There's no sender until the plugin has been initialized, which compiles the stored patch anyway
+        if let Some(sender) = self.sender.lock().unwrap().as_mut() {
+            sender.collect_garbage();
+            sender.send(program);
+        }
+    }
+
+    // Parameters with a slot are set through the host, which passes them on to the graph
+    fn set_parameter(&mut self, name: &str, value: f64) {
+        let slot = {
+            let specs = self.params.specs.read().unwrap();
+            specs.iter().take(PARAMETER_SLOTS)
+                .position(|spec| spec.name == name)
+                .map(|index| (index, specs[index].normalize(value)))
+        };
+        if let Some((index, normalized)) = slot {
+            self.set_slot(index, normalized);
+            return;
+        }
+
+        if let Some(sender) = self.sender.lock().unwrap().as_mut() {
+            if let Err(err) = sender.set_parameter(name, value) {
+                nih_log!("Failed to set parameter: {}", err);
+            }
+        }
+    }
+
+    fn send_midi(&mut self, message: MidiMessage) {
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            sender.send_midi(message);
+        }
+    }
+}
+
+/// Keeps the editor window open until the host drops it
+struct EditorHandle(WindowHandle);
+
+// The handle is only used to close the window, which the host does from its GUI thread
+unsafe impl Send for EditorHandle {}
+
+impl Drop for EditorHandle {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
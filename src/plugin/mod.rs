@@ -0,0 +1,275 @@
+//! The node editor built as a CLAP and VST3 plugin.
+//!
+//! The patch is the plugin's state, so it's saved and restored by the host. The editor is the plugin's GUI
+//! and every graph it compiles replaces the one being played, crossfading like the standalone engine.
+
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex, RwLock};
+
+use nih_plug::prelude::*;
+
+use crate::app::{load_library, BUILTIN_CODE};
+use crate::engine::{EngineStats, GraphSender, Processor};
+use crate::midi::{MidiEvent, MidiMessage};
+use crate::patch::Patch;
+use crate::program::ParameterSpec;
+use crate::render::SAMPLE_RATE;
+
+mod editor;
+
+use editor::NodeEditor;
+
+/// Number of host parameters the parameters of the graph are mapped to, in order.
+/// Plugin formats expect a fixed list of parameters so the graph can't add its own.
+pub const PARAMETER_SLOTS: usize = 8;
+
+// Most MIDI events from the host handled in one buffer without allocating
+const MAX_BUFFER_EVENTS: usize = 1024;
+
+#[derive(Params)]
+pub struct SarusParams {
+    /// The patch being played, stored with the host's project
+    #[persist = "patch"]
+    pub patch: Arc<RwLock<Patch>>,
+
+    #[nested(array, group = "Graph parameters")]
+    pub slots: [SlotParams; PARAMETER_SLOTS],
+
+    /// Parameters of the graph being played, which the slots show their values in
+    pub specs: Arc<RwLock<Vec<ParameterSpec>>>,
+}
+
+/// A host parameter controlling the graph parameter with the same index, mapped from 0..1 to its range.
+///
+/// Hosts read the names of parameters once, before a patch is restored, so the slots stay numbered and
+/// their values show the name, value and unit of the graph parameter instead.
+#[derive(Params)]
+pub struct SlotParams {
+    #[id = "slot"]
+    pub value: FloatParam,
+}
+
+impl SlotParams {
+    fn new(index: usize, specs: &Arc<RwLock<Vec<ParameterSpec>>>) -> Self {
+        let display = specs.clone();
+        let parse = specs.clone();
+
+        Self {
+            value: FloatParam::new(format!("Parameter {}", index + 1), 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_value_to_string(Arc::new(move |normalized| match display.read().unwrap().get(index) {
+                    Some(spec) => format!("{}: {:.3} {}", spec.name, spec.denormalize(normalized as f64), spec.unit).trim_end().to_string(),
+                    None => "Unused".to_string(),
+                }))
+                .with_string_to_value(Arc::new(move |string| {
+                    let specs = parse.read().unwrap();
+                    let spec = specs.get(index)?;
+                    let value = string.trim()
+                        .trim_start_matches(&format!("{}:", spec.name))
+                        .trim_end_matches(spec.unit.as_str())
+                        .trim()
+                        .parse::<f64>()
+                        .ok()?;
+                    Some(spec.normalize(value) as f32)
+                })),
+        }
+    }
+}
+
+impl Default for SarusParams {
+    fn default() -> Self {
+        let code = load_library().map(|library| library.code).unwrap_or_else(|_| BUILTIN_CODE.to_string());
+        let specs = Arc::new(RwLock::new(Vec::new()));
+
+        Self {
+            patch: Arc::new(RwLock::new(Patch::passthrough(&code))),
+            slots: std::array::from_fn(|index| SlotParams::new(index, &specs)),
+            specs,
+        }
+    }
+}
+
+pub struct SarusPlugin {
+    params: Arc<SarusParams>,
+    processor: Option<Processor>,
+    // Shared with the editor, which sends it each graph it compiles
+    sender: Arc<Mutex<Option<GraphSender>>>,
+    // The graph is mono so the channels are mixed into this before processing
+    buffer: Vec<f64>,
+    // Last value of each slot passed to the graph, so parameters are only set when the host changes them
+    slot_values: [f32; PARAMETER_SLOTS],
+    // MIDI from the host for the buffer being processed, up to MAX_BUFFER_EVENTS
+    midi: Vec<MidiEvent>,
+    // Latency last reported to the host, which changes with the graph
+    latency: usize,
+}
+
+impl Default for SarusPlugin {
+    fn default() -> Self {
+        Self {
+            params: Arc::new(SarusParams::default()),
+            processor: None,
+            sender: Arc::new(Mutex::new(None)),
+            buffer: Vec::new(),
+            slot_values: [0.0; PARAMETER_SLOTS],
+            midi: Vec::with_capacity(MAX_BUFFER_EVENTS),
+            latency: 0,
+        }
+    }
+}
+
+impl Plugin for SarusPlugin {
+    const NAME: &'static str = "Sarus";
+    const VENDOR: &'static str = "Sarus";
+    const URL: &'static str = "https://github.com/geom3trik/sarus-plugin";
+    const EMAIL: &'static str = "";
+    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
+        main_input_channels: NonZeroU32::new(2),
+        main_output_channels: NonZeroU32::new(2),
+        ..AudioIOLayout::const_default()
+    }];
+
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
+
+    type SysExMessage = ();
+    type BackgroundTask = ();
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+        Some(Box::new(NodeEditor::new(self.params.clone(), self.sender.clone())))
+    }
+
+    // Also called after the host restores the state, so the restored patch is compiled here
+    fn initialize(&mut self, _audio_io_layout: &AudioIOLayout, buffer_config: &BufferConfig, context: &mut impl InitContext<Self>) -> bool {
+        if buffer_config.sample_rate as u32 != SAMPLE_RATE {
+            nih_log!("Graphs are written for {} Hz but the host runs at {} Hz", SAMPLE_RATE, buffer_config.sample_rate);
+        }
+
+        let patch = self.params.patch.read().unwrap().clone();
+        let mut program = match patch.compile() {
+            Ok(program) => program,
+            Err(err) => {
+                nih_log!("Failed to compile patch: {}", err);
+                return false;
+            }
+        };
+
+        self.latency = program.latency();
+        context.set_latency_samples(self.latency as u32);
+
+        // The slots are restored with the patch, and may have been automated since it was saved
+        for (index, slot) in self.params.slots.iter().enumerate() {
+            self.slot_values[index] = slot.value.value();
+            if let Some(spec) = program.parameters().get(index) {
+                let value = spec.denormalize(self.slot_values[index] as f64);
+                program.set_parameter(index, value);
+            }
+        }
+        *self.params.specs.write().unwrap() = program.parameters().to_vec();
+
+        let (processor, sender) = Processor::new(program, Arc::new(EngineStats::default()));
+        self.processor = Some(processor);
+        *self.sender.lock().unwrap() = Some(sender);
+
+        self.buffer = vec![0.0; buffer_config.max_buffer_size as usize];
+
+        true
+    }
+
+    fn process(&mut self, buffer: &mut Buffer, _aux: &mut AuxiliaryBuffers, context: &mut impl ProcessContext<Self>) -> ProcessStatus {
+        let processor = match &mut self.processor {
+            Some(processor) => processor,
+            None => return ProcessStatus::Error("Not initialized"),
+        };
+
+        for (index, slot) in self.params.slots.iter().enumerate() {
+            let normalized = slot.value.value();
+            if normalized != self.slot_values[index] {
+                self.slot_values[index] = normalized;
+                if let Some(spec) = processor.parameters().get(index) {
+                    let value = spec.denormalize(normalized as f64);
+                    processor.set_parameter(index, value);
+                }
+            }
+        }
+
+        // The host compensates for the latency of the graph, which changes when the editor sends a new one
+        if processor.latency() != self.latency {
+            self.latency = processor.latency();
+            context.set_latency_samples(self.latency as u32);
+        }
+
+        // The host's transport drives the graph's, the patch's settings fill in whatever the host doesn't report
+        let host = context.transport();
+        let mut transport = processor.transport().clone();
+        transport.playing = host.playing;
+        if let Some(tempo) = host.tempo {
+            transport.bpm = tempo;
+        }
+        if let (Some(numerator), Some(denominator)) = (host.time_sig_numerator, host.time_sig_denominator) {
+            transport.time_signature = (numerator.max(1) as u32, denominator.max(1) as u32);
+        }
+        transport.loop_range = host.loop_range_beats();
+        processor.set_transport(transport);
+        if let (Some(beats), Some(seconds)) = (host.pos_beats(), host.pos_seconds()) {
+            processor.locate(beats, seconds);
+        }
+
+        // Values from the host are from 0 to 1 and the graph uses MIDI's 0 to 127
+        let midi_value = |value: f32| (value * 127.0).round().max(0.0).min(127.0) as u8;
+
+        self.midi.clear();
+        while let Some(event) = context.next_event() {
+            let (time, message) = match event {
+                NoteEvent::NoteOn { timing, note, velocity, .. } => (timing, MidiMessage::NoteOn { note, velocity: midi_value(velocity).max(1) }),
+                NoteEvent::NoteOff { timing, note, .. } => (timing, MidiMessage::NoteOff { note }),
+                NoteEvent::PolyPressure { timing, note, pressure, .. } => (timing, MidiMessage::PolyPressure { note, pressure: midi_value(pressure) }),
+                NoteEvent::MidiChannelPressure { timing, pressure, .. } => (timing, MidiMessage::ChannelPressure(midi_value(pressure))),
+                NoteEvent::MidiCC { timing, cc, value, .. } => (timing, MidiMessage::ControlChange { controller: cc, value: midi_value(value) }),
+                _ => continue,
+            };
+            // Any more are dropped so the list never grows on the audio thread
+            if self.midi.len() < MAX_BUFFER_EVENTS {
+                self.midi.push(MidiEvent { time: time as usize, message });
+            }
+        }
+
+        let samples = buffer.samples();
+        let channels = buffer.as_slice();
+        let mono = &mut self.buffer[..samples];
+
+        for (i, sample) in mono.iter_mut().enumerate() {
+            *sample = channels.iter().map(|channel| channel[i] as f64).sum::<f64>() / channels.len() as f64;
+        }
+
+        processor.process_midi(mono, &self.midi);
+
+        for channel in channels.iter_mut() {
+            for (out, sample) in channel.iter_mut().zip(mono.iter()) {
+                *out = *sample as f32;
+            }
+        }
+
+        ProcessStatus::Normal
+    }
+}
+
+impl ClapPlugin for SarusPlugin {
+    const CLAP_ID: &'static str = "org.sarus.sarus-plugin";
+    const CLAP_DESCRIPTION: Option<&'static str> = Some("Audio graphs written in sarus and patched in a node editor");
+    const CLAP_MANUAL_URL: Option<&'static str> = None;
+    const CLAP_SUPPORT_URL: Option<&'static str> = None;
+    const CLAP_FEATURES: &'static [ClapFeature] = &[ClapFeature::AudioEffect, ClapFeature::Instrument, ClapFeature::Stereo];
+}
+
+impl Vst3Plugin for SarusPlugin {
+    const VST3_CLASS_ID: [u8; 16] = *b"SarusPluginGraph";
+    const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] = &[Vst3SubCategory::Fx, Vst3SubCategory::Instrument];
+}
+
+nih_export_clap!(SarusPlugin);
+nih_export_vst3!(SarusPlugin);
@@ -0,0 +1,539 @@
+use std::any::Any;
+
+use crate::fft::{fft_stage, fft_stages, hann};
+use crate::ui::NodeDesc;
+
+/// A node which processes whole windows of its input at once, e.g. to work on its spectrum.
+///
+/// The program collects the first input into windows and overlap-adds the windows the node returns, so
+/// the node only sees complete windows. The other inputs are controls, read once per window. The work on
+/// each window is split into steps which are spread over the samples until the next window arrives, so no
+/// one block has to do all of it.
+pub trait BlockNode: Send {
+    /// Number of steps the work on each window is split into
+    fn steps(&self) -> usize;
+
+    /// Does one step of the work on a window of `input`, shaped according to the node's `Shaping` when
+    /// windows overlap. The steps are run in order from 0 with the same window. `controls` holds the latest
+    /// value of each input after the first, and after the last step `output` holds a window of the same
+    /// length which is shaped again and added into the node's output.
+    fn process_step(&mut self, step: usize, sample_rate: f64, input: &[f64], controls: &[f64], output: &mut [f64]);
+
+    /// Continues from the state of the node being replaced, which was created from the same description
+    fn carry_from(&mut self, previous: &dyn BlockNode);
+
+    /// Lets `carry_from` get at the concrete type of the previous node
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// How overlapping windows are shaped so they add back up to a smooth signal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shaping {
+    /// The square root of a Hann window before and after the node, for nodes which change the
+    /// signal, such as in the frequency domain
+    Spectral,
+    /// The input is left as it is and the output is shaped by a Hann window, for nodes which output
+    /// a value measured from each window so the values crossfade
+    Crossfade,
+}
+
+/// Describes a block node which can be inserted into a graph
+pub struct BlockDesc {
+    pub name: &'static str,
+    /// The first input is the signal split into windows, any others are controls
+    pub inputs: &'static [&'static str],
+    pub output: &'static str,
+    /// Length of each window, a power of two
+    pub size: usize,
+    /// Windows overlapping each sample, 1 for windows which follow each other without shaping
+    pub overlap: usize,
+    pub shaping: Shaping,
+    pub category: &'static str,
+    pub create: fn() -> Box<dyn BlockNode>,
+}
+
+impl BlockDesc {
+    pub fn node_desc(&self) -> NodeDesc {
+        NodeDesc {
+            name: self.name.to_string(),
+            inputs: self.inputs.iter().map(|input| input.to_string()).collect(),
+            outputs: vec![self.output.to_string()],
+            category: self.category.to_string(),
+        }
+    }
+}
+
+/// All the block nodes, looked up by name when a patch is compiled
+pub fn blocks() -> &'static [BlockDesc] {
+    &[
+        BlockDesc {
+            name: "spectral_freeze",
+            inputs: &["a", "freeze"],
+            output: "b",
+            size: 2048,
+            overlap: 4,
+            shaping: Shaping::Spectral,
+            category: "Spectral",
+            create: || Box::new(SpectralFreeze::new(2048)),
+        },
+        BlockDesc {
+            name: "pitch_detect",
+            inputs: &["a"],
+            output: "hz",
+            size: 2048,
+            overlap: 4,
+            shaping: Shaping::Crossfade,
+            category: "Spectral",
+            create: || Box::new(PitchDetect::new(2048)),
+        },
+    ]
+}
+
+pub fn find_block(name: &str) -> Option<&'static BlockDesc> {
+    blocks().iter().find(|block| block.name == name)
+}
+
+/// Runs a block node inside a program, collecting its input into windows and overlap-adding its output.
+///
+/// A window is started each time `size / overlap` new samples have arrived, and the node works on it while
+/// the next `size / overlap` arrive. Overlapping windows are shaped so that the shapes before and after the
+/// node multiplied together sum to a constant. The output can only be added once a whole window has arrived
+/// and been worked on, so it is `size + size / overlap` samples late.
+pub struct BlockRunner {
+    node: Box<dyn BlockNode>,
+    size: usize,
+    hop: usize,
+    // Shapes of the windows given to the node and returned by it
+    analysis: Vec<f64>,
+    synthesis: Vec<f64>,
+    // Makes the overlapping windows sum to 1
+    scale: f64,
+    // The latest `size` samples of input, new samples are written to the last `hop`
+    input: Vec<f64>,
+    // Output waiting to be played, the first `hop` samples are complete
+    accumulated: Vec<f64>,
+    // Samples received since the last window was started
+    filled: usize,
+    // The window the node is working on and the one it returns
+    frame: Vec<f64>,
+    result: Vec<f64>,
+    controls: Vec<f64>,
+    // Steps of the node's work done on `frame`, out of `steps`
+    step: usize,
+    steps: usize,
+    // Whether `frame` holds a window, which it doesn't until the first has arrived
+    started: bool,
+}
+
+impl BlockRunner {
+    pub fn new(desc: &BlockDesc) -> Self {
+        let size = desc.size;
+        let overlap = desc.overlap.max(1).min(size);
+        // Hann windows spaced by a fraction of their length sum to half the number overlapping
+        let (analysis, synthesis, scale) = match desc.shaping {
+            _ if overlap == 1 => (vec![1.0; size], vec![1.0; size], 1.0),
+            Shaping::Spectral => {
+                let window = hann(size).into_iter().map(f64::sqrt).collect::<Vec<_>>();
+                (window.clone(), window, 2.0 / overlap as f64)
+            }
+            Shaping::Crossfade => (vec![1.0; size], hann(size), 2.0 / overlap as f64),
+        };
+
+        let node = (desc.create)();
+        Self {
+            steps: node.steps(),
+            node,
+            size,
+            hop: size / overlap,
+            analysis,
+            synthesis,
+            scale,
+            input: vec![0.0; size],
+            accumulated: vec![0.0; size],
+            filled: 0,
+            frame: vec![0.0; size],
+            result: vec![0.0; size],
+            controls: vec![0.0; desc.inputs.len().saturating_sub(1)],
+            step: 0,
+            started: false,
+        }
+    }
+
+    /// Samples the output is behind the input
+    pub fn latency(&self) -> usize {
+        self.size + self.hop
+    }
+
+    /// Continues from the runner being replaced without allocating, so windows which are part way through
+    /// carry on filling and the one being worked on is finished. Both run the same node, so their buffers
+    /// are the same length.
+    pub fn carry_from(&mut self, previous: &BlockRunner) {
+        self.node.carry_from(previous.node.as_ref());
+        self.input.copy_from_slice(&previous.input);
+        self.accumulated.copy_from_slice(&previous.accumulated);
+        self.filled = previous.filled;
+        self.frame.copy_from_slice(&previous.frame);
+        self.result.copy_from_slice(&previous.result);
+        self.controls.copy_from_slice(&previous.controls);
+        self.step = previous.step;
+        self.started = previous.started;
+    }
+
+    pub fn process(&mut self, sample_rate: f64, inputs: &[Vec<f64>], output: &mut [f64]) {
+        for (i, out) in output.iter_mut().enumerate() {
+            *out = self.accumulated[self.filled];
+            self.input[self.size - self.hop + self.filled] = inputs[0][i];
+            self.filled += 1;
+
+            // Enough steps to keep up with the samples so far, finishing just as the next window arrives
+            if self.started {
+                let target = (self.steps * self.filled + self.hop - 1) / self.hop;
+                while self.step < target {
+                    self.node.process_step(self.step, sample_rate, &self.frame, &self.controls, &mut self.result);
+                    self.step += 1;
+                }
+            }
+
+            if self.filled == self.hop {
+                for (control, input) in self.controls.iter_mut().zip(inputs[1..].iter()) {
+                    *control = input[i];
+                }
+                self.next_window();
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn next_window(&mut self) {
+        // The samples just played are dropped and the window finished over the last hop is added on top of what's left
+        self.accumulated.copy_within(self.hop.., 0);
+        self.accumulated[self.size - self.hop..].iter_mut().for_each(|sample| *sample = 0.0);
+        if self.started {
+            for ((accumulated, result), window) in self.accumulated.iter_mut().zip(self.result.iter()).zip(self.synthesis.iter()) {
+                *accumulated += result * window * self.scale;
+            }
+        }
+
+        for ((frame, input), window) in self.frame.iter_mut().zip(self.input.iter()).zip(self.analysis.iter()) {
+            *frame = input * window;
+        }
+        self.step = 0;
+        self.started = true;
+
+        self.input.copy_within(self.hop.., 0);
+    }
+}
+
+/// Passes its input through until `freeze` goes above 0.5, then holds the spectrum it was playing.
+///
+/// Each bin keeps the magnitude it had and carries on turning by the phase it moved between the last
+/// two windows before the freeze, so the held sound keeps its pitch.
+#[derive(Debug, Clone)]
+pub struct SpectralFreeze {
+    re: Vec<f64>,
+    im: Vec<f64>,
+    magnitudes: Vec<f64>,
+    phases: Vec<f64>,
+    // Phase each bin moves by per window
+    advance: Vec<f64>,
+    previous_phases: Vec<f64>,
+    frozen: bool,
+    // Whether the window being worked on is frozen, and whether its spectrum is needed
+    freeze: bool,
+    analyzing: bool,
+}
+
+impl SpectralFreeze {
+    pub fn new(size: usize) -> Self {
+        Self {
+            re: vec![0.0; size],
+            im: vec![0.0; size],
+            magnitudes: vec![0.0; size],
+            phases: vec![0.0; size],
+            advance: vec![0.0; size],
+            previous_phases: vec![0.0; size],
+            frozen: false,
+            freeze: false,
+            analyzing: false,
+        }
+    }
+}
+
+impl BlockNode for SpectralFreeze {
+    // The stages of the transform, the bins, the stages of the inverse transform and the output
+    fn steps(&self) -> usize {
+        2 * fft_stages(self.re.len()) + 2
+    }
+
+    fn process_step(&mut self, step: usize, _sample_rate: f64, input: &[f64], controls: &[f64], output: &mut [f64]) {
+        let stages = fft_stages(self.re.len());
+
+        if step < stages {
+            if step == 0 {
+                self.freeze = controls[0] > 0.5;
+                // A spectrum which is already frozen doesn't need the input
+                self.analyzing = !self.freeze || !self.frozen;
+                if self.analyzing {
+                    self.re.copy_from_slice(input);
+                    self.im.iter_mut().for_each(|value| *value = 0.0);
+                }
+            }
+            if self.analyzing {
+                fft_stage(&mut self.re, &mut self.im, step, false);
+            }
+        } else if step == stages {
+            if self.analyzing {
+                for bin in 0..self.re.len() {
+                    let phase = self.im[bin].atan2(self.re[bin]);
+                    if self.freeze {
+                        self.magnitudes[bin] = self.re[bin].hypot(self.im[bin]);
+                        self.phases[bin] = phase;
+                        self.advance[bin] = phase - self.previous_phases[bin];
+                    }
+                    self.previous_phases[bin] = phase;
+                }
+            }
+
+            self.frozen = self.freeze;
+            if self.freeze {
+                for bin in 0..self.re.len() {
+                    self.phases[bin] += self.advance[bin];
+                    self.re[bin] = self.magnitudes[bin] * self.phases[bin].cos();
+                    self.im[bin] = self.magnitudes[bin] * self.phases[bin].sin();
+                }
+            }
+        } else if step <= 2 * stages {
+            if self.freeze {
+                fft_stage(&mut self.re, &mut self.im, step - stages - 1, true);
+            }
+        } else if self.freeze {
+            output.copy_from_slice(&self.re);
+        } else {
+            output.copy_from_slice(input);
+        }
+    }
+
+    fn carry_from(&mut self, previous: &dyn BlockNode) {
+        // Both were made for windows of the same size
+        if let Some(previous) = previous.as_any().downcast_ref::<Self>() {
+            self.re.copy_from_slice(&previous.re);
+            self.im.copy_from_slice(&previous.im);
+            self.magnitudes.copy_from_slice(&previous.magnitudes);
+            self.phases.copy_from_slice(&previous.phases);
+            self.advance.copy_from_slice(&previous.advance);
+            self.previous_phases.copy_from_slice(&previous.previous_phases);
+            self.frozen = previous.frozen;
+            self.freeze = previous.freeze;
+            self.analyzing = previous.analyzing;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Range of pitches the detector looks for
+const LOWEST_PITCH: f64 = 50.0;
+const HIGHEST_PITCH: f64 = 2000.0;
+
+/// Outputs the frequency of its input found from the autocorrelation of each window, or 0 when the
+/// input is silent or has no clear pitch
+#[derive(Debug, Clone)]
+pub struct PitchDetect {
+    // Twice the window so the autocorrelation doesn't wrap round
+    re: Vec<f64>,
+    im: Vec<f64>,
+}
+
+impl PitchDetect {
+    pub fn new(size: usize) -> Self {
+        Self {
+            re: vec![0.0; size * 2],
+            im: vec![0.0; size * 2],
+        }
+    }
+
+    // Finds the pitch from the autocorrelation of a window of `len` samples, once it's in `re`
+    fn find_pitch(&self, sample_rate: f64, len: usize) -> f64 {
+        let correlation = &self.re[..len];
+        if correlation[0] <= 1e-9 {
+            return 0.0;
+        }
+
+        let shortest = ((sample_rate / HIGHEST_PITCH) as usize).max(1);
+        let longest = ((sample_rate / LOWEST_PITCH) as usize).min(len - 2);
+        if shortest >= longest {
+            return 0.0;
+        }
+
+        let best = correlation[shortest..=longest].iter().cloned().fold(f64::MIN, f64::max);
+        if best < 0.3 * correlation[0] {
+            return 0.0;
+        }
+
+        // The first peak nearly as high as the best avoids picking a multiple of the period
+        let lag = (shortest..=longest)
+            .find(|&lag| {
+                correlation[lag] >= 0.9 * best && correlation[lag] >= correlation[lag - 1] && correlation[lag] >= correlation[lag + 1]
+            })
+            .unwrap_or(shortest);
+
+        // A parabola through the peak and its neighbours finds the period between samples
+        let (a, b, c) = (correlation[lag - 1], correlation[lag], correlation[lag + 1]);
+        let curve = a - 2.0 * b + c;
+        let offset = if curve != 0.0 { 0.5 * (a - c) / curve } else { 0.0 };
+
+        sample_rate / (lag as f64 + offset)
+    }
+}
+
+impl BlockNode for PitchDetect {
+    // The stages of the transform, the power spectrum, the stages of the inverse transform and the search
+    fn steps(&self) -> usize {
+        2 * fft_stages(self.re.len()) + 2
+    }
+
+    fn process_step(&mut self, step: usize, sample_rate: f64, input: &[f64], _controls: &[f64], output: &mut [f64]) {
+        let stages = fft_stages(self.re.len());
+
+        if step < stages {
+            if step == 0 {
+                self.re[..input.len()].copy_from_slice(input);
+                self.re[input.len()..].iter_mut().for_each(|value| *value = 0.0);
+                self.im.iter_mut().for_each(|value| *value = 0.0);
+            }
+            fft_stage(&mut self.re, &mut self.im, step, false);
+        } else if step == stages {
+            // The autocorrelation is the inverse transform of the power spectrum
+            for (re, im) in self.re.iter_mut().zip(self.im.iter_mut()) {
+                *re = *re * *re + *im * *im;
+                *im = 0.0;
+            }
+        } else if step <= 2 * stages {
+            fft_stage(&mut self.re, &mut self.im, step - stages - 1, true);
+        } else {
+            let hz = self.find_pitch(sample_rate, input.len());
+            output.iter_mut().for_each(|sample| *sample = hz);
+        }
+    }
+
+    fn carry_from(&mut self, previous: &dyn BlockNode) {
+        if let Some(previous) = previous.as_any().downcast_ref::<Self>() {
+            self.re.copy_from_slice(&previous.re);
+            self.im.copy_from_slice(&previous.im);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    struct Passthrough;
+
+    impl BlockNode for Passthrough {
+        fn steps(&self) -> usize {
+            1
+        }
+
+        fn process_step(&mut self, _step: usize, _sample_rate: f64, input: &[f64], _controls: &[f64], output: &mut [f64]) {
+            output.copy_from_slice(input);
+        }
+
+        fn carry_from(&mut self, _previous: &dyn BlockNode) {}
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn passthrough(overlap: usize, shaping: Shaping) -> BlockRunner {
+        BlockRunner::new(&BlockDesc {
+            name: "passthrough",
+            inputs: &["a"],
+            output: "b",
+            size: 64,
+            overlap,
+            shaping,
+            category: "",
+            create: || Box::new(Passthrough),
+        })
+    }
+
+    #[test]
+    fn windows_add_back_up_to_the_input() {
+        let input = (0..640).map(|i| ((i * 31) % 23) as f64 - 11.0).collect::<Vec<_>>();
+
+        for &(overlap, shaping) in [(1, Shaping::Spectral), (2, Shaping::Spectral), (4, Shaping::Spectral), (4, Shaping::Crossfade), (8, Shaping::Crossfade)].iter() {
+            let mut runner = passthrough(overlap, shaping);
+            let mut output = vec![0.0; input.len()];
+            // Blocks which don't line up with the windows
+            for (input, output) in input.chunks(13).zip(output.chunks_mut(13)) {
+                runner.process(48000.0, &[input.to_vec()], output);
+            }
+
+            let latency = runner.latency();
+            for (actual, expected) in output[latency..].iter().zip(input.iter()) {
+                assert!((actual - expected).abs() < 1e-9, "overlap {} {:?}", overlap, shaping);
+            }
+        }
+    }
+
+    #[test]
+    fn carried_runners_carry_on_where_they_were() {
+        let desc = find_block("spectral_freeze").unwrap();
+        let len = desc.size * 4;
+        let input = (0..len).map(|i| (2.0 * PI * 375.0 * i as f64 / 48000.0).sin()).collect::<Vec<_>>();
+        let freeze = (0..len).map(|i| if i < len / 2 { 0.0 } else { 1.0 }).collect::<Vec<_>>();
+
+        // Swapped part way through a window and part way through the work on the one before
+        let split = len / 2 + desc.size / desc.overlap / 2 + 3;
+        let mut runner = BlockRunner::new(desc);
+        let mut expected = vec![0.0; len];
+        runner.process(48000.0, &[input.clone(), freeze.clone()], &mut expected);
+
+        let mut old = BlockRunner::new(desc);
+        let mut output = vec![0.0; len];
+        old.process(48000.0, &[input[..split].to_vec(), freeze[..split].to_vec()], &mut output[..split]);
+        let mut new = BlockRunner::new(desc);
+        new.carry_from(&old);
+        new.process(48000.0, &[input[split..].to_vec(), freeze[split..].to_vec()], &mut output[split..]);
+
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn finds_the_pitch_of_a_tone() {
+        let desc = find_block("pitch_detect").unwrap();
+        let mut runner = BlockRunner::new(desc);
+        let input = (0..desc.size * 4).map(|i| (2.0 * PI * 441.0 * i as f64 / 48000.0).sin()).collect::<Vec<_>>();
+        let mut output = vec![0.0; input.len()];
+        runner.process(48000.0, &[input], &mut output);
+
+        // Once the windows are full of the tone
+        for hz in output[desc.size * 2..].iter() {
+            assert!((hz - 441.0).abs() < 441.0 * 0.01, "{}", hz);
+        }
+    }
+
+    #[test]
+    fn frozen_spectrum_keeps_sounding() {
+        let desc = find_block("spectral_freeze").unwrap();
+        let mut runner = BlockRunner::new(desc);
+        let len = desc.size * 8;
+        let input = (0..len).map(|i| if i < len / 2 { (2.0 * PI * 375.0 * i as f64 / 48000.0).sin() } else { 0.0 }).collect::<Vec<_>>();
+        let freeze = (0..len).map(|i| if i < len / 2 - desc.size / 2 { 0.0 } else { 1.0 }).collect::<Vec<_>>();
+        let mut output = vec![0.0; len];
+        runner.process(48000.0, &[input, freeze], &mut output);
+
+        // Long after the input has stopped the held tone is still there
+        let level = output[len - desc.size..].iter().map(|sample| sample * sample).sum::<f64>() / desc.size as f64;
+        assert!(level > 0.1, "{}", level);
+    }
+}
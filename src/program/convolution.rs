@@ -0,0 +1,206 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use super::load_sample;
+use crate::fft::{fft, ifft};
+use crate::render::STEP_SIZE;
+use crate::ui::NodeDesc;
+
+/// Name of the node which convolves its input with an impulse response
+pub const CONVOLUTION_NODE: &str = "convolution";
+
+/// Length of the pieces the impulse response is split into. The input is collected into blocks this long
+/// before being convolved, so it's also the node's latency. One partition per block spreads the work evenly
+/// across blocks instead of doing all of it every few.
+pub const PARTITION_SIZE: usize = STEP_SIZE;
+
+// Each partition is zero padded to twice its length so the circular convolution doesn't wrap
+const FFT_SIZE: usize = PARTITION_SIZE * 2;
+
+pub fn convolution_desc() -> NodeDesc {
+    NodeDesc {
+        name: CONVOLUTION_NODE.to_string(),
+        inputs: vec!["a".to_string(), "dry".to_string()],
+        outputs: vec!["b".to_string()],
+        category: "Effects".to_string(),
+    }
+}
+
+/// Spectra of the partitions of an impulse response, ready to be multiplied with the input's
+#[derive(Debug, Clone)]
+pub struct ImpulseResponse {
+    // Real and imaginary parts of each partition's spectrum, earliest first
+    partitions: Vec<(Vec<f64>, Vec<f64>)>,
+}
+
+impl ImpulseResponse {
+    pub fn new(samples: &[f64]) -> Self {
+        let partitions = samples.chunks(PARTITION_SIZE)
+            .map(|chunk| {
+                let mut re = vec![0.0; FFT_SIZE];
+                let mut im = vec![0.0; FFT_SIZE];
+                re[..chunk.len()].copy_from_slice(chunk);
+                fft(&mut re, &mut im);
+                (re, im)
+            })
+            .collect();
+
+        Self { partitions }
+    }
+
+    /// An impulse response which passes the input through unchanged, used until a file is chosen
+    pub fn unit() -> Self {
+        Self::new(&[1.0])
+    }
+
+    /// Reads an impulse response from a WAV file, resampled to the graph's rate
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::new(&load_sample(path)?))
+    }
+
+    pub fn num_partitions(&self) -> usize {
+        self.partitions.len()
+    }
+}
+
+/// Uniformly partitioned overlap-save convolution.
+///
+/// Every `PARTITION_SIZE` samples the latest block of input is transformed and multiplied with each
+/// partition of the impulse response against the block that many partitions ago. The sum gives the
+/// next block of output, so both the wet and the dry signal are delayed by `PARTITION_SIZE`.
+#[derive(Debug, Clone)]
+pub struct Convolver {
+    // Shared between the copies of the node made when the graph is recompiled
+    ir: Arc<ImpulseResponse>,
+    // Spectra of the latest input blocks, one per partition, with the newest at `newest`
+    history: Vec<(Vec<f64>, Vec<f64>)>,
+    newest: usize,
+    // The previous block of input followed by the one being collected
+    input: Vec<f64>,
+    // Wet signal played while the next block is collected
+    output: Vec<f64>,
+    // Samples collected of the current block
+    filled: usize,
+    // Scratch space for the transforms
+    re: Vec<f64>,
+    im: Vec<f64>,
+}
+
+impl Convolver {
+    pub fn new(ir: Arc<ImpulseResponse>) -> Self {
+        let partitions = ir.num_partitions();
+        Self {
+            ir,
+            history: vec![(vec![0.0; FFT_SIZE], vec![0.0; FFT_SIZE]); partitions],
+            newest: 0,
+            input: vec![0.0; FFT_SIZE],
+            output: vec![0.0; PARTITION_SIZE],
+            filled: 0,
+            re: vec![0.0; FFT_SIZE],
+            im: vec![0.0; FFT_SIZE],
+        }
+    }
+
+    /// Samples the output is behind the input
+    pub fn latency(&self) -> usize {
+        PARTITION_SIZE
+    }
+
+    /// Continues from the input heard by the convolver being replaced, so a changed impulse
+    /// response takes over without a gap
+    pub fn carry_from(&mut self, previous: &Convolver) {
+        self.input.copy_from_slice(&previous.input);
+        self.output.copy_from_slice(&previous.output);
+        self.filled = previous.filled;
+
+        // Input further back than either response reaches isn't needed
+        let partitions = self.history.len().min(previous.history.len());
+        for age in 0..partitions {
+            let (re, im) = &previous.history[(previous.newest + age) % previous.history.len()];
+            let index = (self.newest + age) % self.history.len();
+            self.history[index].0.copy_from_slice(re);
+            self.history[index].1.copy_from_slice(im);
+        }
+    }
+
+    pub fn process(&mut self, inputs: &[Vec<f64>], output: &mut [f64]) {
+        for (i, out) in output.iter_mut().enumerate() {
+            let (sample, dry) = (inputs[0][i], inputs[1][i]);
+
+            // The input from a block ago lines up with the wet signal
+            let delayed = self.input[self.filled];
+            *out = self.output[self.filled] * (1.0 - dry) + delayed * dry;
+
+            self.input[PARTITION_SIZE + self.filled] = sample;
+            self.filled += 1;
+            if self.filled == PARTITION_SIZE {
+                self.convolve_block();
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn convolve_block(&mut self) {
+        let partitions = self.history.len();
+        if partitions == 0 {
+            self.output.iter_mut().for_each(|sample| *sample = 0.0);
+            self.input.copy_within(PARTITION_SIZE.., 0);
+            return;
+        }
+
+        // The oldest spectrum is replaced by the newest
+        self.newest = (self.newest + partitions - 1) % partitions;
+        let (re, im) = &mut self.history[self.newest];
+        re.copy_from_slice(&self.input);
+        im.iter_mut().for_each(|value| *value = 0.0);
+        fft(re, im);
+
+        self.re.iter_mut().for_each(|value| *value = 0.0);
+        self.im.iter_mut().for_each(|value| *value = 0.0);
+        for (age, (h_re, h_im)) in self.ir.partitions.iter().enumerate() {
+            let (x_re, x_im) = &self.history[(self.newest + age) % partitions];
+            for bin in 0..FFT_SIZE {
+                self.re[bin] += x_re[bin] * h_re[bin] - x_im[bin] * h_im[bin];
+                self.im[bin] += x_re[bin] * h_im[bin] + x_im[bin] * h_re[bin];
+            }
+        }
+        ifft(&mut self.re, &mut self.im);
+
+        // The first half wrapped round from the end and is thrown away
+        self.output.copy_from_slice(&self.re[PARTITION_SIZE..]);
+        self.input.copy_within(PARTITION_SIZE.., 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convolve(ir: &[f64], input: &[f64], dry: f64) -> Vec<f64> {
+        let mut convolver = Convolver::new(Arc::new(ImpulseResponse::new(ir)));
+        let mut output = vec![0.0; input.len()];
+        convolver.process(&[input.to_vec(), vec![dry; input.len()]], &mut output);
+        output
+    }
+
+    #[test]
+    fn matches_direct_convolution_after_the_latency() {
+        // Longer than a few partitions, with a signal that isn't periodic
+        let ir = (0..PARTITION_SIZE * 3 + 5).map(|i| ((i * 7 % 13) as f64 - 6.0) / (1.0 + i as f64)).collect::<Vec<_>>();
+        let input = (0..PARTITION_SIZE * 8).map(|i| ((i * i) % 17) as f64 / 17.0 - 0.5).collect::<Vec<_>>();
+        let output = convolve(&ir, &input, 0.0);
+
+        for n in 0..input.len() - PARTITION_SIZE {
+            let expected = (0..=n).filter(|k| n - k < ir.len()).map(|k| input[k] * ir[n - k]).sum::<f64>();
+            assert!((output[n + PARTITION_SIZE] - expected).abs() < 1e-9, "sample {}", n);
+        }
+        assert!(output[..PARTITION_SIZE].iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn dry_signal_is_delayed_to_line_up() {
+        let input = (0..PARTITION_SIZE * 3).map(|i| i as f64).collect::<Vec<_>>();
+        let output = convolve(&[0.0, 0.5], &input, 1.0);
+        assert_eq!(&output[PARTITION_SIZE..], &input[..PARTITION_SIZE * 2]);
+    }
+}
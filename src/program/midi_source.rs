@@ -0,0 +1,220 @@
+use crate::midi::{note_to_hz, MidiEvent, MidiMessage};
+use crate::ui::NodeDesc;
+
+/// Which part of the incoming MIDI a source node outputs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiValue {
+    /// Frequency in Hz of the current note
+    Pitch,
+    /// Note number of the current note
+    Note,
+    /// 1 while a note is held, 0 otherwise
+    Gate,
+    /// Velocity of the current note from 0 to 1
+    Velocity,
+    /// Channel or note pressure from 0 to 1
+    Aftertouch,
+    /// Value from 0 to 1 of the controller given by the node's input
+    Control,
+}
+
+/// Describes a MIDI source node which can be inserted into a graph
+pub struct MidiSourceDesc {
+    pub name: &'static str,
+    pub inputs: &'static [&'static str],
+    pub outputs: &'static [&'static str],
+    pub value: MidiValue,
+}
+
+impl MidiSourceDesc {
+    pub fn node_desc(&self) -> NodeDesc {
+        NodeDesc {
+            name: self.name.to_string(),
+            inputs: self.inputs.iter().map(|input| input.to_string()).collect(),
+            outputs: self.outputs.iter().map(|output| output.to_string()).collect(),
+            category: "MIDI".to_string(),
+        }
+    }
+}
+
+pub fn midi_sources() -> &'static [MidiSourceDesc] {
+    &[
+        MidiSourceDesc { name: "midi_pitch", inputs: &[], outputs: &["hz"], value: MidiValue::Pitch },
+        MidiSourceDesc { name: "midi_note", inputs: &[], outputs: &["note"], value: MidiValue::Note },
+        MidiSourceDesc { name: "midi_gate", inputs: &[], outputs: &["gate"], value: MidiValue::Gate },
+        MidiSourceDesc { name: "midi_velocity", inputs: &[], outputs: &["velocity"], value: MidiValue::Velocity },
+        MidiSourceDesc { name: "midi_aftertouch", inputs: &[], outputs: &["pressure"], value: MidiValue::Aftertouch },
+        MidiSourceDesc { name: "midi_cc", inputs: &["cc"], outputs: &["value"], value: MidiValue::Control },
+    ]
+}
+
+pub fn find_midi_source(name: &str) -> Option<&'static MidiSourceDesc> {
+    midi_sources().iter().find(|source| source.name == name)
+}
+
+/// The notes held down and the latest controller values.
+///
+/// Monophonic with last note priority: releasing the newest note goes back to the one held before it.
+#[derive(Debug, Clone)]
+pub struct NoteState {
+    // Held notes and their velocities, oldest first
+    held: Vec<(u8, u8)>,
+    note: u8,
+    velocity: u8,
+    pressure: u8,
+    controls: [u8; 128],
+}
+
+impl Default for NoteState {
+    fn default() -> Self {
+        Self {
+            // Every note can be held at once, so this never grows on the audio thread
+            held: Vec::with_capacity(128),
+            note: 69,
+            velocity: 0,
+            pressure: 0,
+            controls: [0; 128],
+        }
+    }
+}
+
+impl NoteState {
+    /// Copies the notes and controllers of another state without allocating
+    pub fn carry_from(&mut self, previous: &NoteState) {
+        self.held.clear();
+        self.held.extend_from_slice(&previous.held);
+        self.note = previous.note;
+        self.velocity = previous.velocity;
+        self.pressure = previous.pressure;
+        self.controls = previous.controls;
+    }
+
+    pub fn apply(&mut self, message: &MidiMessage) {
+        match *message {
+            MidiMessage::NoteOn { note, velocity } => {
+                self.held.retain(|&(held, _)| held != note);
+                self.held.push((note, velocity));
+                self.note = note;
+                self.velocity = velocity;
+                self.pressure = 0;
+            }
+
+            MidiMessage::NoteOff { note } => {
+                self.held.retain(|&(held, _)| held != note);
+                // The pitch stays on the released note so it can ring out
+                if let Some(&(note, velocity)) = self.held.last() {
+                    self.note = note;
+                    self.velocity = velocity;
+                }
+            }
+
+            MidiMessage::PolyPressure { note, pressure } => {
+                if note == self.note {
+                    self.pressure = pressure;
+                }
+            }
+
+            MidiMessage::ChannelPressure(pressure) => self.pressure = pressure,
+
+            MidiMessage::ControlChange { controller, value } => self.controls[controller as usize & 0x7f] = value,
+        }
+    }
+
+    pub fn value(&self, value: MidiValue, controller: f64) -> f64 {
+        match value {
+            MidiValue::Pitch => note_to_hz(self.note as f64),
+            MidiValue::Note => self.note as f64,
+            MidiValue::Gate => if self.held.is_empty() { 0.0 } else { 1.0 },
+            MidiValue::Velocity => self.velocity as f64 / 127.0,
+            MidiValue::Aftertouch => self.pressure as f64 / 127.0,
+            MidiValue::Control => self.controls[(controller.max(0.0) as usize).min(127)] as f64 / 127.0,
+        }
+    }
+}
+
+/// Node outputting one value of the MIDI coming into the graph
+#[derive(Debug, Clone)]
+pub struct MidiSource {
+    value: MidiValue,
+    state: NoteState,
+}
+
+impl MidiSource {
+    pub fn new(value: MidiValue) -> Self {
+        Self {
+            value,
+            state: NoteState::default(),
+        }
+    }
+
+    /// Takes over the notes held by the source this one replaces, so they carry on sounding
+    pub fn carry_from(&mut self, previous: &MidiSource) {
+        self.state.carry_from(&previous.state);
+    }
+
+    /// Fills `output` with the value, applying each event at its sample. Sample `i` of the block is
+    /// at time `offset + i`, events outside the block are skipped.
+    pub fn process(&mut self, events: &[MidiEvent], offset: usize, controller: &[f64], output: &mut [f64]) {
+        let mut events = events.iter().filter(|event| event.time >= offset && event.time < offset + output.len()).peekable();
+
+        for (i, sample) in output.iter_mut().enumerate() {
+            while let Some(event) = events.next_if(|event| event.time <= offset + i) {
+                self.state.apply(&event.message);
+            }
+            *sample = self.state.value(self.value, controller.get(i).copied().unwrap_or(0.0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(time: usize, message: MidiMessage) -> MidiEvent {
+        MidiEvent { time, message }
+    }
+
+    #[test]
+    fn last_note_has_priority() {
+        let mut state = NoteState::default();
+        state.apply(&MidiMessage::NoteOn { note: 60, velocity: 127 });
+        state.apply(&MidiMessage::NoteOn { note: 64, velocity: 127 });
+        assert_eq!(state.value(MidiValue::Note, 0.0), 64.0);
+
+        state.apply(&MidiMessage::NoteOff { note: 64 });
+        assert_eq!(state.value(MidiValue::Note, 0.0), 60.0);
+        assert_eq!(state.value(MidiValue::Gate, 0.0), 1.0);
+
+        state.apply(&MidiMessage::NoteOff { note: 60 });
+        assert_eq!(state.value(MidiValue::Note, 0.0), 60.0);
+        assert_eq!(state.value(MidiValue::Gate, 0.0), 0.0);
+    }
+
+    #[test]
+    fn events_apply_at_their_sample() {
+        let events = [
+            event(2, MidiMessage::NoteOn { note: 69, velocity: 127 }),
+            event(5, MidiMessage::NoteOff { note: 69 }),
+            event(9, MidiMessage::NoteOn { note: 69, velocity: 127 }),
+        ];
+
+        let mut gate = MidiSource::new(MidiValue::Gate);
+        let mut output = [0.0; 4];
+
+        gate.process(&events, 0, &[], &mut output);
+        assert_eq!(output, [0.0, 0.0, 1.0, 1.0]);
+
+        gate.process(&events, 4, &[], &mut output);
+        assert_eq!(output, [1.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn controllers_are_chosen_by_input() {
+        let events = [event(0, MidiMessage::ControlChange { controller: 74, value: 127 })];
+
+        let mut control = MidiSource::new(MidiValue::Control);
+        let mut output = [0.0; 2];
+        control.process(&events, 0, &[74.0, 1.0], &mut output);
+        assert_eq!(output, [1.0, 0.0]);
+    }
+}
@@ -0,0 +1,1626 @@
+use std::collections::HashMap;
+use std::mem;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use sarus::frontend::Declaration;
+use sarus::graph::{Connection, Graph, Node};
+use sarus::{parser, run_fn};
+
+use crate::midi::MidiEvent;
+use crate::patch::Patch;
+use crate::render::{SAMPLE_RATE, STEP_SIZE};
+use crate::ui::graph::IndexGraph;
+
+pub mod native;
+pub use native::*;
+
+pub mod parameter;
+pub use parameter::*;
+
+pub mod midi_source;
+pub use midi_source::*;
+
+pub mod voices;
+pub use voices::*;
+
+pub mod sequencer;
+pub use sequencer::*;
+
+pub mod transport;
+pub use transport::*;
+
+pub mod sample;
+pub use sample::*;
+
+pub mod wavetable;
+pub use wavetable::*;
+
+pub mod convolution;
+pub use convolution::*;
+
+pub mod block;
+pub use block::*;
+
+pub mod rate;
+pub use rate::Rate;
+
+pub mod optimize;
+pub use optimize::Optimization;
+
+mod parallel;
+use parallel::{Disjoint, Level, WorkerPool};
+
+/// Longest block processed in one go, longer blocks are split up
+pub const MAX_BLOCK_SIZE: usize = 1024;
+
+/// Most parameters a sarus function can have to be used as a node in a patch which also has built in
+/// nodes. Patches made only of sarus functions run in the graph function sarus generates, which has no limit,
+/// as long as all their nodes run at audio rate.
+pub const MAX_PARAMS: usize = 8;
+
+// A sarus function in the JIT module, called through a function returning just one of its outputs
+#[derive(Debug, Clone)]
+struct SarusFn {
+    // The function itself if it has one return, otherwise the wrapper generated for each of them
+    outputs: Vec<*const u8>,
+    params: usize,
+}
+
+// The generated code only reads its arguments, so it can be called from any thread while the module is alive
+unsafe impl Send for SarusFn {}
+
+impl SarusFn {
+    // Safety: the function must take `self.params` f64 arguments, with `self.params` at most MAX_PARAMS.
+    // The JIT uses the platform's C calling convention, under which f64 arguments and a single f64 return
+    // are passed in registers or on the stack the same way for Rust's `extern "C"` functions.
+    unsafe fn call(&self, output: usize, args: &[f64; MAX_PARAMS]) -> f64 {
+        let ptr = self.outputs[output];
+        let a = args;
+        match self.params {
+            0 => mem::transmute::<_, extern "C" fn() -> f64>(ptr)(),
+            1 => mem::transmute::<_, extern "C" fn(f64) -> f64>(ptr)(a[0]),
+            2 => mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(ptr)(a[0], a[1]),
+            3 => mem::transmute::<_, extern "C" fn(f64, f64, f64) -> f64>(ptr)(a[0], a[1], a[2]),
+            4 => mem::transmute::<_, extern "C" fn(f64, f64, f64, f64) -> f64>(ptr)(a[0], a[1], a[2], a[3]),
+            5 => mem::transmute::<_, extern "C" fn(f64, f64, f64, f64, f64) -> f64>(ptr)(a[0], a[1], a[2], a[3], a[4]),
+            6 => mem::transmute::<_, extern "C" fn(f64, f64, f64, f64, f64, f64) -> f64>(ptr)(a[0], a[1], a[2], a[3], a[4], a[5]),
+            7 => mem::transmute::<_, extern "C" fn(f64, f64, f64, f64, f64, f64, f64) -> f64>(ptr)(a[0], a[1], a[2], a[3], a[4], a[5], a[6]),
+            _ => mem::transmute::<_, extern "C" fn(f64, f64, f64, f64, f64, f64, f64, f64) -> f64>(ptr)(a[0], a[1], a[2], a[3], a[4], a[5], a[6], a[7]),
+        }
+    }
+
+    // Looks up a function and its wrappers. Fails if it can't be called from Rust.
+    fn find(graph: &mut Graph, decl: &Declaration) -> anyhow::Result<Self> {
+        if decl.params.len() > MAX_PARAMS {
+            anyhow::bail!("Function {} has {} parameters, nodes alongside built in nodes can have at most {}", decl.name, decl.params.len(), MAX_PARAMS);
+        }
+        if decl.returns.is_empty() {
+            anyhow::bail!("Function {} has no returns, nodes must have at least one", decl.name);
+        }
+
+        let outputs = if decl.returns.len() == 1 {
+            vec![graph.jit.get_func(&decl.name)?]
+        } else {
+            (0..decl.returns.len())
+                .map(|output| graph.jit.get_func(&wrapper_name(&decl.name, output)))
+                .collect::<anyhow::Result<Vec<_>>>()?
+        };
+
+        Ok(Self {
+            outputs,
+            params: decl.params.len(),
+        })
+    }
+}
+
+fn wrapper_name(name: &str, output: usize) -> String {
+    format!("{}__return_{}", name, output)
+}
+
+// Sarus code for a function returning each output of the functions with more than one return, which
+// can't be called from Rust directly as the layout of several returns isn't part of the C calling convention
+fn return_wrappers(declarations: &[Declaration]) -> String {
+    let mut code = String::new();
+    for decl in declarations.iter().filter(|decl| decl.returns.len() > 1) {
+        let params = decl.params.join(", ");
+        for (output, name) in decl.returns.iter().enumerate() {
+            code += &format!(
+                "\nfn {}({}) -> ({}) {{\n    {} = {}({})\n}}\n",
+                wrapper_name(&decl.name, output), params, name, decl.returns.join(", "), decl.name, params,
+            );
+        }
+    }
+    code
+}
+
+enum NodeKind {
+    Input,
+    Output,
+    Counter,
+    Sarus(SarusFn),
+    Native(Box<dyn NativeNode>),
+    Parameter(ParameterNode),
+    Midi(MidiSource),
+    VoiceMix,
+    Sequencer(Sequencer),
+    Transport(TransportValue),
+    SamplePlayer(SamplePlayer),
+    Wavetable(WavetableOsc),
+    Convolution(Convolver),
+    Block(BlockRunner),
+}
+
+impl NodeKind {
+    // Samples the node's output is behind its input
+    fn latency(&self) -> usize {
+        match self {
+            NodeKind::Convolution(convolver) => convolver.latency(),
+            NodeKind::Block(runner) => runner.latency(),
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    // Index into `Program::buffers`
+    Buffer(usize),
+    Constant(f64),
+}
+
+struct ProgramNode {
+    id: String,
+    // Function name with its parameters and returns, state is only carried over if it's unchanged
+    signature: String,
+    kind: NodeKind,
+    inputs: Vec<Source>,
+    // Index of the first buffer of this node's outputs in `Program::buffers`
+    first_output: usize,
+    num_outputs: usize,
+    // Holds the node's output buffers while it's being processed, so the other buffers can be read meanwhile
+    outputs: Vec<Vec<f64>>,
+    // Voice of a polyphonic patch the node plays, `None` if it's shared by all of them
+    voice: Option<usize>,
+    rate: Rate,
+    // Outputs of a control rate node at the end of the last block, which the next block ramps from.
+    // Empty until the first block, with room for every output.
+    last: Vec<f64>,
+}
+
+/// A patch ready to be run block by block.
+///
+/// Sarus functions are JIT compiled and called for each sample, native nodes process whole blocks.
+/// Every node output has a buffer holding its latest block. A patch made only of sarus functions runs
+/// in the graph function sarus generates for it instead.
+pub struct Program {
+    // Owns the JIT module the sarus functions live in
+    graph: Graph,
+    nodes: Vec<ProgramNode>,
+    // Levels of nodes in the order they're processed
+    schedule: Vec<Level>,
+    // Threads processing the branches of a level alongside the one running the program, once started
+    pool: Option<WorkerPool>,
+    // Levels in which a task on the pool panicked since the last `take_worker_failures`
+    worker_failures: usize,
+    buffers: Vec<Vec<f64>>,
+    // Scratch space for the inputs of native nodes, a set for each thread
+    native_inputs: Vec<Vec<Vec<f64>>>,
+    // One entry per parameter name, in the order they first appear in the patch
+    parameters: Vec<ParameterSpec>,
+    automation: Vec<(usize, Automation)>,
+    // Splits the MIDI between the voices of a polyphonic patch
+    voices: Option<VoiceAllocator>,
+    transport: TransportState,
+    latency: usize,
+    optimization: Optimization,
+    // Buffers of each probed output, one for each voice playing it
+    probes: Vec<Vec<usize>>,
+    // Whether the patch runs in the graph function sarus generated rather than node by node
+    generated: bool,
+    generation: u64,
+    carry: Option<CarryPlan>,
+}
+
+// The JIT module holds raw pointers to the generated code so the program isn't `Send` by itself.
+// It is only ever used by one thread at a time: it is built on one thread and handed over whole.
+unsafe impl Send for Program {}
+
+/// The nodes of a program, kept by whatever sends programs to the audio thread so the next one can be
+/// matched against it there
+#[derive(Debug, Clone, Default)]
+pub struct ProgramLayout {
+    generation: u64,
+    nodes: Vec<LayoutNode>,
+}
+
+#[derive(Debug, Clone)]
+struct LayoutNode {
+    id: String,
+    signature: String,
+    // Name of the parameter, for parameter nodes
+    parameter: Option<String>,
+}
+
+// The node of the replaced program each node carries on from
+#[derive(Debug, Clone)]
+struct CarryPlan {
+    // Generation of the program the nodes were matched against
+    from: u64,
+    nodes: Vec<Option<usize>>,
+    // Parameter nodes are matched by their name instead
+    parameters: Vec<Option<usize>>,
+}
+
+// Tells programs apart, so a carry plan isn't applied to a different program than it was made for
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Nodes whose state was kept or reset when a program took over from another
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CarriedState {
+    pub kept: Vec<String>,
+    pub reset: Vec<String>,
+}
+
+impl Program {
+    pub fn new(patch: &Patch) -> anyhow::Result<Self> {
+        Self::build(patch, false, &[])
+    }
+
+    /// Compiles a patch after removing the nodes which can't affect its output and folding sarus nodes
+    /// with only constant inputs into the defaults they feed. What was removed is in `optimization`.
+    pub fn optimized(patch: &Patch) -> anyhow::Result<Self> {
+        Self::build(patch, true, &[])
+    }
+
+    /// Compiles a patch like `optimized`, keeping the outputs given as (node index, port) in the patch so
+    /// they can be read with `probe` while the program runs
+    pub fn probed(patch: &Patch, probes: &[(usize, usize)]) -> anyhow::Result<Self> {
+        Self::build(patch, true, probes)
+    }
+
+    fn build(patch: &Patch, optimize: bool, probes: &[(usize, usize)]) -> anyhow::Result<Self> {
+        let declarations = parser::program(&patch.code)?;
+        let code = patch.code.clone() + &return_wrappers(&declarations);
+
+        // Folding only needs the JIT module, so it's given the same minimal graph the editor starts with
+        let io_node = |name: &str| Node {
+            func_name: name.to_string(),
+            id: name.to_string(),
+            port_defaults: vec![0.0],
+            position: (0.0, 0.0),
+        };
+        let mut graph = Graph::new(
+            code.clone(),
+            vec![io_node("INPUT"), io_node("OUTPUT"), io_node("COUNTER")],
+            vec![Connection { src_node: 0, dst_node: 1, src_port: 0, dst_port: 0 }],
+            STEP_SIZE,
+        )?;
+
+        // Nodes are renumbered by the optimization, so probes are found again by the id of their node
+        let probes = probes.iter()
+            .filter_map(|&(node, port)| patch.nodes.get(node).map(|patch_node| (node, patch_node.id.clone(), port)))
+            .collect::<Vec<_>>();
+        let probed = probes.iter().map(|(node, _, _)| *node).collect::<Vec<_>>();
+
+        let (patch, optimization) = if optimize {
+            optimize::optimize(patch, &probed, |name, args| {
+                let decl = match declarations.iter().find(|decl| decl.name == name) {
+                    Some(decl) => decl,
+                    None => return Ok(None),
+                };
+                // Functions which can't be called from here are left for the graph function or the errors below
+                let sarus_fn = match SarusFn::find(&mut graph, decl) {
+                    Ok(sarus_fn) => sarus_fn,
+                    Err(_) => return Ok(None),
+                };
+                let mut call_args = [0.0; MAX_PARAMS];
+                for (arg, value) in call_args.iter_mut().zip(args.iter()).take(sarus_fn.params) {
+                    *arg = *value;
+                }
+
+                // Safety: `find` checked the arity against MAX_PARAMS
+                Ok(Some((0..sarus_fn.outputs.len()).map(|output| unsafe { sarus_fn.call(output, &call_args) }).collect()))
+            })?
+        } else {
+            (patch.clone(), Optimization::default())
+        };
+
+        // Polyphonic patches are run as one copy of the voice nodes for each voice
+        let Expanded { patch, voice_of, polyphony } = voices::expand(&patch)?;
+        let patch = &patch;
+        let num_voices = polyphony.as_ref().map_or(1, |polyphony| polyphony.voices);
+
+        // A patch made only of sarus functions runs in the graph function sarus generates for it, unless
+        // some of its outputs have to be read while it runs or some of its nodes don't run every sample
+        let mut generated = probes.is_empty()
+            && patch.nodes.iter().all(|node| {
+                node.rate.is_none() && (matches!(node.func_name.as_str(), "INPUT" | "OUTPUT" | "COUNTER") || !optimize::builtin(&node.func_name))
+            });
+
+        let mut nodes = Vec::with_capacity(patch.nodes.len());
+        let mut num_buffers = 0;
+        let mut parameters: Vec<ParameterSpec> = Vec::new();
+        // Each file is only read once, however many players use it
+        let mut samples: HashMap<&str, Arc<Vec<f64>>> = HashMap::new();
+        let mut wavetables: HashMap<&str, Arc<Wavetable>> = HashMap::new();
+        let mut impulse_responses: HashMap<&str, Arc<ImpulseResponse>> = HashMap::new();
+
+        for (node, voice) in patch.nodes.iter().zip(voice_of) {
+            let (kind, inputs, outputs, signature) = match node.func_name.as_str() {
+                "INPUT" => (NodeKind::Input, 0, 1, "INPUT".to_string()),
+                "OUTPUT" => (NodeKind::Output, 1, 0, "OUTPUT".to_string()),
+                "COUNTER" => (NodeKind::Counter, 0, 1, "COUNTER".to_string()),
+                PARAMETER_NODE => {
+                    let spec = node.parameter.clone().unwrap_or_default();
+                    // Every node of a parameter uses the spec of the first one
+                    let index = match parameters.iter().position(|parameter| parameter.name == spec.name) {
+                        Some(index) => index,
+                        None => {
+                            parameters.push(spec);
+                            parameters.len() - 1
+                        }
+                    };
+                    let node = ParameterNode::new(parameters[index].clone(), index);
+                    (NodeKind::Parameter(node), 0, 1, PARAMETER_NODE.to_string())
+                }
+                SEQUENCER_NODE => {
+                    let sequencer = Sequencer::new(node.pattern.clone().unwrap_or_default());
+                    (NodeKind::Sequencer(sequencer), 1, 3, format!("{}(bpm) -> (hz, gate, velocity)", SEQUENCER_NODE))
+                }
+                SAMPLE_PLAYER_NODE => {
+                    // A player without a sample is silent
+                    let data = match node.sample.as_deref() {
+                        Some(path) => match samples.get(path) {
+                            Some(data) => data.clone(),
+                            None => {
+                                let data = Arc::new(load_sample(Path::new(path))?);
+                                samples.insert(path, data.clone());
+                                data
+                            }
+                        },
+                        None => Arc::new(Vec::new()),
+                    };
+                    (NodeKind::SamplePlayer(SamplePlayer::new(data)), 4, 1, format!("{}(trigger, rate, start, loop) -> (out)", SAMPLE_PLAYER_NODE))
+                }
+                WAVETABLE_NODE => {
+                    // An oscillator without a table plays a sine
+                    let table = match node.sample.as_deref() {
+                        Some(path) => match wavetables.get(path) {
+                            Some(table) => table.clone(),
+                            None => {
+                                let table = Arc::new(Wavetable::load(Path::new(path))?);
+                                wavetables.insert(path, table.clone());
+                                table
+                            }
+                        },
+                        None => Arc::new(Wavetable::sine()),
+                    };
+                    (NodeKind::Wavetable(WavetableOsc::new(table)), 2, 1, format!("{}(f, position) -> (a)", WAVETABLE_NODE))
+                }
+                CONVOLUTION_NODE => {
+                    // Without an impulse response the input is only delayed
+                    let ir = match node.sample.as_deref() {
+                        Some(path) => match impulse_responses.get(path) {
+                            Some(ir) => ir.clone(),
+                            None => {
+                                let ir = Arc::new(ImpulseResponse::load(Path::new(path))?);
+                                impulse_responses.insert(path, ir.clone());
+                                ir
+                            }
+                        },
+                        None => Arc::new(ImpulseResponse::unit()),
+                    };
+                    (NodeKind::Convolution(Convolver::new(ir)), 2, 1, format!("{}(a, dry) -> (b)", CONVOLUTION_NODE))
+                }
+                // Has an input for each voice, or just the one when the patch isn't polyphonic
+                VOICE_MIX_NODE => (NodeKind::VoiceMix, num_voices, 1, format!("{}({} voices) -> (mix)", VOICE_MIX_NODE, num_voices)),
+                name => {
+                    if let Some(source) = find_transport_source(name) {
+                        let signature = format!("{}() -> ({})", name, source.output);
+                        (NodeKind::Transport(source.value), 0, 1, signature)
+                    } else if let Some(source) = find_midi_source(name) {
+                        let signature = format!("{}({}) -> ({})", name, source.inputs.join(", "), source.outputs.join(", "));
+                        (NodeKind::Midi(MidiSource::new(source.value)), source.inputs.len(), source.outputs.len(), signature)
+                    } else if let Some(block) = find_block(name) {
+                        let signature = format!("{}({}) -> ({})", name, block.inputs.join(", "), block.output);
+                        (NodeKind::Block(BlockRunner::new(block)), block.inputs.len(), 1, signature)
+                    } else if let Some(native) = find_native(name) {
+                        let signature = format!("{}({}) -> ({})", name, native.inputs.join(", "), native.outputs.join(", "));
+                        (NodeKind::Native((native.create)()), native.inputs.len(), native.outputs.len(), signature)
+                    } else {
+                        let decl = declarations.iter()
+                            .find(|decl| decl.name == name)
+                            .ok_or_else(|| anyhow::anyhow!("Node {} uses unknown function {}", node.id, name))?;
+
+                        // The graph function calls the function itself, so it doesn't need to be callable from here
+                        let sarus_fn = if generated {
+                            SarusFn { outputs: Vec::new(), params: decl.params.len() }
+                        } else {
+                            SarusFn::find(&mut graph, decl)?
+                        };
+                        let signature = format!("{}({}) -> ({})", name, decl.params.join(", "), decl.returns.join(", "));
+                        (NodeKind::Sarus(sarus_fn), decl.params.len(), decl.returns.len(), signature)
+                    }
+                }
+            };
+
+            // Unconnected inputs use their default value, the extra voice inputs of a Voice Mix node are silent
+            // and a sample player plays at the original speed
+            let inputs = (0..inputs)
+                .map(|port| match kind {
+                    NodeKind::VoiceMix if port > 0 => Source::Constant(0.0),
+                    // A rate of 0 would never move, so it's taken to mean the rate hasn't been set
+                    NodeKind::SamplePlayer(_) if port == 1 => match node.port_defaults.get(port).copied() {
+                        Some(rate) if rate != 0.0 => Source::Constant(rate),
+                        _ => Source::Constant(1.0),
+                    },
+                    _ => Source::Constant(node.port_defaults.get(port).cloned().unwrap_or(0.0)),
+                })
+                .collect();
+
+            nodes.push(ProgramNode {
+                id: node.id.clone(),
+                signature,
+                kind,
+                inputs,
+                first_output: num_buffers,
+                num_outputs: outputs,
+                outputs: vec![Vec::new(); outputs],
+                voice,
+                rate: Rate::Audio,
+                last: Vec::with_capacity(outputs),
+            });
+            num_buffers += outputs;
+        }
+
+        let mut adjacency = vec![Vec::new(); nodes.len()];
+
+        for connection in patch.connections.iter() {
+            let src = nodes.get(connection.src_node).ok_or_else(|| anyhow::anyhow!("Connection from missing node {}", connection.src_node))?;
+            if connection.src_port >= src.num_outputs {
+                anyhow::bail!("Node {} has no output {}", src.id, connection.src_port);
+            }
+            let buffer = src.first_output + connection.src_port;
+
+            let dst = nodes.get_mut(connection.dst_node).ok_or_else(|| anyhow::anyhow!("Connection to missing node {}", connection.dst_node))?;
+            if connection.src_node == connection.dst_node {
+                anyhow::bail!("Node {} is connected to itself", dst.id);
+            }
+
+            // If an input has more than one connection the last one wins
+            match dst.inputs.get_mut(connection.dst_port) {
+                Some(input) => *input = Source::Buffer(buffer),
+                None => anyhow::bail!("Node {} has no input {}", dst.id, connection.dst_port),
+            }
+
+            adjacency[connection.src_node].push(connection.dst_node);
+        }
+
+        let order = IndexGraph::from_adjacency_list(&adjacency)
+            .toposort()
+            .ok_or_else(|| anyhow::anyhow!("The graph contains a cycle"))?;
+
+        let marked = patch.nodes.iter().map(|node| node.rate).collect::<Vec<_>>();
+        for (node, rate) in nodes.iter_mut().zip(rate::resolve_rates(&nodes, &order, &marked)) {
+            node.rate = rate;
+        }
+
+        // The graph function evaluates every node each sample, so a patch with control rate nodes runs
+        // node by node and its functions are looked up after all
+        if generated && nodes.iter().any(|node| node.rate != Rate::Audio) {
+            generated = false;
+            for (node, patch_node) in nodes.iter_mut().zip(patch.nodes.iter()) {
+                if let NodeKind::Sarus(sarus_fn) = &mut node.kind {
+                    let decl = declarations.iter()
+                        .find(|decl| decl.name == patch_node.func_name)
+                        .ok_or_else(|| anyhow::anyhow!("Node {} uses unknown function {}", patch_node.id, patch_node.func_name))?;
+                    *sarus_fn = SarusFn::find(&mut graph, decl)?;
+                }
+            }
+        }
+
+        // Built once the patch is known to be valid
+        if generated {
+            let graph_nodes = patch.nodes.iter()
+                .map(|node| Node {
+                    func_name: node.func_name.clone(),
+                    id: node.id.clone(),
+                    port_defaults: node.port_defaults.clone(),
+                    position: (0.0, 0.0),
+                })
+                .collect();
+            let connections = patch.connections.iter()
+                .map(|connection| Connection {
+                    src_node: connection.src_node,
+                    dst_node: connection.dst_node,
+                    src_port: connection.src_port,
+                    dst_port: connection.dst_port,
+                })
+                .collect();
+            graph = Graph::new(code, graph_nodes, connections, STEP_SIZE)?;
+        }
+
+        // The latency of the graph is that of the slowest path to OUTPUT
+        let mut path_latency = vec![0; nodes.len()];
+        for &index in order.iter() {
+            path_latency[index] += nodes[index].kind.latency();
+            for &dst in adjacency[index].iter() {
+                path_latency[dst] = path_latency[dst].max(path_latency[index]);
+            }
+        }
+        let latency = nodes.iter()
+            .position(|node| matches!(node.kind, NodeKind::Output))
+            .map_or(0, |output| path_latency[output]);
+
+        // The copies of a node made for each voice are all probed
+        let probes = probes.iter()
+            .map(|(_, id, port)| {
+                nodes.iter()
+                    .filter(|node| node.id == *id || node.id.strip_prefix(id.as_str()).map_or(false, |rest| rest.starts_with('#')))
+                    .filter(|node| *port < node.num_outputs)
+                    .map(|node| node.first_output + port)
+                    .collect()
+            })
+            .collect();
+
+        let threads = if generated { 1 } else { patch.threads.unwrap_or(1).max(1) };
+        let schedule = parallel::schedule(&nodes, &order, &adjacency, threads);
+
+        // MIDI sources always have scratch space for their controller input
+        let max_inputs = nodes.iter().map(|node| node.inputs.len()).max().unwrap_or(0).max(1);
+
+        let automation = patch.automation.iter().map(|automation| {
+            parameters.iter()
+                .position(|parameter| parameter.name == automation.parameter)
+                .map(|index| (index, automation.clone()))
+                .ok_or_else(|| anyhow::anyhow!("Automation of unknown parameter {}", automation.parameter))
+        }).collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            graph,
+            nodes,
+            schedule,
+            pool: None,
+            worker_failures: 0,
+            buffers: vec![Vec::with_capacity(MAX_BLOCK_SIZE); num_buffers],
+            native_inputs: vec![vec![Vec::with_capacity(MAX_BLOCK_SIZE); max_inputs]; threads],
+            parameters,
+            automation,
+            voices: polyphony.as_ref().map(VoiceAllocator::new),
+            transport: TransportState::new(patch.transport.clone().unwrap_or_default()),
+            latency,
+            optimization,
+            probes,
+            generated,
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+            carry: None,
+        })
+    }
+
+    /// The nodes left out when the program was compiled with `optimized`
+    pub fn optimization(&self) -> &Optimization {
+        &self.optimization
+    }
+
+    /// Samples the output is behind the input, from the nodes which collect blocks before processing them
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// Writes the latest block of a probed output to `output`, in the order the probes were given to
+    /// `probed`. A probe inside the voices of a polyphonic patch hears them all summed.
+    pub fn probe(&self, probe: usize, output: &mut [f64]) {
+        output.iter_mut().for_each(|sample| *sample = 0.0);
+        for &buffer in self.probes.get(probe).into_iter().flatten() {
+            for (sample, value) in output.iter_mut().zip(self.buffers[buffer].iter()) {
+                *sample += value;
+            }
+        }
+    }
+
+    /// Number of threads the graph is processed on, one until `start_workers` is called
+    pub fn threads(&self) -> usize {
+        self.pool.as_ref().map_or(1, WorkerPool::threads)
+    }
+
+    /// Starts the threads which help process the graph, as many as the patch's `threads` allows and the
+    /// branches of the graph can use. Only programs played live start them; offline renders, previews and
+    /// benchmarks run on one thread.
+    pub fn start_workers(&mut self) -> anyhow::Result<()> {
+        let threads = self.schedule.iter().map(|level| level.parallel.len()).max().unwrap_or(1);
+        if self.pool.is_none() && threads > 1 {
+            self.pool = Some(WorkerPool::new(threads)?);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of levels in which a node on a worker thread panicked since the last call, and
+    /// starts counting again. The outputs of the nodes which didn't finish are left as they were.
+    pub fn take_worker_failures(&mut self) -> usize {
+        mem::take(&mut self.worker_failures)
+    }
+
+    /// The parameters of the graph, which can be changed while it runs
+    pub fn parameters(&self) -> &[ParameterSpec] {
+        &self.parameters
+    }
+
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.parameters.iter().position(|parameter| parameter.name == name)
+    }
+
+    /// Sets the value of a parameter, which its nodes glide to. Doesn't allocate so it can be called on the audio thread.
+    pub fn set_parameter(&mut self, index: usize, value: f64) {
+        for node in self.nodes.iter_mut() {
+            if let NodeKind::Parameter(parameter) = &mut node.kind {
+                if parameter.index == index {
+                    parameter.set(value);
+                }
+            }
+        }
+    }
+
+    /// Sets the automated parameters to their values at sample `n`. Only offline renders apply automation,
+    /// when running live the parameters are left to the host or the user.
+    pub fn automate(&mut self, n: usize) {
+        let time = n as f64 / SAMPLE_RATE as f64;
+        for i in 0..self.automation.len() {
+            let (index, automation) = &self.automation[i];
+            if let Some(value) = automation.value_at(time) {
+                let index = *index;
+                self.set_parameter(index, value);
+            }
+        }
+    }
+
+    /// Settings of the transport the time source nodes and sequencers follow
+    pub fn transport(&self) -> &Transport {
+        self.transport.settings()
+    }
+
+    /// Changes the tempo, time signature, loop or whether the transport is playing, keeping its position
+    pub fn set_transport(&mut self, settings: Transport) {
+        self.transport.set(settings);
+    }
+
+    /// Moves the transport to a position in beats and seconds
+    pub fn locate(&mut self, beats: f64, seconds: f64) {
+        self.transport.locate(beats, seconds);
+    }
+
+    /// The graph holding the JIT module, e.g. to print its AST
+    pub fn graph(&self) -> &Graph {
+        &self.graph
+    }
+
+    /// Processes a block of any length, in place like the graph function sarus generates.
+    /// `buffer` feeds the INPUT node and is overwritten with the OUTPUT node, `n` is the sample
+    /// index of the first sample given to the COUNTER node.
+    pub fn process(&mut self, n: usize, buffer: &mut [f64]) {
+        self.process_midi(n, buffer, &[], 0);
+    }
+
+    /// Processes a block along with the MIDI events which happen during it. Events are sorted by time,
+    /// with the first sample of `buffer` at time `offset`, and those outside the block are ignored.
+    pub fn process_midi(&mut self, n: usize, buffer: &mut [f64], events: &[MidiEvent], offset: usize) {
+        for (i, chunk) in buffer.chunks_mut(MAX_BLOCK_SIZE).enumerate() {
+            self.process_chunk(n + i * MAX_BLOCK_SIZE, chunk, events, offset + i * MAX_BLOCK_SIZE);
+        }
+    }
+
+    fn process_chunk(&mut self, n: usize, buffer: &mut [f64], events: &[MidiEvent], offset: usize) {
+        let len = buffer.len();
+
+        if self.generated {
+            self.process_generated(n, buffer);
+            self.transport.advance(len, SAMPLE_RATE as f64);
+            return;
+        }
+
+        let context = ProcessContext {
+            sample_rate: SAMPLE_RATE as f64,
+            n,
+            len,
+        };
+
+        for buffer in self.buffers.iter_mut() {
+            buffer.resize(len, 0.0);
+        }
+
+        // The input is read before anything is written to the output
+        for node in self.nodes.iter() {
+            if let NodeKind::Input = node.kind {
+                self.buffers[node.first_output].copy_from_slice(buffer);
+            }
+        }
+
+        if let Some(voices) = &mut self.voices {
+            voices.distribute(events, offset, len);
+        }
+
+        let nodes = &mut self.nodes;
+        let buffers = &mut self.buffers;
+        let native_inputs = &mut self.native_inputs;
+        let voices = &mut self.voices;
+        let pool = &self.pool;
+        let worker_failures = &mut self.worker_failures;
+
+        for level in self.schedule.iter() {
+            {
+                let shared = Shared {
+                    context,
+                    events,
+                    offset,
+                    voices: voices.as_ref(),
+                    transport: &self.transport,
+                };
+                let buffers = Disjoint::new(buffers);
+                let nodes = Disjoint::new(nodes);
+                let native_inputs = Disjoint::new(native_inputs);
+
+                // Each node is in the list of one thread and each thread has its own scratch space. The
+                // buffers a node writes are only read by the nodes after it in the same branch and by
+                // later levels, so a thread only touches the buffers of its own branches.
+                let task = |thread: usize| {
+                    let scratch = unsafe { native_inputs.get(thread) };
+                    for &index in level.parallel[thread].iter() {
+                        let node = unsafe { nodes.get(index) };
+
+                        // The outputs are moved into the node while it writes them, leaving the buffers it reads untouched
+                        for (output, buffer) in node.outputs.iter_mut().zip(node.first_output..) {
+                            mem::swap(output, unsafe { buffers.get(buffer) });
+                        }
+                        process_node(node, &buffers, scratch, &shared);
+                        for (output, buffer) in node.outputs.iter_mut().zip(node.first_output..) {
+                            mem::swap(output, unsafe { buffers.get(buffer) });
+                        }
+                    }
+                };
+
+                match pool {
+                    Some(pool) if level.parallel.len() > 1 => {
+                        if !pool.run(level.parallel.len(), &task) {
+                            *worker_failures += 1;
+                        }
+                    }
+                    _ => (0..level.parallel.len()).for_each(&task),
+                }
+            }
+
+            for &index in level.serial.iter() {
+                let node = &nodes[index];
+                match &node.kind {
+                    NodeKind::Output => match node.inputs[0] {
+                        Source::Buffer(source) => buffer.copy_from_slice(&buffers[source]),
+                        Source::Constant(value) => buffer.iter_mut().for_each(|sample| *sample = value),
+                    },
+
+                    NodeKind::VoiceMix => {
+                        let mix = node.first_output;
+                        buffers[mix].iter_mut().for_each(|sample| *sample = 0.0);
+
+                        for (voice, source) in node.inputs.iter().enumerate() {
+                            let mut peak = 0.0f64;
+                            for i in 0..len {
+                                let sample = match *source {
+                                    Source::Buffer(buffer) => buffers[buffer][i],
+                                    Source::Constant(value) => value,
+                                };
+                                buffers[mix][i] += sample;
+                                peak = peak.max(sample.abs());
+                            }
+
+                            // How loud each voice is decides which one is stolen by quietest voice stealing
+                            if let Some(voices) = voices.as_mut().filter(|voices| voice < voices.num_voices()) {
+                                voices.set_level(voice, peak);
+                            }
+                        }
+                    }
+
+                    _ => {}
+                }
+            }
+        }
+
+        self.transport.advance(len, context.sample_rate);
+    }
+
+    // Runs the graph function, which processes STEP_SIZE samples at a time
+    fn process_generated(&mut self, n: usize, buffer: &mut [f64]) {
+        for (i, chunk) in buffer.chunks_mut(STEP_SIZE).enumerate() {
+            // The functions are pure, so padding a short block doesn't affect the next one
+            let mut block = [0.0; STEP_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+
+            let count = (n + i * STEP_SIZE) as f64;
+            // Safety: the graph function takes the block to process in place and the sample count
+            if unsafe { run_fn(&mut self.graph.jit, "graph", (&mut block, count)) }.is_err() {
+                block = [0.0; STEP_SIZE];
+            }
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+    }
+
+    /// What the state of the program looks like, for matching the program which replaces it against
+    pub fn layout(&self) -> ProgramLayout {
+        ProgramLayout {
+            generation: self.generation,
+            nodes: self.nodes.iter()
+                .map(|node| LayoutNode {
+                    id: node.id.clone(),
+                    signature: node.signature.clone(),
+                    parameter: match &node.kind {
+                        NodeKind::Parameter(parameter) => Some(parameter.spec().name.clone()),
+                        _ => None,
+                    },
+                })
+                .collect(),
+        }
+    }
+
+    /// Matches the nodes of this program with those of the program it will replace, so `apply_carry`
+    /// only has to copy their state. Run before the program is handed to the audio thread.
+    ///
+    /// Nodes with the same id and signature continue from the previous node's state, any others start
+    /// fresh. Parameters keep their value as long as their name is unchanged.
+    pub fn plan_carry(&mut self, previous: &ProgramLayout) -> CarriedState {
+        let mut carried = CarriedState::default();
+        let mut plan = CarryPlan {
+            from: previous.generation,
+            nodes: Vec::with_capacity(self.nodes.len()),
+            parameters: Vec::with_capacity(self.nodes.len()),
+        };
+
+        for node in self.nodes.iter() {
+            let parameter = match &node.kind {
+                NodeKind::Parameter(parameter) => {
+                    let name = parameter.spec().name.as_str();
+                    let matching = previous.nodes.iter().position(|old| old.parameter.as_deref() == Some(name));
+                    match matching {
+                        Some(_) => carried.kept.push(node.id.clone()),
+                        None => carried.reset.push(node.id.clone()),
+                    }
+                    matching
+                }
+                _ => None,
+            };
+            plan.parameters.push(parameter);
+
+            // The signature names the function, so a matching node is always of the same kind
+            let matching = previous.nodes.iter().position(|old| old.id == node.id && old.signature == node.signature);
+            plan.nodes.push(matching);
+
+            let stateful = matches!(
+                node.kind,
+                NodeKind::Native(_) | NodeKind::Midi(_) | NodeKind::Sequencer(_) | NodeKind::SamplePlayer(_)
+                | NodeKind::Wavetable(_) | NodeKind::Convolution(_) | NodeKind::Block(_)
+            );
+            if stateful {
+                match matching {
+                    Some(_) => carried.kept.push(node.id.clone()),
+                    None => carried.reset.push(node.id.clone()),
+                }
+            }
+        }
+
+        self.carry = Some(plan);
+        carried
+    }
+
+    /// Copies the state of the nodes matched by `plan_carry` from the program this one replaces. Doesn't
+    /// allocate, so it can run on the audio thread. Node state is left alone if `previous` isn't the
+    /// program the plan was made against, but the transport and voices always carry on.
+    pub fn apply_carry(&mut self, previous: &Program) {
+        self.transport.carry_from(&previous.transport);
+
+        // Voices keep their notes as long as there are as many of them
+        if let (Some(voices), Some(old)) = (&mut self.voices, &previous.voices) {
+            voices.carry_from(old);
+        }
+
+        let plan = match &self.carry {
+            Some(plan) if plan.from == previous.generation => plan,
+            _ => return,
+        };
+
+        for ((node, matching), matching_parameter) in self.nodes.iter_mut().zip(plan.nodes.iter()).zip(plan.parameters.iter()) {
+            if let (NodeKind::Parameter(parameter), Some(index)) = (&mut node.kind, matching_parameter) {
+                if let NodeKind::Parameter(old) = &previous.nodes[*index].kind {
+                    parameter.carry_from(old);
+                }
+            }
+
+            let old = match matching {
+                Some(index) => &previous.nodes[*index],
+                None => continue,
+            };
+
+            // Control rate nodes ramp on from where they were rather than from their first value
+            if old.rate == node.rate {
+                node.last.clear();
+                node.last.extend_from_slice(&old.last);
+            }
+
+            match (&mut node.kind, &old.kind) {
+                (NodeKind::Native(native), NodeKind::Native(old)) => native.carry_from(old.as_ref()),
+                // Windows which are part way through carry on filling
+                (NodeKind::Block(runner), NodeKind::Block(old)) => runner.carry_from(old),
+                // Notes which are held carry on sounding
+                (NodeKind::Midi(source), NodeKind::Midi(old)) => source.carry_from(old),
+                // The pattern may have been edited, so only the position in it is carried over
+                (NodeKind::Sequencer(sequencer), NodeKind::Sequencer(old)) => sequencer.carry_from(old),
+                // A sample which is playing carries on, even if the file has changed
+                (NodeKind::SamplePlayer(player), NodeKind::SamplePlayer(old)) => player.carry_from(old),
+                // The input already heard is convolved with the new impulse response
+                (NodeKind::Convolution(convolver), NodeKind::Convolution(old)) => convolver.carry_from(old),
+                // Changing the table doesn't restart the cycle
+                (NodeKind::Wavetable(osc), NodeKind::Wavetable(old)) => osc.carry_from(old),
+                _ => {}
+            }
+        }
+    }
+
+    /// Takes over the state of nodes from the program this one replaces, planning and applying the
+    /// carry over in one go for programs which aren't being played
+    pub fn carry_state_from(&mut self, previous: &Program) -> CarriedState {
+        let carried = self.plan_carry(&previous.layout());
+        self.apply_carry(previous);
+        carried
+    }
+}
+
+// What the nodes share while a block is processed
+struct Shared<'a> {
+    context: ProcessContext,
+    events: &'a [MidiEvent],
+    offset: usize,
+    voices: Option<&'a VoiceAllocator>,
+    transport: &'a TransportState,
+}
+
+// Processes a node other than INPUT, OUTPUT and Voice Mix, which only reads `buffers` and writes to the
+// buffers moved into `node.outputs`, so nodes which don't depend on each other can run on any thread
+fn process_node(node: &mut ProgramNode, buffers: &Disjoint<Vec<f64>>, native_inputs: &mut [Vec<f64>], shared: &Shared) {
+    let context = &shared.context;
+    let len = context.len;
+    let outputs = &mut node.outputs;
+
+    match &mut node.kind {
+        NodeKind::Input | NodeKind::Output | NodeKind::VoiceMix => {}
+
+        NodeKind::Counter => {
+            for (i, sample) in outputs[0].iter_mut().enumerate() {
+                *sample = (context.n + i) as f64;
+            }
+        }
+
+        // Evaluated once with the inputs at the end of the block
+        NodeKind::Sarus(sarus_fn) if node.rate != Rate::Audio && len > 0 => {
+            let mut args = [0.0; MAX_PARAMS];
+            for (arg, source) in args.iter_mut().zip(node.inputs.iter()) {
+                *arg = match *source {
+                    Source::Buffer(buffer) => buffers[buffer][len - 1],
+                    Source::Constant(value) => value,
+                };
+            }
+
+            for output in 0..node.num_outputs {
+                // Safety: `find` checked the arity against MAX_PARAMS
+                let value = unsafe { sarus_fn.call(output, &args) };
+                rate::fill_control(node.rate, node.last.get(output).copied(), value, &mut outputs[output]);
+
+                // There's room for every output, so this doesn't allocate
+                match node.last.get_mut(output) {
+                    Some(last) => *last = value,
+                    None => node.last.push(value),
+                }
+            }
+        }
+
+        NodeKind::Sarus(sarus_fn) => {
+            let mut args = [0.0; MAX_PARAMS];
+            for i in 0..len {
+                for (arg, source) in args.iter_mut().zip(node.inputs.iter()) {
+                    *arg = match *source {
+                        Source::Buffer(buffer) => buffers[buffer][i],
+                        Source::Constant(value) => value,
+                    };
+                }
+
+                for (output, buffer) in outputs.iter_mut().enumerate() {
+                    // Safety: `find` checked the arity against MAX_PARAMS
+                    buffer[i] = unsafe { sarus_fn.call(output, &args) };
+                }
+            }
+        }
+
+        NodeKind::Parameter(parameter) => {
+            parameter.process(&mut outputs[0]);
+        }
+
+        NodeKind::Midi(source) => {
+            // The controller number of midi_cc is its only input
+            let controller = &mut native_inputs[0];
+            controller.clear();
+            match node.inputs.first() {
+                Some(Source::Buffer(buffer)) => controller.extend_from_slice(&buffers[*buffer]),
+                Some(Source::Constant(value)) => controller.resize(len, *value),
+                None => {}
+            }
+
+            // Each voice only hears the notes it was given
+            let events = match (node.voice, shared.voices) {
+                (Some(voice), Some(voices)) => voices.events(voice),
+                _ => shared.events,
+            };
+
+            source.process(events, shared.offset, controller, &mut outputs[0]);
+        }
+
+        NodeKind::Sequencer(sequencer) => {
+            let scratch = &mut native_inputs[0];
+            scratch.clear();
+            match node.inputs[0] {
+                Source::Buffer(buffer) => scratch.extend_from_slice(&buffers[buffer]),
+                Source::Constant(_) => {
+                    scratch.resize(len, 0.0);
+                    shared.transport.fill(TransportValue::Beats, context.sample_rate, scratch);
+                }
+            }
+
+            // A connected tempo runs the sequencer freely, otherwise it follows the transport
+            match node.inputs[0] {
+                Source::Buffer(_) => sequencer.process(context.sample_rate, scratch, outputs),
+                Source::Constant(_) => sequencer.follow(shared.transport.settings().playing, scratch, outputs),
+            }
+        }
+
+        NodeKind::SamplePlayer(player) => {
+            read_inputs(&node.inputs, buffers, len, native_inputs);
+            player.process(&native_inputs[..node.inputs.len()], &mut outputs[0]);
+        }
+
+        NodeKind::Wavetable(osc) => {
+            read_inputs(&node.inputs, buffers, len, native_inputs);
+            osc.process(context.sample_rate, &native_inputs[..node.inputs.len()], &mut outputs[0]);
+        }
+
+        NodeKind::Convolution(convolver) => {
+            read_inputs(&node.inputs, buffers, len, native_inputs);
+            convolver.process(&native_inputs[..node.inputs.len()], &mut outputs[0]);
+        }
+
+        NodeKind::Block(runner) => {
+            read_inputs(&node.inputs, buffers, len, native_inputs);
+            runner.process(context.sample_rate, &native_inputs[..node.inputs.len()], &mut outputs[0]);
+        }
+
+        NodeKind::Transport(value) => {
+            shared.transport.fill(*value, context.sample_rate, &mut outputs[0]);
+        }
+
+        NodeKind::Native(native) => {
+            read_inputs(&node.inputs, buffers, len, native_inputs);
+            native.process(context, &native_inputs[..node.inputs.len()], outputs);
+        }
+    }
+}
+
+// Copies each input into the scratch space of a node which processes whole blocks
+fn read_inputs(inputs: &[Source], buffers: &Disjoint<Vec<f64>>, len: usize, native_inputs: &mut [Vec<f64>]) {
+    for (scratch, source) in native_inputs.iter_mut().zip(inputs.iter()) {
+        scratch.clear();
+        match *source {
+            Source::Buffer(buffer) => scratch.extend_from_slice(&buffers[buffer]),
+            Source::Constant(value) => scratch.resize(len, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::{PatchConnection, PatchNode};
+
+    fn node(func_name: &str, id: &str, port_defaults: Vec<f64>) -> PatchNode {
+        PatchNode {
+            func_name: func_name.to_string(),
+            id: id.to_string(),
+            port_defaults,
+            position: (0.0, 0.0),
+            parameter: None,
+            pattern: None,
+            sample: None,
+            rate: None,
+        }
+    }
+
+    fn connection(src_node: usize, dst_node: usize, dst_port: usize) -> PatchConnection {
+        PatchConnection { src_node, dst_node, src_port: 0, dst_port }
+    }
+
+    // An oscillator at `frequency` going to OUTPUT through `double`
+    fn oscillator_patch(frequency: f64) -> Patch {
+        Patch {
+            code: "fn double(a) -> (b) {\n    b = 2.0 * a\n}\n".to_string(),
+            nodes: vec![
+                node("INPUT", "INPUT", vec![0.0]),
+                node("OUTPUT", "OUTPUT", vec![0.0]),
+                node("sine_osc", "osc", vec![frequency, 0.0]),
+                node("double", "double", vec![0.0, 0.0]),
+            ],
+            connections: vec![connection(2, 3, 0), connection(3, 1, 0)],
+            automation: Vec::new(),
+            polyphony: None,
+            transport: None,
+            threads: None,
+        }
+    }
+
+    fn run(program: &mut Program, n: usize, len: usize) -> Vec<f64> {
+        let mut buffer = vec![0.0; len];
+        program.process(n, &mut buffer);
+        buffer
+    }
+
+    #[test]
+    fn unchanged_nodes_keep_their_state() {
+        let mut previous = Program::new(&oscillator_patch(440.0)).unwrap();
+        let mut reference = Program::new(&oscillator_patch(440.0)).unwrap();
+        run(&mut previous, 0, 1000);
+        run(&mut reference, 0, 1000);
+
+        // The oscillator has the same id and signature so its phase carries on
+        let mut next = Program::new(&oscillator_patch(440.0)).unwrap();
+        let carried = next.carry_state_from(&previous);
+        assert_eq!(carried.kept, vec!["osc".to_string()]);
+        assert!(carried.reset.is_empty());
+
+        assert_eq!(run(&mut next, 1000, 100), run(&mut reference, 1000, 100));
+    }
+
+    #[test]
+    fn changed_nodes_start_fresh() {
+        let mut previous = Program::new(&oscillator_patch(440.0)).unwrap();
+        run(&mut previous, 0, 1000);
+
+        let mut patch = oscillator_patch(440.0);
+        patch.nodes[2].id = "other_osc".to_string();
+        let mut next = Program::new(&patch).unwrap();
+        let carried = next.carry_state_from(&previous);
+        assert_eq!(carried.reset, vec!["other_osc".to_string()]);
+
+        let mut fresh = Program::new(&oscillator_patch(440.0)).unwrap();
+        assert_eq!(run(&mut next, 1000, 100), run(&mut fresh, 0, 100));
+    }
+
+    #[test]
+    fn carry_plans_only_apply_to_the_program_they_were_made_for() {
+        let mut previous = Program::new(&oscillator_patch(440.0)).unwrap();
+        let mut reference = Program::new(&oscillator_patch(440.0)).unwrap();
+        run(&mut previous, 0, 1000);
+        run(&mut reference, 0, 1000);
+
+        // Planned against another program with the same nodes, so nothing is copied
+        let other = Program::new(&oscillator_patch(440.0)).unwrap();
+        let mut next = Program::new(&oscillator_patch(440.0)).unwrap();
+        assert_eq!(next.plan_carry(&other.layout()).kept, vec!["osc".to_string()]);
+        next.apply_carry(&previous);
+        let mut fresh = Program::new(&oscillator_patch(440.0)).unwrap();
+        assert_eq!(run(&mut next, 1000, 100), run(&mut fresh, 0, 100));
+
+        let mut next = Program::new(&oscillator_patch(440.0)).unwrap();
+        next.plan_carry(&previous.layout());
+        next.apply_carry(&previous);
+        assert_eq!(run(&mut next, 1000, 100), run(&mut reference, 1000, 100));
+    }
+
+    #[test]
+    fn loaded_patches_carry_on_from_the_saved_graph() {
+        let patch = oscillator_patch(440.0);
+        let previous = Program::new(&patch).unwrap();
+
+        let path = std::env::temp_dir().join(format!("sarus_carry_{}.json", std::process::id()));
+        patch.save(&path).unwrap();
+        let loaded = Patch::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // The ids are saved with the patch, so the recompiled nodes still match the ones playing
+        let mut next = Program::new(&loaded).unwrap();
+        let carried = next.plan_carry(&previous.layout());
+        assert_eq!(carried.kept, vec!["osc".to_string()]);
+        assert!(carried.reset.is_empty());
+    }
+
+    #[test]
+    fn blocks_can_be_any_length() {
+        let mut whole = Program::new(&oscillator_patch(1000.0)).unwrap();
+        let mut split = Program::new(&oscillator_patch(1000.0)).unwrap();
+
+        let expected = run(&mut whole, 0, 3000);
+        let mut actual = run(&mut split, 0, 7);
+        actual.extend(run(&mut split, 7, 2993));
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn every_return_of_a_function_is_an_output() {
+        let mut patch = parameter_patch();
+        patch.code = "fn split(a) -> (b, c) {\n    b = 2.0 * a\n    c = 3.0 * a\n}\nfn add(a, b) -> (c) {\n    c = a + b\n}\n".to_string();
+        patch.nodes.push(node("split", "split", vec![0.0, 0.0, 0.0]));
+        patch.nodes.push(node("add", "add", vec![0.0, 0.0, 0.0]));
+        patch.connections = vec![
+            connection(2, 3, 0),
+            connection(3, 4, 0),
+            PatchConnection { src_node: 3, dst_node: 4, src_port: 1, dst_port: 1 },
+            connection(4, 1, 0),
+        ];
+
+        // The parameter starts at 0.5
+        let mut program = Program::new(&patch).unwrap();
+        assert!(!program.generated);
+        assert_eq!(run(&mut program, 0, 4), vec![2.5; 4]);
+    }
+
+    #[test]
+    fn sarus_only_patches_run_in_the_generated_graph() {
+        let mut patch = oscillator_patch(440.0);
+        patch.code = "fn sum(a, b, c, d, e, f, g, h, i) -> (s) {\n    s = a + b + c + d + e + f + g + h + i\n}\n".to_string();
+        patch.nodes = vec![
+            node("INPUT", "INPUT", vec![0.0]),
+            node("OUTPUT", "OUTPUT", vec![0.0]),
+            node("sum", "sum", vec![0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0]),
+        ];
+        patch.connections = vec![connection(0, 2, 0), connection(2, 1, 0)];
+
+        // More parameters than can be called from Rust, with a block which isn't a whole number of steps
+        let mut program = Program::new(&patch).unwrap();
+        assert!(program.generated);
+        let mut buffer = (0..STEP_SIZE * 2 + 3).map(|i| i as f64).collect::<Vec<_>>();
+        program.process(0, &mut buffer);
+        assert_eq!(buffer, (0..STEP_SIZE * 2 + 3).map(|i| i as f64 + 8.0).collect::<Vec<_>>());
+
+        // Probing a node runs it node by node instead, which sounds the same
+        let mut patch = patch;
+        patch.code = "fn double(a) -> (b) {\n    b = 2.0 * a\n}\n".to_string();
+        patch.nodes[2] = node("double", "double", vec![0.0, 0.0]);
+        let mut generated = Program::new(&patch).unwrap();
+        let mut probed = Program::probed(&patch, &[(2, 0)]).unwrap();
+        assert!(generated.generated && !probed.generated);
+
+        let input = (0..100).map(|i| (i as f64 * 0.1).sin()).collect::<Vec<_>>();
+        let (mut expected, mut actual) = (input.clone(), input);
+        generated.process(0, &mut expected);
+        probed.process(0, &mut actual);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        let mut patch = oscillator_patch(440.0);
+        patch.nodes.push(node("double", "double_2", vec![0.0, 0.0]));
+        patch.connections.push(connection(3, 4, 0));
+        patch.connections.push(connection(4, 3, 0));
+        assert!(Program::new(&patch).is_err());
+    }
+
+    // `four` doubled by `double`, which is fed by a parameter instead when `parameter` is set
+    fn control_patch(parameter: bool) -> Patch {
+        let mut patch = parameter_patch();
+        patch.code = "fn four() -> (b) {\n    b = 4.0\n}\nfn double(a) -> (b) {\n    b = 2.0 * a\n}\n".to_string();
+        patch.nodes.push(node("four", "four", vec![0.0]));
+        patch.nodes.push(node("double", "double", vec![0.0, 0.0]));
+        let src = if parameter { 2 } else { 3 };
+        patch.connections = vec![connection(src, 4, 0), connection(4, 1, 0)];
+        patch
+    }
+
+    fn control_rate_nodes(program: &Program) -> Vec<&str> {
+        program.nodes.iter().filter(|node| node.rate != Rate::Audio).map(|node| node.id.as_str()).collect()
+    }
+
+    #[test]
+    fn constant_subgraphs_run_at_control_rate() {
+        let mut program = Program::new(&control_patch(false)).unwrap();
+        assert_eq!(control_rate_nodes(&program), vec!["four", "double"]);
+        assert_eq!(run(&mut program, 0, 4), vec![8.0; 4]);
+
+        // Anything fed by an audio rate node stays at audio rate
+        let program = Program::new(&oscillator_patch(440.0)).unwrap();
+        assert!(control_rate_nodes(&program).is_empty());
+    }
+
+    #[test]
+    fn control_rate_nodes_ramp_to_parameter_changes() {
+        let mut program = Program::new(&control_patch(true)).unwrap();
+        assert_eq!(control_rate_nodes(&program), vec!["double"]);
+        assert_eq!(run(&mut program, 0, 4), vec![1.0; 4]);
+
+        program.set_parameter(0, 1.5);
+        assert_eq!(run(&mut program, 4, 4), vec![1.5, 2.0, 2.5, 3.0]);
+
+        // A node set to hold jumps straight to the new value, and one set to audio rate follows every sample
+        for (rate, expected) in [(Rate::Hold, vec![3.0; 4]), (Rate::Audio, vec![3.0; 4])].iter() {
+            let mut patch = control_patch(true);
+            patch.nodes[4].rate = Some(*rate);
+            let mut program = Program::new(&patch).unwrap();
+            run(&mut program, 0, 4);
+            program.set_parameter(0, 1.5);
+            assert_eq!(&run(&mut program, 4, 4), expected);
+        }
+    }
+
+    #[test]
+    fn sarus_only_patches_with_control_rate_nodes_run_node_by_node() {
+        let mut patch = oscillator_patch(440.0);
+        patch.code = "fn four() -> (b) {\n    b = 4.0\n}\nfn double(a) -> (b) {\n    b = 2.0 * a\n}\n".to_string();
+        patch.nodes = vec![
+            node("INPUT", "INPUT", vec![0.0]),
+            node("OUTPUT", "OUTPUT", vec![0.0]),
+            node("four", "four", vec![0.0]),
+            node("double", "double", vec![0.0, 0.0]),
+        ];
+        patch.connections = vec![connection(2, 3, 0), connection(3, 1, 0)];
+
+        let mut program = Program::new(&patch).unwrap();
+        assert!(!program.generated);
+        assert_eq!(control_rate_nodes(&program), vec!["four", "double"]);
+        assert_eq!(run(&mut program, 0, 4), vec![8.0; 4]);
+
+        // A rate set in the patch is followed even when nothing would be inferred
+        patch.connections = vec![connection(0, 3, 0), connection(3, 1, 0)];
+        patch.nodes[3].rate = Some(Rate::Hold);
+        let program = Program::new(&patch).unwrap();
+        assert!(!program.generated);
+        assert_eq!(control_rate_nodes(&program), vec!["double"]);
+    }
+
+    #[test]
+    fn nodes_not_reaching_the_output_are_pruned() {
+        let mut patch = oscillator_patch(440.0);
+        patch.nodes.push(node("double", "stray", vec![0.0, 0.0]));
+        patch.nodes.push(node("sine_osc", "unused", vec![220.0, 0.0]));
+        patch.connections.push(connection(2, 4, 0));
+
+        let mut optimized = Program::optimized(&patch).unwrap();
+        assert_eq!(optimized.optimization().pruned, vec!["stray".to_string(), "unused".to_string()]);
+        assert_eq!(optimized.nodes.len(), 4);
+
+        let mut reference = Program::new(&patch).unwrap();
+        assert_eq!(run(&mut optimized, 0, 100), run(&mut reference, 0, 100));
+    }
+
+    #[test]
+    fn probed_nodes_are_kept_and_recorded() {
+        let mut patch = oscillator_patch(440.0);
+        patch.nodes.push(node("sine_osc", "unused", vec![440.0, 0.0]));
+
+        // The probed node doesn't reach OUTPUT but isn't pruned, and hears half of what `double` does
+        let mut program = Program::probed(&patch, &[(4, 0), (3, 0)]).unwrap();
+        assert!(program.optimization().pruned.is_empty());
+        let output = run(&mut program, 0, 16);
+        let mut probed = vec![0.0; 16];
+        program.probe(0, &mut probed);
+        assert_eq!(probed.iter().map(|sample| sample * 2.0).collect::<Vec<_>>(), output);
+        program.probe(1, &mut probed);
+        assert_eq!(probed, output);
+    }
+
+    #[test]
+    fn constant_nodes_are_folded_into_defaults() {
+        let mut program = Program::optimized(&control_patch(false)).unwrap();
+        assert_eq!(program.optimization().folded, vec!["four".to_string(), "double".to_string()]);
+        // Only INPUT, OUTPUT and the parameter are left
+        assert_eq!(program.nodes.len(), 3);
+        assert_eq!(run(&mut program, 0, 4), vec![8.0; 4]);
+
+        // A parameter can change, so nothing it feeds is folded
+        let program = Program::optimized(&control_patch(true)).unwrap();
+        assert!(program.optimization().is_empty());
+    }
+
+    #[test]
+    fn latency_is_the_slowest_path_to_the_output() {
+        // INPUT reaches OUTPUT through two convolutions in a row, and through one on a path mixed in alongside
+        let patch = Patch {
+            code: "fn add(a, b) -> (c) {\n    c = a + b\n}\n".to_string(),
+            nodes: vec![
+                node("INPUT", "INPUT", vec![0.0]),
+                node("OUTPUT", "OUTPUT", vec![0.0]),
+                node(CONVOLUTION_NODE, "first", vec![0.0, 0.0]),
+                node(CONVOLUTION_NODE, "second", vec![0.0, 0.0]),
+                node(CONVOLUTION_NODE, "parallel", vec![0.0, 0.0]),
+                node("add", "add", vec![0.0, 0.0]),
+            ],
+            connections: vec![
+                connection(0, 2, 0),
+                connection(2, 3, 0),
+                connection(0, 4, 0),
+                connection(3, 5, 0),
+                connection(4, 5, 1),
+                connection(5, 1, 0),
+            ],
+            automation: Vec::new(),
+            polyphony: None,
+            transport: None,
+            threads: None,
+        };
+
+        let mut program = Program::new(&patch).unwrap();
+        assert_eq!(program.latency(), 2 * PARTITION_SIZE);
+
+        // An impulse comes out of the longer path when the latency says it will
+        let mut buffer = vec![0.0; 4 * PARTITION_SIZE];
+        buffer[0] = 1.0;
+        program.process(0, &mut buffer);
+        assert!((buffer[PARTITION_SIZE] - 1.0).abs() < 1e-9);
+        assert!((buffer[2 * PARTITION_SIZE] - 1.0).abs() < 1e-9);
+    }
+
+    // A parameter without smoothing going straight to OUTPUT
+    fn parameter_patch() -> Patch {
+        let mut parameter = node(PARAMETER_NODE, "gain", vec![0.0]);
+        parameter.parameter = Some(ParameterSpec {
+            name: "gain".to_string(),
+            min: 0.0,
+            max: 2.0,
+            default: 0.5,
+            unit: String::new(),
+            smoothing: 0.0,
+        });
+
+        Patch {
+            code: String::new(),
+            nodes: vec![node("INPUT", "INPUT", vec![0.0]), node("OUTPUT", "OUTPUT", vec![0.0]), parameter],
+            connections: vec![connection(2, 1, 0)],
+            automation: Vec::new(),
+            polyphony: None,
+            transport: None,
+            threads: None,
+        }
+    }
+
+    #[test]
+    fn parameters_can_be_set_while_running() {
+        let mut program = Program::new(&parameter_patch()).unwrap();
+        assert_eq!(program.parameters().len(), 1);
+        assert_eq!(run(&mut program, 0, 4), vec![0.5; 4]);
+
+        let index = program.parameter_index("gain").unwrap();
+        program.set_parameter(index, 1.5);
+        assert_eq!(run(&mut program, 4, 4), vec![1.5; 4]);
+
+        // Out of range values are clamped
+        program.set_parameter(index, 10.0);
+        assert_eq!(run(&mut program, 8, 4), vec![2.0; 4]);
+
+        // The value survives a recompile
+        let mut next = Program::new(&parameter_patch()).unwrap();
+        next.carry_state_from(&program);
+        assert_eq!(run(&mut next, 12, 4), vec![2.0; 4]);
+    }
+
+    #[test]
+    fn automation_sets_parameters_offline() {
+        let mut patch = parameter_patch();
+        patch.automation.push(Automation {
+            parameter: "gain".to_string(),
+            points: vec![(0.0, 0.0), (1.0, 2.0)],
+        });
+        let mut program = Program::new(&patch).unwrap();
+
+        let n = SAMPLE_RATE as usize / 2;
+        program.automate(n);
+        assert_eq!(run(&mut program, n, 4), vec![1.0; 4]);
+
+        patch.automation[0].parameter = "missing".to_string();
+        assert!(Program::new(&patch).is_err());
+    }
+
+    // The pitch of each voice summed by a voice_mix going to OUTPUT
+    fn polyphonic_patch(voices: usize) -> Patch {
+        Patch {
+            code: String::new(),
+            nodes: vec![
+                node("INPUT", "INPUT", vec![0.0]),
+                node("OUTPUT", "OUTPUT", vec![0.0]),
+                node("midi_pitch", "pitch", vec![0.0]),
+                node(VOICE_MIX_NODE, "mix", vec![0.0, 0.0]),
+            ],
+            connections: vec![connection(2, 3, 0), connection(3, 1, 0)],
+            automation: Vec::new(),
+            polyphony: Some(Polyphony { voices, stealing: VoiceStealing::Oldest }),
+            transport: None,
+            threads: None,
+        }
+    }
+
+    #[test]
+    fn voices_play_notes_together() {
+        use crate::midi::{note_to_hz, MidiMessage};
+
+        let events = [
+            MidiEvent { time: 0, message: MidiMessage::NoteOn { note: 60, velocity: 100 } },
+            MidiEvent { time: 0, message: MidiMessage::NoteOn { note: 64, velocity: 100 } },
+        ];
+
+        let mut program = Program::new(&polyphonic_patch(2)).unwrap();
+        let mut buffer = vec![0.0; 4];
+        program.process_midi(0, &mut buffer, &events, 0);
+        assert_eq!(buffer, vec![note_to_hz(60.0) + note_to_hz(64.0); 4]);
+
+        // With one voice the second note takes over
+        let mut program = Program::new(&polyphonic_patch(1)).unwrap();
+        program.process_midi(0, &mut buffer, &events, 0);
+        assert_eq!(buffer, vec![note_to_hz(64.0); 4]);
+    }
+
+    #[test]
+    fn voices_must_go_through_the_mix() {
+        let mut patch = polyphonic_patch(2);
+        patch.connections.push(connection(2, 1, 0));
+        assert!(Program::new(&patch).is_err());
+    }
+
+    #[test]
+    fn threads_render_the_same_as_one() {
+        use crate::midi::MidiMessage;
+
+        // Each voice's oscillator and double are independent of the other voices'
+        let mut patch = polyphonic_patch(4);
+        patch.code = oscillator_patch(0.0).code;
+        patch.nodes.push(node("sine_osc", "osc", vec![0.0, 0.0]));
+        patch.nodes.push(node("double", "double", vec![0.0, 0.0]));
+        patch.nodes.push(node("delay", "delay", vec![0.0, 0.001, 0.0]));
+        patch.connections = vec![connection(2, 4, 0), connection(4, 5, 0), connection(5, 3, 0), connection(3, 6, 0), connection(6, 1, 0)];
+
+        let events = [60, 64, 67].iter()
+            .map(|&note| MidiEvent { time: 0, message: MidiMessage::NoteOn { note, velocity: 100 } })
+            .collect::<Vec<_>>();
+
+        let render = |patch: &Patch| {
+            let mut program = Program::new(patch).unwrap();
+            program.start_workers().unwrap();
+            let mut output = Vec::new();
+            let mut n = 0;
+            for (block, len) in [64, 1000, 7, 2048].iter().enumerate() {
+                let mut buffer = vec![0.0; *len];
+                let events = if block == 0 { &events[..] } else { &[] };
+                program.process_midi(n, &mut buffer, events, n);
+                output.extend_from_slice(&buffer);
+                n += len;
+            }
+            (program.threads(), output)
+        };
+
+        let (threads, single) = render(&patch);
+        assert_eq!(threads, 1);
+        assert!(single.iter().any(|sample| *sample != 0.0));
+
+        patch.threads = Some(4);
+        let (threads, parallel) = render(&patch);
+        assert_eq!(threads, 4);
+        assert_eq!(parallel, single);
+    }
+
+    #[test]
+    fn transport_keeps_its_position_across_recompiles() {
+        let patch = Patch {
+            nodes: vec![node("INPUT", "INPUT", vec![0.0]), node("OUTPUT", "OUTPUT", vec![0.0]), node("song_beats", "beats", vec![0.0])],
+            connections: vec![connection(2, 1, 0)],
+            transport: Some(Transport { bpm: 60.0, ..Transport::default() }),
+            ..parameter_patch()
+        };
+
+        // One second at 60 BPM is a beat
+        let mut program = Program::new(&patch).unwrap();
+        run(&mut program, 0, SAMPLE_RATE as usize);
+
+        let mut next = Program::new(&patch).unwrap();
+        next.carry_state_from(&program);
+        assert!((run(&mut next, SAMPLE_RATE as usize, 1)[0] - 1.0).abs() < 1e-9);
+    }
+}
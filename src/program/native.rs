@@ -0,0 +1,151 @@
+use std::any::Any;
+use std::f64::consts::PI;
+
+use crate::render::SAMPLE_RATE;
+use crate::ui::NodeDesc;
+
+/// Everything a node may need to know about the block being processed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProcessContext {
+    pub sample_rate: f64,
+    /// Sample index of the first sample in the block
+    pub n: usize,
+    /// Number of samples in the block
+    pub len: usize,
+}
+
+/// A node implemented in Rust rather than sarus.
+///
+/// Native nodes own their state, such as an oscillator phase or a delay line, which is carried over to the
+/// new program when the graph is recompiled.
+pub trait NativeNode: Send {
+    /// Processes one block. Each input and output holds `context.len` samples.
+    fn process(&mut self, context: &ProcessContext, inputs: &[Vec<f64>], outputs: &mut [Vec<f64>]);
+
+    /// Takes over the state of the node this one replaces, which has the same type. Runs on the audio
+    /// thread, so it copies into the node's own storage rather than allocating.
+    fn carry_from(&mut self, previous: &dyn NativeNode);
+
+    /// Lets `carry_from` get at the concrete type of the previous node
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Describes a native node which can be inserted into a graph
+pub struct NativeDesc {
+    pub name: &'static str,
+    pub inputs: &'static [&'static str],
+    pub outputs: &'static [&'static str],
+    pub category: &'static str,
+    pub create: fn() -> Box<dyn NativeNode>,
+}
+
+impl NativeDesc {
+    pub fn node_desc(&self) -> NodeDesc {
+        NodeDesc {
+            name: self.name.to_string(),
+            inputs: self.inputs.iter().map(|input| input.to_string()).collect(),
+            outputs: self.outputs.iter().map(|output| output.to_string()).collect(),
+            category: self.category.to_string(),
+        }
+    }
+}
+
+/// All the native nodes, looked up by name when a patch is compiled
+pub fn natives() -> &'static [NativeDesc] {
+    &[
+        NativeDesc {
+            name: "sine_osc",
+            inputs: &["f"],
+            outputs: &["a"],
+            category: "Oscillators",
+            create: || Box::new(SineOsc::default()),
+        },
+        NativeDesc {
+            name: "delay",
+            inputs: &["a", "seconds"],
+            outputs: &["b"],
+            category: "Effects",
+            create: || Box::new(Delay::new()),
+        },
+    ]
+}
+
+pub fn find_native(name: &str) -> Option<&'static NativeDesc> {
+    natives().iter().find(|native| native.name == name)
+}
+
+/// Sine oscillator which accumulates its phase, so changing frequency doesn't jump
+#[derive(Debug, Clone, Default)]
+pub struct SineOsc {
+    phase: f64,
+}
+
+impl NativeNode for SineOsc {
+    fn process(&mut self, context: &ProcessContext, inputs: &[Vec<f64>], outputs: &mut [Vec<f64>]) {
+        for (frequency, out) in inputs[0].iter().zip(outputs[0].iter_mut()) {
+            *out = (2.0 * PI * self.phase).sin();
+            self.phase = (self.phase + frequency / context.sample_rate).fract();
+        }
+    }
+
+    fn carry_from(&mut self, previous: &dyn NativeNode) {
+        if let Some(previous) = previous.as_any().downcast_ref::<Self>() {
+            self.phase = previous.phase;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Longest delay time in seconds
+pub const MAX_DELAY: f64 = 2.0;
+
+/// Delay line with a delay time in seconds, interpolated between samples
+#[derive(Debug, Clone)]
+pub struct Delay {
+    buffer: Vec<f64>,
+    write: usize,
+}
+
+impl Delay {
+    pub fn new() -> Self {
+        Self {
+            buffer: vec![0.0; (MAX_DELAY * SAMPLE_RATE as f64) as usize + 2],
+            write: 0,
+        }
+    }
+}
+
+impl NativeNode for Delay {
+    fn process(&mut self, context: &ProcessContext, inputs: &[Vec<f64>], outputs: &mut [Vec<f64>]) {
+        let len = self.buffer.len();
+
+        for ((input, seconds), out) in inputs[0].iter().zip(inputs[1].iter()).zip(outputs[0].iter_mut()) {
+            self.buffer[self.write] = *input;
+
+            let delay = (seconds * context.sample_rate).max(0.0).min((len - 2) as f64);
+            let whole = delay.floor();
+            let fraction = delay - whole;
+
+            let a = self.buffer[(self.write + len - whole as usize) % len];
+            let b = self.buffer[(self.write + len - whole as usize - 1) % len];
+            *out = a + (b - a) * fraction;
+
+            self.write = (self.write + 1) % len;
+        }
+    }
+
+    fn carry_from(&mut self, previous: &dyn NativeNode) {
+        // Both lines are MAX_DELAY long
+        if let Some(previous) = previous.as_any().downcast_ref::<Self>() {
+            self.buffer.copy_from_slice(&previous.buffer);
+            self.write = previous.write;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
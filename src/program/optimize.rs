@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{
+    find_block, find_midi_source, find_native, find_transport_source, CONVOLUTION_NODE, PARAMETER_NODE,
+    SAMPLE_PLAYER_NODE, SEQUENCER_NODE, VOICE_MIX_NODE, WAVETABLE_NODE,
+};
+use crate::patch::{Patch, PatchConnection};
+use crate::ui::graph::IndexGraph;
+
+/// What was taken out of a patch before it was compiled
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Optimization {
+    /// Ids of the nodes removed because nothing they output reaches OUTPUT
+    pub pruned: Vec<String>,
+    /// Ids of the nodes replaced by the constants they output
+    pub folded: Vec<String>,
+}
+
+impl Optimization {
+    pub fn is_empty(&self) -> bool {
+        self.pruned.is_empty() && self.folded.is_empty()
+    }
+}
+
+impl fmt::Display for Optimization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pruned {} nodes not connected to OUTPUT", self.pruned.len())?;
+        if !self.pruned.is_empty() {
+            write!(f, " ({})", self.pruned.join(", "))?;
+        }
+        write!(f, ", folded {} constant nodes", self.folded.len())?;
+        if !self.folded.is_empty() {
+            write!(f, " ({})", self.folded.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// Nodes which are kept whether or not they reach OUTPUT. Parameters stay so their indices, which
+// plugin hosts and automation refer to, don't depend on how the patch is wired.
+fn always_kept(name: &str) -> bool {
+    matches!(name, "INPUT" | "OUTPUT" | "COUNTER" | PARAMETER_NODE)
+}
+
+// Names the program gives its own meaning to before looking for a sarus function
+pub(super) fn builtin(name: &str) -> bool {
+    always_kept(name)
+        || matches!(name, SEQUENCER_NODE | SAMPLE_PLAYER_NODE | WAVETABLE_NODE | CONVOLUTION_NODE | VOICE_MIX_NODE)
+        || find_transport_source(name).is_some()
+        || find_midi_source(name).is_some()
+        || find_block(name).is_some()
+        || find_native(name).is_some()
+}
+
+// Nodes which act differently depending on whether an input is connected, so a connection into
+// them can't be swapped for a default
+fn needs_connections(name: &str) -> bool {
+    matches!(name, SEQUENCER_NODE | SAMPLE_PLAYER_NODE | VOICE_MIX_NODE)
+}
+
+/// Removes the nodes of a patch which can't affect its output and folds sarus nodes whose inputs are
+/// all constant into the defaults of the inputs they feed.
+///
+/// `evaluate` calls a sarus function with the given arguments, returning `None` if there is no function
+/// of that name which can be a node. The nodes in `probed` are treated like OUTPUT, so they're kept along
+/// with everything they depend on. Patches with a cycle or a connection to a missing node are left alone
+/// for the program to reject.
+pub(super) fn optimize<F>(patch: &Patch, probed: &[usize], mut evaluate: F) -> anyhow::Result<(Patch, Optimization)>
+    where F: FnMut(&str, &[f64]) -> anyhow::Result<Option<Vec<f64>>>
+{
+    let len = patch.nodes.len();
+    let mut optimization = Optimization::default();
+    if patch.connections.iter().any(|connection| connection.src_node >= len || connection.dst_node >= len) {
+        return Ok((patch.clone(), optimization));
+    }
+
+    let mut graph = IndexGraph::with_vertices(len);
+    for connection in patch.connections.iter() {
+        graph.add_edge(connection.src_node, connection.dst_node);
+    }
+    let order = match graph.clone().toposort() {
+        Some(order) => order,
+        None => return Ok((patch.clone(), optimization)),
+    };
+
+    // Only nodes with a path to OUTPUT or a probe are needed
+    graph.transpose();
+    let outputs = patch.nodes.iter().enumerate()
+        .filter(|(index, node)| node.func_name == "OUTPUT" || probed.contains(index))
+        .map(|(index, _)| index);
+    let reaches_output = graph.reachable_from(outputs);
+    let mut keep = patch.nodes.iter().enumerate()
+        .map(|(index, node)| reaches_output[index] || always_kept(&node.func_name))
+        .collect::<Vec<_>>();
+
+    optimization.pruned = patch.nodes.iter().zip(keep.iter()).filter(|(_, &keep)| !keep).map(|(node, _)| node.id.clone()).collect();
+
+    let mut nodes = patch.nodes.clone();
+    let mut connections = patch.connections.iter()
+        .filter(|connection| keep[connection.src_node] && keep[connection.dst_node])
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // Outputs of the nodes folded so far
+    let mut values: HashMap<usize, Vec<f64>> = HashMap::new();
+
+    for &index in order.iter().filter(|&&index| keep[index]) {
+        let node = &nodes[index];
+        if builtin(&node.func_name) || probed.contains(&index) {
+            continue;
+        }
+
+        let outgoing = connections.iter().filter(|connection| connection.src_node == index).collect::<Vec<_>>();
+        if outgoing.is_empty() || outgoing.iter().any(|connection| needs_connections(&nodes[connection.dst_node].func_name)) {
+            continue;
+        }
+
+        // Every connected input has to come from a node which has already been folded. If an input has
+        // more than one connection the last one wins, as it does in the program.
+        let mut args = node.port_defaults.clone();
+        let mut constant = true;
+        for connection in connections.iter().filter(|connection| connection.dst_node == index) {
+            match values.get(&connection.src_node).and_then(|outputs| outputs.get(connection.src_port)) {
+                Some(&value) => {
+                    if args.len() <= connection.dst_port {
+                        args.resize(connection.dst_port + 1, 0.0);
+                    }
+                    args[connection.dst_port] = value;
+                }
+                None => constant = false,
+            }
+        }
+        if !constant {
+            continue;
+        }
+
+        if let Some(outputs) = evaluate(&node.func_name, &args)? {
+            values.insert(index, outputs);
+        }
+    }
+
+    // Connections from folded nodes become the defaults of the inputs they went to
+    for connection in connections.iter() {
+        if let Some(value) = values.get(&connection.src_node).and_then(|outputs| outputs.get(connection.src_port)) {
+            let defaults = &mut nodes[connection.dst_node].port_defaults;
+            if defaults.len() <= connection.dst_port {
+                defaults.resize(connection.dst_port + 1, 0.0);
+            }
+            defaults[connection.dst_port] = *value;
+        }
+    }
+    connections.retain(|connection| !values.contains_key(&connection.src_node));
+
+    for &index in order.iter().filter(|index| values.contains_key(index)) {
+        optimization.folded.push(nodes[index].id.clone());
+        keep[index] = false;
+    }
+
+    // Nodes move up to fill the gaps left by the ones removed
+    let mut new_index = vec![usize::MAX; len];
+    let mut kept = Vec::new();
+    for (index, node) in nodes.into_iter().enumerate().filter(|(index, _)| keep[*index]) {
+        new_index[index] = kept.len();
+        kept.push(node);
+    }
+
+    let connections = connections.into_iter()
+        .map(|connection| PatchConnection {
+            src_node: new_index[connection.src_node],
+            dst_node: new_index[connection.dst_node],
+            ..connection
+        })
+        .collect();
+
+    let patch = Patch {
+        nodes: kept,
+        connections,
+        ..patch.clone()
+    };
+
+    Ok((patch, optimization))
+}
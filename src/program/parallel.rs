@@ -0,0 +1,355 @@
+use std::cell::UnsafeCell;
+use std::cmp::Reverse;
+use std::hint;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Index;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use super::{NodeKind, ProgramNode};
+
+/// Branches of the graph which don't read each other's outputs, so they can be processed in any order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(super) struct Level {
+    /// The nodes each thread processes, whole branches in the order of the graph's toposort
+    pub parallel: Vec<Vec<usize>>,
+    /// Nodes which write to the block being processed or to the voices, processed afterwards by the
+    /// thread running the program
+    pub serial: Vec<usize>,
+}
+
+// Nodes which use more than their own inputs and outputs
+fn is_serial(kind: &NodeKind) -> bool {
+    matches!(kind, NodeKind::Input | NodeKind::Output | NodeKind::VoiceMix)
+}
+
+// Whether the node reading `src` has to wait for the next level rather than joining its branch. INPUT is
+// filled before anything else, and nodes shared by the voices run before the voices so each voice is a
+// branch of its own.
+fn starts_level(src: &ProgramNode, dst: &ProgramNode) -> bool {
+    matches!(src.kind, NodeKind::Output | NodeKind::VoiceMix) || src.voice != dst.voice
+}
+
+// Follows a node's links to the first node of its branch, shortening them on the way
+fn root(branches: &mut [usize], mut index: usize) -> usize {
+    while branches[index] != index {
+        branches[index] = branches[branches[index]];
+        index = branches[index];
+    }
+    index
+}
+
+/// Splits the graph into levels separated by the nodes run by the thread running the program, such as
+/// `voice_mix`, and splits each level into branches of connected nodes, such as the copies of a voice or
+/// separate effect chains. Branches don't read each other, so each is processed whole by one thread and
+/// the threads only wait for each other between levels. The branches are dealt out largest first to the
+/// thread with the fewest nodes, which keeps the split the same every time a patch is compiled.
+pub(super) fn schedule(nodes: &[ProgramNode], order: &[usize], adjacency: &[Vec<usize>], threads: usize) -> Vec<Level> {
+    let mut depth = vec![0; nodes.len()];
+    for &index in order.iter() {
+        for &dst in adjacency[index].iter() {
+            let next = if starts_level(&nodes[index], &nodes[dst]) { 1 } else { 0 };
+            depth[dst] = depth[dst].max(depth[index] + next);
+        }
+    }
+
+    // Connections within a level join the nodes at either end into one branch
+    let mut branches = (0..nodes.len()).collect::<Vec<_>>();
+    for &src in order.iter().filter(|&&index| !is_serial(&nodes[index].kind)) {
+        for &dst in adjacency[src].iter().filter(|&&dst| !is_serial(&nodes[dst].kind) && depth[dst] == depth[src]) {
+            let (a, b) = (root(&mut branches, src), root(&mut branches, dst));
+            branches[a.max(b)] = a.min(b);
+        }
+    }
+
+    let num_levels = depth.iter().max().map_or(0, |depth| depth + 1);
+    let mut levels = vec![Level::default(); num_levels];
+    // The nodes of each branch of each level, found by the first node of the branch
+    let mut level_branches = vec![Vec::<(usize, Vec<usize>)>::new(); num_levels];
+    for &index in order.iter() {
+        if is_serial(&nodes[index].kind) {
+            levels[depth[index]].serial.push(index);
+            continue;
+        }
+
+        let root = root(&mut branches, index);
+        let branches = &mut level_branches[depth[index]];
+        match branches.iter_mut().find(|(first, _)| *first == root) {
+            Some((_, nodes)) => nodes.push(index),
+            None => branches.push((root, vec![index])),
+        }
+    }
+
+    for (level, mut branches) in levels.iter_mut().zip(level_branches) {
+        // The sort is stable, so branches of the same size stay in the order they start in
+        branches.sort_by_key(|(_, nodes)| Reverse(nodes.len()));
+        level.parallel = vec![Vec::new(); threads.max(1).min(branches.len())];
+        for (_, nodes) in branches {
+            let thread = (0..level.parallel.len()).min_by_key(|&thread| level.parallel[thread].len()).unwrap_or(0);
+            level.parallel[thread].extend(nodes);
+        }
+    }
+
+    levels
+}
+
+/// Mutable access to separate elements of a slice from several threads at once, and shared access to
+/// the elements which no thread is writing
+pub(super) struct Disjoint<'a, T> {
+    ptr: *mut T,
+    len: usize,
+    slice: PhantomData<&'a mut [T]>,
+}
+
+unsafe impl<T: Send> Sync for Disjoint<'_, T> {}
+
+impl<'a, T> Disjoint<'a, T> {
+    pub fn new(slice: &'a mut [T]) -> Self {
+        Self {
+            ptr: slice.as_mut_ptr(),
+            len: slice.len(),
+            slice: PhantomData,
+        }
+    }
+
+    /// Safety: only one reference to each element may be alive at a time, and the element mustn't be
+    /// indexed while it is
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get(&self, index: usize) -> &mut T {
+        assert!(index < self.len, "Index {} out of range for {} elements", index, self.len);
+        &mut *self.ptr.add(index)
+    }
+}
+
+// Elements are only indexed when no thread has them from `get`, such as the outputs of earlier levels
+impl<T> Index<usize> for Disjoint<'_, T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        assert!(index < self.len, "Index {} out of range for {} elements", index, self.len);
+        unsafe { &*self.ptr.add(index) }
+    }
+}
+
+// Bits of `Slot::state` holding the number of tasks in the job, and below them the next task to claim
+const INDEX_BITS: u32 = 16;
+const INDEX_MASK: u64 = (1 << INDEX_BITS) - 1;
+
+// Times a waiting worker checks for a job before it sleeps. Levels and blocks follow each other quickly,
+// so a worker which spins for a while is ready for the next job without having to be woken.
+const SPINS: usize = 1 << 14;
+
+// The job shared with the workers, allocated once when the pool starts
+struct Slot {
+    // Generation of the job in the top 32 bits, then the number of tasks and the next task to claim.
+    // A new generation is published once the task is in place.
+    state: AtomicU64,
+    // Borrowed by `WorkerPool::run`, which doesn't return until every task of its job has finished
+    task: UnsafeCell<Option<*const (dyn Fn(usize) + Sync)>>,
+    // Tasks of the current job which haven't finished
+    pending: AtomicUsize,
+    // Set when a task of the current job panics
+    failed: AtomicBool,
+    stop: AtomicBool,
+}
+
+// The task is only written while no job is running, and only read by a thread which has claimed a task
+unsafe impl Send for Slot {}
+unsafe impl Sync for Slot {}
+
+impl Slot {
+    // Claims the next task of the job of `generation`, if it's still running and has one left
+    fn claim(&self, generation: u64) -> Option<usize> {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            let tasks = (state >> INDEX_BITS) & INDEX_MASK;
+            let next = state & INDEX_MASK;
+            if state >> 32 != generation || next >= tasks {
+                return None;
+            }
+
+            match self.state.compare_exchange_weak(state, state + 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(next as usize),
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    // Runs tasks of the job of `generation` until there are none left to claim
+    fn work(&self, generation: u64) {
+        while let Some(index) = self.claim(generation) {
+            // Safety: the claimed task hasn't finished, so `run` is still waiting and the task it points to is alive
+            let finished = match unsafe { *self.task.get() } {
+                Some(task) => panic::catch_unwind(AssertUnwindSafe(|| unsafe { (*task)(index) })).is_ok(),
+                None => false,
+            };
+            if !finished {
+                self.failed.store(true, Ordering::Relaxed);
+            }
+            self.pending.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    // Waits for each job and helps with it, until the pool is dropped
+    fn serve(&self) {
+        let mut seen = 0;
+        loop {
+            let mut spins = 0;
+            let generation = loop {
+                if self.stop.load(Ordering::Acquire) {
+                    return;
+                }
+                let generation = self.state.load(Ordering::Acquire) >> 32;
+                if generation != seen {
+                    break generation;
+                }
+
+                // A job published after the check unparks the thread, so parking returns straight away
+                if spins < SPINS {
+                    spins += 1;
+                    hint::spin_loop();
+                } else {
+                    thread::park();
+                }
+            };
+
+            seen = generation;
+            self.work(generation);
+        }
+    }
+}
+
+/// Threads which help the thread running a program process the branches of its graph.
+///
+/// The threads are started once for a program which is played live and wait for work in between levels,
+/// so processing a block doesn't start threads, allocate or take locks.
+pub(super) struct WorkerPool {
+    slot: Arc<Slot>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Starts a pool which runs tasks on `threads` threads, counting the one calling `run`
+    pub fn new(threads: usize) -> anyhow::Result<Self> {
+        let slot = Arc::new(Slot {
+            state: AtomicU64::new(0),
+            task: UnsafeCell::new(None),
+            pending: AtomicUsize::new(0),
+            failed: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+        });
+
+        let mut pool = Self { slot, workers: Vec::with_capacity(threads.saturating_sub(1)) };
+        for number in 1..threads {
+            let slot = pool.slot.clone();
+            // Dropping the pool stops the workers already started
+            let worker = thread::Builder::new()
+                .name(format!("graph worker {}", number))
+                .spawn(move || slot.serve())?;
+            pool.workers.push(worker);
+        }
+
+        Ok(pool)
+    }
+
+    /// Number of threads tasks are run on, counting the one calling `run`
+    pub fn threads(&self) -> usize {
+        self.workers.len() + 1
+    }
+
+    /// Calls `task` with every index below `tasks`, on this thread and the workers, returning once they
+    /// have all finished. Doesn't allocate or block, apart from spinning while the last tasks finish on
+    /// the workers. Returns false if a task panicked.
+    pub fn run(&self, tasks: usize, task: &(dyn Fn(usize) + Sync)) -> bool {
+        // Tasks are the branches of a level split between the threads, so there are never this many
+        debug_assert!(tasks as u64 <= INDEX_MASK, "Too many tasks for one job");
+        let tasks = (tasks as u64).min(INDEX_MASK);
+
+        // The borrow is given to the workers as a pointer, which stays valid because this function
+        // doesn't return until every task has finished
+        let pointer = unsafe {
+            mem::transmute::<*const (dyn Fn(usize) + Sync + '_), *const (dyn Fn(usize) + Sync + 'static)>(task)
+        };
+
+        // Safety: the last job has finished, so no thread is reading the task
+        unsafe {
+            *self.slot.task.get() = Some(pointer);
+        }
+        self.slot.pending.store(tasks as usize, Ordering::Relaxed);
+        self.slot.failed.store(false, Ordering::Relaxed);
+        let generation = ((self.slot.state.load(Ordering::Relaxed) >> 32) + 1) & 0xffff_ffff;
+        self.slot.state.store(generation << 32 | tasks << INDEX_BITS, Ordering::Release);
+
+        for worker in self.workers.iter() {
+            worker.thread().unpark();
+        }
+
+        // This thread takes tasks too, so the job finishes even if no worker wakes up in time
+        self.slot.work(generation);
+        while self.slot.pending.load(Ordering::Acquire) > 0 {
+            hint::spin_loop();
+        }
+
+        !self.slot.failed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.slot.stop.store(true, Ordering::Release);
+        for worker in self.workers.iter() {
+            worker.thread().unpark();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_runs_every_task_once() {
+        let pool = WorkerPool::new(3).unwrap();
+        let counts = (0..5).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>();
+
+        // More tasks than threads are taken by whichever thread is free
+        for tasks in 0..=5 {
+            assert!(pool.run(tasks, &|index| {
+                counts[index].fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+
+        let counts = counts.iter().map(|count| count.load(Ordering::SeqCst)).collect::<Vec<_>>();
+        assert_eq!(counts, vec![5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn disjoint_elements_are_written_from_several_threads() {
+        let pool = WorkerPool::new(4).unwrap();
+        let mut values = vec![0; 4];
+        {
+            let disjoint = Disjoint::new(&mut values);
+            pool.run(4, &|index| *unsafe { disjoint.get(index) } = index * 10);
+        }
+        assert_eq!(values, vec![0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn panicking_tasks_are_reported() {
+        let pool = WorkerPool::new(2).unwrap();
+        assert!(!pool.run(4, &|index| assert_ne!(index, 2)));
+
+        // The pool carries on working afterwards
+        let count = AtomicUsize::new(0);
+        assert!(pool.run(4, &|_| {
+            count.fetch_add(1, Ordering::SeqCst);
+        }));
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
+}
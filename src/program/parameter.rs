@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+
+use crate::render::SAMPLE_RATE;
+use crate::ui::NodeDesc;
+
+/// Name of the node which outputs the value of a parameter
+pub const PARAMETER_NODE: &str = "parameter";
+
+/// A value which can be changed while the graph runs, without recompiling it.
+///
+/// Parameter nodes with the same name share one value, so a parameter can be used in several places.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParameterSpec {
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    pub default: f64,
+    /// Shown after the value, e.g. "Hz" or "dB"
+    #[serde(default)]
+    pub unit: String,
+    /// Time in seconds taken to get most of the way to a new value, so changes don't click
+    #[serde(default)]
+    pub smoothing: f64,
+}
+
+impl Default for ParameterSpec {
+    fn default() -> Self {
+        Self {
+            name: "param".to_string(),
+            min: 0.0,
+            max: 1.0,
+            default: 0.0,
+            unit: String::new(),
+            smoothing: 0.02,
+        }
+    }
+}
+
+impl ParameterSpec {
+    pub fn clamp(&self, value: f64) -> f64 {
+        value.max(self.min).min(self.max)
+    }
+
+    /// Maps a value in the range to 0..1
+    pub fn normalize(&self, value: f64) -> f64 {
+        if self.max > self.min {
+            (self.clamp(value) - self.min) / (self.max - self.min)
+        } else {
+            0.0
+        }
+    }
+
+    /// Maps 0..1 to a value in the range
+    pub fn denormalize(&self, normalized: f64) -> f64 {
+        self.min + normalized.max(0.0).min(1.0) * (self.max - self.min)
+    }
+
+    pub fn node_desc() -> NodeDesc {
+        NodeDesc {
+            name: PARAMETER_NODE.to_string(),
+            inputs: Vec::new(),
+            outputs: vec!["value".to_string()],
+            category: "Parameters".to_string(),
+        }
+    }
+}
+
+/// Outputs a parameter's value, gliding towards it when it's changed
+#[derive(Debug, Clone)]
+pub struct ParameterNode {
+    spec: ParameterSpec,
+    // Index into `Program::parameters`
+    pub(crate) index: usize,
+    target: f64,
+    current: f64,
+}
+
+impl ParameterNode {
+    pub fn new(spec: ParameterSpec, index: usize) -> Self {
+        let value = spec.clamp(spec.default);
+        Self {
+            spec,
+            index,
+            target: value,
+            current: value,
+        }
+    }
+
+    pub fn spec(&self) -> &ParameterSpec {
+        &self.spec
+    }
+
+    pub fn set(&mut self, value: f64) {
+        self.target = self.spec.clamp(value);
+    }
+
+    /// Continues from the value of the node this one replaces, within this node's range
+    pub fn carry_from(&mut self, previous: &ParameterNode) {
+        self.target = self.spec.clamp(previous.target);
+        self.current = self.spec.clamp(previous.current);
+    }
+
+    pub fn process(&mut self, output: &mut [f64]) {
+        // One pole low pass reaching 63% of a change after the smoothing time
+        let coefficient = if self.spec.smoothing > 0.0 {
+            1.0 - (-1.0 / (self.spec.smoothing * SAMPLE_RATE as f64)).exp()
+        } else {
+            1.0
+        };
+
+        for sample in output.iter_mut() {
+            self.current += (self.target - self.current) * coefficient;
+            *sample = self.current;
+        }
+    }
+}
+
+/// Breakpoints of a parameter over time, applied when rendering offline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Automation {
+    pub parameter: String,
+    /// Times in seconds and the values at them, sorted by time
+    pub points: Vec<(f64, f64)>,
+}
+
+impl Automation {
+    /// Value at a time in seconds, interpolating linearly between breakpoints and holding the first and last values
+    pub fn value_at(&self, time: f64) -> Option<f64> {
+        let first = self.points.first()?;
+        let last = self.points.last()?;
+
+        if time <= first.0 {
+            return Some(first.1);
+        }
+        if time >= last.0 {
+            return Some(last.1);
+        }
+
+        let after = self.points.iter().position(|&(t, _)| t > time)?;
+        let (t0, v0) = self.points[after - 1];
+        let (t1, v1) = self.points[after];
+
+        Some(v0 + (v1 - v0) * (time - t0) / (t1 - t0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn automation_interpolates_between_breakpoints() {
+        let automation = Automation {
+            parameter: "gain".to_string(),
+            points: vec![(1.0, 0.0), (2.0, 1.0), (4.0, 0.0)],
+        };
+
+        assert_eq!(automation.value_at(0.0), Some(0.0));
+        assert_eq!(automation.value_at(1.5), Some(0.5));
+        assert_eq!(automation.value_at(3.0), Some(0.5));
+        assert_eq!(automation.value_at(10.0), Some(0.0));
+    }
+
+    #[test]
+    fn smoothing_glides_to_new_values() {
+        let mut node = ParameterNode::new(ParameterSpec { smoothing: 0.01, ..ParameterSpec::default() }, 0);
+        node.set(1.0);
+
+        let mut output = vec![0.0; SAMPLE_RATE as usize / 100];
+        node.process(&mut output);
+        assert!(output[0] > 0.0 && output[0] < 0.01);
+        assert!((output[output.len() - 1] - (1.0 - (-1.0f64).exp())).abs() < 1e-3);
+
+        // Values are kept within the range
+        node.set(5.0);
+        node.process(&mut output);
+        assert!(output.iter().all(|&value| value <= 1.0));
+    }
+}
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+
+use super::{NodeKind, ProgramNode, Source};
+
+/// How often a sarus node is evaluated, saved with the node when it's set rather than inferred
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rate {
+    /// Every sample
+    Audio,
+    /// Once per block, ramping from the previous block's value
+    Control,
+    /// Once per block, holding the value for the whole block
+    Hold,
+}
+
+/// Works out the rate of each node, in the same order as `nodes`.
+///
+/// Only sarus nodes run at control rate. Those without a rate of their own do when every input is a
+/// constant, a parameter or the output of another control rate node, since sarus functions only depend
+/// on their arguments. A node set to control rate samples its inputs once per block whatever they are.
+pub(super) fn resolve_rates(nodes: &[ProgramNode], order: &[usize], marked: &[Option<Rate>]) -> Vec<Rate> {
+    // The node writing to each buffer
+    let mut owner = Vec::new();
+    for (index, node) in nodes.iter().enumerate() {
+        owner.resize(node.first_output + node.num_outputs, index);
+    }
+
+    let mut rates = vec![Rate::Audio; nodes.len()];
+    for &index in order.iter() {
+        let node = &nodes[index];
+        if !matches!(node.kind, NodeKind::Sarus(_)) {
+            continue;
+        }
+
+        rates[index] = match marked[index] {
+            Some(rate) => rate,
+            None => {
+                let slow = node.inputs.iter().all(|source| match *source {
+                    Source::Constant(_) => true,
+                    Source::Buffer(buffer) => {
+                        let src = owner[buffer];
+                        matches!(nodes[src].kind, NodeKind::Parameter(_)) || rates[src] != Rate::Audio
+                    }
+                });
+                if slow { Rate::Control } else { Rate::Audio }
+            }
+        };
+    }
+
+    rates
+}
+
+/// Fills a control rate output with `value`, ramping from the value of the previous block if there was one
+pub(super) fn fill_control(rate: Rate, previous: Option<f64>, value: f64, output: &mut [f64]) {
+    match (rate, previous) {
+        (Rate::Control, Some(previous)) if previous != value => {
+            let len = output.len() as f64;
+            for (i, sample) in output.iter_mut().enumerate() {
+                *sample = previous + (value - previous) * (i + 1) as f64 / len;
+            }
+        }
+        _ => output.iter_mut().for_each(|sample| *sample = value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_rate_ramps_and_hold_steps() {
+        let mut output = [0.0; 4];
+        fill_control(Rate::Control, Some(1.0), 3.0, &mut output);
+        assert_eq!(output, [1.5, 2.0, 2.5, 3.0]);
+
+        fill_control(Rate::Hold, Some(1.0), 3.0, &mut output);
+        assert_eq!(output, [3.0; 4]);
+
+        // The first block has nothing to ramp from
+        fill_control(Rate::Control, None, 3.0, &mut output);
+        assert_eq!(output, [3.0; 4]);
+    }
+}
@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::render::SAMPLE_RATE;
+use crate::ui::NodeDesc;
+
+/// Name of the node which plays a WAV file
+pub const SAMPLE_PLAYER_NODE: &str = "sample_player";
+
+/// Directory the editor offers samples from
+pub const SAMPLE_DIR: &str = "samples";
+
+pub fn sample_player_desc() -> NodeDesc {
+    NodeDesc {
+        name: SAMPLE_PLAYER_NODE.to_string(),
+        inputs: vec!["trigger".to_string(), "rate".to_string(), "start".to_string(), "loop".to_string()],
+        outputs: vec!["out".to_string()],
+        category: "Sampling".to_string(),
+    }
+}
+
+/// The WAV files in `SAMPLE_DIR`, sorted by name
+pub fn sample_files() -> Vec<PathBuf> {
+    let mut paths = std::fs::read_dir(SAMPLE_DIR)
+        .map(|entries| {
+            entries.filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("wav")))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    paths.sort();
+    paths
+}
+
+/// Reads a WAV file of any bit depth and channel count, mixed down to mono and resampled to the graph's rate
+pub fn load_sample(path: &Path) -> anyhow::Result<Vec<f64>> {
+    let (samples, sample_rate) = read_wav_mono(path)?;
+    Ok(resample(&samples, sample_rate as f64, SAMPLE_RATE as f64))
+}
+
+/// Reads a WAV file of any bit depth and channel count mixed down to mono, along with its sample rate
+pub fn read_wav_mono(path: &Path) -> anyhow::Result<(Vec<f64>, u32)> {
+    let mut reader = hound::WavReader::open(path)
+        .map_err(|err| anyhow::anyhow!("Failed to open sample {}: {}", path.display(), err))?;
+    let spec = reader.spec();
+
+    let interleaved = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>()
+            .map(|sample| sample.map(|sample| sample as f64))
+            .collect::<Result<Vec<_>, _>>()?,
+        // Integers of any width are scaled so full scale is 1
+        hound::SampleFormat::Int => {
+            let scale = (1u64 << (spec.bits_per_sample.max(1) - 1)) as f64;
+            reader.samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f64 / scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+
+    let channels = spec.channels.max(1) as usize;
+    let mono = interleaved.chunks(channels)
+        .map(|frame| frame.iter().sum::<f64>() / channels as f64)
+        .collect::<Vec<_>>();
+
+    Ok((mono, spec.sample_rate))
+}
+
+/// Changes the rate of a signal with cubic interpolation
+pub fn resample(samples: &[f64], from: f64, to: f64) -> Vec<f64> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let len = ((samples.len() as f64) * to / from).round() as usize;
+    (0..len).map(|i| interpolate(samples, i as f64 * from / to)).collect()
+}
+
+/// Value between samples using a 4 point cubic Hermite spline, silent outside the samples
+pub fn interpolate(samples: &[f64], position: f64) -> f64 {
+    let index = position.floor();
+    let t = position - index;
+    let index = index as isize;
+    let at = |offset: isize| {
+        let i = index + offset;
+        if i >= 0 && (i as usize) < samples.len() { samples[i as usize] } else { 0.0 }
+    };
+
+    let (y0, y1, y2, y3) = (at(-1), at(0), at(1), at(2));
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+    ((c3 * t + c2) * t + c1) * t + y1
+}
+
+/// Plays a sample from the start offset each time its trigger goes above 0.5.
+///
+/// The rate is relative to the original speed, negative rates play backwards. The start is a fraction
+/// of the sample's length, and while the loop input is above 0.5 the sample loops back to the start.
+#[derive(Debug, Clone)]
+pub struct SamplePlayer {
+    // Shared between the copies of the node made when the graph is recompiled
+    samples: Arc<Vec<f64>>,
+    position: f64,
+    playing: bool,
+    triggered: bool,
+}
+
+impl SamplePlayer {
+    pub fn new(samples: Arc<Vec<f64>>) -> Self {
+        Self {
+            samples,
+            position: 0.0,
+            playing: false,
+            triggered: false,
+        }
+    }
+
+    /// Continues playing from where the player being replaced got to, with this player's sample
+    pub fn carry_from(&mut self, previous: &SamplePlayer) {
+        self.position = previous.position;
+        self.playing = previous.playing;
+        self.triggered = previous.triggered;
+    }
+
+    pub fn process(&mut self, inputs: &[Vec<f64>], output: &mut [f64]) {
+        let len = self.samples.len() as f64;
+
+        for (i, out) in output.iter_mut().enumerate() {
+            let (trigger, rate, start, looping) = (inputs[0][i], inputs[1][i], inputs[2][i], inputs[3][i] > 0.5);
+            let start = start.max(0.0).min(1.0) * len;
+
+            let triggered = trigger > 0.5;
+            if triggered && !self.triggered {
+                self.playing = true;
+                self.position = start;
+            }
+            self.triggered = triggered;
+
+            if !self.playing || len == 0.0 {
+                *out = 0.0;
+                continue;
+            }
+
+            *out = interpolate(&self.samples, self.position);
+            self.position += rate;
+
+            // Running off either end stops the sample or loops it back round
+            if self.position >= len || self.position < 0.0 {
+                if looping && len > start {
+                    let span = len - start;
+                    self.position = start + (self.position - start).rem_euclid(span);
+                } else {
+                    self.playing = false;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolation_passes_through_samples() {
+        let samples = [0.0, 1.0, 0.5, -1.0];
+        for (i, sample) in samples.iter().enumerate() {
+            assert_eq!(interpolate(&samples, i as f64), *sample);
+        }
+        assert_eq!(resample(&samples, 1.0, 2.0).len(), 8);
+    }
+
+    #[test]
+    fn wavs_of_any_depth_are_resampled_to_mono() {
+        let path = std::env::temp_dir().join("sarus_sample_test.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE / 2,
+            bits_per_sample: 24,
+            sample_format: hound::SampleFormat::Int,
+        };
+
+        // Half scale on the left and silence on the right
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..100 {
+            writer.write_sample(1 << 22).unwrap();
+            writer.write_sample(0).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let samples = load_sample(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(samples.len(), 200);
+        assert_eq!(samples[100], 0.25);
+    }
+
+    #[test]
+    fn triggers_play_from_the_start() {
+        let mut player = SamplePlayer::new(Arc::new(vec![1.0, 2.0, 3.0, 4.0]));
+        let inputs = vec![
+            vec![0.0, 1.0, 1.0, 1.0, 1.0, 0.0, 1.0, 1.0],
+            vec![1.0; 8],
+            vec![0.5; 8],
+            vec![0.0; 8],
+        ];
+        let mut output = [0.0; 8];
+        player.process(&inputs, &mut output);
+
+        // Starting half way through, playing to the end and stopping until triggered again
+        assert_eq!(output, [0.0, 3.0, 4.0, 0.0, 0.0, 0.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn loops_wrap_to_the_start() {
+        let mut player = SamplePlayer::new(Arc::new(vec![1.0, 2.0, 3.0, 4.0]));
+        let inputs = vec![vec![1.0; 6], vec![1.0; 6], vec![0.5; 6], vec![1.0; 6]];
+        let mut output = [0.0; 6];
+        player.process(&inputs, &mut output);
+        assert_eq!(output, [3.0, 4.0, 3.0, 4.0, 3.0, 4.0]);
+    }
+}
@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+
+use crate::midi::note_to_hz;
+use crate::ui::NodeDesc;
+
+/// Name of the node which plays a pattern of steps
+pub const SEQUENCER_NODE: &str = "step_sequencer";
+
+/// Lowest and highest notes a step can play
+pub const STEP_NOTES: (u8, u8) = (24, 96);
+
+/// One step of a pattern
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    /// Whether the step plays a note, otherwise the gate stays closed for it
+    pub on: bool,
+    pub note: u8,
+    /// From 0 to 1
+    pub velocity: f64,
+    /// Fraction of the step the gate is open for
+    pub gate: f64,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self {
+            on: false,
+            note: 60,
+            velocity: 0.8,
+            gate: 0.5,
+        }
+    }
+}
+
+/// The steps played by a sequencer node, saved with the patch
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pattern {
+    pub steps: Vec<Step>,
+    /// Steps played in each beat, 4 for sixteenth notes
+    pub steps_per_beat: usize,
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self {
+            steps: vec![Step::default(); 16],
+            steps_per_beat: 4,
+        }
+    }
+}
+
+impl Pattern {
+    pub fn node_desc() -> NodeDesc {
+        NodeDesc {
+            name: SEQUENCER_NODE.to_string(),
+            inputs: vec!["bpm".to_string()],
+            outputs: vec!["hz".to_string(), "gate".to_string(), "velocity".to_string()],
+            category: "Sequencing".to_string(),
+        }
+    }
+}
+
+/// Plays a pattern in time with the transport, or at the tempo given by its input when it's connected.
+///
+/// The pitch and velocity of the last step played are held until the next step which is on,
+/// so notes ring out after the gate closes. A step starting while the gate is still open closes it
+/// for a sample, so steps held for their whole length still retrigger.
+#[derive(Debug, Clone)]
+pub struct Sequencer {
+    pattern: Pattern,
+    // Steps since the start, the fraction is how far through the current step it is
+    position: f64,
+    note: u8,
+    velocity: f64,
+    // Position and gate of the last sample output, to tell when a step starts with the gate open
+    last_position: f64,
+    gate_open: bool,
+}
+
+impl Sequencer {
+    pub fn new(pattern: Pattern) -> Self {
+        Self {
+            pattern,
+            position: 0.0,
+            note: 60,
+            velocity: 0.0,
+            last_position: 0.0,
+            gate_open: false,
+        }
+    }
+
+    /// Continues from where the sequencer being replaced got to, with this one's pattern
+    pub fn carry_from(&mut self, previous: &Sequencer) {
+        self.position = previous.position;
+        self.note = previous.note;
+        self.velocity = previous.velocity;
+        self.last_position = previous.last_position;
+        self.gate_open = previous.gate_open;
+    }
+
+    /// Fills the pitch, gate and velocity outputs, advancing by the tempo in BPM of each sample
+    pub fn process(&mut self, sample_rate: f64, bpm: &[f64], outputs: &mut [Vec<f64>]) {
+        let len = self.pattern.steps.len();
+        let steps_per_beat = self.pattern.steps_per_beat.max(1) as f64;
+
+        for i in 0..bpm.len() {
+            self.output(i, true, outputs);
+
+            self.position += bpm[i].max(0.0) / 60.0 * steps_per_beat / sample_rate;
+            // Wrapped so precision isn't lost over a long run
+            if len > 0 && self.position >= len as f64 {
+                self.position -= len as f64;
+            }
+        }
+    }
+
+    /// Fills the outputs with the steps at the transport's position in beats at each sample.
+    /// The gate stays closed while the transport is stopped.
+    pub fn follow(&mut self, playing: bool, beats: &[f64], outputs: &mut [Vec<f64>]) {
+        let len = self.pattern.steps.len().max(1) as f64;
+        let steps_per_beat = self.pattern.steps_per_beat.max(1) as f64;
+
+        for (i, beat) in beats.iter().enumerate() {
+            self.position = (beat * steps_per_beat).rem_euclid(len);
+            self.output(i, playing, outputs);
+        }
+    }
+
+    fn output(&mut self, i: usize, playing: bool, outputs: &mut [Vec<f64>]) {
+        let len = self.pattern.steps.len();
+        let mut gate = if len > 0 && playing {
+            let step = self.pattern.steps[self.position as usize % len];
+            if step.on {
+                self.note = step.note;
+                self.velocity = step.velocity;
+            }
+            step.on && self.position.fract() < step.gate
+        } else {
+            false
+        };
+
+        // Also true when the pattern loops back to the step it's on
+        let started = self.position.floor() != self.last_position.floor() || self.position < self.last_position;
+        if started && self.gate_open {
+            gate = false;
+        }
+        self.last_position = self.position;
+        self.gate_open = gate;
+
+        outputs[0][i] = note_to_hz(self.note as f64);
+        outputs[1][i] = if gate { 1.0 } else { 0.0 };
+        outputs[2][i] = self.velocity;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_follow_the_tempo() {
+        let mut pattern = Pattern { steps: vec![Step::default(); 2], steps_per_beat: 1 };
+        pattern.steps[0] = Step { on: true, note: 69, velocity: 1.0, gate: 0.5 };
+
+        // At 60 BPM with one step per beat and 4 samples a second, each step lasts 4 samples
+        let mut sequencer = Sequencer::new(pattern);
+        let mut outputs = vec![vec![0.0; 8]; 3];
+        sequencer.process(4.0, &[60.0; 8], &mut outputs);
+
+        assert_eq!(outputs[1], vec![1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(outputs[0], vec![440.0; 8]);
+        assert_eq!(outputs[2], vec![1.0; 8]);
+
+        // The pattern loops
+        sequencer.process(4.0, &[60.0; 8], &mut outputs);
+        assert_eq!(outputs[1][..4], [1.0, 1.0, 0.0, 0.0]);
+
+        // Following the transport, the second beat is the second step
+        let mut outputs = vec![vec![0.0; 2]; 3];
+        sequencer.follow(true, &[0.25, 1.25], &mut outputs);
+        assert_eq!(outputs[1], vec![1.0, 0.0]);
+    }
+
+    #[test]
+    fn held_steps_retrigger_and_stop_with_the_transport() {
+        let step = Step { on: true, note: 69, velocity: 1.0, gate: 1.0 };
+        let mut sequencer = Sequencer::new(Pattern { steps: vec![step; 2], steps_per_beat: 1 });
+
+        // The gate closes for the first sample of each step, including when the pattern loops
+        let mut outputs = vec![vec![0.0; 6]; 3];
+        sequencer.follow(true, &[0.0, 0.5, 1.0, 1.5, 2.0, 2.5], &mut outputs);
+        assert_eq!(outputs[1], vec![1.0, 1.0, 0.0, 1.0, 0.0, 1.0]);
+
+        // Stopped, the gate closes and the note is held
+        sequencer.follow(false, &[2.5, 2.5], &mut outputs);
+        assert_eq!(outputs[1][..2], [0.0, 0.0]);
+        assert_eq!(outputs[0][..2], [440.0, 440.0]);
+
+        // Starting again opens it without waiting for the next step
+        sequencer.follow(true, &[2.5], &mut outputs);
+        assert_eq!(outputs[1][0], 1.0);
+    }
+}
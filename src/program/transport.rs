@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ui::NodeDesc;
+
+/// Tempo until one is set
+pub const DEFAULT_BPM: f64 = 120.0;
+
+/// Settings of the transport the time source nodes follow, saved with the patch.
+///
+/// Positions are measured in beats, which are quarter notes whatever the time signature.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transport {
+    pub playing: bool,
+    pub bpm: f64,
+    /// Beats in a bar over the note value of a beat, e.g. (6, 8)
+    pub time_signature: (u32, u32),
+    /// Start and end in beats of the region played over and over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loop_range: Option<(f64, f64)>,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self {
+            playing: true,
+            bpm: DEFAULT_BPM,
+            time_signature: (4, 4),
+            loop_range: None,
+        }
+    }
+}
+
+impl Transport {
+    /// Length of a bar in quarter note beats
+    pub fn beats_per_bar(&self) -> f64 {
+        let (numerator, denominator) = self.time_signature;
+        numerator.max(1) as f64 * 4.0 / denominator.max(1) as f64
+    }
+
+    // Wraps a position which has run past the end of the loop back into it
+    fn wrap(&self, beats: f64) -> f64 {
+        match self.loop_range {
+            Some((start, end)) if end > start && beats >= end => start + (beats - start) % (end - start),
+            _ => beats,
+        }
+    }
+}
+
+/// Which part of the transport a time source node outputs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportValue {
+    /// Song position in seconds
+    Seconds,
+    /// Song position in beats
+    Beats,
+    /// How far through the current bar, from 0 to 1
+    BarPhase,
+    /// Tempo in BPM
+    Tempo,
+    /// 1 while the transport is playing, 0 when stopped
+    Playing,
+}
+
+/// Describes a time source node which can be inserted into a graph
+pub struct TransportSourceDesc {
+    pub name: &'static str,
+    pub output: &'static str,
+    pub value: TransportValue,
+}
+
+impl TransportSourceDesc {
+    pub fn node_desc(&self) -> NodeDesc {
+        NodeDesc {
+            name: self.name.to_string(),
+            inputs: Vec::new(),
+            outputs: vec![self.output.to_string()],
+            category: "Transport".to_string(),
+        }
+    }
+}
+
+pub fn transport_sources() -> &'static [TransportSourceDesc] {
+    &[
+        TransportSourceDesc { name: "song_seconds", output: "seconds", value: TransportValue::Seconds },
+        TransportSourceDesc { name: "song_beats", output: "beats", value: TransportValue::Beats },
+        TransportSourceDesc { name: "bar_phase", output: "phase", value: TransportValue::BarPhase },
+        TransportSourceDesc { name: "tempo", output: "bpm", value: TransportValue::Tempo },
+        TransportSourceDesc { name: "transport_playing", output: "playing", value: TransportValue::Playing },
+    ]
+}
+
+pub fn find_transport_source(name: &str) -> Option<&'static TransportSourceDesc> {
+    transport_sources().iter().find(|source| source.name == name)
+}
+
+/// The transport's settings and where it has got to
+#[derive(Debug, Clone, Default)]
+pub struct TransportState {
+    settings: Transport,
+    beats: f64,
+    seconds: f64,
+}
+
+impl TransportState {
+    pub fn new(settings: Transport) -> Self {
+        Self {
+            settings,
+            beats: 0.0,
+            seconds: 0.0,
+        }
+    }
+
+    pub fn settings(&self) -> &Transport {
+        &self.settings
+    }
+
+    /// Changes the settings, carrying on from the same position
+    pub fn set(&mut self, settings: Transport) {
+        self.settings = settings;
+    }
+
+    /// Moves to a position, e.g. the one reported by a plugin host
+    pub fn locate(&mut self, beats: f64, seconds: f64) {
+        self.beats = beats;
+        self.seconds = seconds;
+    }
+
+    /// Continues from the position of the transport being replaced
+    pub fn carry_from(&mut self, previous: &TransportState) {
+        self.beats = previous.beats;
+        self.seconds = previous.seconds;
+    }
+
+    fn beats_per_sample(&self, sample_rate: f64) -> f64 {
+        if self.settings.playing {
+            self.settings.bpm.max(0.0) / 60.0 / sample_rate
+        } else {
+            0.0
+        }
+    }
+
+    /// Fills `output` with a value at each sample of the block starting at the current position
+    pub fn fill(&self, value: TransportValue, sample_rate: f64, output: &mut [f64]) {
+        let step = self.beats_per_sample(sample_rate);
+        let beats_per_bar = self.settings.beats_per_bar();
+        let seconds_per_beat = if self.settings.bpm > 0.0 { 60.0 / self.settings.bpm } else { 0.0 };
+
+        for (i, sample) in output.iter_mut().enumerate() {
+            let beats = self.settings.wrap(self.beats + i as f64 * step);
+            *sample = match value {
+                // Jumps back along with the beats when the loop wraps
+                TransportValue::Seconds => self.seconds + (beats - self.beats) * seconds_per_beat,
+                TransportValue::Beats => beats,
+                TransportValue::BarPhase => (beats / beats_per_bar).rem_euclid(1.0),
+                TransportValue::Tempo => self.settings.bpm,
+                TransportValue::Playing => if self.settings.playing { 1.0 } else { 0.0 },
+            };
+        }
+    }
+
+    /// Moves on by a block of `len` samples
+    pub fn advance(&mut self, len: usize, sample_rate: f64) {
+        let beats = self.settings.wrap(self.beats + len as f64 * self.beats_per_sample(sample_rate));
+        let seconds_per_beat = if self.settings.bpm > 0.0 { 60.0 / self.settings.bpm } else { 0.0 };
+        self.seconds += (beats - self.beats) * seconds_per_beat;
+        self.beats = beats;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bars_follow_the_time_signature() {
+        // At 60 BPM and 2 samples a second each beat lasts 2 samples, and a bar of 3/4 lasts 6
+        let settings = Transport { bpm: 60.0, time_signature: (3, 4), ..Transport::default() };
+        let transport = TransportState::new(settings);
+
+        let mut phase = [0.0; 6];
+        transport.fill(TransportValue::BarPhase, 2.0, &mut phase);
+        let expected = [0.0, 1.0 / 6.0, 2.0 / 6.0, 3.0 / 6.0, 4.0 / 6.0, 5.0 / 6.0];
+        for (actual, expected) in phase.iter().zip(expected.iter()) {
+            assert!((actual - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn loops_jump_back() {
+        let settings = Transport { bpm: 60.0, loop_range: Some((1.0, 2.0)), ..Transport::default() };
+        let mut transport = TransportState::new(settings);
+
+        let mut beats = [0.0; 6];
+        transport.fill(TransportValue::Beats, 2.0, &mut beats);
+        assert_eq!(beats, [0.0, 0.5, 1.0, 1.5, 1.0, 1.5]);
+
+        transport.advance(6, 2.0);
+        let mut seconds = [0.0; 1];
+        transport.fill(TransportValue::Seconds, 2.0, &mut seconds);
+        assert_eq!(seconds, [1.0]);
+    }
+
+    #[test]
+    fn stopped_transport_holds_its_position() {
+        let mut transport = TransportState::new(Transport { playing: false, ..Transport::default() });
+        transport.locate(8.0, 4.0);
+        transport.advance(1000, 48000.0);
+
+        let mut beats = [0.0; 2];
+        transport.fill(TransportValue::Beats, 48000.0, &mut beats);
+        assert_eq!(beats, [8.0, 8.0]);
+    }
+}
@@ -0,0 +1,350 @@
+use serde::{Deserialize, Serialize};
+
+use super::find_midi_source;
+use crate::midi::{MidiEvent, MidiMessage};
+use crate::patch::{Patch, PatchConnection, PatchNode};
+use crate::ui::graph::IndexGraph;
+use crate::ui::NodeDesc;
+
+/// Name of the node which sums the voices of a polyphonic patch
+pub const VOICE_MIX_NODE: &str = "voice_mix";
+
+/// Most MIDI events given to one voice in a block without allocating
+const MAX_VOICE_EVENTS: usize = 256;
+
+/// How a voice is chosen for a new note when they're all playing
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VoiceStealing {
+    /// The voice whose note started first
+    Oldest,
+    /// The voice whose output is currently the lowest
+    Quietest,
+}
+
+/// Polyphony settings of a patch with a Voice Mix node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Polyphony {
+    pub voices: usize,
+    pub stealing: VoiceStealing,
+}
+
+impl Default for Polyphony {
+    fn default() -> Self {
+        Self {
+            voices: 8,
+            stealing: VoiceStealing::Oldest,
+        }
+    }
+}
+
+pub fn voice_mix_desc() -> NodeDesc {
+    NodeDesc {
+        name: VOICE_MIX_NODE.to_string(),
+        inputs: vec!["voice".to_string()],
+        outputs: vec!["mix".to_string()],
+        category: "MIDI".to_string(),
+    }
+}
+
+/// A patch with its voices laid out as separate nodes
+pub struct Expanded {
+    pub patch: Patch,
+    /// The voice each node of `patch` belongs to, or `None` for nodes shared by every voice
+    pub voice_of: Vec<Option<usize>>,
+    pub polyphony: Option<Polyphony>,
+}
+
+/// Makes a copy for each voice of the nodes between the MIDI sources and the Voice Mix nodes.
+///
+/// Each copy of a node is connected to the same copy of the nodes before it, or to the shared node
+/// if that node isn't part of the voice. Copy `v` goes to input `v` of the Voice Mix nodes.
+pub fn expand(patch: &Patch) -> anyhow::Result<Expanded> {
+    let is_mix = patch.nodes.iter().map(|node| node.func_name == VOICE_MIX_NODE).collect::<Vec<_>>();
+
+    if !is_mix.contains(&true) {
+        return Ok(Expanded {
+            patch: patch.clone(),
+            voice_of: vec![None; patch.nodes.len()],
+            polyphony: None,
+        });
+    }
+
+    let polyphony = patch.polyphony.clone().unwrap_or_default();
+    if polyphony.voices == 0 {
+        anyhow::bail!("A polyphonic patch needs at least one voice");
+    }
+
+    // Voices are the nodes which are both fed by a MIDI source and feed a Voice Mix node
+    let mut graph = IndexGraph::with_vertices(patch.nodes.len());
+    for connection in patch.connections.iter() {
+        graph.add_edge(connection.src_node, connection.dst_node);
+    }
+
+    let sources = patch.nodes.iter().enumerate()
+        .filter(|(_, node)| find_midi_source(&node.func_name).is_some())
+        .map(|(index, _)| index);
+    let from_midi = graph.reachable_from(sources);
+
+    graph.transpose();
+    let mixes = is_mix.iter().enumerate().filter(|(_, &mix)| mix).map(|(index, _)| index);
+    let to_mix = graph.reachable_from(mixes);
+
+    let in_voice = (0..patch.nodes.len()).map(|index| from_midi[index] && to_mix[index] && !is_mix[index]).collect::<Vec<_>>();
+
+    // Shared nodes come first, keeping their order, followed by every node of each voice in turn
+    let mut nodes = Vec::new();
+    let mut voice_of = Vec::new();
+    let mut shared_index = vec![0; patch.nodes.len()];
+    let mut voice_index = vec![vec![0; patch.nodes.len()]; polyphony.voices];
+
+    for (index, node) in patch.nodes.iter().enumerate().filter(|(index, _)| !in_voice[*index]) {
+        shared_index[index] = nodes.len();
+        nodes.push(node.clone());
+        voice_of.push(None);
+    }
+
+    for (voice, indices) in voice_index.iter_mut().enumerate() {
+        for (index, node) in patch.nodes.iter().enumerate().filter(|(index, _)| in_voice[*index]) {
+            indices[index] = nodes.len();
+            nodes.push(PatchNode {
+                id: format!("{}#{}", node.id, voice),
+                ..node.clone()
+            });
+            voice_of.push(Some(voice));
+        }
+    }
+
+    let mut connections = Vec::new();
+    for connection in patch.connections.iter() {
+        let (src, dst) = (connection.src_node, connection.dst_node);
+
+        match (in_voice[src], in_voice[dst] || is_mix[dst]) {
+            (false, false) => connections.push(PatchConnection {
+                src_node: shared_index[src],
+                dst_node: shared_index[dst],
+                ..*connection
+            }),
+
+            // A shared node going into a Voice Mix node is only mixed in once
+            (false, true) if is_mix[dst] => connections.push(PatchConnection {
+                src_node: shared_index[src],
+                dst_node: shared_index[dst],
+                ..*connection
+            }),
+
+            (false, true) => {
+                for indices in voice_index.iter() {
+                    connections.push(PatchConnection {
+                        src_node: shared_index[src],
+                        dst_node: indices[dst],
+                        ..*connection
+                    });
+                }
+            }
+
+            (true, true) if is_mix[dst] => {
+                for (voice, indices) in voice_index.iter().enumerate() {
+                    connections.push(PatchConnection {
+                        src_node: indices[src],
+                        dst_node: shared_index[dst],
+                        src_port: connection.src_port,
+                        dst_port: voice,
+                    });
+                }
+            }
+
+            (true, true) => {
+                for indices in voice_index.iter() {
+                    connections.push(PatchConnection {
+                        src_node: indices[src],
+                        dst_node: indices[dst],
+                        ..*connection
+                    });
+                }
+            }
+
+            (true, false) => anyhow::bail!(
+                "Node {} is part of a voice so it has to go through a voice_mix before reaching {}",
+                patch.nodes[src].id, patch.nodes[dst].id
+            ),
+        }
+    }
+
+    Ok(Expanded {
+        patch: Patch {
+            nodes,
+            connections,
+            ..patch.clone()
+        },
+        voice_of,
+        polyphony: Some(polyphony),
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    // Note being held, `None` once it's released
+    note: Option<u8>,
+    // When the voice last started a note, counted in notes
+    started: u64,
+    // Peak output of the voice in the last block
+    level: f64,
+}
+
+/// Hands out incoming notes to voices and splits the MIDI into a stream for each voice
+#[derive(Debug, Clone)]
+pub struct VoiceAllocator {
+    stealing: VoiceStealing,
+    voices: Vec<Voice>,
+    notes_started: u64,
+    // Events for each voice in the block being processed
+    events: Vec<Vec<MidiEvent>>,
+}
+
+impl VoiceAllocator {
+    pub fn new(polyphony: &Polyphony) -> Self {
+        Self {
+            stealing: polyphony.stealing,
+            voices: vec![Voice { note: None, started: 0, level: 0.0 }; polyphony.voices],
+            notes_started: 0,
+            events: vec![Vec::with_capacity(MAX_VOICE_EVENTS); polyphony.voices],
+        }
+    }
+
+    pub fn num_voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// Takes over the notes held by the allocator of the program this one replaces, if it has as many voices
+    pub fn carry_from(&mut self, previous: &VoiceAllocator) {
+        if previous.voices.len() == self.voices.len() {
+            self.voices.copy_from_slice(&previous.voices);
+            self.notes_started = previous.notes_started;
+        }
+    }
+
+    /// MIDI for a voice in the block being processed
+    pub fn events(&self, voice: usize) -> &[MidiEvent] {
+        &self.events[voice]
+    }
+
+    /// Records how loud a voice was, for quietest voice stealing
+    pub fn set_level(&mut self, voice: usize, level: f64) {
+        self.voices[voice].level = level;
+    }
+
+    /// Splits the events in the block starting at time `offset` between the voices. Notes go to one voice,
+    /// controllers and channel pressure to all of them.
+    pub fn distribute(&mut self, events: &[MidiEvent], offset: usize, len: usize) {
+        for events in self.events.iter_mut() {
+            events.clear();
+        }
+
+        for event in events.iter().filter(|event| event.time >= offset && event.time < offset + len) {
+            match event.message {
+                MidiMessage::NoteOn { note, .. } => {
+                    let voice = self.choose_voice(note);
+
+                    // A stolen voice lets go of its old note first
+                    if let Some(old) = self.voices[voice].note {
+                        if old != note {
+                            self.events[voice].push(MidiEvent { time: event.time, message: MidiMessage::NoteOff { note: old } });
+                        }
+                    }
+
+                    self.voices[voice].note = Some(note);
+                    self.voices[voice].started = self.notes_started;
+                    self.notes_started += 1;
+                    self.events[voice].push(*event);
+                }
+
+                MidiMessage::NoteOff { note } | MidiMessage::PolyPressure { note, .. } => {
+                    if let Some(voice) = self.voices.iter().position(|voice| voice.note == Some(note)) {
+                        if let MidiMessage::NoteOff { .. } = event.message {
+                            self.voices[voice].note = None;
+                        }
+                        self.events[voice].push(*event);
+                    }
+                }
+
+                MidiMessage::ChannelPressure(_) | MidiMessage::ControlChange { .. } => {
+                    for events in self.events.iter_mut() {
+                        events.push(*event);
+                    }
+                }
+            }
+        }
+    }
+
+    // A voice already playing the note is retriggered, otherwise a free voice is used before stealing one
+    fn choose_voice(&self, note: u8) -> usize {
+        if let Some(voice) = self.voices.iter().position(|voice| voice.note == Some(note)) {
+            return voice;
+        }
+
+        // Free voices come before playing ones, so a note is only stolen when every voice is in use. Free voices
+        // are also picked by the policy, so released notes have the longest time to ring out.
+        let voices = self.voices.iter().enumerate();
+        let chosen = match self.stealing {
+            VoiceStealing::Oldest => voices.min_by_key(|(_, voice)| (voice.note.is_some(), voice.started)),
+            VoiceStealing::Quietest => voices.min_by(|(_, a), (_, b)| {
+                a.note.is_some().cmp(&b.note.is_some())
+                    .then(a.level.partial_cmp(&b.level).unwrap_or(std::cmp::Ordering::Equal))
+            }),
+        };
+
+        chosen.map_or(0, |(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(time: usize, note: u8) -> MidiEvent {
+        MidiEvent { time, message: MidiMessage::NoteOn { note, velocity: 100 } }
+    }
+
+    fn notes(allocator: &VoiceAllocator) -> Vec<Vec<MidiMessage>> {
+        (0..allocator.num_voices()).map(|voice| allocator.events(voice).iter().map(|event| event.message).collect()).collect()
+    }
+
+    #[test]
+    fn chords_spread_over_voices() {
+        let mut allocator = VoiceAllocator::new(&Polyphony { voices: 3, stealing: VoiceStealing::Oldest });
+        allocator.distribute(&[note_on(0, 60), note_on(0, 64), note_on(0, 67)], 0, 16);
+
+        assert_eq!(notes(&allocator), vec![
+            vec![MidiMessage::NoteOn { note: 60, velocity: 100 }],
+            vec![MidiMessage::NoteOn { note: 64, velocity: 100 }],
+            vec![MidiMessage::NoteOn { note: 67, velocity: 100 }],
+        ]);
+    }
+
+    #[test]
+    fn oldest_voice_is_stolen() {
+        let mut allocator = VoiceAllocator::new(&Polyphony { voices: 2, stealing: VoiceStealing::Oldest });
+        allocator.distribute(&[note_on(0, 60), note_on(1, 64), note_on(2, 67)], 0, 16);
+
+        assert_eq!(notes(&allocator)[0], vec![
+            MidiMessage::NoteOn { note: 60, velocity: 100 },
+            MidiMessage::NoteOff { note: 60 },
+            MidiMessage::NoteOn { note: 67, velocity: 100 },
+        ]);
+    }
+
+    #[test]
+    fn quietest_voice_is_stolen() {
+        let mut allocator = VoiceAllocator::new(&Polyphony { voices: 2, stealing: VoiceStealing::Quietest });
+        allocator.distribute(&[note_on(0, 60), note_on(1, 64)], 0, 16);
+        allocator.set_level(0, 0.9);
+        allocator.set_level(1, 0.1);
+
+        allocator.distribute(&[note_on(16, 67)], 16, 16);
+        assert_eq!(notes(&allocator)[1], vec![
+            MidiMessage::NoteOff { note: 64 },
+            MidiMessage::NoteOn { note: 67, velocity: 100 },
+        ]);
+    }
+}
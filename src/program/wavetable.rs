@@ -0,0 +1,238 @@
+use std::f64::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{interpolate, read_wav_mono};
+use crate::fft::{fft, ifft};
+use crate::ui::NodeDesc;
+
+/// Name of the oscillator node which plays a wavetable
+pub const WAVETABLE_NODE: &str = "wavetable_osc";
+
+/// Length every frame of a table is resampled to, and the frame length of multi-frame files
+pub const TABLE_SIZE: usize = 2048;
+
+// Each level has half the harmonics of the one before, down to just the fundamental
+const LEVELS: usize = 11;
+
+pub fn wavetable_desc() -> NodeDesc {
+    NodeDesc {
+        name: WAVETABLE_NODE.to_string(),
+        inputs: vec!["f".to_string(), "position".to_string()],
+        outputs: vec!["a".to_string()],
+        category: "Oscillators".to_string(),
+    }
+}
+
+/// Single cycle waveforms an oscillator can morph between.
+///
+/// Every frame is kept at each level of band-limiting so the oscillator can pick the one
+/// with as many harmonics as fit under Nyquist at the frequency it's playing.
+#[derive(Debug, Clone)]
+pub struct Wavetable {
+    // Indexed by frame, then level
+    frames: Vec<Vec<Vec<f64>>>,
+}
+
+impl Wavetable {
+    /// Builds a table from single cycles of any length
+    pub fn new(cycles: Vec<Vec<f64>>) -> Self {
+        let frames = cycles.iter()
+            .filter(|cycle| !cycle.is_empty())
+            .map(|cycle| band_limit(&fit_cycle(cycle)))
+            .collect::<Vec<_>>();
+
+        if frames.is_empty() {
+            Self::sine()
+        } else {
+            Self { frames }
+        }
+    }
+
+    /// A table with one frame holding a sine wave, used until a file is chosen
+    pub fn sine() -> Self {
+        let cycle = (0..TABLE_SIZE).map(|i| (2.0 * PI * i as f64 / TABLE_SIZE as f64).sin()).collect::<Vec<_>>();
+        Self {
+            frames: vec![vec![cycle; LEVELS]],
+        }
+    }
+
+    /// Reads a table from a WAV file. Files whose length is a whole number of `TABLE_SIZE` frames
+    /// are split into those frames, anything else is taken to be a single cycle.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let (samples, _) = read_wav_mono(path)?;
+        if samples.is_empty() {
+            anyhow::bail!("Wavetable {} is empty", path.display());
+        }
+
+        let cycles = if samples.len() % TABLE_SIZE == 0 {
+            samples.chunks(TABLE_SIZE).map(|chunk| chunk.to_vec()).collect()
+        } else {
+            vec![samples]
+        };
+        Ok(Self::new(cycles))
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The full bandwidth waveform of a frame
+    pub fn frame(&self, index: usize) -> &[f64] {
+        &self.frames[index][0]
+    }
+
+    /// Value at `phase` through the cycle, from 0 to 1, blending between the two frames either
+    /// side of `position`, also from 0 to 1
+    pub fn sample(&self, position: f64, phase: f64, hz: f64, sample_rate: f64) -> f64 {
+        let level = level_for(hz, sample_rate);
+
+        let position = position.max(0.0).min(1.0) * (self.frames.len() - 1) as f64;
+        let first = position.floor() as usize;
+        let second = (first + 1).min(self.frames.len() - 1);
+        let blend = position - first as f64;
+
+        let a = lookup(&self.frames[first][level], phase);
+        if blend == 0.0 {
+            a
+        } else {
+            a + (lookup(&self.frames[second][level], phase) - a) * blend
+        }
+    }
+}
+
+// Resamples a cycle of any length to `TABLE_SIZE`, wrapping round at the ends
+fn fit_cycle(cycle: &[f64]) -> Vec<f64> {
+    if cycle.len() == TABLE_SIZE {
+        return cycle.to_vec();
+    }
+
+    let len = cycle.len();
+    let mut wrapped = Vec::with_capacity(len + 3);
+    wrapped.push(cycle[len - 1]);
+    wrapped.extend_from_slice(cycle);
+    wrapped.push(cycle[0]);
+    wrapped.push(cycle[1 % len]);
+
+    (0..TABLE_SIZE)
+        .map(|i| interpolate(&wrapped, 1.0 + i as f64 * len as f64 / TABLE_SIZE as f64))
+        .collect()
+}
+
+// Copies of a cycle with the harmonics above each level's limit removed
+fn band_limit(cycle: &[f64]) -> Vec<Vec<f64>> {
+    let mut re = cycle.to_vec();
+    let mut im = vec![0.0; TABLE_SIZE];
+    fft(&mut re, &mut im);
+
+    (0..LEVELS)
+        .map(|level| {
+            let harmonics = (TABLE_SIZE / 2) >> level;
+            let (mut re, mut im) = (re.clone(), im.clone());
+            // Bins above the limit along with their mirror images
+            for bin in harmonics + 1..TABLE_SIZE - harmonics {
+                re[bin] = 0.0;
+                im[bin] = 0.0;
+            }
+            ifft(&mut re, &mut im);
+            re
+        })
+        .collect()
+}
+
+// The level with the most harmonics which all stay below Nyquist
+fn level_for(hz: f64, sample_rate: f64) -> usize {
+    let hz = hz.abs();
+    if hz <= 0.0 || sample_rate <= 0.0 {
+        return 0;
+    }
+
+    let ratio = (TABLE_SIZE / 2) as f64 * hz / (sample_rate / 2.0);
+    if ratio <= 1.0 {
+        0
+    } else {
+        (ratio.log2().ceil() as usize).min(LEVELS - 1)
+    }
+}
+
+// Linear interpolation, which is smooth enough at this table size
+fn lookup(table: &[f64], phase: f64) -> f64 {
+    let position = phase * table.len() as f64;
+    let index = position.floor() as usize % table.len();
+    let next = (index + 1) % table.len();
+    let t = position - position.floor();
+    table[index] + (table[next] - table[index]) * t
+}
+
+/// Oscillator reading a wavetable at frequency `f`, with `position` morphing through its frames
+#[derive(Debug, Clone)]
+pub struct WavetableOsc {
+    // Shared between the copies of the node made when the graph is recompiled
+    table: Arc<Wavetable>,
+    phase: f64,
+}
+
+impl WavetableOsc {
+    pub fn new(table: Arc<Wavetable>) -> Self {
+        Self {
+            table,
+            phase: 0.0,
+        }
+    }
+
+    /// Continues from the phase of the oscillator being replaced, with this oscillator's table
+    pub fn carry_from(&mut self, previous: &WavetableOsc) {
+        self.phase = previous.phase;
+    }
+
+    pub fn process(&mut self, sample_rate: f64, inputs: &[Vec<f64>], output: &mut [f64]) {
+        for (i, out) in output.iter_mut().enumerate() {
+            let (hz, position) = (inputs[0][i], inputs[1][i]);
+            *out = self.table.sample(position, self.phase, hz, sample_rate);
+            self.phase = (self.phase + hz / sample_rate).rem_euclid(1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Strength of a harmonic of a table
+    fn harmonic(table: &[f64], harmonic: usize) -> f64 {
+        let (mut re, mut im) = (table.to_vec(), vec![0.0; table.len()]);
+        fft(&mut re, &mut im);
+        (re[harmonic] * re[harmonic] + im[harmonic] * im[harmonic]).sqrt()
+    }
+
+    #[test]
+    fn high_notes_drop_harmonics_above_nyquist() {
+        let saw = (0..TABLE_SIZE).map(|i| 1.0 - 2.0 * i as f64 / TABLE_SIZE as f64).collect::<Vec<_>>();
+        let table = Wavetable::new(vec![saw]);
+
+        // At 3kHz only harmonics up to the 8th fit under Nyquist when running at 48kHz
+        let level = level_for(3000.0, 48000.0);
+        assert_eq!(level, 7);
+        let cycle = &table.frames[0][level];
+        assert!(harmonic(cycle, 8) > 1.0);
+        assert!(harmonic(cycle, 9) < 1e-9);
+
+        assert_eq!(level_for(10.0, 48000.0), 0);
+    }
+
+    #[test]
+    fn short_cycles_are_stretched_to_the_table() {
+        let table = Wavetable::new(vec![vec![1.0, -1.0]]);
+        assert_eq!(table.frame(0).len(), TABLE_SIZE);
+        assert!((table.sample(0.0, 0.0, 10.0, 48000.0) - 1.0).abs() < 1e-9);
+        assert!((table.sample(0.0, 0.5, 10.0, 48000.0) + 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn position_morphs_between_frames() {
+        let table = Wavetable::new(vec![vec![1.0; 4], vec![-1.0; 4], vec![0.5; 4]]);
+        assert!((table.sample(0.0, 0.3, 100.0, 48000.0) - 1.0).abs() < 1e-9);
+        assert!((table.sample(0.25, 0.3, 100.0, 48000.0)).abs() < 1e-9);
+        assert!((table.sample(1.0, 0.3, 100.0, 48000.0) - 0.5).abs() < 1e-9);
+    }
+}
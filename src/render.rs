@@ -0,0 +1,68 @@
+use std::path::Path;
+
+use crate::midi::MidiEvent;
+use crate::program::Program;
+
+/// Number of samples in each block the graph is processed in
+pub const STEP_SIZE: usize = 16usize;
+
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// Renders `steps` blocks of audio through the graph, calling `keep_going` before each block.
+/// Returns `None` if it was stopped early.
+///
+/// The INPUT node is fed with a sine sweep and the COUNTER node with the sample index.
+/// Automated parameters are updated at the start of each block. `midi` is played from the start of the
+/// render, with times in samples.
+pub fn render_while(program: &mut Program, steps: usize, midi: &[MidiEvent], mut keep_going: impl FnMut() -> bool) -> Option<Vec<f64>> {
+    let mut output = Vec::with_capacity(steps * STEP_SIZE);
+    let mut n = 0;
+    for _ in 0..steps {
+        if !keep_going() {
+            return None;
+        }
+
+        let mut audio_buffer = [0.0f64; STEP_SIZE];
+        process_block(program, &mut n, &mut audio_buffer, midi);
+        //Collect output audio
+        output.extend_from_slice(&audio_buffer);
+    }
+
+    Some(output)
+}
+
+/// Runs one block through the program, advancing the sample counter `n`.
+/// `midi` holds events for the whole render, sorted by time.
+pub fn process_block(program: &mut Program, n: &mut usize, audio_buffer: &mut [f64], midi: &[MidiEvent]) {
+    let start = midi.partition_point(|event| event.time < *n);
+    let end = midi.partition_point(|event| event.time < *n + audio_buffer.len());
+
+    sine_sweep(*n, audio_buffer);
+    program.automate(*n);
+    program.process_midi(*n, audio_buffer, &midi[start..end], *n);
+    *n += audio_buffer.len();
+}
+
+/// Fills the buffer with the sine sweep used as the input when there's no audio to process,
+/// starting at sample index `n`
+pub fn sine_sweep(n: usize, buffer: &mut [f64]) {
+    for (i, sample) in buffer.iter_mut().enumerate() {
+        *sample = (((n + i) as f64).powi(2) * 0.000001).sin();
+    }
+}
+
+/// Writes mono samples to a 32 bit float WAV file
+pub fn write_wav(samples: &[f64], path: &Path) -> anyhow::Result<()> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for sample in samples {
+        writer.write_sample(*sample as f32)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
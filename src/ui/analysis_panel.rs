@@ -0,0 +1,275 @@
+use tuix::*;
+use femtovg::{
+    Canvas, renderer::OpenGl, Paint, Path,
+};
+
+use crate::analysis::{Analysis, SPECTRUM_BANDS};
+use crate::program::Optimization;
+
+// Range of levels shown by the spectrum display
+const MIN_DB: f32 = -100.0;
+const MAX_DB: f32 = 6.0;
+// Number of colours the spectrogram levels are quantised to
+const SPECTROGRAM_LEVELS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpectrumMode {
+    Average,
+    Spectrogram,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnalysisEvent {
+    Update(Analysis),
+    // The nodes taken out of the graph before it was rendered
+    Optimized(Optimization),
+    SetMode(SpectrumMode),
+}
+
+// Panel showing the levels and spectrum of the rendered output, and what was removed from the graph
+pub struct AnalysisPanel {
+    peak_label: Entity,
+    rms_label: Entity,
+    clip_label: Entity,
+    dc_label: Entity,
+    pruned_label: Entity,
+    folded_label: Entity,
+    spectrum_view: Entity,
+}
+
+impl AnalysisPanel {
+    pub fn new() -> Self {
+        Self {
+            peak_label: Entity::null(),
+            rms_label: Entity::null(),
+            clip_label: Entity::null(),
+            dc_label: Entity::null(),
+            pruned_label: Entity::null(),
+            folded_label: Entity::null(),
+            spectrum_view: Entity::null(),
+        }
+    }
+
+    fn add_label(state: &mut State, parent: Entity) -> Entity {
+        Label::new("").build(state, parent, |builder|
+            builder
+                .set_height(Pixels(20.0))
+                .set_child_space(Stretch(1.0))
+                .set_child_left(Pixels(5.0))
+                .set_hoverable(false)
+        )
+    }
+}
+
+// Lists the ids of the nodes removed from the graph in one way
+fn removed_text(removal: &str, ids: &[String]) -> String {
+    if ids.is_empty() {
+        format!("{} nodes: none", removal)
+    } else {
+        format!("{} nodes ({}): {}", removal, ids.len(), ids.join(", "))
+    }
+}
+
+impl Widget for AnalysisPanel {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        Label::new("OUTPUT").build(state, entity, |builder|
+            builder
+                .set_height(Pixels(30.0))
+                .set_child_space(Stretch(1.0))
+                .set_hoverable(false)
+                .class("node_label")
+        );
+
+        self.peak_label = Self::add_label(state, entity);
+        self.rms_label = Self::add_label(state, entity);
+        self.clip_label = Self::add_label(state, entity);
+        self.dc_label = Self::add_label(state, entity);
+        self.pruned_label = Self::add_label(state, entity);
+        self.folded_label = Self::add_label(state, entity);
+
+        let row = Row::new().build(state, entity, |builder|
+            builder
+                .set_height(Pixels(30.0))
+                .set_child_space(Pixels(5.0))
+                .set_col_between(Pixels(5.0))
+        );
+
+        Button::with_label("Average")
+            .on_press(|_, state, button| {
+                button.emit(state, AnalysisEvent::SetMode(SpectrumMode::Average));
+            })
+            .build(state, row, |builder|
+                builder
+                    .set_child_space(Stretch(1.0))
+                    .class("analysis_mode")
+            );
+
+        Button::with_label("Spectrogram")
+            .on_press(|_, state, button| {
+                button.emit(state, AnalysisEvent::SetMode(SpectrumMode::Spectrogram));
+            })
+            .build(state, row, |builder|
+                builder
+                    .set_child_space(Stretch(1.0))
+                    .class("analysis_mode")
+            );
+
+        self.spectrum_view = SpectrumView::new().build(state, entity, |builder|
+            builder
+                .set_height(Pixels(160.0))
+                .set_space(Pixels(5.0))
+        );
+
+        entity
+            .set_width(state, Pixels(320.0))
+            .set_height(state, Auto)
+            .set_top(state, Pixels(10.0))
+            .set_right(state, Pixels(10.0))
+            .set_left(state, Stretch(1.0))
+            .set_bottom(state, Stretch(1.0))
+            .set_position_type(state, PositionType::SelfDirected)
+            .set_border_radius(state, Pixels(3.0))
+            .set_display(state, Display::None)
+            .class(state, "node")
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(analysis_event) = event.message.downcast() {
+            match analysis_event {
+                AnalysisEvent::Update(analysis) => {
+                    self.peak_label.set_text(state, &format!("Peak: {:.1} dBFS", analysis.peak_db));
+                    self.rms_label.set_text(state, &format!("RMS: {:.1} dBFS", analysis.rms_db));
+                    self.clip_label.set_text(state, &format!("Clipped samples: {}", analysis.clip_count));
+                    self.dc_label.set_text(state, &format!("DC offset: {:.5}", analysis.dc_offset));
+
+                    entity.set_display(state, Display::Flex);
+                    entity.emit_to(state, self.spectrum_view, AnalysisEvent::Update(analysis.clone()));
+                }
+
+                AnalysisEvent::Optimized(optimization) => {
+                    self.pruned_label.set_text(state, &removed_text("Pruned", &optimization.pruned));
+                    self.folded_label.set_text(state, &removed_text("Folded", &optimization.folded));
+                }
+
+                AnalysisEvent::SetMode(mode) => {
+                    if event.target != self.spectrum_view {
+                        entity.emit_to(state, self.spectrum_view, AnalysisEvent::SetMode(*mode));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Draws the magnitude spectrum, either averaged over the whole render or over time
+pub struct SpectrumView {
+    analysis: Option<Analysis>,
+    mode: SpectrumMode,
+}
+
+impl SpectrumView {
+    pub fn new() -> Self {
+        Self {
+            analysis: None,
+            mode: SpectrumMode::Average,
+        }
+    }
+}
+
+impl Widget for SpectrumView {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, _state: &mut State, entity: Entity) -> Self::Ret {
+        entity
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(analysis_event) = event.message.downcast() {
+            if event.target == entity {
+                match analysis_event {
+                    AnalysisEvent::Update(analysis) => {
+                        self.analysis = Some(analysis.clone());
+                    }
+
+                    AnalysisEvent::SetMode(mode) => {
+                        self.mode = *mode;
+                    }
+
+                    AnalysisEvent::Optimized(_) => {}
+                }
+
+                state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+            }
+        }
+    }
+
+    fn on_draw(&mut self, state: &mut State, entity: Entity, canvas: &mut Canvas<OpenGl>) {
+        let bounds = state.data.get_bounds(entity);
+
+        canvas.save();
+
+        let transform = state.data.get_transform(entity);
+        canvas.set_transform(transform[0], transform[1], transform[2], transform[3], transform[4], transform[5]);
+
+        let mut path = Path::new();
+        path.rect(bounds.x, bounds.y, bounds.w, bounds.h);
+        canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(20, 20, 20)));
+
+        // Normalised level between the bottom and top of the display
+        let level = |db: f32| ((db - MIN_DB) / (MAX_DB - MIN_DB)).max(0.0).min(1.0);
+
+        if let Some(analysis) = &self.analysis {
+            let band_width = bounds.w / SPECTRUM_BANDS as f32;
+
+            match self.mode {
+                SpectrumMode::Average => {
+                    let mut path = Path::new();
+                    for (band, db) in analysis.spectrum.iter().enumerate() {
+                        let x = bounds.x + (band as f32 + 0.5) * band_width;
+                        let y = bounds.y + bounds.h * (1.0 - level(*db));
+                        if band == 0 {
+                            path.move_to(x, y);
+                        } else {
+                            path.line_to(x, y);
+                        }
+                    }
+                    let mut paint = Paint::color(femtovg::Color::rgb(80, 200, 80));
+                    paint.set_line_width(1.5);
+                    canvas.stroke_path(&mut path, paint);
+                }
+
+                SpectrumMode::Spectrogram => {
+                    // Time runs left to right, with low frequencies at the bottom.
+                    // Cells are batched into one path per colour.
+                    let frame_width = bounds.w / analysis.spectrogram.len().max(1) as f32;
+                    let band_height = bounds.h / SPECTRUM_BANDS as f32;
+
+                    let mut paths = (0..SPECTROGRAM_LEVELS).map(|_| Path::new()).collect::<Vec<_>>();
+                    for (frame, bands) in analysis.spectrogram.iter().enumerate() {
+                        for (band, db) in bands.iter().enumerate() {
+                            let index = (level(*db) * (SPECTROGRAM_LEVELS - 1) as f32).round() as usize;
+                            if index == 0 {
+                                continue;
+                            }
+                            let x = bounds.x + frame as f32 * frame_width;
+                            let y = bounds.y + bounds.h - (band + 1) as f32 * band_height;
+                            paths[index].rect(x, y, frame_width + 0.5, band_height + 0.5);
+                        }
+                    }
+
+                    for (index, path) in paths.iter_mut().enumerate() {
+                        let intensity = index as f32 / (SPECTROGRAM_LEVELS - 1) as f32;
+                        let color = femtovg::Color::rgbf(intensity, intensity * intensity, 0.3 * (1.0 - intensity) + 0.2 * intensity);
+                        canvas.fill_path(path, Paint::color(color));
+                    }
+                }
+            }
+        }
+
+        canvas.restore();
+    }
+}
@@ -0,0 +1,92 @@
+use tuix::*;
+
+use crate::bench::BenchReport;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BenchEvent {
+    Update(BenchReport),
+}
+
+// Panel showing the render timings of the graph for each block size
+pub struct BenchPanel {
+    summary: Entity,
+    rows: Vec<Entity>,
+}
+
+impl BenchPanel {
+    pub fn new() -> Self {
+        Self {
+            summary: Entity::null(),
+            rows: Vec::new(),
+        }
+    }
+}
+
+impl Widget for BenchPanel {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        Label::new("Benchmark").build(state, entity, |builder|
+            builder
+                .set_height(Pixels(30.0))
+                .set_child_space(Stretch(1.0))
+                .set_hoverable(false)
+                .class("node_label")
+        );
+
+        self.summary = Label::new("").build(state, entity, |builder|
+            builder
+                .set_height(Pixels(25.0))
+                .set_child_space(Stretch(1.0))
+                .set_child_left(Pixels(5.0))
+                .set_hoverable(false)
+        );
+
+        entity
+            .set_width(state, Pixels(520.0))
+            .set_height(state, Auto)
+            .set_top(state, Pixels(10.0))
+            .set_left(state, Pixels(10.0))
+            .set_right(state, Stretch(1.0))
+            .set_bottom(state, Stretch(1.0))
+            .set_position_type(state, PositionType::SelfDirected)
+            .set_border_radius(state, Pixels(3.0))
+            .set_display(state, Display::None)
+            .class(state, "node")
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(bench_event) = event.message.downcast() {
+            match bench_event {
+                BenchEvent::Update(report) => {
+                    for row in self.rows.drain(..) {
+                        state.remove(row);
+                    }
+
+                    for line in report.lines() {
+                        let row = Label::new(&line).build(state, entity, |builder|
+                            builder
+                                .set_height(Pixels(20.0))
+                                .set_child_space(Stretch(1.0))
+                                .set_child_left(Pixels(5.0))
+                                .set_hoverable(false)
+                                .class("bench_row")
+                        );
+                        self.rows.push(row);
+                    }
+
+                    let summary = match report.configured() {
+                        Some(row) if row.is_real_time_safe() => format!("Real time safe at block size {} ({:.1}% load)", row.block_size, row.cpu_load()),
+                        Some(row) => format!("Not real time safe at block size {} ({:.1}% load)", row.block_size, row.cpu_load()),
+                        None => String::new(),
+                    };
+                    self.summary.set_text(state, &summary);
+
+                    entity.set_display(state, Display::Flex);
+                    state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+                }
+            }
+        }
+    }
+}
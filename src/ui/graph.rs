@@ -108,6 +108,23 @@ impl IndexGraph {
         }
     }
 
+    /// Returns which vertices can be reached by following out edges from any of the `starts`, including the starts
+    pub fn reachable_from<I>(&self, starts: I) -> Vec<bool>
+        where I: IntoIterator<Item = usize>
+    {
+        let mut reached = vec![false; self.vertices.len()];
+        let mut stack = starts.into_iter().collect::<Vec<_>>();
+
+        while let Some(idx) = stack.pop() {
+            if !reached[idx] {
+                reached[idx] = true;
+                stack.extend(self.vertices[idx].out_edges.iter().filter(|&&edge| !reached[edge]));
+            }
+        }
+
+        reached
+    }
+
     fn try_toposort_internal(mut self) -> Result<Vec<usize>, IndexGraph> {
         let mut queue = Queue::new();
         let mut sorted = Vec::new();
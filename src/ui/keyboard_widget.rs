@@ -0,0 +1,253 @@
+use tuix::*;
+use femtovg::{
+    Canvas, renderer::OpenGl, Paint, Path,
+};
+
+use super::AppEvent;
+use crate::midi::MidiMessage;
+
+// Lowest note drawn, C2
+const FIRST_NOTE: u8 = 36;
+// Five octaves and the C above them
+const NUM_KEYS: u8 = 61;
+// Velocity of notes played on the keyboard, which has no way of telling how hard a key is hit
+const VELOCITY: u8 = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyboardEvent {
+    // Computer keys pressed and released over the node view
+    KeyDown(Code),
+    KeyUp(Code),
+}
+
+// Semitones above the keyboard's octave played by the computer keys, laid out like a piano
+// on the home row with the black keys on the row above
+pub fn qwerty_offset(code: Code) -> Option<u8> {
+    let offset = match code {
+        Code::KeyA => 0,
+        Code::KeyW => 1,
+        Code::KeyS => 2,
+        Code::KeyE => 3,
+        Code::KeyD => 4,
+        Code::KeyF => 5,
+        Code::KeyT => 6,
+        Code::KeyG => 7,
+        Code::KeyY => 8,
+        Code::KeyH => 9,
+        Code::KeyU => 10,
+        Code::KeyJ => 11,
+        Code::KeyK => 12,
+        Code::KeyO => 13,
+        Code::KeyL => 14,
+        Code::KeyP => 15,
+        Code::Semicolon => 16,
+        _ => return None,
+    };
+
+    Some(offset)
+}
+
+fn is_black(note: u8) -> bool {
+    matches!(note % 12, 1 | 3 | 6 | 8 | 10)
+}
+
+// Piano keyboard under the node view which plays notes into the running graph
+pub struct Keyboard {
+    // Note played by the A key, moved an octave at a time with Z and X
+    octave: u8,
+    held: [bool; 128],
+    // Note held down with the mouse, which changes as the mouse slides across the keys
+    mouse_note: Option<u8>,
+    // Computer keys and the notes they started, so a note is released even if the octave changed since
+    keys: Vec<(Code, u8)>,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self {
+            octave: 60,
+            held: [false; 128],
+            mouse_note: None,
+            keys: Vec::new(),
+        }
+    }
+
+    fn note_on(&mut self, state: &mut State, entity: Entity, note: u8) {
+        self.held[note as usize] = true;
+        entity.emit(state, AppEvent::Midi(MidiMessage::NoteOn { note, velocity: VELOCITY }));
+        state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+    }
+
+    fn note_off(&mut self, state: &mut State, entity: Entity, note: u8) {
+        self.held[note as usize] = false;
+        entity.emit(state, AppEvent::Midi(MidiMessage::NoteOff { note }));
+        state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+    }
+
+    // Returns the note under the cursor. Black keys cover the top part of the white keys.
+    fn note_at(&self, state: &State, entity: Entity, x: f32, y: f32) -> Option<u8> {
+        let bounds = state.data.get_bounds(entity);
+
+        let mut transform = state.data.get_transform(entity);
+        transform.inverse();
+        let (mx, my) = transform.transform_point(x, y);
+
+        let white_keys = (FIRST_NOTE..FIRST_NOTE + NUM_KEYS).filter(|note| !is_black(*note)).count();
+        let white_width = bounds.w / white_keys as f32;
+        if white_width <= 0.0 || mx < bounds.x || mx >= bounds.x + bounds.w {
+            return None;
+        }
+
+        if my < bounds.y + bounds.h * 0.6 {
+            let black = self.key_rects(&bounds).find(|(note, x, w)| is_black(*note) && mx >= *x && mx < x + w);
+            if let Some((note, _, _)) = black {
+                return Some(note);
+            }
+        }
+
+        let index = ((mx - bounds.x) / white_width) as usize;
+        (FIRST_NOTE..FIRST_NOTE + NUM_KEYS).filter(|note| !is_black(*note)).nth(index)
+    }
+
+    // Left edge and width of each key
+    fn key_rects(&self, bounds: &BoundingBox) -> impl Iterator<Item = (u8, f32, f32)> {
+        let white_keys = (FIRST_NOTE..FIRST_NOTE + NUM_KEYS).filter(|note| !is_black(*note)).count();
+        let white_width = bounds.w / white_keys as f32;
+        let left = bounds.x;
+
+        let mut whites = 0;
+        (FIRST_NOTE..FIRST_NOTE + NUM_KEYS).map(move |note| {
+            if is_black(note) {
+                // Black keys sit across the line between two white keys
+                (note, left + whites as f32 * white_width - white_width * 0.3, white_width * 0.6)
+            } else {
+                whites += 1;
+                (note, left + (whites - 1) as f32 * white_width, white_width)
+            }
+        })
+    }
+
+    fn key_down(&mut self, state: &mut State, entity: Entity, code: Code) {
+        match code {
+            Code::KeyZ => self.octave = self.octave.saturating_sub(12),
+            Code::KeyX => self.octave = (self.octave + 12).min(108),
+            code => {
+                // Held keys repeat, which mustn't retrigger the note
+                if self.keys.iter().any(|(key, _)| *key == code) {
+                    return;
+                }
+
+                if let Some(note) = qwerty_offset(code).map(|offset| self.octave + offset).filter(|note| *note < 128) {
+                    self.keys.push((code, note));
+                    self.note_on(state, entity, note);
+                }
+            }
+        }
+    }
+
+    fn key_up(&mut self, state: &mut State, entity: Entity, code: Code) {
+        if let Some(index) = self.keys.iter().position(|(key, _)| *key == code) {
+            let (_, note) = self.keys.remove(index);
+            self.note_off(state, entity, note);
+        }
+    }
+}
+
+impl Widget for Keyboard {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        entity
+            .set_height(state, Pixels(80.0))
+            .set_width(state, Stretch(1.0))
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(keyboard_event) = event.message.downcast() {
+            match keyboard_event {
+                KeyboardEvent::KeyDown(code) => {
+                    let code = *code;
+                    self.key_down(state, entity, code);
+                }
+
+                KeyboardEvent::KeyUp(code) => {
+                    let code = *code;
+                    self.key_up(state, entity, code);
+                }
+            }
+        }
+
+        if let Some(window_event) = event.message.downcast() {
+            match window_event {
+                WindowEvent::MouseDown(button) if *button == MouseButton::Left && event.target == entity => {
+                    state.capture(entity);
+                    let (x, y) = (state.mouse.cursorx, state.mouse.cursory);
+                    if let Some(note) = self.note_at(state, entity, x, y) {
+                        self.mouse_note = Some(note);
+                        self.note_on(state, entity, note);
+                    }
+                    event.consume();
+                }
+
+                WindowEvent::MouseUp(button) if *button == MouseButton::Left && event.target == entity => {
+                    state.release(entity);
+                    if let Some(note) = self.mouse_note.take() {
+                        self.note_off(state, entity, note);
+                    }
+                }
+
+                // Sliding across the keys plays each one in turn
+                WindowEvent::MouseMove(x, y) if self.mouse_note.is_some() && event.target == entity => {
+                    let note = self.note_at(state, entity, *x, *y);
+                    if note != self.mouse_note {
+                        if let Some(old) = self.mouse_note.take() {
+                            self.note_off(state, entity, old);
+                        }
+                        if let Some(note) = note {
+                            self.mouse_note = Some(note);
+                            self.note_on(state, entity, note);
+                        }
+                    }
+                }
+
+                _ => {}
+            }
+        }
+    }
+
+    fn on_draw(&mut self, state: &mut State, entity: Entity, canvas: &mut Canvas<OpenGl>) {
+        let bounds = state.data.get_bounds(entity);
+
+        canvas.save();
+
+        let transform = state.data.get_transform(entity);
+        canvas.set_transform(transform[0], transform[1], transform[2], transform[3], transform[4], transform[5]);
+
+        let held = femtovg::Color::rgb(50, 50, 150);
+
+        // White keys first so the black keys are drawn on top of them
+        for (note, x, w) in self.key_rects(&bounds).filter(|(note, _, _)| !is_black(*note)) {
+            let mut path = Path::new();
+            path.rect(x + 0.5, bounds.y, w - 1.0, bounds.h);
+            let color = if self.held[note as usize] { held } else { femtovg::Color::rgb(210, 210, 210) };
+            canvas.fill_path(&mut path, Paint::color(color));
+        }
+
+        for (note, x, w) in self.key_rects(&bounds).filter(|(note, _, _)| is_black(*note)) {
+            let mut path = Path::new();
+            path.rect(x, bounds.y, w, bounds.h * 0.6);
+            let color = if self.held[note as usize] { held } else { femtovg::Color::rgb(20, 20, 20) };
+            canvas.fill_path(&mut path, Paint::color(color));
+        }
+
+        // Marks the key played by A so it's clear where the computer keys are
+        if let Some((_, x, w)) = self.key_rects(&bounds).find(|(note, _, _)| *note == self.octave) {
+            let mut path = Path::new();
+            path.rect(x + 2.0, bounds.y + bounds.h - 6.0, w - 4.0, 3.0);
+            canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(50, 150, 50)));
+        }
+
+        canvas.restore();
+    }
+}
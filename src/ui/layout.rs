@@ -0,0 +1,214 @@
+use super::graph::IndexGraph;
+
+// Horizontal gap between layer columns
+const LAYER_SPACING: f32 = 100.0;
+// Vertical gap between nodes within a column
+const NODE_SPACING: f32 = 40.0;
+// Height reserved for a wire passing through a column
+const DUMMY_HEIGHT: f32 = 20.0;
+// Number of down/up barycenter sweeps used to reduce crossings
+const SWEEPS: usize = 8;
+
+/// Arranges the nodes of a graph into layered columns, left to right.
+///
+/// `edges` are `(source, destination)` pairs of node indices and `sizes` are the `(width, height)`
+/// of each node. Nodes listed in `sinks` are pushed into the last column. Returns the top-left
+/// position of each node, with the whole layout centred on the origin.
+pub fn layered_layout(sizes: &[(f32, f32)], edges: &[(usize, usize)], sinks: &[usize]) -> Vec<(f32, f32)> {
+    let num_nodes = sizes.len();
+
+    if num_nodes == 0 {
+        return Vec::new();
+    }
+
+    let layers = assign_layers(num_nodes, edges, sinks);
+
+    // Split edges which span more than one layer with dummy vertices so that every edge
+    // connects adjacent layers. Dummies are numbered after the real nodes.
+    let mut layer_of = layers.clone();
+    let mut short_edges = Vec::new();
+    for &(src, dst) in edges {
+        if src == dst || layers[src] >= layers[dst] {
+            continue;
+        }
+
+        let mut prev = src;
+        for layer in layers[src] + 1..layers[dst] {
+            let dummy = layer_of.len();
+            layer_of.push(layer);
+            short_edges.push((prev, dummy));
+            prev = dummy;
+        }
+        short_edges.push((prev, dst));
+    }
+
+    let num_layers = layer_of.iter().max().map_or(0, |max| max + 1);
+    let mut order = vec![Vec::new(); num_layers];
+    for (vertex, &layer) in layer_of.iter().enumerate() {
+        order[layer].push(vertex);
+    }
+
+    let mut preds = vec![Vec::new(); layer_of.len()];
+    let mut succs = vec![Vec::new(); layer_of.len()];
+    for &(src, dst) in &short_edges {
+        succs[src].push(dst);
+        preds[dst].push(src);
+    }
+
+    minimise_crossings(&mut order, &preds, &succs);
+
+    // Columns are as wide as their widest node and nodes are stacked top to bottom
+    let size_of = |vertex: usize| {
+        if vertex < num_nodes {
+            sizes[vertex]
+        } else {
+            (0.0, DUMMY_HEIGHT)
+        }
+    };
+
+    let mut positions = vec![(0.0, 0.0); num_nodes];
+    let mut x = 0.0;
+    for layer in order.iter().filter(|layer| !layer.is_empty()) {
+        let column_width = layer.iter().map(|&v| size_of(v).0).fold(0.0f32, f32::max);
+        let column_height = layer.iter().map(|&v| size_of(v).1).sum::<f32>()
+            + NODE_SPACING * (layer.len().saturating_sub(1)) as f32;
+
+        let mut y = -column_height / 2.0;
+        for &vertex in layer {
+            let (_, h) = size_of(vertex);
+            if vertex < num_nodes {
+                positions[vertex] = (x, y);
+            }
+            y += h + NODE_SPACING;
+        }
+
+        x += column_width + LAYER_SPACING;
+    }
+
+    // Centre horizontally
+    let total_width = x - LAYER_SPACING;
+    for position in positions.iter_mut() {
+        position.0 -= total_width / 2.0;
+    }
+
+    positions
+}
+
+// Longest-path layering in topological order, falling back to insertion order if the graph has a cycle
+fn assign_layers(num_nodes: usize, edges: &[(usize, usize)], sinks: &[usize]) -> Vec<usize> {
+    let mut adjacency = vec![Vec::new(); num_nodes];
+    for &(src, dst) in edges {
+        if src != dst {
+            adjacency[src].push(dst);
+        }
+    }
+
+    let sorted = IndexGraph::from_adjacency_list(&adjacency)
+        .toposort()
+        .unwrap_or_else(|| (0..num_nodes).collect());
+
+    let mut layers = vec![0usize; num_nodes];
+    for &vertex in &sorted {
+        for &next in &adjacency[vertex] {
+            if layers[next] <= layers[vertex] {
+                layers[next] = layers[vertex] + 1;
+            }
+        }
+    }
+
+    // Sinks (e.g. OUTPUT) always end up in the rightmost column, apart from the sources
+    let last = layers.iter().max().cloned().unwrap_or(0).max(1);
+    for &sink in sinks {
+        if adjacency[sink].is_empty() {
+            layers[sink] = last;
+        }
+    }
+
+    layers
+}
+
+// Barycenter heuristic, keeping the ordering with the fewest crossings
+fn minimise_crossings(order: &mut [Vec<usize>], preds: &[Vec<usize>], succs: &[Vec<usize>]) {
+    let mut best = order.to_vec();
+    let mut best_crossings = count_crossings(order, succs);
+
+    for sweep in 0..SWEEPS {
+        if best_crossings == 0 {
+            break;
+        }
+
+        if sweep % 2 == 0 {
+            for layer in 1..order.len() {
+                reorder_layer(order, layer, layer - 1, preds);
+            }
+        } else {
+            for layer in (0..order.len().saturating_sub(1)).rev() {
+                reorder_layer(order, layer, layer + 1, succs);
+            }
+        }
+
+        let crossings = count_crossings(order, succs);
+        if crossings < best_crossings {
+            best_crossings = crossings;
+            best = order.to_vec();
+        }
+    }
+
+    order.clone_from_slice(&best);
+}
+
+// Sort a layer by the mean position of each vertex's neighbours in the fixed layer
+fn reorder_layer(order: &mut [Vec<usize>], layer: usize, fixed: usize, neighbours: &[Vec<usize>]) {
+    let mut rank = vec![0.0f32; neighbours.len()];
+    for (index, &vertex) in order[fixed].iter().enumerate() {
+        rank[vertex] = index as f32;
+    }
+
+    let mut keyed = order[layer]
+        .iter()
+        .enumerate()
+        .map(|(index, &vertex)| {
+            let adjacent = &neighbours[vertex];
+            let key = if adjacent.is_empty() {
+                // Vertices with no neighbours keep their current position
+                index as f32
+            } else {
+                adjacent.iter().map(|&n| rank[n]).sum::<f32>() / adjacent.len() as f32
+            };
+            (key, vertex)
+        })
+        .collect::<Vec<_>>();
+
+    // Stable sort so ties keep their relative order
+    keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    order[layer] = keyed.into_iter().map(|(_, vertex)| vertex).collect();
+}
+
+fn count_crossings(order: &[Vec<usize>], succs: &[Vec<usize>]) -> usize {
+    let mut rank = vec![0usize; succs.len()];
+    for layer in order {
+        for (index, &vertex) in layer.iter().enumerate() {
+            rank[vertex] = index;
+        }
+    }
+
+    let mut crossings = 0;
+    for layer in order {
+        let wires = layer
+            .iter()
+            .flat_map(|&src| succs[src].iter().map(move |&dst| (src, dst)))
+            .map(|(src, dst)| (rank[src], rank[dst]))
+            .collect::<Vec<_>>();
+
+        for (i, a) in wires.iter().enumerate() {
+            for b in &wires[i + 1..] {
+                if (a.0 < b.0 && a.1 > b.1) || (a.0 > b.0 && a.1 < b.1) {
+                    crossings += 1;
+                }
+            }
+        }
+    }
+
+    crossings
+}
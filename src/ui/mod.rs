@@ -1,6 +1,6 @@
 
 pub mod node_view;
-use std::{collections::HashMap, ops::Index};
+use std::{collections::HashMap, ops::Index, path::PathBuf, time::Duration};
 
 pub use node_view::*;
 
@@ -10,11 +10,54 @@ pub use node_widget::*;
 pub mod socket_widget;
 pub use socket_widget::*;
 
+pub mod node_palette;
+pub use node_palette::*;
+
+pub mod probe_widget;
+pub use probe_widget::*;
+
+pub mod analysis_panel;
+pub use analysis_panel::*;
+
+pub mod bench_panel;
+pub use bench_panel::*;
+
+pub mod progress_widget;
+pub use progress_widget::*;
+
+pub mod parameter_widget;
+pub use parameter_widget::*;
+
+pub mod keyboard_widget;
+pub use keyboard_widget::*;
+
+pub mod sequencer_widget;
+pub use sequencer_widget::*;
+
+pub mod transport_widget;
+pub use transport_widget::*;
+
+pub mod sample_widget;
+pub use sample_widget::*;
+
+pub mod wavetable_widget;
+pub use wavetable_widget::*;
+
+pub mod graph;
+
+pub mod layout;
+use layout::layered_layout;
+
 use tuix::*;
 
-use sarus::{graph::{Graph, Node, Connection}, run_fn};
 
-const STEP_SIZE: usize = 16usize;
+use crate::render::{SAMPLE_RATE, STEP_SIZE};
+use crate::worker::{BenchJob, JobStatus, RenderJob};
+use crate::program::{Automation, ParameterSpec, Pattern, Polyphony, Program, Rate, Transport, CONVOLUTION_NODE, PARAMETER_NODE, SAMPLE_PLAYER_NODE, SEQUENCER_NODE, WAVETABLE_NODE};
+use crate::engine::{BackendKind, Engine, DEFAULT_CROSSFADE};
+use crate::patch::{Patch, PatchNode, PatchConnection};
+use crate::library::Library;
+use crate::midi::{MidiEvent, MidiMessage};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum NodeEvent {
@@ -29,6 +72,15 @@ pub enum NodeEvent {
 
     AddConnection(ConnectionDesc),
     RemoveConnection(ConnectionDesc),
+
+    // Animate a node to a new position in canvas coordinates
+    MoveTo(f32, f32),
+
+    // A wire being dragged from a socket was released over the hovered entity
+    DropWire(Entity, Entity),
+
+    // Attach or remove a probe on an output socket
+    ToggleProbe(Entity),
 }
 
 #[derive(PartialEq, Clone)]
@@ -36,13 +88,41 @@ pub struct NodeDesc {
     pub name: String,
     pub inputs: Vec<String>,
     pub outputs: Vec<String>,
+    pub category: String,
+}
+
+impl NodeDesc {
+    // Returns the inputs and outputs in the same form as the sarus declaration, e.g. "(a, b) -> (c)"
+    pub fn signature(&self) -> String {
+        format!("({}) -> ({})", self.inputs.join(", "), self.outputs.join(", "))
+    }
 }
 
 #[derive(PartialEq)]
 pub enum AppEvent {
     AddNode(NodeDesc),
-    InsertNode(String),
+    // Insert a node by name at a position in canvas coordinates
+    InsertNode(String, (f32, f32)),
+    OpenPalette,
     Run,
+    // Sent every frame while a run is in progress to check on the worker
+    PollJob,
+    CancelRun,
+    TogglePlay,
+    AutoLayout,
+    Bench,
+    // A parameter slider was moved, setting the parameter with this name
+    SetParameter(String, f64),
+    // A note played on the on-screen keyboard
+    Midi(MidiMessage),
+    // The grid of a sequencer node was edited
+    SetPattern(Entity, Pattern),
+    // A transport control was changed
+    SetTransport(Transport),
+    // Move the transport back to the start
+    Rewind,
+    // A sample player, wavetable oscillator or convolution node was given a different file
+    SetSample(Entity, String),
 }
 
 #[derive(Debug)]
@@ -61,68 +141,159 @@ pub struct ConnectionDesc {
     input_socket: Entity,
 }
 
+/// Receives each newly compiled graph when the editor is embedded in another program, such as a plugin
+pub trait GraphHost: Send {
+    fn set_graph(&mut self, patch: Patch, program: Program);
+
+    /// Changes a parameter of the graph being played without recompiling it
+    fn set_parameter(&mut self, _name: &str, _value: f64) {}
+
+    // Called with each note played on the keyboard
+    fn send_midi(&mut self, _message: MidiMessage) {}
+}
+
+// Number of blocks rendered when the graph is run
+const STEPS: usize = 5 * SAMPLE_RATE as usize / STEP_SIZE;
+
 // Where everything lives
 // TODO - Rename me
 pub struct NodeApp {
-    graph: Graph,
+    graph: Program,
     node_view: Entity,
-    menu: Entity,
+    palette: Entity,
+    analysis_panel: Entity,
+    bench_panel: Entity,
+    transport_bar: Entity,
+    keyboard: Entity,
     node_descriptions: HashMap<String, NodeDesc>,
     code: String,
 
+    // Socket of a wire dropped onto empty space, to be connected to the next inserted node
+    pending_wire: Option<Entity>,
+
+    // Probe widgets keyed by the output socket they record
+    probes: HashMap<Entity, Entity>,
+
+    // Compile and render running in the background
+    job: Option<RenderJob>,
+    bench_job: Option<BenchJob>,
+    progress: Entity,
+    // Sockets of the probes included in the running job, in the order of its probe patches
+    probe_sockets: Vec<Entity>,
+    // Patch of the running job, handed to the host along with the program compiled for it
+    job_patch: Option<Patch>,
+
+    // Live playback, created on first use
+    backend: BackendKind,
+    crossfade: Duration,
+    engine: Option<Engine>,
+
+    // Set when embedded, in which case the compiled graph is handed over instead of only being rendered
+    host: Option<Box<dyn GraphHost>>,
+    // Patch to show instead of the default INPUT -> OUTPUT wire
+    initial_patch: Option<Patch>,
+
+    // Played into offline renders
+    midi: Vec<MidiEvent>,
+    // MIDI input port the engine listens to while playing
+    midi_port: Option<String>,
+
+    // Name, range and value of each parameter node, keyed by the node
+    parameters: HashMap<Entity, ParameterSpec>,
+    // Steps of each sequencer node, keyed by the node
+    patterns: HashMap<Entity, Pattern>,
+    // File read by each sample player, wavetable oscillator and convolution node, keyed by the node
+    samples: HashMap<Entity, String>,
+    // Rate set in the patch file for a sarus node, kept so saving doesn't lose it
+    rates: HashMap<Entity, Rate>,
+    // Id of each node in the patch. Loaded nodes keep theirs so their state carries over when recompiled.
+    ids: HashMap<Entity, String>,
+    // Automation of the loaded patch, kept as it is since the editor has no controls for it
+    automation: Vec<Automation>,
+    // Voices of the loaded patch, also kept as they are
+    polyphony: Option<Polyphony>,
+    // Threads the loaded patch is processed on, also kept as it is
+    threads: Option<usize>,
+    transport: Transport,
+
     nodes: Vec<NodeDesc2>,
     connections: Vec<ConnectionDesc>,
 }
 
 impl NodeApp {
-    pub fn new(code: &str) -> Self {
-        
-        let nodes = vec![
-            Node {
-                func_name: "INPUT".to_string(),
-                id: "INPUT".to_string(),
-                port_defaults: vec![0.0],
-                position: (0.0, 0.0),
-            },
-            Node {
-                func_name: "OUTPUT".to_string(),
-                id: "OUTPUT".to_string(),
-                port_defaults: vec![0.0],
-                position: (0.0, 0.0),
-            },
-            Node {
-                func_name: "COUNTER".to_string(),
-                id: "COUNTER".to_string(),
-                port_defaults: vec![0.0],
-                position: (0.0, 0.0),
-            },
-        ];
-
-        let connections = vec![
-            Connection {
-                src_node: 0,
-                dst_node: 1,
-                src_port: 0,
-                dst_port: 0,
-            },
-        ];
-
-        println!("Nodes: {:?}", nodes);
-        println!("Connections: {:?}", connections);
+    pub fn new(code: &str, backend: BackendKind) -> Self {
+        let patch = Patch::passthrough(code);
 
         Self {
-            graph: Graph::new(code.to_string(), nodes, connections, STEP_SIZE).unwrap(),
+            graph: patch.compile().unwrap(),
             node_view: Entity::null(),
-            menu: Entity::null(),
+            palette: Entity::null(),
+            analysis_panel: Entity::null(),
+            bench_panel: Entity::null(),
+            transport_bar: Entity::null(),
+            keyboard: Entity::null(),
             node_descriptions: HashMap::new(),
             code: code.to_string(),
+            pending_wire: None,
+            probes: HashMap::new(),
+            job: None,
+            bench_job: None,
+            progress: Entity::null(),
+            probe_sockets: Vec::new(),
+            job_patch: None,
+            backend,
+            crossfade: DEFAULT_CROSSFADE,
+            engine: None,
+            host: None,
+            initial_patch: None,
+            midi: Vec::new(),
+            midi_port: None,
+            parameters: HashMap::new(),
+            patterns: HashMap::new(),
+            samples: HashMap::new(),
+            rates: HashMap::new(),
+            ids: HashMap::new(),
+            automation: Vec::new(),
+            polyphony: None,
+            threads: None,
+            transport: Transport::default(),
             nodes: Vec::new(),
             connections: Vec::new(),
         }
     }
 
-    pub fn compile(&mut self) {
-        println!("Compile: {} {}", self.nodes.len(), self.connections.len());
+    // Sets how long the playing graph is crossfaded into the newly compiled one
+    pub fn with_crossfade(mut self, crossfade: Duration) -> Self {
+        self.crossfade = crossfade;
+        self
+    }
+
+    // Opens the editor with an existing patch
+    pub fn with_patch(mut self, patch: Patch) -> Self {
+        self.initial_patch = Some(patch);
+        self
+    }
+
+    // Plays MIDI, e.g. read from a MIDI file, into every render
+    pub fn with_midi(mut self, midi: Vec<MidiEvent>) -> Self {
+        self.midi = midi;
+        self
+    }
+
+    // Listens to a MIDI input port while playing, see `Engine::set_midi_input`
+    pub fn with_midi_input(mut self, port: Option<String>) -> Self {
+        self.midi_port = port;
+        self
+    }
+
+    // Sends every compiled graph to a host
+    pub fn with_host(mut self, host: Box<dyn GraphHost>) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    // Converts the nodes and connections of the editor into a patch
+    pub fn patch(&self) -> Patch {
         let nodes = self.nodes.iter().map(|node_desc| {
             let mut defaults = node_desc.inputs.iter().map(|_| 0.0f64).collect::<Vec<_>>();
             defaults.append(&mut node_desc.outputs.iter().map(|_|0.0f64).collect::<Vec<_>>());
@@ -133,14 +304,18 @@ impl NodeApp {
             } else if node_desc.name == "COUNTER" {
                 "COUNTER".to_string()
             } else {
-                node_desc.entity.to_string()
+                self.ids.get(&node_desc.entity).cloned().unwrap_or_else(|| node_desc.entity.to_string())
             };
 
-            Node {
+            PatchNode {
                 func_name: node_desc.name.clone(),
                 id,
                 port_defaults: defaults,
                 position: (0.0, 0.0),
+                parameter: self.parameters.get(&node_desc.entity).cloned(),
+                pattern: self.patterns.get(&node_desc.entity).cloned(),
+                sample: self.samples.get(&node_desc.entity).cloned(),
+                rate: self.rates.get(&node_desc.entity).copied(),
             }
         }).collect::<Vec<_>>();
         println!("{:?} {:?}", self.nodes, self.connections);
@@ -149,68 +324,487 @@ impl NodeApp {
             let src_port = self.nodes.iter().find(|node_desc| node_desc.entity == con_desc.source).unwrap().outputs.iter().position(|&id| id == con_desc.output_socket).unwrap();
             let dst_port = self.nodes.iter().find(|node_desc| node_desc.entity == con_desc.dest).unwrap().inputs.iter().position(|&id| id == con_desc.input_socket).unwrap();
             
-            Connection {
+            PatchConnection {
                 src_node: self.nodes.iter().position(|node_desc| node_desc.entity == con_desc.source).unwrap(),
                 dst_node: self.nodes.iter().position(|node_desc| node_desc.entity == con_desc.dest).unwrap(),
                 src_port,
                 dst_port,
             }
         }).collect::<Vec<_>>();
-        
-        println!("Nodes: {:?}", nodes);
-        println!("Connections: {:?}", connections);
 
-        self.graph = Graph::new(self.code.clone(), nodes, connections, STEP_SIZE).expect("Failed to rebuild graph");
+        Patch {
+            code: self.code.clone(),
+            nodes,
+            connections,
+            automation: self.automation.clone(),
+            polyphony: self.polyphony.clone(),
+            transport: Some(self.transport.clone()).filter(|transport| *transport != Transport::default()),
+            threads: self.threads,
+        }
+    }
 
-        self.run();
-        
+    // Converts the editor into a patch which includes where each node has been placed
+    fn patch_with_layout(&self, state: &State) -> Patch {
+        let mut patch = self.patch();
+        for (patch_node, node_desc) in patch.nodes.iter_mut().zip(self.nodes.iter()) {
+            let left = state.style.left.get(node_desc.entity);
+            let top = state.style.top.get(node_desc.entity);
+            if let (Some(Pixels(x)), Some(Pixels(y))) = (left, top) {
+                patch_node.position = (*x, *y);
+            }
+        }
+        patch
     }
 
-    pub fn run(&mut self) -> anyhow::Result<()> {
+    // Starts compiling and rendering the graph on a worker thread, cancelling any run in progress.
+    // The current graph keeps being used until the new one is ready.
+    pub fn compile(&mut self, state: &mut State) {
+        println!("Compile: {} {}", self.nodes.len(), self.connections.len());
+        let patch = self.patch_with_layout(state);
+
+        println!("Nodes: {:?}", patch.nodes);
+        println!("Connections: {:?}", patch.connections);
 
-        for d in &self.graph.ast {
-            println!("{}", d);
+        if let Some(job) = self.job.take() {
+            job.cancel();
         }
 
-        const STEPS: usize = 5 * 48000 / STEP_SIZE;
-        //let mut output_arr = [[0.0f64; STEP_SIZE]; STEPS];
-        let mut output_arr = vec![vec![0.0f64; STEP_SIZE]; STEPS];
-        let mut n = 0;
-        let mut step_count = 0;
-        for i in 0..STEPS {
-            let mut audio_buffer = [0.0f64; STEP_SIZE];
-            let count = n;
-            for j in 0..STEP_SIZE {
-                audio_buffer[j] = ((n as f64).powi(2) * 0.000001).sin(); //sound source is sine sweep
-                n += 1;
+        let (sockets, probes): (Vec<_>, Vec<_>) = self.probe_outputs().into_iter().unzip();
+        self.probe_sockets = sockets;
+
+        // A host or a playing engine is handed the graph when the job finishes, as a fresh program which
+        // carries on from the one it replaces rather than the one the preview has already run. Both are
+        // compiled by the job so the editor doesn't wait for the compiler.
+        let wav_path = match self.host {
+            Some(_) => None,
+            None => Some(PathBuf::from("graph_test.wav")),
+        };
+        let live = self.host.is_some() || self.engine.as_ref().map_or(false, Engine::is_running);
+
+        self.job = Some(RenderJob::spawn(patch.clone(), probes, STEPS, self.midi.clone(), wav_path, live));
+        self.job_patch = Some(patch);
+
+        self.node_view.emit_to(state, self.progress, ProgressEvent::Show);
+    }
+
+    // Starts streaming the current patch to the audio backend, or stops it if it's already playing
+    fn toggle_play(&mut self) -> anyhow::Result<()> {
+        if let Some(engine) = &mut self.engine {
+            if engine.is_running() {
+                println!("Stopped {}", engine.backend_name());
+                return engine.stop();
             }
-            unsafe { run_fn(&mut self.graph.jit, "graph", (&mut audio_buffer, count as f64))?}; 
-            //Collect output audio
-            output_arr[i] = audio_buffer.to_vec();
-        }
-
-        //Flatten output audio chunks for saving as wav
-        let flat = output_arr
-        .iter()
-        .flatten()
-        .map(|x| *x)
-        .collect::<Vec<f64>>();
-        self.write_wav(&flat, "graph_test.wav");
+        }
+
+        let graph = self.patch().compile()?;
+        let latency = graph.latency();
+
+        if self.engine.is_none() {
+            let mut engine = Engine::new(self.backend.create()?);
+            engine.set_crossfade(self.crossfade);
+            engine.set_midi_input(self.midi_port.clone());
+            self.engine = Some(engine);
+        }
+
+        let engine = self.engine.as_mut().unwrap();
+        engine.start(graph)?;
+        println!("Playing on {}", engine.backend_name());
+        if latency > 0 {
+            println!("The graph has {} samples of latency", latency);
+        }
+
         Ok(())
     }
 
-    fn write_wav(&self, samples: &[f64], path: &str) {
-        let spec = hound::WavSpec {
-            channels: 1,
-            sample_rate: 48000,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
+    // Checks on the running jobs, swapping in the new graph or showing the benchmark once they have finished
+    fn poll_job(&mut self, state: &mut State) {
+        // Both are polled so a finished one is picked up while the other is still running
+        let render = self.poll_render(state);
+        let bench = self.poll_bench(state);
+        match render.or(bench) {
+            Some(progress) => self.node_view.emit_to(state, self.progress, ProgressEvent::SetProgress(progress)),
+            None => self.node_view.emit_to(state, self.progress, ProgressEvent::Hide),
+        }
+    }
+
+    // Returns the progress of the render if it's still running
+    fn poll_render(&mut self, state: &mut State) -> Option<f32> {
+        let status = match &self.job {
+            Some(job) => job.poll(),
+            None => return None,
         };
-        let mut writer = hound::WavWriter::create(path, spec).unwrap();
-        for sample in samples {
-            writer.write_sample(*sample as f32).unwrap();
+
+        match status {
+            JobStatus::Running(progress) => return Some(progress),
+
+            JobStatus::Finished(Ok(result)) => {
+                for d in &result.program.graph().ast {
+                    println!("{}", d);
+                }
+                self.node_view.emit_to(state, self.analysis_panel, AnalysisEvent::Optimized(result.program.optimization().clone()));
+                self.node_view.emit_to(state, self.analysis_panel, AnalysisEvent::Update(result.analysis));
+
+                self.graph = result.program;
+
+                if let (Some(program), Some(patch)) = (result.live, self.job_patch.take()) {
+                    if let Some(host) = &mut self.host {
+                        host.set_graph(patch, program);
+                    } else if let Some(engine) = self.engine.as_mut().filter(|engine| engine.is_running()) {
+                        engine.set_graph(program);
+                    }
+                }
+
+                for (socket, samples) in self.probe_sockets.iter().zip(result.probes.into_iter()) {
+                    // The probe may have been removed while the job was running
+                    if let Some(&probe) = self.probes.get(socket) {
+                        self.node_view.emit_to(state, probe, ProbeEvent::Set(samples));
+                    }
+                }
+            }
+
+            JobStatus::Finished(Err(err)) => println!("Failed to run graph: {}", err),
+
+            JobStatus::Cancelled => {}
+        }
+
+        self.job = None;
+        self.job_patch = None;
+        None
+    }
+
+    // Returns the progress of the benchmark if it's still running
+    fn poll_bench(&mut self, state: &mut State) -> Option<f32> {
+        let status = match &self.bench_job {
+            Some(job) => job.poll(),
+            None => return None,
+        };
+
+        match status {
+            JobStatus::Running(progress) => return Some(progress),
+
+            JobStatus::Finished(Ok(report)) => {
+                print!("{}", report);
+                self.node_view.emit_to(state, self.bench_panel, BenchEvent::Update(report));
+            }
+
+            JobStatus::Finished(Err(err)) => println!("Failed to benchmark graph: {}", err),
+
+            JobStatus::Cancelled => {}
+        }
+
+        self.bench_job = None;
+        None
+    }
+
+    // The benchmark runs on a worker like the render, and replaces one which is already running
+    fn bench(&mut self, state: &mut State) {
+        if let Some(job) = self.bench_job.take() {
+            job.cancel();
+        }
+        self.bench_job = Some(BenchJob::spawn(self.patch()));
+        self.node_view.emit_to(state, self.progress, ProgressEvent::Show);
+    }
+
+    fn cancel_job(&mut self, state: &mut State) {
+        if let Some(job) = self.job.take() {
+            job.cancel();
+        }
+        self.job_patch = None;
+        if let Some(job) = self.bench_job.take() {
+            job.cancel();
+        }
+        self.node_view.emit_to(state, self.progress, ProgressEvent::Hide);
+    }
+
+    // The node index and port in the patch of each probed output socket
+    fn probe_outputs(&self) -> Vec<(Entity, (usize, usize))> {
+        self.probes.keys()
+            .filter_map(|&socket| {
+                self.nodes.iter().enumerate().find_map(|(node_index, node_desc)| {
+                    node_desc.outputs.iter().position(|&output| output == socket).map(|port| (socket, (node_index, port)))
+                })
+            })
+            .collect()
+    }
+
+    fn toggle_probe(&mut self, state: &mut State, socket: Entity) {
+        if let Some(probe) = self.probes.remove(&socket) {
+            state.remove(probe);
+            return;
+        }
+
+        let node = self.nodes.iter().find(|node_desc| node_desc.outputs.contains(&socket)).map(|node_desc| node_desc.entity);
+
+        if let Some(node) = node {
+            let probe = ProbeWidget::new().build(state, node, |builder| builder);
+            self.probes.insert(socket, probe);
+        }
+    }
+
+    // Creates a node widget for a function at a position in canvas coordinates, returning its index in `nodes`.
+    // Parameter nodes are given a new parameter unless one is passed in.
+    // A node loaded from a patch brings its parameter, pattern or sample with it, new nodes start from the defaults
+    fn insert_node(&mut self, state: &mut State, name: &str, (x, y): (f32, f32), saved: Option<&PatchNode>) -> Option<usize> {
+        let node_desc = self.node_descriptions.get(name)?;
+
+        // Create the node from the description
+
+        let node = NodeWidget::new(name).build(state, self.node_view, |builder| 
+            builder
+                .set_left(Pixels(x))
+                .set_top(Pixels(y))
+        );
+
+        let mut node_desc2 = NodeDesc2 {
+            entity: node,
+            name: name.to_string(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        };
+
+        // let graph_node = Node {
+        //     func_name: node_desc.name.clone(),
+        //     id: node.to_string(),
+        //     port_defaults: node_desc.inputs.iter().map(|_| 0.0).collect(),
+        //     position: (0.0, 0.0),
+        // };
+
+        // self.graph.nodes.push(graph_node);
+
+        for (index, param) in node_desc.inputs.iter().enumerate() {
+
+            let row = Row::new().build(state, node, |builder| 
+                builder
+                    .set_height(Pixels(30.0))
+                    .set_child_space(Stretch(1.0))
+            );
+        
+            let input_socket = InputSocket::new().build(state, row, |builder| 
+                builder
+                    .set_left(Pixels(-10.0))
+                    .set_right(Stretch(0.0))
+            );
+        
+            Label::new(&param).build(state, row, |builder| 
+                builder
+                    .set_child_space(Stretch(1.0))
+                    .set_child_left(Pixels(5.0))
+                    .set_space(Pixels(0.0))
+                    .set_hoverable(false)
+            );
+
+            node_desc2.inputs.push(input_socket);
+        }
+
+        for (index, ret) in node_desc.outputs.iter().enumerate() {
+            let row = Row::new().build(state, node, |builder| 
+                    builder
+                        .set_height(Pixels(30.0))
+                        .set_child_space(Stretch(1.0))
+                );
+    
+            Label::new(&ret).build(state, row, |builder| 
+                builder
+                    .set_child_space(Stretch(1.0))
+                    .set_child_right(Pixels(5.0))
+                    .set_space(Pixels(0.0))
+                    .set_hoverable(false)
+            );
+    
+            let output_socket = OutputSocket::new().build(state, row, |builder| 
+                builder
+                    .set_left(Stretch(0.0))
+                    .set_right(Pixels(-10.0))
+            );
+
+            node_desc2.outputs.push(output_socket);
+        }
+
+        if name == PARAMETER_NODE {
+            let spec = saved.and_then(|saved| saved.parameter.clone()).unwrap_or_else(|| self.new_parameter_spec());
+            ParameterSlider::new(spec.clone()).build(state, node, |builder| builder);
+            self.parameters.insert(node, spec);
+        }
+
+        if name == SEQUENCER_NODE {
+            let pattern = saved.and_then(|saved| saved.pattern.clone()).unwrap_or_default();
+            SequencerGrid::new(pattern.clone(), node).build(state, node, |builder| builder);
+            self.patterns.insert(node, pattern);
+        }
+
+        // A convolution node's impulse response is chosen and shown the same way as a sample
+        if name == SAMPLE_PLAYER_NODE || name == CONVOLUTION_NODE {
+            let path = saved.and_then(|saved| saved.sample.clone());
+            SampleView::new(path.clone(), node).build(state, node, |builder| builder);
+            if let Some(path) = path {
+                self.samples.insert(node, path);
+            }
+        }
+
+        if name == WAVETABLE_NODE {
+            let path = saved.and_then(|saved| saved.sample.clone());
+            WavetableView::new(path.clone(), node).build(state, node, |builder| builder);
+            if let Some(path) = path {
+                self.samples.insert(node, path);
+            }
+        }
+
+        if let Some(rate) = saved.and_then(|saved| saved.rate) {
+            self.rates.insert(node, rate);
+        }
+
+        let id = match saved {
+            Some(saved) => saved.id.clone(),
+            None => self.new_node_id(node),
+        };
+        self.ids.insert(node, id);
+
+        self.nodes.push(node_desc2);
+
+        Some(self.nodes.len() - 1)
+    }
+
+    // An id for a node created in the editor which no loaded node already has
+    fn new_node_id(&self, node: Entity) -> String {
+        let taken = |id: &String| self.ids.values().any(|other| other == id);
+        let id = node.to_string();
+        if !taken(&id) {
+            return id;
+        }
+
+        (1..).map(|i| format!("{}_{}", id, i)).find(|id| !taken(id)).unwrap()
+    }
+
+    // A parameter with a name no other parameter node uses
+    fn new_parameter_spec(&self) -> ParameterSpec {
+        let name = (1..)
+            .map(|i| format!("param_{}", i))
+            .find(|name| !self.parameters.values().any(|spec| &spec.name == name))
+            .unwrap();
+
+        ParameterSpec { name, ..ParameterSpec::default() }
+    }
+
+    // Sets a parameter from its slider. The value becomes its default so renders use it too,
+    // and the graph being played is updated without recompiling.
+    fn set_parameter(&mut self, name: &str, value: f64) {
+        for spec in self.parameters.values_mut().filter(|spec| spec.name == name) {
+            spec.default = spec.clamp(value);
+        }
+
+        if let Some(engine) = &mut self.engine {
+            if engine.is_running() {
+                if let Err(err) = engine.set_parameter(name, value) {
+                    println!("Failed to set parameter: {}", err);
+                }
+            }
+        }
+
+        if let Some(host) = &mut self.host {
+            host.set_parameter(name, value);
+        }
+    }
+
+    // Plays a note from the keyboard into the running graph
+    fn send_midi(&mut self, message: MidiMessage) {
+        if let Some(engine) = &self.engine {
+            if engine.is_running() {
+                engine.send_midi(message);
+            }
+        }
+
+        if let Some(host) = &mut self.host {
+            host.send_midi(message);
+        }
+    }
+
+    // Recreates the nodes and connections of a patch, reusing the INPUT, OUTPUT and COUNTER nodes the editor starts with
+    fn load_patch(&mut self, state: &mut State, patch: &Patch) -> anyhow::Result<()> {
+        // The palette is filled after the editor is built so the patch's own code describes its nodes
+        let mut library = Library::parse("Patch", &patch.code)?;
+        library.extend(Library::native());
+        for node in library.nodes {
+            self.node_descriptions.entry(node.name.clone()).or_insert(node);
+        }
+
+        self.automation = patch.automation.clone();
+        self.polyphony = patch.polyphony.clone();
+        self.threads = patch.threads;
+        self.transport = patch.transport.clone().unwrap_or_default();
+        self.transport_bar.emit_to(state, self.transport_bar, TransportEvent::Set(self.transport.clone()));
+
+        let mut indices = Vec::new();
+        for patch_node in patch.nodes.iter() {
+            let index = match self.nodes.iter().position(|node_desc| node_desc.name == patch_node.id) {
+                Some(index) => index,
+                None => self.insert_node(state, &patch_node.func_name, patch_node.position, Some(patch_node))
+                    .ok_or_else(|| anyhow::anyhow!("Unknown node {} in patch", patch_node.func_name))?,
+            };
+            indices.push(index);
+        }
+
+        for connection in patch.connections.iter() {
+            let output_socket = self.nodes[indices[connection.src_node]].outputs.get(connection.src_port).copied();
+            let input_socket = self.nodes[indices[connection.dst_node]].inputs.get(connection.dst_port).copied();
+            if let (Some(output_socket), Some(input_socket)) = (output_socket, input_socket) {
+                input_socket.emit_to(state, output_socket, NodeEvent::ConnectInput);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the centre of the node view in canvas coordinates
+    fn view_centre(&self, state: &mut State) -> (f32, f32) {
+        let nx = state.data.get_posx(self.node_view);
+        let ny = state.data.get_posy(self.node_view);
+        let nw = state.data.get_width(self.node_view);
+        let nh = state.data.get_height(self.node_view);
+
+        let mut transform = state.data.get_transform(self.node_view);
+        transform.inverse();
+
+        transform.transform_point(nx + nw / 2.0, ny + nh / 2.0)
+    }
+
+    // Returns the position of the mouse cursor in canvas coordinates
+    fn cursor_position(&self, state: &mut State) -> (f32, f32) {
+        let mut transform = state.data.get_transform(self.node_view);
+        transform.inverse();
+
+        let (x, y) = transform.transform_point(state.mouse.cursorx, state.mouse.cursory);
+
+        (x - state.data.get_posx(self.node_view), y - state.data.get_posy(self.node_view))
+    }
+
+    fn is_output_socket(&self, socket: Entity) -> bool {
+        self.nodes.iter().any(|node_desc| node_desc.outputs.contains(&socket))
+    }
+
+    // Arrange the nodes in columns from the sources to OUTPUT
+    fn auto_layout(&mut self, state: &mut State) {
+        let sizes = self.nodes.iter().map(|node_desc| {
+            (state.data.get_width(node_desc.entity), state.data.get_height(node_desc.entity))
+        }).collect::<Vec<_>>();
+
+        let edges = self.connections.iter().filter_map(|con_desc| {
+            let src = self.nodes.iter().position(|node_desc| node_desc.entity == con_desc.source)?;
+            let dst = self.nodes.iter().position(|node_desc| node_desc.entity == con_desc.dest)?;
+            Some((src, dst))
+        }).collect::<Vec<_>>();
+
+        let sinks = self.nodes.iter().enumerate()
+            .filter(|(_, node_desc)| node_desc.name == "OUTPUT")
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+
+        let positions = layered_layout(&sizes, &edges, &sinks);
+
+        let (cx, cy) = self.view_centre(state);
+
+        for (node_desc, (x, y)) in self.nodes.iter().zip(positions.into_iter()) {
+            self.node_view.emit_to(state, node_desc.entity, NodeEvent::MoveTo(cx + x, cy + y));
         }
-        writer.finalize().unwrap();
     }
 }
 
@@ -220,24 +814,23 @@ impl Widget for NodeApp {
 
     fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
 
-        let popup = Popup::new()
-            .build(state, entity, |builder| {
-                builder
-                    .set_width(Pixels(100.0))
-                    .set_height(Auto)
-                    .set_z_order(10)
-            });
-
-        self.menu = List::new()
-            .build(state, popup, |builder| {
-                builder
-                    .set_height(Auto)
-            });
-
         self.node_view = NodeView::new().build(state, entity, |builder| {
             builder
         });
 
+        self.transport_bar = TransportBar::new().build(state, entity, |builder| builder);
+
+        // Docked under the node view, which passes it the keys pressed over the graph
+        self.keyboard = Keyboard::new().build(state, entity, |builder| builder);
+
+        self.palette = NodePalette::new().build(state, entity, |builder| builder);
+
+        self.analysis_panel = AnalysisPanel::new().build(state, entity, |builder| builder);
+
+        self.bench_panel = BenchPanel::new().build(state, entity, |builder| builder);
+
+        self.progress = ProgressWidget::new().build(state, entity, |builder| builder);
+
 
         
 
@@ -358,7 +951,15 @@ impl Widget for NodeApp {
         self.nodes.push(node_desc2);
 
 
-        dst_socket.emit_to(state, src_socket, NodeEvent::ConnectInput);
+        match self.initial_patch.take() {
+            Some(patch) => {
+                if let Err(err) = self.load_patch(state, &patch) {
+                    println!("Failed to load patch: {}", err);
+                }
+            }
+
+            None => dst_socket.emit_to(state, src_socket, NodeEvent::ConnectInput),
+        }
 
 
         self.node_view
@@ -369,119 +970,90 @@ impl Widget for NodeApp {
             match app_event {
 
                 AppEvent::Run => {
-                    self.compile();
-                    //self.run().expect("Failed to run");
+                    self.compile(state);
                 }
 
-                AppEvent::AddNode(node) => {
-
-                    let node_name = node.name.clone();
+                AppEvent::TogglePlay => {
+                    if let Err(err) = self.toggle_play() {
+                        println!("Failed to play graph: {}", err);
+                    }
+                }
 
-                    self.node_descriptions.insert(node.name.clone(), node.clone());
+                AppEvent::PollJob => {
+                    self.poll_job(state);
+                }
 
-                    // Add a button to the menu from the node description
+                AppEvent::CancelRun => {
+                    self.cancel_job(state);
+                }
 
-                    Button::with_label(&node.name)
-                        .on_release(move |_, state, button| {
-                            button.emit(state, AppEvent::InsertNode(node_name.clone()));
-                            button.emit(state, PopupEvent::Close);
-                        })
-                        .build(state, self.menu, |builder| 
-                            builder
-                    );
+                AppEvent::AutoLayout => {
+                    self.auto_layout(state);
                 }
 
-                AppEvent::InsertNode(name) => {
-                    if let Some(node_desc) = self.node_descriptions.get(name) {
+                AppEvent::Bench => {
+                    self.bench(state);
+                }
 
+                AppEvent::SetParameter(name, value) => {
+                    self.set_parameter(name, *value);
+                }
 
-                        let nx = state.data.get_posx(self.node_view);
-                        let ny = state.data.get_posy(self.node_view);
-                        let nw = state.data.get_width(self.node_view);
-                        let nh = state.data.get_height(self.node_view);
+                AppEvent::Midi(message) => {
+                    self.send_midi(*message);
+                }
 
-                        let mut transform = state.data.get_transform(self.node_view);
-                        transform.inverse();
+                // Takes effect the next time the graph is run
+                AppEvent::SetPattern(node, pattern) => {
+                    self.patterns.insert(*node, pattern.clone());
+                }
 
-                        let (x, y) = transform.transform_point(nx + nw / 2.0, ny + nh / 2.0);
-                        
+                // A plugin follows the host's transport, so the settings are only saved with the patch
+                AppEvent::SetTransport(transport) => {
+                    self.transport = transport.clone();
+                    if let Some(engine) = &self.engine {
+                        engine.set_transport(transport.clone());
+                    }
+                }
 
-                        // Create the node from the description
+                // Takes effect the next time the graph is run
+                AppEvent::SetSample(node, path) => {
+                    self.samples.insert(*node, path.clone());
+                }
 
-                        let node = NodeWidget::new(&name).build(state, self.node_view, |builder| 
-                            builder
-                                .set_left(Pixels(x))
-                                .set_top(Pixels(y))
-                        );
+                AppEvent::Rewind => {
+                    if let Some(engine) = &self.engine {
+                        engine.locate(0.0, 0.0);
+                    }
+                }
 
-                        let mut node_desc2 = NodeDesc2 {
-                            entity: node,
-                            name: name.clone(),
-                            inputs: Vec::new(),
-                            outputs: Vec::new(),
-                        };
+                AppEvent::AddNode(node) => {
 
-                        // let graph_node = Node {
-                        //     func_name: node_desc.name.clone(),
-                        //     id: node.to_string(),
-                        //     port_defaults: node_desc.inputs.iter().map(|_| 0.0).collect(),
-                        //     position: (0.0, 0.0),
-                        // };
+                    self.node_descriptions.insert(node.name.clone(), node.clone());
 
-                        // self.graph.nodes.push(graph_node);
-            
-                        for (index, param) in node_desc.inputs.iter().enumerate() {
-            
-                            let row = Row::new().build(state, node, |builder| 
-                                builder
-                                    .set_height(Pixels(30.0))
-                                    .set_child_space(Stretch(1.0))
-                            );
-                        
-                            let input_socket = InputSocket::new().build(state, row, |builder| 
-                                builder
-                                    .set_left(Pixels(-10.0))
-                                    .set_right(Stretch(0.0))
-                            );
-                        
-                            Label::new(&param).build(state, row, |builder| 
-                                builder
-                                    .set_child_space(Stretch(1.0))
-                                    .set_child_left(Pixels(5.0))
-                                    .set_space(Pixels(0.0))
-                                    .set_hoverable(false)
-                            );
-
-                            node_desc2.inputs.push(input_socket);
-                        }
-            
-                        for (index, ret) in node_desc.outputs.iter().enumerate() {
-                            let row = Row::new().build(state, node, |builder| 
-                                    builder
-                                        .set_height(Pixels(30.0))
-                                        .set_child_space(Stretch(1.0))
-                                );
-                    
-                            Label::new(&ret).build(state, row, |builder| 
-                                builder
-                                    .set_child_space(Stretch(1.0))
-                                    .set_child_right(Pixels(5.0))
-                                    .set_space(Pixels(0.0))
-                                    .set_hoverable(false)
-                            );
-                    
-                            let output_socket = OutputSocket::new().build(state, row, |builder| 
-                                builder
-                                    .set_left(Stretch(0.0))
-                                    .set_right(Pixels(-10.0))
-                            );
-
-                            node_desc2.outputs.push(output_socket);
-                        }
+                    // Make the node available in the palette
+                    entity.emit_to(state, self.palette, PaletteEvent::AddEntry(node.clone()));
+                }
 
-                        self.nodes.push(node_desc2);
+                AppEvent::OpenPalette => {
+                    self.pending_wire = None;
+                    let (x, y) = self.cursor_position(state);
+                    entity.emit_to(state, self.palette, PaletteEvent::Open(x, y, PaletteFilter::All));
+                }
 
-                        //self.run().expect("Failed to compile and run graph");
+                AppEvent::InsertNode(name, (x, y)) => {
+                    if let Some(index) = self.insert_node(state, name, (*x, *y), None) {
+                        // Connect a wire which was dropped onto empty space to the first compatible socket
+                        if let Some(socket) = self.pending_wire.take() {
+                            let node_desc2 = &self.nodes[index];
+                            if self.is_output_socket(socket) {
+                                if let Some(&input_socket) = node_desc2.inputs.first() {
+                                    input_socket.emit_to(state, socket, NodeEvent::ConnectInput);
+                                }
+                            } else if let Some(&output_socket) = node_desc2.outputs.first() {
+                                socket.emit_to(state, output_socket, NodeEvent::ConnectInput);
+                            }
+                        }
                     }
                 }
 
@@ -498,6 +1070,26 @@ impl Widget for NodeApp {
                     println!("Add. Connections: {:?}", self.connections);
                 }
 
+                NodeEvent::ToggleProbe(socket) => {
+                    self.toggle_probe(state, *socket);
+                }
+
+                NodeEvent::DropWire(socket, hovered) => {
+                    // Only wires dropped onto the background of the node view open the palette
+                    let empty_space = *hovered == self.node_view || Some(*hovered) == self.node_view.parent(&state.tree);
+                    if empty_space {
+                        let filter = if self.is_output_socket(*socket) {
+                            PaletteFilter::HasInputs
+                        } else {
+                            PaletteFilter::HasOutputs
+                        };
+
+                        self.pending_wire = Some(*socket);
+                        let (x, y) = self.cursor_position(state);
+                        entity.emit_to(state, self.palette, PaletteEvent::Open(x, y, filter));
+                    }
+                }
+
                 NodeEvent::RemoveConnection(con_desc) => {
                     //println!("Remove {:?}", con_desc);
                     //self.connections.remove(&con_desc.input_socket);
@@ -510,14 +1102,43 @@ impl Widget for NodeApp {
             }
         }
 
+        if let Some(keyboard_event) = event.message.downcast::<KeyboardEvent>() {
+            // Keys pressed over the node view are played on the keyboard
+            if event.target != self.keyboard {
+                entity.emit_to(state, self.keyboard, keyboard_event.clone());
+            }
+        }
+
         if let Some(window_event) = event.message.downcast() {
             match window_event {
                 WindowEvent::MouseUp(button) if *button == MouseButton::Right => {
-                    self.menu.emit(state, PopupEvent::OpenAtCursor);
+                    self.pending_wire = None;
+                    let (x, y) = self.cursor_position(state);
+                    entity.emit_to(state, self.palette, PaletteEvent::Open(x, y, PaletteFilter::All));
+                }
+
+                WindowEvent::MouseDown(_) => {
+                    // Clicking anywhere outside of the palette closes it
+                    if !is_within(state, event.target, self.palette) {
+                        entity.emit_to(state, self.palette, PaletteEvent::Close);
+                    }
                 }
 
                 _=> {}
             }
         }
     }
-}
\ No newline at end of file
+}
+
+// Returns true if the entity is the ancestor or one of its descendants
+fn is_within(state: &State, entity: Entity, ancestor: Entity) -> bool {
+    let mut current = Some(entity);
+    while let Some(e) = current {
+        if e == ancestor {
+            return true;
+        }
+        current = e.parent(&state.tree);
+    }
+
+    false
+}
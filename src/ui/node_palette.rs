@@ -0,0 +1,391 @@
+use tuix::*;
+
+use super::{AppEvent, NodeDesc};
+
+// Maximum number of nodes listed at once
+const MAX_RESULTS: usize = 30;
+
+// Restricts which nodes are listed in the palette
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaletteFilter {
+    All,
+    // Nodes with at least one input, for connecting a dragged output
+    HasInputs,
+    // Nodes with at least one output, for connecting a dragged input
+    HasOutputs,
+}
+
+impl PaletteFilter {
+    fn accepts(&self, node_desc: &NodeDesc) -> bool {
+        match self {
+            PaletteFilter::All => true,
+            PaletteFilter::HasInputs => !node_desc.inputs.is_empty(),
+            PaletteFilter::HasOutputs => !node_desc.outputs.is_empty(),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone)]
+pub enum PaletteEvent {
+    // Add a node description to the palette
+    AddEntry(NodeDesc),
+    // Open the palette at the cursor. The position is where the chosen node will be inserted (in canvas coordinates).
+    Open(f32, f32, PaletteFilter),
+    Close,
+    // Insert the node at the given index of the filtered results
+    Choose(usize),
+}
+
+// Searchable list of nodes, grouped by category
+pub struct NodePalette {
+    entries: Vec<NodeDesc>,
+    // Indices into entries of the currently listed nodes
+    results: Vec<usize>,
+    selected: usize,
+    query: String,
+    filter: PaletteFilter,
+    open: bool,
+
+    // Where the chosen node will be inserted
+    insert_x: f32,
+    insert_y: f32,
+
+    // Entity to give focus back to when the palette closes
+    prev_focus: Entity,
+
+    query_label: Entity,
+    list: Entity,
+    // Rows of the list which can be chosen
+    rows: Vec<Entity>,
+    // Every child of the list, including category headers
+    list_items: Vec<Entity>,
+}
+
+impl NodePalette {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            results: Vec::new(),
+            selected: 0,
+            query: String::new(),
+            filter: PaletteFilter::All,
+            open: false,
+
+            insert_x: 0.0,
+            insert_y: 0.0,
+
+            prev_focus: Entity::null(),
+
+            query_label: Entity::null(),
+            list: Entity::null(),
+            rows: Vec::new(),
+            list_items: Vec::new(),
+        }
+    }
+
+    fn open(&mut self, state: &mut State, entity: Entity) {
+        self.query.clear();
+        self.selected = 0;
+        if !self.open {
+            self.prev_focus = state.focused;
+        }
+        self.open = true;
+
+        let parent = entity.get_parent(state).unwrap();
+        let px = state.data.get_posx(parent);
+        let py = state.data.get_posy(parent);
+
+        entity
+            .set_left(state, Pixels(state.mouse.cursorx - px))
+            .set_top(state, Pixels(state.mouse.cursory - py))
+            .set_display(state, Display::Flex);
+
+        state.set_focus(entity);
+
+        self.filter(state);
+    }
+
+    fn close(&mut self, state: &mut State, entity: Entity) {
+        if !self.open {
+            return;
+        }
+        self.open = false;
+
+        entity.set_display(state, Display::None);
+
+        if self.prev_focus != Entity::null() {
+            state.set_focus(self.prev_focus);
+        }
+
+        state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+    }
+
+    fn choose(&mut self, state: &mut State, entity: Entity, index: usize) {
+        if let Some(&entry) = self.results.get(index) {
+            let name = self.entries[entry].name.clone();
+            entity.emit(state, AppEvent::InsertNode(name, (self.insert_x, self.insert_y)));
+        }
+
+        self.close(state, entity);
+    }
+
+    // Recompute the results for the current query and rebuild the list
+    fn filter(&mut self, state: &mut State) {
+        let entries = &self.entries;
+        let query = &self.query;
+        let filter = self.filter;
+
+        let mut scored = entries.iter().enumerate().filter(|(_, entry)| filter.accepts(entry)).filter_map(|(index, entry)| {
+            fuzzy_score(query, &entry.name)
+                .or_else(|| fuzzy_score(query, &format!("{} {}", entry.category, entry.name)).map(|score| score - 100))
+                .map(|score| (score, index))
+        }).collect::<Vec<_>>();
+
+        // Best matches first, ties keep declaration order
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        scored.truncate(MAX_RESULTS);
+
+        // Group by category, ordering the categories by their best match
+        let mut categories: Vec<&str> = Vec::new();
+        for (_, index) in scored.iter() {
+            let category = entries[*index].category.as_str();
+            if !categories.contains(&category) {
+                categories.push(category);
+            }
+        }
+
+        if self.query.is_empty() {
+            categories.sort();
+        }
+
+        self.results = categories.iter().flat_map(|category| {
+            scored.iter()
+                .filter(move |(_, index)| entries[*index].category == *category)
+                .map(|(_, index)| *index)
+        }).collect();
+
+        self.selected = self.selected.min(self.results.len().saturating_sub(1));
+
+        self.rebuild(state);
+    }
+
+    fn rebuild(&mut self, state: &mut State) {
+        for item in self.list_items.drain(..) {
+            state.remove(item);
+        }
+        self.rows.clear();
+
+        if self.query.is_empty() {
+            self.query_label.set_text(state, "Search...");
+        } else {
+            self.query_label.set_text(state, &self.query);
+        }
+
+        let mut current_category = None;
+
+        for (row_index, &entry) in self.results.iter().enumerate() {
+            let node_desc = &self.entries[entry];
+
+            if current_category != Some(&node_desc.category) {
+                current_category = Some(&node_desc.category);
+                let header = Label::new(&node_desc.category).build(state, self.list, |builder|
+                    builder
+                        .set_height(Pixels(25.0))
+                        .set_child_left(Pixels(5.0))
+                        .set_hoverable(false)
+                        .class("palette_category")
+                );
+                self.list_items.push(header);
+            }
+
+            let row = Button::new()
+                .on_release(move |_, state, button| {
+                    button.emit(state, PaletteEvent::Choose(row_index));
+                })
+                .build(state, self.list, |builder|
+                    builder
+                        .set_layout_type(LayoutType::Row)
+                        .set_height(Pixels(30.0))
+                        .set_child_space(Stretch(1.0))
+                        .set_child_left(Pixels(15.0))
+                        .set_child_right(Pixels(5.0))
+                        .class("palette_entry")
+                );
+
+            Label::new(&node_desc.name).build(state, row, |builder|
+                builder
+                    .set_child_space(Stretch(1.0))
+                    .set_child_left(Pixels(0.0))
+                    .set_hoverable(false)
+            );
+
+            Label::new(&node_desc.signature()).build(state, row, |builder|
+                builder
+                    .set_child_space(Stretch(1.0))
+                    .set_child_right(Pixels(0.0))
+                    .set_hoverable(false)
+                    .class("palette_signature")
+            );
+
+            self.rows.push(row);
+            self.list_items.push(row);
+        }
+
+        self.update_selection(state);
+    }
+
+    fn update_selection(&mut self, state: &mut State) {
+        for (index, row) in self.rows.iter().enumerate() {
+            row.set_checked(state, index == self.selected);
+        }
+
+        state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+    }
+}
+
+impl Widget for NodePalette {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        self.query_label = Label::new("Search...").build(state, entity, |builder|
+            builder
+                .set_height(Pixels(30.0))
+                .set_child_space(Stretch(1.0))
+                .set_child_left(Pixels(5.0))
+                .set_hoverable(false)
+                .class("palette_query")
+        );
+
+        self.list = Element::new().build(state, entity, |builder|
+            builder
+                .set_height(Auto)
+        );
+
+        entity
+            .set_width(state, Pixels(300.0))
+            .set_height(state, Auto)
+            .set_position_type(state, PositionType::SelfDirected)
+            .set_z_order(state, 10)
+            .set_display(state, Display::None)
+            .class(state, "palette")
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(palette_event) = event.message.downcast() {
+            match palette_event {
+                PaletteEvent::AddEntry(node_desc) => {
+                    self.entries.push(node_desc.clone());
+                }
+
+                PaletteEvent::Open(x, y, filter) => {
+                    self.insert_x = *x;
+                    self.insert_y = *y;
+                    self.filter = *filter;
+                    self.open(state, entity);
+                }
+
+                PaletteEvent::Close => {
+                    self.close(state, entity);
+                }
+
+                PaletteEvent::Choose(index) => {
+                    self.choose(state, entity, *index);
+                    event.consume();
+                }
+            }
+        }
+
+        if let Some(window_event) = event.message.downcast() {
+            match window_event {
+                WindowEvent::CharInput(c) => {
+                    // The key which opened the palette shouldn't start the query
+                    if !c.is_control() && !(self.query.is_empty() && c.is_whitespace()) {
+                        self.query.push(*c);
+                        self.selected = 0;
+                        self.filter(state);
+                    }
+                }
+
+                WindowEvent::KeyDown(code, _) => {
+                    match *code {
+                        Code::Backspace => {
+                            self.query.pop();
+                            self.selected = 0;
+                            self.filter(state);
+                        }
+
+                        Code::ArrowDown => {
+                            if self.selected + 1 < self.results.len() {
+                                self.selected += 1;
+                                self.update_selection(state);
+                            }
+                        }
+
+                        Code::ArrowUp => {
+                            if self.selected > 0 {
+                                self.selected -= 1;
+                                self.update_selection(state);
+                            }
+                        }
+
+                        Code::Enter => {
+                            self.choose(state, entity, self.selected);
+                        }
+
+                        Code::Escape => {
+                            self.close(state, entity);
+                        }
+
+                        _=> {}
+                    }
+                }
+
+                _=> {}
+            }
+        }
+    }
+}
+
+// Scores how well the query matches the text as a case-insensitive subsequence.
+// Consecutive characters and characters at the start of words score higher.
+// Returns None if the query doesn't match.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i32> {
+    if query.is_empty() {
+        return Some(0);
+    }
+
+    let text = text.chars().collect::<Vec<_>>();
+    let mut score = 0;
+    let mut position = 0;
+    let mut prev_match: Option<usize> = None;
+
+    for q in query.chars().flat_map(char::to_lowercase) {
+        if q.is_whitespace() {
+            continue;
+        }
+
+        let found = (position..text.len()).find(|&i| text[i].to_lowercase().eq(std::iter::once(q)))?;
+
+        score += 1;
+
+        if prev_match.map_or(false, |prev| prev + 1 == found) {
+            score += 5;
+        }
+
+        let word_start = found == 0
+            || !text[found - 1].is_alphanumeric()
+            || (text[found].is_uppercase() && text[found - 1].is_lowercase());
+        if word_start {
+            score += 10;
+        }
+
+        // Penalise gaps between matched characters
+        score -= (found - position).min(5) as i32;
+
+        prev_match = Some(found);
+        position = found + 1;
+    }
+
+    Some(score)
+}
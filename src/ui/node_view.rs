@@ -4,6 +4,7 @@ use tuix::*;
 
 use super::AppEvent;
 use super::NodeEvent;
+use super::keyboard_widget::KeyboardEvent;
 use super::node_widget::*;
 use super::socket_widget::*;
 
@@ -68,6 +69,57 @@ impl Widget for NodeView {
                     .set_child_space(Stretch(1.0))
             );
 
+        Button::with_label("Layout")
+            .on_press(|_, state, button|{
+                button.emit(state, AppEvent::AutoLayout);
+            })
+            .build(state, entity, |builder|
+                builder
+                    .set_background_color(Color::rgb(50, 50, 150))
+                    .set_width(Pixels(100.0))
+                    .set_height(Pixels(30.0))
+                    .set_space(Stretch(1.0))
+                    .set_bottom(Pixels(10.0))
+                    .set_right(Pixels(120.0))
+                    .set_position_type(PositionType::SelfDirected)
+                    .set_border_radius(Pixels(3.0))
+                    .set_child_space(Stretch(1.0))
+            );
+
+        Button::with_label("Bench")
+            .on_press(|_, state, button|{
+                button.emit(state, AppEvent::Bench);
+            })
+            .build(state, entity, |builder|
+                builder
+                    .set_background_color(Color::rgb(50, 50, 150))
+                    .set_width(Pixels(100.0))
+                    .set_height(Pixels(30.0))
+                    .set_space(Stretch(1.0))
+                    .set_bottom(Pixels(10.0))
+                    .set_right(Pixels(230.0))
+                    .set_position_type(PositionType::SelfDirected)
+                    .set_border_radius(Pixels(3.0))
+                    .set_child_space(Stretch(1.0))
+            );
+
+        Button::with_label("Play")
+            .on_press(|_, state, button|{
+                button.emit(state, AppEvent::TogglePlay);
+            })
+            .build(state, entity, |builder|
+                builder
+                    .set_background_color(Color::rgb(50, 50, 150))
+                    .set_width(Pixels(100.0))
+                    .set_height(Pixels(30.0))
+                    .set_space(Stretch(1.0))
+                    .set_bottom(Pixels(10.0))
+                    .set_right(Pixels(340.0))
+                    .set_position_type(PositionType::SelfDirected)
+                    .set_border_radius(Pixels(3.0))
+                    .set_child_space(Stretch(1.0))
+            );
+
 
         self.canvas
     }
@@ -133,12 +185,22 @@ impl Widget for NodeView {
                 WindowEvent::KeyDown(code, key) => {
                     println!("Key: {:?} {:?}", code, key);
                     match *code {
+                        // Open the node palette
+                        Code::Tab | Code::Space => {
+                            entity.emit(state, AppEvent::OpenPalette);
+                        }
 
-
-                        _=> {}
+                        // Any other key may play a note on the keyboard
+                        code => {
+                            entity.emit(state, KeyboardEvent::KeyDown(code));
+                        }
                     }
                 }
 
+                WindowEvent::KeyUp(code, _) => {
+                    entity.emit(state, KeyboardEvent::KeyUp(*code));
+                }
+
                 _=> {}
             }
         }
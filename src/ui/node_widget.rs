@@ -8,6 +8,8 @@ use femtovg::{
 
 use super::{NodeEvent, socket_widget::*};
 
+// Duration of the animation when a node is moved by the auto layout
+const MOVE_DURATION: std::time::Duration = std::time::Duration::from_millis(300);
 
 
 pub struct NodeWidget {
@@ -186,6 +188,42 @@ impl Widget for NodeWidget {
                 _=> {}
             }
         }
+
+        if let Some(node_event) = event.message.downcast() {
+            match node_event {
+                NodeEvent::MoveTo(x, y) => {
+                    if event.target == entity {
+                        let left = state.style.left.get(entity).cloned().unwrap_or_default();
+                        let top = state.style.top.get(entity).cloned().unwrap_or_default();
+
+                        let left_animation = state.style.left.insert_animation(
+                            AnimationState::new()
+                                .with_duration(MOVE_DURATION)
+                                .with_keyframe((0.0, left))
+                                .with_keyframe((1.0, Pixels(*x)))
+                        );
+
+                        let top_animation = state.style.top.insert_animation(
+                            AnimationState::new()
+                                .with_duration(MOVE_DURATION)
+                                .with_keyframe((0.0, top))
+                                .with_keyframe((1.0, Pixels(*y)))
+                        );
+
+                        // Set the final position so the node stays there once the animation is done
+                        entity
+                            .set_left(state, Pixels(*x))
+                            .set_top(state, Pixels(*y));
+
+                        state.style.left.play_animation(entity, left_animation);
+                        state.style.top.play_animation(entity, top_animation);
+                        state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+                    }
+                }
+
+                _=> {}
+            }
+        }
     }
 
 
@@ -0,0 +1,113 @@
+use tuix::*;
+use femtovg::{
+    Canvas, renderer::OpenGl, Paint, Path,
+};
+
+use super::AppEvent;
+use crate::program::ParameterSpec;
+
+// Slider inside a parameter node which sets the parameter's value by dragging across it
+pub struct ParameterSlider {
+    spec: ParameterSpec,
+    value: f64,
+    dragging: bool,
+    label: Entity,
+}
+
+impl ParameterSlider {
+    pub fn new(spec: ParameterSpec) -> Self {
+        Self {
+            value: spec.clamp(spec.default),
+            spec,
+            dragging: false,
+            label: Entity::null(),
+        }
+    }
+
+    fn text(&self) -> String {
+        format!("{} {:.3} {}", self.spec.name, self.value, self.spec.unit)
+    }
+
+    // Sets the value from the cursor position across the slider and sends it to the app
+    fn drag_to(&mut self, state: &mut State, entity: Entity, x: f32, y: f32) {
+        let bounds = state.data.get_bounds(entity);
+
+        let mut transform = state.data.get_transform(entity);
+        transform.inverse();
+        let (mx, _) = transform.transform_point(x, y);
+
+        let normalized = if bounds.w > 0.0 { ((mx - bounds.x) / bounds.w) as f64 } else { 0.0 };
+        self.value = self.spec.denormalize(normalized);
+        self.label.set_text(state, &self.text());
+
+        entity.emit(state, AppEvent::SetParameter(self.spec.name.clone(), self.value));
+        state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+    }
+}
+
+impl Widget for ParameterSlider {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        self.label = Label::new(&self.text()).build(state, entity, |builder|
+            builder
+                .set_child_space(Stretch(1.0))
+                .set_space(Pixels(0.0))
+                .set_hoverable(false)
+        );
+
+        entity
+            .set_height(state, Pixels(24.0))
+            .set_left(state, Pixels(5.0))
+            .set_right(state, Pixels(5.0))
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(window_event) = event.message.downcast() {
+            match window_event {
+                WindowEvent::MouseDown(button) if *button == MouseButton::Left && event.target == entity => {
+                    self.dragging = true;
+                    state.capture(entity);
+                    let (x, y) = (state.mouse.cursorx, state.mouse.cursory);
+                    self.drag_to(state, entity, x, y);
+                    event.consume();
+                }
+
+                WindowEvent::MouseUp(button) if *button == MouseButton::Left && event.target == entity => {
+                    self.dragging = false;
+                    state.release(entity);
+                }
+
+                WindowEvent::MouseMove(x, y) if self.dragging && event.target == entity => {
+                    let (x, y) = (*x, *y);
+                    self.drag_to(state, entity, x, y);
+                }
+
+                _ => {}
+            }
+        }
+    }
+
+    fn on_draw(&mut self, state: &mut State, entity: Entity, canvas: &mut Canvas<OpenGl>) {
+        let bounds = state.data.get_bounds(entity);
+
+        canvas.save();
+
+        let transform = state.data.get_transform(entity);
+        canvas.set_transform(transform[0], transform[1], transform[2], transform[3], transform[4], transform[5]);
+
+        let mut path = Path::new();
+        path.rounded_rect(bounds.x, bounds.y, bounds.w, bounds.h, 3.0);
+        canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(20, 20, 20)));
+
+        let filled = self.spec.normalize(self.value) as f32;
+        if filled > 0.0 {
+            let mut path = Path::new();
+            path.rounded_rect(bounds.x, bounds.y, bounds.w * filled, bounds.h, 3.0);
+            canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(50, 50, 150)));
+        }
+
+        canvas.restore();
+    }
+}
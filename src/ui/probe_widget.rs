@@ -0,0 +1,190 @@
+use tuix::*;
+use femtovg::{
+    Canvas, renderer::OpenGl, Paint, Path,
+};
+
+// Number of columns of the waveform display
+const PROBE_COLUMNS: usize = 180;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeEvent {
+    // Discard the recorded signal
+    Clear,
+    // Replace the recorded signal, fitting all of it into the display
+    Set(Vec<f64>),
+}
+
+// Signal recorded by a probe, reduced to a min/max pair per display column
+#[derive(Debug, Clone)]
+pub struct ProbeData {
+    columns: Vec<(f64, f64)>,
+    // Number of samples reduced into each column
+    samples_per_column: usize,
+    // Column currently being accumulated and how many samples are in it
+    pending: (f64, f64),
+    pending_count: usize,
+
+    pub min: f64,
+    pub max: f64,
+    pub last: f64,
+}
+
+impl ProbeData {
+    pub fn new(samples_per_column: usize) -> Self {
+        Self {
+            columns: Vec::with_capacity(PROBE_COLUMNS),
+            samples_per_column: samples_per_column.max(1),
+            pending: (f64::INFINITY, f64::NEG_INFINITY),
+            pending_count: 0,
+
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            last: 0.0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    pub fn push(&mut self, samples: &[f64]) {
+        for &sample in samples {
+            self.min = self.min.min(sample);
+            self.max = self.max.max(sample);
+            self.last = sample;
+
+            self.pending.0 = self.pending.0.min(sample);
+            self.pending.1 = self.pending.1.max(sample);
+            self.pending_count += 1;
+
+            if self.pending_count == self.samples_per_column {
+                // Drop the oldest column so the display scrolls
+                if self.columns.len() == PROBE_COLUMNS {
+                    self.columns.remove(0);
+                }
+                self.columns.push(self.pending);
+                self.pending = (f64::INFINITY, f64::NEG_INFINITY);
+                self.pending_count = 0;
+            }
+        }
+    }
+}
+
+// Small oscilloscope shown inside a node, displaying the signal recorded at an output socket
+pub struct ProbeWidget {
+    data: ProbeData,
+    info: Entity,
+}
+
+impl ProbeWidget {
+    pub fn new() -> Self {
+        Self {
+            data: ProbeData::new(1),
+            info: Entity::null(),
+        }
+    }
+
+    fn update_info(&mut self, state: &mut State) {
+        let text = if self.data.is_empty() {
+            "No signal".to_string()
+        } else {
+            format!("min {:.3}  max {:.3}  last {:.3}", self.data.min, self.data.max, self.data.last)
+        };
+        self.info.set_text(state, &text);
+    }
+}
+
+impl Widget for ProbeWidget {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        // Values are shown on hover
+        self.info = Label::new("No signal").build(state, entity, |builder|
+            builder
+                .set_height(Pixels(20.0))
+                .set_child_space(Stretch(1.0))
+                .set_hoverable(false)
+                .set_display(Display::None)
+                .class("probe_info")
+        );
+
+        entity
+            .set_height(state, Pixels(60.0))
+            .set_child_space(Stretch(1.0))
+            .set_child_top(Stretch(0.0))
+            .class(state, "probe")
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(probe_event) = event.message.downcast() {
+            match probe_event {
+                ProbeEvent::Clear => {
+                    self.data = ProbeData::new(1);
+                }
+
+                ProbeEvent::Set(samples) => {
+                    self.data = ProbeData::new((samples.len() + PROBE_COLUMNS - 1) / PROBE_COLUMNS);
+                    self.data.push(samples);
+                }
+            }
+
+            self.update_info(state);
+            state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+        }
+
+        if let Some(window_event) = event.message.downcast() {
+            match window_event {
+                WindowEvent::MouseOver => {
+                    if event.target == entity {
+                        self.info.set_display(state, Display::Flex);
+                    }
+                }
+
+                WindowEvent::MouseOut => {
+                    if event.target == entity {
+                        self.info.set_display(state, Display::None);
+                    }
+                }
+
+                _=> {}
+            }
+        }
+    }
+
+    fn on_draw(&mut self, state: &mut State, entity: Entity, canvas: &mut Canvas<OpenGl>) {
+        let bounds = state.data.get_bounds(entity);
+
+        canvas.save();
+
+        let transform = state.data.get_transform(entity);
+        canvas.set_transform(transform[0], transform[1], transform[2], transform[3], transform[4], transform[5]);
+
+        let mut path = Path::new();
+        path.rect(bounds.x, bounds.y, bounds.w, bounds.h);
+        canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(20, 20, 20)));
+
+        if self.data.is_empty() {
+            canvas.restore();
+            return;
+        }
+
+        // Scale to the peak of the recorded signal so quiet signals are still visible
+        let peak = self.data.min.abs().max(self.data.max.abs()).max(1e-9) as f32;
+        let column_width = bounds.w / PROBE_COLUMNS as f32;
+        let mid_y = bounds.y + bounds.h / 2.0;
+        let scale = (bounds.h / 2.0 - 2.0) / peak;
+
+        let mut path = Path::new();
+        for (index, (min, max)) in self.data.columns.iter().enumerate() {
+            let x = bounds.x + index as f32 * column_width;
+            path.move_to(x, mid_y - *max as f32 * scale);
+            path.line_to(x, mid_y - *min as f32 * scale + 0.5);
+        }
+        let mut paint = Paint::color(femtovg::Color::rgb(80, 200, 80));
+        paint.set_line_width(column_width.max(1.0));
+        canvas.stroke_path(&mut path, paint);
+
+        canvas.restore();
+    }
+}
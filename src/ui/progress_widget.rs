@@ -0,0 +1,169 @@
+use tuix::*;
+use femtovg::{
+    Canvas, renderer::OpenGl, Paint, Path,
+};
+
+use super::AppEvent;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    Show,
+    Hide,
+    // Fraction of the job completed, from 0.0 to 1.0
+    SetProgress(f32),
+}
+
+// Progress of the graph render running in the background, with a button to cancel it
+pub struct ProgressWidget {
+    bar: Entity,
+    visible: bool,
+}
+
+impl ProgressWidget {
+    pub fn new() -> Self {
+        Self {
+            bar: Entity::null(),
+            visible: false,
+        }
+    }
+}
+
+impl Widget for ProgressWidget {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        self.bar = ProgressBar::new().build(state, entity, |builder|
+            builder
+                .set_height(Pixels(10.0))
+                .set_space(Stretch(1.0))
+                .set_left(Pixels(10.0))
+        );
+
+        Button::with_label("Cancel")
+            .on_press(|_, state, button| {
+                button.emit(state, AppEvent::CancelRun);
+            })
+            .build(state, entity, |builder|
+                builder
+                    .set_width(Pixels(80.0))
+                    .set_height(Pixels(24.0))
+                    .set_space(Stretch(1.0))
+                    .set_left(Pixels(10.0))
+                    .set_right(Pixels(5.0))
+                    .set_border_radius(Pixels(3.0))
+                    .set_child_space(Stretch(1.0))
+                    .class("analysis_mode")
+            );
+
+        entity
+            .set_layout_type(state, LayoutType::Row)
+            .set_width(state, Pixels(320.0))
+            .set_height(state, Pixels(34.0))
+            .set_left(state, Stretch(1.0))
+            .set_right(state, Stretch(1.0))
+            .set_top(state, Stretch(1.0))
+            .set_bottom(state, Pixels(50.0))
+            .set_position_type(state, PositionType::SelfDirected)
+            .set_border_radius(state, Pixels(3.0))
+            .set_display(state, Display::None)
+            .class(state, "node")
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(progress_event) = event.message.downcast() {
+            match progress_event {
+                ProgressEvent::Show => {
+                    self.visible = true;
+                    entity.set_display(state, Display::Flex);
+                    entity.emit_to(state, self.bar, ProgressEvent::SetProgress(0.0));
+                }
+
+                ProgressEvent::Hide => {
+                    self.visible = false;
+                    entity.set_display(state, Display::None);
+                }
+
+                ProgressEvent::SetProgress(progress) => {
+                    if event.target == entity {
+                        entity.emit_to(state, self.bar, ProgressEvent::SetProgress(*progress));
+                    }
+                }
+            }
+
+            state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+        }
+    }
+
+    fn on_draw(&mut self, state: &mut State, entity: Entity, canvas: &mut Canvas<OpenGl>) {
+        // There's no way for the worker thread to wake the UI, so while a job is running
+        // every frame asks the app to check on it, which in turn schedules another frame
+        if self.visible {
+            state.insert_event(Event::new(AppEvent::PollJob).target(entity));
+        }
+
+        let bounds = state.data.get_bounds(entity);
+
+        canvas.save();
+
+        let transform = state.data.get_transform(entity);
+        canvas.set_transform(transform[0], transform[1], transform[2], transform[3], transform[4], transform[5]);
+
+        let mut path = Path::new();
+        path.rounded_rect(bounds.x, bounds.y, bounds.w, bounds.h, 3.0);
+        canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(48, 48, 48)));
+
+        canvas.restore();
+    }
+}
+
+// Horizontal bar filled up to the current progress
+pub struct ProgressBar {
+    progress: f32,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        Self {
+            progress: 0.0,
+        }
+    }
+}
+
+impl Widget for ProgressBar {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, _state: &mut State, entity: Entity) -> Self::Ret {
+        entity
+    }
+
+    fn on_event(&mut self, _state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(ProgressEvent::SetProgress(progress)) = event.message.downcast() {
+            if event.target == entity {
+                self.progress = progress.max(0.0).min(1.0);
+            }
+        }
+    }
+
+    fn on_draw(&mut self, state: &mut State, entity: Entity, canvas: &mut Canvas<OpenGl>) {
+        let bounds = state.data.get_bounds(entity);
+
+        canvas.save();
+
+        let transform = state.data.get_transform(entity);
+        canvas.set_transform(transform[0], transform[1], transform[2], transform[3], transform[4], transform[5]);
+
+        let mut path = Path::new();
+        path.rounded_rect(bounds.x, bounds.y, bounds.w, bounds.h, bounds.h / 2.0);
+        canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(20, 20, 20)));
+
+        if self.progress > 0.0 {
+            let mut path = Path::new();
+            path.rounded_rect(bounds.x, bounds.y, bounds.w * self.progress, bounds.h, bounds.h / 2.0);
+            canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(50, 50, 150)));
+        }
+
+        canvas.restore();
+    }
+}
@@ -0,0 +1,150 @@
+use std::path::Path;
+
+use tuix::*;
+use femtovg::{
+    Canvas, renderer::OpenGl, Paint, Path as CanvasPath,
+};
+
+use super::AppEvent;
+use crate::program::{load_sample, sample_files};
+
+// Number of columns the waveform is drawn with
+const COLUMNS: usize = 200;
+
+// Body of a sample player node showing the file it plays and its waveform.
+// Clicking steps through the files in the samples directory.
+pub struct SampleView {
+    path: Option<String>,
+    // The sample player node the view belongs to
+    node: Entity,
+    // Lowest and highest sample in each column
+    overview: Vec<(f32, f32)>,
+    label: Entity,
+}
+
+impl SampleView {
+    pub fn new(path: Option<String>, node: Entity) -> Self {
+        Self {
+            path,
+            node,
+            overview: Vec::new(),
+            label: Entity::null(),
+        }
+    }
+
+    fn name(&self) -> String {
+        match &self.path {
+            Some(path) => Path::new(path).file_name().map_or(path.clone(), |name| name.to_string_lossy().to_string()),
+            None => "No sample".to_string(),
+        }
+    }
+
+    // Reads the sample and works out the shape of its waveform
+    fn load(&mut self) {
+        self.overview.clear();
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        match load_sample(Path::new(path)) {
+            Ok(samples) if !samples.is_empty() => {
+                let column = (samples.len() + COLUMNS - 1) / COLUMNS;
+                self.overview = samples.chunks(column.max(1))
+                    .map(|chunk| chunk.iter().fold((0.0f32, 0.0f32), |(low, high), &sample| (low.min(sample as f32), high.max(sample as f32))))
+                    .collect();
+            }
+            Ok(_) => {}
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    // Moves on to the next file in the samples directory, after the last one going back to the first
+    fn next_sample(&mut self, state: &mut State, entity: Entity) {
+        let files = sample_files();
+        if files.is_empty() {
+            println!("No WAV files found in the samples directory");
+            return;
+        }
+
+        let current = files.iter().position(|file| Some(file.to_string_lossy().as_ref()) == self.path.as_deref());
+        let next = current.map_or(0, |index| (index + 1) % files.len());
+        let path = files[next].to_string_lossy().to_string();
+
+        self.path = Some(path.clone());
+        self.load();
+        self.label.set_text(state, &self.name());
+
+        entity.emit(state, AppEvent::SetSample(self.node, path));
+        state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+    }
+}
+
+impl Widget for SampleView {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        self.load();
+
+        self.label = Label::new(&self.name()).build(state, entity, |builder|
+            builder
+                .set_height(Pixels(20.0))
+                .set_child_space(Stretch(1.0))
+                .set_space(Pixels(0.0))
+                .set_hoverable(false)
+        );
+
+        entity
+            .set_height(state, Pixels(80.0))
+            .set_left(state, Pixels(5.0))
+            .set_right(state, Pixels(5.0))
+            .set_bottom(state, Pixels(5.0))
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(window_event) = event.message.downcast() {
+            match window_event {
+                WindowEvent::MouseDown(button) if *button == MouseButton::Left && event.target == entity => {
+                    self.next_sample(state, entity);
+                    event.consume();
+                }
+
+                _ => {}
+            }
+        }
+    }
+
+    fn on_draw(&mut self, state: &mut State, entity: Entity, canvas: &mut Canvas<OpenGl>) {
+        let bounds = state.data.get_bounds(entity);
+
+        canvas.save();
+
+        let transform = state.data.get_transform(entity);
+        canvas.set_transform(transform[0], transform[1], transform[2], transform[3], transform[4], transform[5]);
+
+        let mut path = CanvasPath::new();
+        path.rounded_rect(bounds.x, bounds.y, bounds.w, bounds.h, 3.0);
+        canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(20, 20, 20)));
+
+        // The waveform fills the space under the label
+        let top = bounds.y + 20.0;
+        let height = (bounds.h - 20.0).max(0.0);
+        let middle = top + height / 2.0;
+
+        if !self.overview.is_empty() {
+            let width = bounds.w / self.overview.len() as f32;
+            let mut path = CanvasPath::new();
+            for (index, (low, high)) in self.overview.iter().enumerate() {
+                let x = bounds.x + index as f32 * width;
+                let y = middle - high.min(1.0) * height / 2.0;
+                let bottom = middle - low.max(-1.0) * height / 2.0;
+                path.rect(x, y, width.max(1.0), (bottom - y).max(1.0));
+            }
+            canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(50, 150, 50)));
+        }
+
+        canvas.restore();
+    }
+}
@@ -0,0 +1,185 @@
+use tuix::*;
+use femtovg::{
+    Canvas, renderer::OpenGl, Paint, Path,
+};
+
+use super::AppEvent;
+use crate::program::{Pattern, STEP_NOTES};
+
+// Rows of the grid, from top to bottom
+const ROWS: usize = 4;
+const ON_ROW: usize = 0;
+const NOTE_ROW: usize = 1;
+const VELOCITY_ROW: usize = 2;
+const GATE_ROW: usize = 3;
+
+const STEP_WIDTH: f32 = 14.0;
+const ROW_HEIGHT: f32 = 24.0;
+
+// Grid inside a sequencer node with a column for each step. The top row turns steps on and off,
+// the bars below set the note, velocity and gate length by dragging up and down.
+pub struct SequencerGrid {
+    pattern: Pattern,
+    // The sequencer node the grid edits
+    node: Entity,
+    // Row being dragged across, so a drag only changes one kind of value
+    dragging: Option<usize>,
+    // Value steps are set to while dragging across the on/off row
+    drag_on: bool,
+}
+
+impl SequencerGrid {
+    pub fn new(pattern: Pattern, node: Entity) -> Self {
+        Self {
+            pattern,
+            node,
+            dragging: None,
+            drag_on: false,
+        }
+    }
+
+    // Returns the step, row and how far up the row the cursor is, from 0 to 1
+    fn cell_at(&self, state: &State, entity: Entity, x: f32, y: f32) -> Option<(usize, usize, f64)> {
+        let bounds = state.data.get_bounds(entity);
+
+        let mut transform = state.data.get_transform(entity);
+        transform.inverse();
+        let (mx, my) = transform.transform_point(x, y);
+
+        if bounds.w <= 0.0 || bounds.h <= 0.0 {
+            return None;
+        }
+
+        let step = ((mx - bounds.x) / bounds.w * self.pattern.steps.len() as f32).floor();
+        let row = ((my - bounds.y) / bounds.h * ROWS as f32).floor();
+        let height = 1.0 - ((my - bounds.y) / bounds.h * ROWS as f32 - row);
+
+        if step < 0.0 || step as usize >= self.pattern.steps.len() {
+            return None;
+        }
+
+        Some((step as usize, (row.max(0.0) as usize).min(ROWS - 1), height.max(0.0).min(1.0) as f64))
+    }
+
+    fn edit(&mut self, state: &mut State, entity: Entity, x: f32, y: f32) {
+        let (step, row, height) = match self.cell_at(state, entity, x, y) {
+            Some(cell) => cell,
+            None => return,
+        };
+
+        // While dragging, the cursor sets values in the row the drag started in
+        let row = self.dragging.unwrap_or(row);
+        let step = &mut self.pattern.steps[step];
+
+        match row {
+            ON_ROW => step.on = self.drag_on,
+            NOTE_ROW => {
+                let (low, high) = STEP_NOTES;
+                step.note = low + (height * (high - low) as f64).round() as u8;
+            }
+            VELOCITY_ROW => step.velocity = height,
+            GATE_ROW => step.gate = height,
+            _ => {}
+        }
+
+        entity.emit(state, AppEvent::SetPattern(self.node, self.pattern.clone()));
+        state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+    }
+}
+
+impl Widget for SequencerGrid {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        entity
+            .set_width(state, Pixels(STEP_WIDTH * self.pattern.steps.len() as f32))
+            .set_height(state, Pixels(ROW_HEIGHT * ROWS as f32))
+            .set_left(state, Pixels(5.0))
+            .set_right(state, Pixels(5.0))
+            .set_bottom(state, Pixels(5.0))
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(window_event) = event.message.downcast() {
+            match window_event {
+                WindowEvent::MouseDown(button) if *button == MouseButton::Left && event.target == entity => {
+                    let (x, y) = (state.mouse.cursorx, state.mouse.cursory);
+                    if let Some((step, row, _)) = self.cell_at(state, entity, x, y) {
+                        // Dragging from a step which is off turns steps on, and the other way round
+                        self.drag_on = !self.pattern.steps[step].on;
+                        self.edit(state, entity, x, y);
+                        self.dragging = Some(row);
+                        state.capture(entity);
+                    }
+                    event.consume();
+                }
+
+                WindowEvent::MouseUp(button) if *button == MouseButton::Left && event.target == entity => {
+                    self.dragging = None;
+                    state.release(entity);
+                }
+
+                WindowEvent::MouseMove(x, y) if self.dragging.is_some() && event.target == entity => {
+                    let (x, y) = (*x, *y);
+                    self.edit(state, entity, x, y);
+                }
+
+                _ => {}
+            }
+        }
+    }
+
+    fn on_draw(&mut self, state: &mut State, entity: Entity, canvas: &mut Canvas<OpenGl>) {
+        let bounds = state.data.get_bounds(entity);
+
+        canvas.save();
+
+        let transform = state.data.get_transform(entity);
+        canvas.set_transform(transform[0], transform[1], transform[2], transform[3], transform[4], transform[5]);
+
+        let mut path = Path::new();
+        path.rect(bounds.x, bounds.y, bounds.w, bounds.h);
+        canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(20, 20, 20)));
+
+        let steps = self.pattern.steps.len().max(1);
+        let width = bounds.w / steps as f32;
+        let height = bounds.h / ROWS as f32;
+        let (low, high) = STEP_NOTES;
+
+        for (index, step) in self.pattern.steps.iter().enumerate() {
+            let x = bounds.x + index as f32 * width;
+
+            // Every beat starts with a lighter column so the pattern is easier to read
+            let beat_start = self.pattern.steps_per_beat > 0 && index % self.pattern.steps_per_beat == 0;
+            let off = if beat_start { femtovg::Color::rgb(70, 70, 70) } else { femtovg::Color::rgb(50, 50, 50) };
+            let on = femtovg::Color::rgb(50, 150, 50);
+            let bar = if step.on { femtovg::Color::rgb(50, 50, 150) } else { femtovg::Color::rgb(40, 40, 80) };
+
+            let mut path = Path::new();
+            path.rect(x + 1.0, bounds.y + 1.0, width - 2.0, height - 2.0);
+            canvas.fill_path(&mut path, Paint::color(if step.on { on } else { off }));
+
+            let values = [
+                (NOTE_ROW, (step.note.max(low) - low) as f32 / (high - low) as f32),
+                (VELOCITY_ROW, step.velocity as f32),
+                (GATE_ROW, step.gate as f32),
+            ];
+
+            for (row, value) in values.iter() {
+                let top = bounds.y + *row as f32 * height;
+                let filled = (height - 2.0) * value.max(0.0).min(1.0);
+
+                let mut path = Path::new();
+                path.rect(x + 1.0, top + 1.0, width - 2.0, height - 2.0);
+                canvas.fill_path(&mut path, Paint::color(off));
+
+                let mut path = Path::new();
+                path.rect(x + 1.0, top + height - 1.0 - filled, width - 2.0, filled);
+                canvas.fill_path(&mut path, Paint::color(bar));
+            }
+        }
+
+        canvas.restore();
+    }
+}
@@ -163,6 +163,7 @@ impl Widget for InputSocket {
                             //state.insert_event(Event::new(NodeEvent::ConnectSockets(entity, state.hovered)).direct(state.hovered).origin(entity));
                             //state.insert_event(Event::new(NodeEvent::ConnectInput).direct(state.hovered).origin(entity));
                             entity.emit_to(state, state.hovered, NodeEvent::ConnectInput);
+                            entity.emit(state, NodeEvent::DropWire(entity, state.hovered));
                             entity.set_z_order(state, 0);                            
                         }
                         state.release(entity);
@@ -459,11 +460,18 @@ impl Widget for OutputSocket {
                 }
 
                 WindowEvent::MouseUp(button) => {
+                    // Right clicking an output socket attaches a probe to it
+                    if *button == MouseButton::Right && event.target == entity {
+                        entity.emit(state, NodeEvent::ToggleProbe(entity));
+                        event.consume();
+                    }
+
                     if *button == MouseButton::Left {
                         state.release(entity);
                         self.connecting = false;
                         state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
                         state.insert_event(Event::new(NodeEvent::ConnectOutput).direct(state.hovered).origin(entity));
+                        entity.emit(state, NodeEvent::DropWire(entity, state.hovered));
                         
                         entity.set_z_order(state, 0);
                     }
@@ -0,0 +1,152 @@
+use tuix::*;
+
+use super::AppEvent;
+use crate::program::Transport;
+
+// Time signatures the signature button steps through
+const TIME_SIGNATURES: [(u32, u32); 5] = [(4, 4), (3, 4), (6, 8), (5, 4), (7, 8)];
+// Loop lengths in bars the loop button steps through, none for no loop
+const LOOP_BARS: [Option<f64>; 4] = [None, Some(1.0), Some(2.0), Some(4.0)];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportEvent {
+    TogglePlaying,
+    Rewind,
+    // Change the tempo by this many BPM
+    Nudge(f64),
+    NextTimeSignature,
+    NextLoop,
+    // Show the settings of a loaded patch
+    Set(Transport),
+}
+
+// Row of transport controls between the node view and the keyboard
+pub struct TransportBar {
+    transport: Transport,
+    play_button: Entity,
+    tempo_label: Entity,
+    signature_button: Entity,
+    loop_button: Entity,
+}
+
+impl TransportBar {
+    pub fn new() -> Self {
+        Self {
+            transport: Transport::default(),
+            play_button: Entity::null(),
+            tempo_label: Entity::null(),
+            signature_button: Entity::null(),
+            loop_button: Entity::null(),
+        }
+    }
+
+    fn loop_text(&self) -> String {
+        match self.transport.loop_range {
+            Some((start, end)) => format!("Loop {}-{}", start, end),
+            None => "No loop".to_string(),
+        }
+    }
+
+    fn update_labels(&self, state: &mut State) {
+        self.play_button.set_text(state, if self.transport.playing { "Stop" } else { "Start" });
+        self.tempo_label.set_text(state, &format!("{:.1} BPM", self.transport.bpm));
+        let (beats, note) = self.transport.time_signature;
+        self.signature_button.set_text(state, &format!("{}/{}", beats, note));
+        self.loop_button.set_text(state, &self.loop_text());
+    }
+
+    fn changed(&self, state: &mut State, entity: Entity) {
+        self.update_labels(state);
+        entity.emit(state, AppEvent::SetTransport(self.transport.clone()));
+    }
+}
+
+fn control(state: &mut State, parent: Entity, text: &str, width: f32, event: TransportEvent) -> Entity {
+    Button::with_label(text)
+        .on_press(move |_, state, button| {
+            button.emit(state, event.clone());
+        })
+        .build(state, parent, |builder|
+            builder
+                .set_background_color(Color::rgb(50, 50, 150))
+                .set_width(Pixels(width))
+                .set_height(Pixels(24.0))
+                .set_border_radius(Pixels(3.0))
+                .set_child_space(Stretch(1.0))
+        )
+}
+
+impl Widget for TransportBar {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        self.play_button = control(state, entity, "Stop", 60.0, TransportEvent::TogglePlaying);
+        control(state, entity, "|<", 30.0, TransportEvent::Rewind);
+        control(state, entity, "-", 24.0, TransportEvent::Nudge(-1.0));
+
+        self.tempo_label = Label::new("").build(state, entity, |builder|
+            builder
+                .set_width(Pixels(90.0))
+                .set_child_space(Stretch(1.0))
+                .set_hoverable(false)
+        );
+
+        control(state, entity, "+", 24.0, TransportEvent::Nudge(1.0));
+        self.signature_button = control(state, entity, "4/4", 50.0, TransportEvent::NextTimeSignature);
+        self.loop_button = control(state, entity, "No loop", 90.0, TransportEvent::NextLoop);
+
+        self.update_labels(state);
+
+        entity
+            .set_layout_type(state, LayoutType::Row)
+            .set_height(state, Pixels(34.0))
+            .set_child_space(state, Pixels(5.0))
+            .set_col_between(state, Pixels(5.0))
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(transport_event) = event.message.downcast() {
+            match transport_event {
+                TransportEvent::TogglePlaying => {
+                    self.transport.playing = !self.transport.playing;
+                    self.changed(state, entity);
+                }
+
+                TransportEvent::Rewind => {
+                    entity.emit(state, AppEvent::Rewind);
+                }
+
+                TransportEvent::Nudge(bpm) => {
+                    self.transport.bpm = (self.transport.bpm + *bpm).max(20.0).min(300.0);
+                    self.changed(state, entity);
+                }
+
+                TransportEvent::NextTimeSignature => {
+                    // The loop covers whole bars, which change length with the signature
+                    let bars = self.transport.loop_range.map(|(start, end)| (end - start) / self.transport.beats_per_bar());
+
+                    let index = TIME_SIGNATURES.iter().position(|signature| *signature == self.transport.time_signature);
+                    self.transport.time_signature = TIME_SIGNATURES[index.map_or(0, |index| (index + 1) % TIME_SIGNATURES.len())];
+                    if let Some(bars) = bars {
+                        self.transport.loop_range = Some((0.0, bars.round().max(1.0) * self.transport.beats_per_bar()));
+                    }
+                    self.changed(state, entity);
+                }
+
+                TransportEvent::NextLoop => {
+                    let bars = self.transport.loop_range.map(|(start, end)| ((end - start) / self.transport.beats_per_bar()).round());
+                    let index = LOOP_BARS.iter().position(|loop_bars| *loop_bars == bars).unwrap_or(0);
+                    let next = LOOP_BARS[(index + 1) % LOOP_BARS.len()];
+                    self.transport.loop_range = next.map(|bars| (0.0, bars * self.transport.beats_per_bar()));
+                    self.changed(state, entity);
+                }
+
+                TransportEvent::Set(transport) => {
+                    self.transport = transport.clone();
+                    self.update_labels(state);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::path::Path;
+
+use tuix::*;
+use femtovg::{
+    Canvas, renderer::OpenGl, Paint, Path as CanvasPath,
+};
+
+use super::AppEvent;
+use crate::program::{sample_files, Wavetable};
+
+// Most frames drawn, tables with more show an even spread of them
+const MAX_FRAMES: usize = 16;
+// Points each frame is drawn with
+const POINTS: usize = 128;
+
+// Body of a wavetable oscillator node showing its table, with the frames stacked from back to front
+// so the way it morphs can be seen. Clicking steps through the files in the samples directory.
+pub struct WavetableView {
+    path: Option<String>,
+    // The oscillator node the view belongs to
+    node: Entity,
+    // Frames to draw, from the first to the last
+    frames: Vec<Vec<f32>>,
+    label: Entity,
+}
+
+impl WavetableView {
+    pub fn new(path: Option<String>, node: Entity) -> Self {
+        Self {
+            path,
+            node,
+            frames: Vec::new(),
+            label: Entity::null(),
+        }
+    }
+
+    fn name(&self) -> String {
+        match &self.path {
+            Some(path) => Path::new(path).file_name().map_or(path.clone(), |name| name.to_string_lossy().to_string()),
+            None => "Sine".to_string(),
+        }
+    }
+
+    // Reads the table and picks out the frames to draw
+    fn load(&mut self) {
+        let table = match &self.path {
+            Some(path) => match Wavetable::load(Path::new(path)) {
+                Ok(table) => table,
+                Err(err) => {
+                    println!("{}", err);
+                    Wavetable::sine()
+                }
+            },
+            None => Wavetable::sine(),
+        };
+
+        let shown = table.num_frames().min(MAX_FRAMES);
+        self.frames = (0..shown)
+            .map(|index| {
+                let frame = table.frame(if shown > 1 { index * (table.num_frames() - 1) / (shown - 1) } else { 0 });
+                let step = frame.len() / POINTS;
+                frame.iter().step_by(step.max(1)).map(|&sample| sample as f32).collect()
+            })
+            .collect();
+    }
+
+    // Moves on to the next file in the samples directory, after the last one going back to the first
+    fn next_table(&mut self, state: &mut State, entity: Entity) {
+        let files = sample_files();
+        if files.is_empty() {
+            println!("No WAV files found in the samples directory");
+            return;
+        }
+
+        let current = files.iter().position(|file| Some(file.to_string_lossy().as_ref()) == self.path.as_deref());
+        let next = current.map_or(0, |index| (index + 1) % files.len());
+        let path = files[next].to_string_lossy().to_string();
+
+        self.path = Some(path.clone());
+        self.load();
+        self.label.set_text(state, &self.name());
+
+        entity.emit(state, AppEvent::SetSample(self.node, path));
+        state.insert_event(Event::new(WindowEvent::Redraw).target(Entity::root()));
+    }
+}
+
+impl Widget for WavetableView {
+    type Ret = Entity;
+    type Data = ();
+
+    fn on_build(&mut self, state: &mut State, entity: Entity) -> Self::Ret {
+        self.load();
+
+        self.label = Label::new(&self.name()).build(state, entity, |builder|
+            builder
+                .set_height(Pixels(20.0))
+                .set_child_space(Stretch(1.0))
+                .set_space(Pixels(0.0))
+                .set_hoverable(false)
+        );
+
+        entity
+            .set_height(state, Pixels(100.0))
+            .set_left(state, Pixels(5.0))
+            .set_right(state, Pixels(5.0))
+            .set_bottom(state, Pixels(5.0))
+    }
+
+    fn on_event(&mut self, state: &mut State, entity: Entity, event: &mut Event) {
+        if let Some(window_event) = event.message.downcast() {
+            match window_event {
+                WindowEvent::MouseDown(button) if *button == MouseButton::Left && event.target == entity => {
+                    self.next_table(state, entity);
+                    event.consume();
+                }
+
+                _ => {}
+            }
+        }
+    }
+
+    fn on_draw(&mut self, state: &mut State, entity: Entity, canvas: &mut Canvas<OpenGl>) {
+        let bounds = state.data.get_bounds(entity);
+
+        canvas.save();
+
+        let transform = state.data.get_transform(entity);
+        canvas.set_transform(transform[0], transform[1], transform[2], transform[3], transform[4], transform[5]);
+
+        let mut path = CanvasPath::new();
+        path.rounded_rect(bounds.x, bounds.y, bounds.w, bounds.h, 3.0);
+        canvas.fill_path(&mut path, Paint::color(femtovg::Color::rgb(20, 20, 20)));
+
+        // Later frames are drawn further up and to the right, leaving a quarter of the space for the offsets
+        let top = bounds.y + 20.0;
+        let height = (bounds.h - 20.0).max(0.0);
+        let (depth_x, depth_y) = (bounds.w / 4.0, height / 4.0);
+        let (width, height) = (bounds.w - depth_x, height - depth_y);
+
+        let count = self.frames.len();
+        for (index, frame) in self.frames.iter().enumerate().rev() {
+            let depth = if count > 1 { index as f32 / (count - 1) as f32 } else { 0.0 };
+            let left = bounds.x + depth * depth_x;
+            let middle = top + depth_y * (1.0 - depth) + height / 2.0;
+
+            let mut path = CanvasPath::new();
+            for (point, sample) in frame.iter().enumerate() {
+                let x = left + point as f32 * width / frame.len().max(1) as f32;
+                let y = middle - sample.max(-1.0).min(1.0) * height / 2.0;
+                if point == 0 {
+                    path.move_to(x, y);
+                } else {
+                    path.line_to(x, y);
+                }
+            }
+
+            // The first frame is brightest, the ones behind it fade out
+            let shade = (150.0 - 100.0 * depth) as u8;
+            let mut paint = Paint::color(femtovg::Color::rgb(50, shade, 50));
+            paint.set_line_width(1.0);
+            canvas.stroke_path(&mut path, paint);
+        }
+
+        canvas.restore();
+    }
+}
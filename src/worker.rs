@@ -0,0 +1,178 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+use crate::analysis::Analysis;
+use crate::bench::{self, bench_while, BenchReport};
+use crate::midi::MidiEvent;
+use crate::patch::Patch;
+use crate::program::Program;
+use crate::render::{process_block, write_wav, SAMPLE_RATE, STEP_SIZE};
+
+/// Everything produced by a render job
+pub struct RenderResult {
+    pub program: Program,
+    pub analysis: Analysis,
+    /// Recording of each probed output, in the order they were given
+    pub probes: Vec<Vec<f64>>,
+    /// A fresh program to be played, when asked for. The rendered one has already moved on and records the probes.
+    pub live: Option<Program>,
+}
+
+pub enum JobStatus<T> {
+    Running(f32),
+    Finished(anyhow::Result<T>),
+    Cancelled,
+}
+
+/// Work running on its own thread which counts up to `total` as it goes and stops when cancelled
+struct Job<T> {
+    progress: Arc<AtomicUsize>,
+    total: usize,
+    cancel: Arc<AtomicBool>,
+    receiver: Receiver<Option<anyhow::Result<T>>>,
+}
+
+impl<T: Send + 'static> Job<T> {
+    // `run` returns None if it saw the job being cancelled
+    fn spawn(total: usize, run: impl FnOnce(&AtomicUsize, &AtomicBool) -> anyhow::Result<Option<T>> + Send + 'static) -> Self {
+        let progress = Arc::new(AtomicUsize::new(0));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel();
+
+        let job = Self {
+            progress: progress.clone(),
+            total,
+            cancel: cancel.clone(),
+            receiver,
+        };
+
+        thread::spawn(move || {
+            let result = run(&progress, &cancel);
+
+            // Nothing is listening if the job was dropped
+            let _ = sender.send(result.transpose());
+        });
+
+        job
+    }
+
+    fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    fn poll(&self) -> JobStatus<T> {
+        match self.receiver.try_recv() {
+            Ok(Some(result)) => JobStatus::Finished(result),
+            Ok(None) => JobStatus::Cancelled,
+            Err(TryRecvError::Empty) => {
+                JobStatus::Running(self.progress.load(Ordering::Relaxed) as f32 / self.total.max(1) as f32)
+            }
+            Err(TryRecvError::Disconnected) => {
+                JobStatus::Finished(Err(anyhow::anyhow!("Worker stopped unexpectedly")))
+            }
+        }
+    }
+}
+
+impl<T> Drop for Job<T> {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Compiles and renders a patch on a worker thread
+pub struct RenderJob(Job<RenderResult>);
+
+impl RenderJob {
+    /// Starts compiling and rendering `steps` blocks of the patch playing `midi`, recording the outputs
+    /// given as (node index, port) in `probes` along the way. The output is written to `wav_path`, if
+    /// given, when the render completes. With `live` the patch is also compiled for playing.
+    pub fn spawn(patch: Patch, probes: Vec<(usize, usize)>, steps: usize, midi: Vec<MidiEvent>, wav_path: Option<PathBuf>, live: bool) -> Self {
+        Self(Job::spawn(steps, move |progress, cancel| {
+            run_job(&patch, &probes, steps, &midi, wav_path.as_deref(), live, progress, cancel)
+        }))
+    }
+
+    /// Asks the worker to stop at the next block
+    pub fn cancel(&self) {
+        self.0.cancel();
+    }
+
+    pub fn poll(&self) -> JobStatus<RenderResult> {
+        self.0.poll()
+    }
+}
+
+/// Benchmarks a patch on a worker thread
+pub struct BenchJob(Job<BenchReport>);
+
+impl BenchJob {
+    /// Starts timing the patch at each of the benchmark's block sizes
+    pub fn spawn(patch: Patch) -> Self {
+        Self(Job::spawn(bench::total_blocks(), move |progress, cancel| {
+            bench_while(&patch, || {
+                if cancel.load(Ordering::Relaxed) {
+                    return false;
+                }
+                progress.fetch_add(1, Ordering::Relaxed);
+                true
+            })
+        }))
+    }
+
+    /// Asks the worker to stop at the next block
+    pub fn cancel(&self) {
+        self.0.cancel();
+    }
+
+    pub fn poll(&self) -> JobStatus<BenchReport> {
+        self.0.poll()
+    }
+}
+
+// Returns None if the job was cancelled
+fn run_job(patch: &Patch, probes: &[(usize, usize)], steps: usize, midi: &[MidiEvent], wav_path: Option<&Path>, live: bool, progress: &AtomicUsize, cancel: &AtomicBool) -> anyhow::Result<Option<RenderResult>> {
+    let mut program = Program::probed(patch, probes)?;
+    let live = if live { Some(patch.compile()?) } else { None };
+
+    let mut output = Vec::with_capacity(steps * STEP_SIZE);
+    let mut recordings = vec![Vec::with_capacity(steps * STEP_SIZE); probes.len()];
+    let mut n = 0;
+    for _ in 0..steps {
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        let mut block = [0.0; STEP_SIZE];
+        process_block(&mut program, &mut n, &mut block, midi);
+        output.extend_from_slice(&block);
+
+        // The probed outputs are read from the same render as the output
+        for (probe, recording) in recordings.iter_mut().enumerate() {
+            program.probe(probe, &mut block);
+            recording.extend_from_slice(&block);
+        }
+
+        progress.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // A job cancelled after its last block must not overwrite the file with a stale render
+    if cancel.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+
+    if let Some(wav_path) = wav_path {
+        write_wav(&output, wav_path)?;
+    }
+    let analysis = Analysis::new(&output, SAMPLE_RATE);
+
+    Ok(Some(RenderResult {
+        program,
+        analysis,
+        probes: recordings,
+        live,
+    }))
+}